@@ -0,0 +1,142 @@
+//! Instrumenting a project received as a zip archive, see `--from-archive`, and handing over
+//! the instrumented project as one, see `--to-archive`.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::error::{CliError, Result};
+use crate::remote::LOCAL_ONLY_FILES;
+use crate::utils::to_zip_entry_name;
+
+/// A temporary directory the content of an archive was extracted to, removed when dropped.
+pub struct ExtractedArchive {
+    dir: PathBuf,
+}
+
+impl ExtractedArchive {
+    /// Extracts `archive` to a new temporary directory. Fails without extracting anything if
+    /// an entry would be written outside of it, e.g. named `../x` or `/etc/x`.
+    pub fn extract(archive: &Path) -> Result<Self> {
+        let file = File::open(archive).map_err(|e| CliError::path(archive, e))?;
+        let mut zip = ZipArchive::new(file).map_err(|e| {
+            CliError::Config(format!(
+                "{} is not a valid zip archive: {}",
+                archive.display(),
+                e
+            ))
+        })?;
+        for i in 0..zip.len() {
+            let entry = zip.by_index_raw(i)?;
+            if entry.enclosed_name().is_none() || entry.is_symlink() {
+                return Err(CliError::Config(format!(
+                    "Refusing to extract {}: its entry {} would be written outside of the project",
+                    archive.display(),
+                    entry.name()
+                )));
+            }
+        }
+
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "ariana-archive-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).map_err(|e| CliError::path(&dir, e))?;
+        // Removes what was extracted if the rest fails
+        let extracted = ExtractedArchive { dir };
+        for i in 0..zip.len() {
+            let mut entry = zip.by_index(i)?;
+            let Some(name) = entry.enclosed_name() else {
+                continue;
+            };
+            let path = extracted.dir.join(name);
+            if entry.is_dir() {
+                fs::create_dir_all(&path)?;
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = File::create(&path).map_err(|e| CliError::path(&path, e))?;
+            io::copy(&mut entry, &mut file)?;
+            #[cfg(unix)]
+            if let Some(mode) = entry.unix_mode() {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o777))?;
+            }
+        }
+        Ok(extracted)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for ExtractedArchive {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Writes the content of `dir` to the zip `archive`, following links so that linked files
+/// and directories are archived too, and leaving out the files that only matter to this run.
+/// Returns the number of files archived.
+pub fn write_archive(dir: &Path, archive: &Path) -> Result<usize> {
+    let file = File::create(archive).map_err(|e| CliError::path(archive, e))?;
+    let mut zip = ZipWriter::new(file);
+    let mut visited = HashSet::new();
+    let mut pending = vec![dir.to_path_buf()];
+    let mut archived = 0;
+    while let Some(current) = pending.pop() {
+        // Links to a parent directory would be followed forever
+        if !visited.insert(fs::canonicalize(&current)?) {
+            continue;
+        }
+        let mut entries = fs::read_dir(&current)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            if current == dir
+                && LOCAL_ONLY_FILES.contains(&entry.file_name().to_string_lossy().as_ref())
+            {
+                continue;
+            }
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    eprintln!(
+                        "[Ariana] Leaving {} out of the archive: {}",
+                        path.display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            if metadata.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let name = to_zip_entry_name(&path, dir)?;
+            let mut options =
+                FileOptions::<()>::default().compression_method(CompressionMethod::Deflated);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                options = options.unix_permissions(metadata.permissions().mode());
+            }
+            zip.start_file(name, options)?;
+            zip.write_all(&fs::read(&path)?)?;
+            archived += 1;
+        }
+    }
+    zip.finish()?;
+    Ok(archived)
+}
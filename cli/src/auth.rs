@@ -1,28 +1,30 @@
-use anyhow::{anyhow, Result};
-use ariana_server::web::auth::{
-    AuthResponse, RequestLoginCodeRequest, ValidateLoginCodeRequest,
-};
+use ariana_server::web::auth::{AuthResponse, RequestLoginCodeRequest, ValidateLoginCodeRequest};
 use rand::{distributions::Alphanumeric, Rng};
 use reqwest::{blocking::Client, StatusCode};
 use std::io::{self, Write};
 
-use crate::config::Config;
+use crate::config::{Config, ConfigOverrides};
+use crate::error::{CliError, Result};
+use crate::utils::api_endpoint;
 
-pub async fn ensure_authenticated(api_url: &str) -> Result<()> {
-    let mut config = Config::load()?;
+pub async fn ensure_authenticated(api_url: &str, overrides: &ConfigOverrides) -> Result<()> {
+    let mut config = Config::load(overrides)?;
 
     // Try existing JWT if available
     if let Some(jwt) = &config.jwt {
         let client = Client::new();
         let res = client
-            .get(&format!("{}/authenticated/account", api_url))
+            .get(api_endpoint(api_url, "authenticated/account"))
             .header("Authorization", format!("Bearer {}", jwt))
             .send()?;
 
         if res.status().is_success() {
             println!("[Ariana] Successfully authenticated with existing credentials");
             let account: AuthResponse = res.json()?;
-            println!("[Ariana] Account balance: {} credits", account.account.credits);
+            println!(
+                "[Ariana] Account balance: {} credits",
+                account.account.credits
+            );
             return Ok(());
         }
 
@@ -40,8 +42,10 @@ pub async fn ensure_authenticated(api_url: &str) -> Result<()> {
     // Try to request login code
     let client = Client::new();
     let res = client
-        .post(&format!("{}/unauthenticated/request-login-code", api_url))
-        .json(&RequestLoginCodeRequest { email: email.clone() })
+        .post(api_endpoint(api_url, "unauthenticated/request-login-code"))
+        .json(&RequestLoginCodeRequest {
+            email: email.clone(),
+        })
         .send()?;
 
     match res.status() {
@@ -55,7 +59,7 @@ pub async fn ensure_authenticated(api_url: &str) -> Result<()> {
             let code = code.trim().to_string();
 
             let res = client
-                .post(&format!("{}/unauthenticated/validate-login-code", api_url))
+                .post(api_endpoint(api_url, "unauthenticated/validate-login-code"))
                 .json(&ValidateLoginCodeRequest {
                     email: email.clone(),
                     code,
@@ -63,18 +67,24 @@ pub async fn ensure_authenticated(api_url: &str) -> Result<()> {
                 .send()?;
 
             if !res.status().is_success() {
-                return Err(anyhow!("Invalid login code: {}", res.text()?));
+                return Err(CliError::Auth(format!(
+                    "Invalid login code: {}",
+                    res.text()?
+                )));
             }
 
             let auth_response: AuthResponse = res.json()?;
             config.set_jwt(auth_response.token)?;
             println!("[Ariana] Successfully logged in");
-            println!("[Ariana] Account balance: {} credits", auth_response.account.credits);
+            println!(
+                "[Ariana] Account balance: {} credits",
+                auth_response.account.credits
+            );
         }
         StatusCode::NOT_FOUND => {
             // New account - register
             println!("[Ariana] No account found with this email. Creating new account...");
-            
+
             // Generate random password
             let password: String = rand::thread_rng()
                 .sample_iter(&Alphanumeric)
@@ -83,7 +93,7 @@ pub async fn ensure_authenticated(api_url: &str) -> Result<()> {
                 .collect();
 
             let res = client
-                .post(&format!("{}/unauthenticated/register", api_url))
+                .post(api_endpoint(api_url, "unauthenticated/register"))
                 .json(&ariana_server::web::auth::RegisterRequest {
                     email: email.clone(),
                     password: password.clone(),
@@ -91,7 +101,10 @@ pub async fn ensure_authenticated(api_url: &str) -> Result<()> {
                 .send()?;
 
             if !res.status().is_success() {
-                return Err(anyhow!("Failed to register: {}", res.text()?));
+                return Err(CliError::Auth(format!(
+                    "Failed to register: {}",
+                    res.text()?
+                )));
             }
 
             println!("[Ariana] Account created. Verification code sent to your email.");
@@ -102,19 +115,20 @@ pub async fn ensure_authenticated(api_url: &str) -> Result<()> {
             let code = code.trim().to_string();
 
             let res = client
-                .post(&format!("{}/unauthenticated/validate-email", api_url))
-                .json(&ariana_server::web::auth::VerifyEmailRequest {
-                    code: code.clone(),
-                })
+                .post(api_endpoint(api_url, "unauthenticated/validate-email"))
+                .json(&ariana_server::web::auth::VerifyEmailRequest { code: code.clone() })
                 .send()?;
 
             if !res.status().is_success() {
-                return Err(anyhow!("Invalid verification code: {}", res.text()?));
+                return Err(CliError::Auth(format!(
+                    "Invalid verification code: {}",
+                    res.text()?
+                )));
             }
 
             // Now login
             let res = client
-                .post(&format!("{}/unauthenticated/login", api_url))
+                .post(api_endpoint(api_url, "unauthenticated/login"))
                 .json(&ariana_server::web::auth::LoginRequest {
                     email: email.clone(),
                     password: password.clone(),
@@ -122,19 +136,25 @@ pub async fn ensure_authenticated(api_url: &str) -> Result<()> {
                 .send()?;
 
             if !res.status().is_success() {
-                return Err(anyhow!("Failed to login after registration: {}", res.text()?));
+                return Err(CliError::Auth(format!(
+                    "Failed to login after registration: {}",
+                    res.text()?
+                )));
             }
 
             let auth_response: AuthResponse = res.json()?;
             config.set_jwt(auth_response.token)?;
             println!("[Ariana] Successfully registered and logged in");
-            println!("[Ariana] Account balance: {} credits", auth_response.account.credits);
+            println!(
+                "[Ariana] Account balance: {} credits",
+                auth_response.account.credits
+            );
         }
         _ => {
-            return Err(anyhow!(
+            return Err(CliError::Auth(format!(
                 "Unexpected error requesting login code: {}",
                 res.text()?
-            ));
+            )));
         }
     }
 
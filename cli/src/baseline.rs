@@ -0,0 +1,97 @@
+use regex::Regex;
+use similar::TextDiff;
+use std::fs;
+use std::path::Path;
+
+use crate::error::{CliError, Result};
+
+/// Name of the file in `.ariana` holding the output recorded with `--baseline`. It is kept
+/// when `.ariana` is recreated.
+pub const BASELINE_FILE: &str = "baseline.txt";
+
+/// Volatile content replaced before recording or comparing output, unless patterns are given.
+const DEFAULT_NORMALIZE_PATTERNS: &[&str] = &[
+    // Dates and times, e.g. 2025-04-01T12:34:56.789Z or 2025-04-01 12:34:56
+    r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(\.\d+)?(Z|[+-]\d{2}:?\d{2})?",
+    // Times of day, e.g. 12:34:56.789
+    r"\b\d{2}:\d{2}:\d{2}(\.\d+)?\b",
+];
+
+const NORMALIZED: &str = "<normalized>";
+
+/// What a run does with the baseline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaselineMode {
+    /// Records the output of the commands as the new baseline.
+    Record,
+    /// Compares the output of the commands with the recorded baseline.
+    Compare,
+}
+
+/// The stdout of the commands, traces stripped, recorded once and compared on later runs to
+/// catch regressions.
+pub struct Baseline {
+    pub mode: BaselineMode,
+    patterns: Vec<Regex>,
+}
+
+impl Baseline {
+    /// Matches of `normalize_patterns`, or of timestamps without any, are replaced in the
+    /// output so that they don't make runs differ.
+    pub fn new(mode: BaselineMode, normalize_patterns: &[String]) -> Result<Self> {
+        let patterns = if normalize_patterns.is_empty() {
+            DEFAULT_NORMALIZE_PATTERNS
+                .iter()
+                .map(|pattern| Regex::new(pattern).map_err(|e| CliError::Config(e.to_string())))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            normalize_patterns
+                .iter()
+                .map(|pattern| {
+                    Regex::new(pattern).map_err(|e| {
+                        CliError::Config(format!(
+                            "Invalid normalization pattern `{}`: {}",
+                            pattern, e
+                        ))
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+        Ok(Baseline { mode, patterns })
+    }
+
+    pub fn normalize(&self, line: &str) -> String {
+        let mut line = line.to_string();
+        for pattern in &self.patterns {
+            line = pattern.replace_all(&line, NORMALIZED).into_owned();
+        }
+        line
+    }
+
+    /// Writes normalized `lines` as the baseline in `ariana_dir`.
+    pub fn record(&self, ariana_dir: &Path, lines: &[String]) -> Result<()> {
+        let mut content = lines.join("\n");
+        content.push('\n');
+        let path = ariana_dir.join(BASELINE_FILE);
+        fs::write(&path, content).map_err(|e| CliError::path(&path, e))?;
+        Ok(())
+    }
+
+    /// A unified diff of the baseline in `ariana_dir` against normalized `lines`, `None` if
+    /// they are the same.
+    pub fn compare(&self, ariana_dir: &Path, lines: &[String]) -> Result<Option<String>> {
+        let path = ariana_dir.join(BASELINE_FILE);
+        let baseline = fs::read_to_string(&path).map_err(|e| CliError::path(&path, e))?;
+        let mut output = lines.join("\n");
+        output.push('\n');
+        if baseline == output {
+            return Ok(None);
+        }
+        Ok(Some(
+            TextDiff::from_lines(&baseline, &output)
+                .unified_diff()
+                .header("baseline", "this run")
+                .to_string(),
+        ))
+    }
+}
@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::ConfigOverrides;
+use crate::error::{CliError, Result};
+use crate::utils::api_endpoint;
+
+/// File in the config directory caching the capabilities last fetched from the server.
+pub const CAPABILITIES_CACHE_FILE: &str = "capabilities.json";
+
+/// How long cached capabilities are used without asking the server again.
+pub const CAPABILITIES_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A language the server can instrument.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SupportedLanguage {
+    pub name: String,
+    /// Extensions of its files, without dot, e.g. `["py"]`.
+    pub extensions: Vec<String>,
+}
+
+/// What the server can instrument, as answered by its `capabilities` endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerCapabilities {
+    pub languages: Vec<SupportedLanguage>,
+}
+
+impl ServerCapabilities {
+    /// What servers that predate the `capabilities` endpoint can instrument.
+    pub fn builtin() -> Self {
+        let language = |name: &str, extensions: &[&str]| SupportedLanguage {
+            name: name.to_string(),
+            extensions: extensions.iter().map(|ext| ext.to_string()).collect(),
+        };
+        ServerCapabilities {
+            languages: vec![
+                language("JavaScript", &["js", "jsx"]),
+                language("TypeScript", &["ts", "tsx"]),
+                language("Python", &["py"]),
+            ],
+        }
+    }
+
+    /// Extensions of the files the server can instrument, lowercase and without dot.
+    pub fn extensions(&self) -> Vec<String> {
+        let mut extensions = self
+            .languages
+            .iter()
+            .flat_map(|language| &language.extensions)
+            .map(|ext| ext.trim_start_matches('.').to_lowercase())
+            .collect::<Vec<_>>();
+        extensions.sort();
+        extensions.dedup();
+        extensions
+    }
+}
+
+/// Where `load_capabilities` got the capabilities from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapabilitiesSource {
+    Server,
+    /// Fetched earlier, because they were fresh enough or the server couldn't tell.
+    Cache,
+    /// `ServerCapabilities::builtin`, the server couldn't tell and nothing was cached.
+    Builtin,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedCapabilities {
+    api_url: String,
+    /// Seconds since the epoch.
+    fetched_at: u64,
+    capabilities: ServerCapabilities,
+}
+
+/// What the server at `api_url` can instrument. Capabilities cached less than
+/// `CAPABILITIES_CACHE_TTL` ago are used without asking it, unless `refresh`. When the server
+/// can't tell, e.g. because it doesn't know the endpoint, the cached ones are used however old,
+/// or else the built-in ones. They are cached in the config directory of `overrides`.
+pub async fn load_capabilities(
+    api_url: &str,
+    refresh: bool,
+    overrides: &ConfigOverrides,
+) -> (ServerCapabilities, CapabilitiesSource) {
+    let cache_path = cache_path(overrides);
+    let cached = cache_path
+        .as_deref()
+        .and_then(|cache_path| load_cached_capabilities(cache_path, api_url));
+    if let Some(cached) = &cached {
+        let age = now_secs().saturating_sub(cached.fetched_at);
+        if !refresh && Duration::from_secs(age) < CAPABILITIES_CACHE_TTL {
+            return (cached.capabilities.clone(), CapabilitiesSource::Cache);
+        }
+    }
+    match fetch_capabilities(api_url).await {
+        Ok(capabilities) => {
+            if let Some(cache_path) = &cache_path {
+                store_cached_capabilities(cache_path, api_url, &capabilities);
+            }
+            (capabilities, CapabilitiesSource::Server)
+        }
+        Err(_) => match cached {
+            Some(cached) => (cached.capabilities, CapabilitiesSource::Cache),
+            None => (ServerCapabilities::builtin(), CapabilitiesSource::Builtin),
+        },
+    }
+}
+
+async fn fetch_capabilities(api_url: &str) -> Result<ServerCapabilities> {
+    let response = reqwest::Client::new()
+        .get(api_endpoint(api_url, "unauthenticated/capabilities"))
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(CliError::Server {
+            status: response.status(),
+            message: "Failed to get the server capabilities".to_string(),
+        });
+    }
+    let capabilities: ServerCapabilities = response.json().await?;
+    if capabilities.extensions().is_empty() {
+        return Err(CliError::Instrumentation(
+            "The server listed no language it can instrument".to_string(),
+        ));
+    }
+    Ok(capabilities)
+}
+
+fn cache_path(overrides: &ConfigOverrides) -> Option<PathBuf> {
+    overrides
+        .config_dir()
+        .ok()
+        .map(|dir| dir.join(CAPABILITIES_CACHE_FILE))
+}
+
+/// The cached capabilities of the server at `api_url`, `None` if those cached are another
+/// server's or can't be read.
+fn load_cached_capabilities(path: &Path, api_url: &str) -> Option<CachedCapabilities> {
+    let content = fs::read_to_string(path).ok()?;
+    let cached: CachedCapabilities = serde_json::from_str(&content).ok()?;
+    (cached.api_url == api_url).then_some(cached)
+}
+
+/// Caches the capabilities, failing silently: they are fetched again next time.
+fn store_cached_capabilities(path: &Path, api_url: &str, capabilities: &ServerCapabilities) {
+    let cached = CachedCapabilities {
+        api_url: api_url.to_string(),
+        fetched_at: now_secs(),
+        capabilities: capabilities.clone(),
+    };
+    if let Some(parent) = path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    if let Ok(content) = serde_json::to_vec(&cached) {
+        let _ = fs::write(path, content);
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
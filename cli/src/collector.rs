@@ -1,73 +1,343 @@
+use crate::error::{CliError, Result};
+use crate::lang_map::LangMap;
 use crate::utils::{compute_dest_path, should_copy_or_link_directory, should_explore_directory};
-use anyhow::Result;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
-use ignore::gitignore::GitignoreBuilder;
 
 pub struct CollectedItems {
     pub directories_to_link_or_copy: Vec<(PathBuf, PathBuf)>,
     pub files_to_instrument: Vec<(PathBuf, PathBuf)>,
     pub files_to_link_or_copy: Vec<(PathBuf, PathBuf)>,
+    /// Source files that would be instrumented but can't be, they are linked or copied as is.
+    pub skipped_files: Vec<SkippedFile>,
 }
 
-pub fn collect_items(project_root: &Path, ariana_dir: &Path) -> Result<CollectedItems> {
+/// A source file left uninstrumented, and why.
+#[derive(Debug, Clone)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub reason: String,
+    pub cause: SkipCause,
+}
+
+/// Who left a `SkippedFile` uninstrumented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipCause {
+    /// Ariana, before or after asking the server, e.g. for a file too large or not UTF-8.
+    Local,
+    /// The server, which answered without instrumented code for it, usually because it
+    /// doesn't support its language.
+    Declined,
+    /// The instrumentation request failed, or its answer couldn't be used.
+    Failed,
+}
+
+/// Settings for `collect_items`, mostly coming from the CLI flags.
+#[derive(Debug, Clone, Default)]
+pub struct CollectOptions {
+    /// Directory names never explored, on top of the default skip list.
+    pub exclude_dirs: Vec<String>,
+    /// Directory names explored even though the default skip list would skip them.
+    pub include_dirs: Vec<String>,
+    /// Ignore files applied like a `.arianaignore` at the project root, wherever they are.
+    pub exclude_from: Vec<PathBuf>,
+    /// Extensions of the files the server can instrument, lowercase and without dot, see
+    /// `capabilities::load_capabilities`. `DEFAULT_SOURCE_EXTENSIONS` if `None`.
+    pub source_extensions: Option<Vec<String>>,
+    /// Files it maps to a language are source files whatever their extension.
+    pub lang_map: LangMap,
+    /// Leaves JavaScript and TypeScript files uninstrumented.
+    pub skip_js: bool,
+    /// Leaves Python files uninstrumented.
+    pub skip_python: bool,
+    /// Instruments files of any size, instead of leaving those over
+    /// `MAX_INSTRUMENTED_FILE_BYTES` uninstrumented.
+    pub allow_large_files: bool,
+    /// Absolute paths of files instrumented whatever their size.
+    pub allowed_large_files: Vec<PathBuf>,
+    /// Files are instrumented in place, so instrumenting a symlink edits its target.
+    pub inplace: bool,
+    /// Instruments symlinks to files outside the project even with `inplace`.
+    pub instrument_external_symlinks: bool,
+    /// Explores symlinked directories for files to instrument, instead of only mirroring them
+    /// into `.ariana` as they are. Those leading into the project, or to a directory already
+    /// explored, are never explored.
+    pub follow_symlinks: bool,
+    /// Files matching one of these are left uninstrumented, see `is_test_file`. Usually
+    /// `DEFAULT_TEST_PATTERNS` with `--skip-tests`, empty otherwise.
+    pub test_patterns: Vec<String>,
+}
+
+/// Files this big or bigger are not instrumented, unless allowed through `CollectOptions`.
+pub const MAX_INSTRUMENTED_FILE_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Extensions of the files instrumented when the server didn't tell which it can instrument.
+pub const DEFAULT_SOURCE_EXTENSIONS: &[&str] = &["js", "ts", "tsx", "jsx", "py"];
+
+/// Test files left uninstrumented with `--skip-tests`, see `is_test_file` for how they match.
+pub const DEFAULT_TEST_PATTERNS: &[&str] = &[
+    "*.test.*",
+    "*.spec.*",
+    "__tests__/",
+    "test_*.py",
+    "*_test.py",
+];
+
+impl CollectOptions {
+    fn should_explore_directory(&self, dir_name: &str) -> bool {
+        if self.exclude_dirs.iter().any(|dir| dir == dir_name) {
+            return false;
+        }
+        if self.include_dirs.iter().any(|dir| dir == dir_name) {
+            return true;
+        }
+        should_explore_directory(dir_name)
+    }
+
+    fn is_size_cap_lifted(&self, path: &Path) -> bool {
+        self.allow_large_files
+            || self
+                .allowed_large_files
+                .iter()
+                .any(|allowed| allowed == path)
+    }
+
+    /// Whether a source file symlinked from outside the project is left uninstrumented, so that
+    /// files the user may not expect to be modified aren't.
+    fn skips_external_symlinks(&self) -> bool {
+        self.inplace && !self.instrument_external_symlinks
+    }
+
+    /// Whether `path` matches one of `test_patterns`. Patterns ending with `/` match a directory
+    /// of that name anywhere between `project_root` and `path`, the others match the file name.
+    /// In both, `*` stands for any sequence of characters.
+    fn is_test_file(&self, project_root: &Path, path: &Path) -> bool {
+        if self.test_patterns.is_empty() {
+            return false;
+        }
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        let relative_parent = path
+            .parent()
+            .and_then(|parent| parent.strip_prefix(project_root).ok())
+            .unwrap_or(Path::new(""));
+        self.test_patterns
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('/') {
+                Some(dir_pattern) => relative_parent.components().any(|component| {
+                    wildcard_match(dir_pattern, &component.as_os_str().to_string_lossy())
+                }),
+                None => wildcard_match(pattern, file_name),
+            })
+    }
+
+    fn is_source_extension(&self, extension: &str) -> bool {
+        match &self.source_extensions {
+            Some(extensions) => extensions.iter().any(|ext| ext == extension),
+            None => DEFAULT_SOURCE_EXTENSIONS.contains(&extension),
+        }
+    }
+
+    fn is_language_enabled(&self, extension: &str) -> bool {
+        match extension {
+            "js" | "ts" | "tsx" | "jsx" => !self.skip_js,
+            "py" => !self.skip_python,
+            _ => true,
+        }
+    }
+}
+
+/// What collection does with a path of the project.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathAction {
+    /// Sent to the server to be instrumented.
+    Instrument,
+    /// Mirrored into `.ariana` as is, by itself or as part of a linked directory.
+    LinkOrCopy,
+    /// Left out of `.ariana`.
+    Skip,
+}
+
+/// Classifies a single path the way `collect_items` would, without walking the whole project:
+/// only the ignore files of the project root and of the directories leading to `path` are read,
+/// so the patterns of ignore files elsewhere in the project, which `collect_items` also applies
+/// to the paths it walks after reading them, are not. Meant for handling changes to a few paths
+/// after a full collection.
+pub fn classify_path(
+    project_root: &Path,
+    path: &Path,
+    options: &CollectOptions,
+) -> Result<PathAction> {
+    let Ok(relative_path) = path.strip_prefix(project_root) else {
+        return Ok(PathAction::Skip);
+    };
+
+    let mut ignore = IgnoreRules::new(project_root, options)?;
+    let mut dir = project_root.to_path_buf();
+    for component in relative_path
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+    {
+        dir.push(component);
+        let dir_name = dir.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        if !should_copy_or_link_directory(dir_name) {
+            return Ok(PathAction::Skip);
+        }
+        ignore.add_dir(&dir);
+        if !explores_directory(&ignore.matcher, &dir, options) {
+            // Not looked into, so mirrored along with this directory
+            return Ok(PathAction::LinkOrCopy);
+        }
+    }
+
+    if path.is_dir() {
+        let dir_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        if should_copy_or_link_directory(dir_name) {
+            Ok(PathAction::LinkOrCopy)
+        } else {
+            Ok(PathAction::Skip)
+        }
+    } else if path.is_symlink() && is_source_file(path, options) {
+        match external_symlink_target(project_root, path) {
+            Some(_) if !options.skips_external_symlinks() => {
+                Ok(classify_file(project_root, path, options))
+            }
+            _ => Ok(PathAction::LinkOrCopy),
+        }
+    } else {
+        Ok(classify_file(project_root, path, options))
+    }
+}
+
+pub fn collect_items(
+    project_root: &Path,
+    ariana_dir: &Path,
+    options: &CollectOptions,
+) -> Result<CollectedItems> {
     let mut directories_to_link_or_copy = HashSet::new();
-    let mut parents_of_files = HashSet::new();
+    let mut directories_excluded_from_linking = HashSet::new();
+    let mut parents_of_instrumented_files = HashSet::new();
     let mut files_to_instrument = HashSet::new();
     let mut files_to_link_or_copy = HashSet::new();
+    let mut skipped_files = vec![];
 
-    let mut ignore_builder = GitignoreBuilder::new(project_root);
-    // Add local .gitignore if it exists
-    ignore_builder.add(project_root.join(".gitignore"));
-    // Add .arianaignore if it exists
-    ignore_builder.add(project_root.join(".arianaignore"));
+    let mut ignore = IgnoreRules::new(project_root, options)?;
+
+    let canonical_root = fs::canonicalize(project_root)?;
+    let mut followed_targets = HashSet::new();
 
     let mut entries = fs::read_dir(project_root)?.collect::<Vec<_>>();
     while let Some(entry) = entries.pop() {
         let entry = entry?;
         let path = entry.path();
 
-        ignore_builder.add(path.join(".gitignore"));
-        ignore_builder.add(path.join(".arianaignore"));
-        let ignore = ignore_builder.build()?;
+        ignore.add_dir(&path);
 
         let file_type = entry.file_type().unwrap();
 
-        if file_type.is_dir() {
+        if file_type.is_dir() || (file_type.is_symlink() && path.is_dir()) {
             let dir_name = path.file_name().unwrap().to_str().unwrap_or("");
+            let follows = !file_type.is_symlink()
+                || (options.follow_symlinks
+                    && follows_symlinked_directory(&canonical_root, &path, &mut followed_targets));
 
-            if ignore.matched(&path, path.is_dir()).is_none() && should_explore_directory(&dir_name) {
+            // Exploring (looking for files to instrument) and linking (mirroring the
+            // directory into .ariana) are decided independently.
+            if follows && explores_directory(&ignore.matcher, &path, options) {
                 entries.extend(fs::read_dir(&path)?);
             }
 
             if should_copy_or_link_directory(dir_name) {
                 directories_to_link_or_copy.insert(path.to_owned());
+            } else {
+                directories_excluded_from_linking.insert(path.to_owned());
             }
-        } else if file_type.is_file() {
-            let mut tmp = path.clone();
-            while let Some(parent) = tmp.parent() {
-                if parents_of_files.contains(parent) {
-                    break;
+        } else if file_type.is_file()
+            || (file_type.is_symlink() && path.is_file() && is_source_file(&path, options))
+        {
+            if file_type.is_symlink() {
+                match external_symlink_target(project_root, &path) {
+                    Some(target) if options.skips_external_symlinks() => {
+                        eprintln!(
+                            "[Ariana] WARNING: not instrumenting {}, it links to {} outside the project which --inplace would modify. Use --instrument-external-symlinks to instrument it anyway",
+                            path.display(),
+                            target.display()
+                        );
+                        skipped_files.push(SkippedFile {
+                            path: path.to_owned(),
+                            reason: format!(
+                                "links to {} outside the project, see --instrument-external-symlinks",
+                                target.display()
+                            ),
+                            cause: SkipCause::Local,
+                        });
+                        files_to_link_or_copy.insert(path.to_owned());
+                        continue;
+                    }
+                    Some(target) => eprintln!(
+                        "[Ariana] WARNING: {} links to {} outside the project",
+                        path.display(),
+                        target.display()
+                    ),
+                    // Its target is collected on its own
+                    None => {
+                        files_to_link_or_copy.insert(path.to_owned());
+                        continue;
+                    }
                 }
-                parents_of_files.insert(parent.to_owned());
-                tmp = parent.to_owned();
             }
-            if should_instrument_file(&path) {
+            if classify_file(project_root, &path, options) == PathAction::Instrument {
+                let mut tmp = path.clone();
+                while let Some(parent) = tmp.parent() {
+                    if parents_of_instrumented_files.contains(parent) {
+                        break;
+                    }
+                    parents_of_instrumented_files.insert(parent.to_owned());
+                    tmp = parent.to_owned();
+                }
                 files_to_instrument.insert(path.to_owned());
             } else {
+                if is_source_file(&path, options) {
+                    if let Some(reason) = skip_reason(&path, options) {
+                        skipped_files.push(SkippedFile {
+                            path: path.to_owned(),
+                            reason,
+                            cause: SkipCause::Local,
+                        });
+                    }
+                }
                 files_to_link_or_copy.insert(path.to_owned());
             }
         }
     }
 
+    // A directory holding files to instrument can't be linked as a whole, its content is
+    // mirrored entry by entry instead. Any other directory is linked in one go, which also
+    // covers everything below it.
     let directories_to_link_or_copy = directories_to_link_or_copy
-        .difference(&parents_of_files)
+        .difference(&parents_of_instrumented_files)
+        .cloned()
+        .collect::<HashSet<_>>();
+    let directories_to_link_or_copy = directories_to_link_or_copy
+        .iter()
+        .filter(|dir| !has_ancestor_in(dir, &directories_to_link_or_copy, project_root))
+        .filter(|dir| !has_ancestor_in(dir, &directories_excluded_from_linking, project_root))
+        .cloned()
         .collect::<HashSet<_>>();
 
     let files_to_link_or_copy = files_to_link_or_copy
-        .difference(&files_to_instrument)
-        .collect::<HashSet<_>>(); // redundant but for my sanity
+        .difference(&files_to_instrument) // redundant but for my sanity
+        .filter(|file| !has_ancestor_in(file, &directories_to_link_or_copy, project_root))
+        .filter(|file| !has_ancestor_in(file, &directories_excluded_from_linking, project_root))
+        .collect::<HashSet<_>>();
 
     Ok(CollectedItems {
         directories_to_link_or_copy: directories_to_link_or_copy
@@ -97,30 +367,296 @@ pub fn collect_items(project_root: &Path, ariana_dir: &Path) -> Result<Collected
                 )
             })
             .collect(),
+        skipped_files,
     })
 }
 
-fn should_instrument_file(path: &Path) -> bool {
-    let valid_extensions = ["js", "ts", "tsx", "jsx", "py"];
+/// Where `path` leads if it is a symlink resolving outside of `project_root`.
+fn external_symlink_target(project_root: &Path, path: &Path) -> Option<PathBuf> {
+    if !path.is_symlink() {
+        return None;
+    }
+    let target = fs::canonicalize(path).ok()?;
+    let project_root = fs::canonicalize(project_root).ok()?;
+    (!target.starts_with(&project_root)).then_some(target)
+}
+
+/// Whether the symlinked directory `path` leads outside of the project to a directory not
+/// followed yet, in which case its target is added to `followed_targets`.
+fn follows_symlinked_directory(
+    canonical_root: &Path,
+    path: &Path,
+    followed_targets: &mut HashSet<PathBuf>,
+) -> bool {
+    let Ok(target) = fs::canonicalize(path) else {
+        return false;
+    };
+    !target.starts_with(canonical_root) && followed_targets.insert(target)
+}
+
+/// Whether collection looks into `dir` for files to instrument. Directories that are not
+/// explored are still linked or copied as a whole.
+fn explores_directory(ignore: &Gitignore, dir: &Path, options: &CollectOptions) -> bool {
+    let dir_name = dir.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    ignore.matched(dir, true).is_none() && options.should_explore_directory(dir_name)
+}
+
+fn classify_file(project_root: &Path, path: &Path, options: &CollectOptions) -> PathAction {
+    if should_instrument_file(project_root, path, options) {
+        PathAction::Instrument
+    } else {
+        PathAction::LinkOrCopy
+    }
+}
+
+/// The ignore files read so far, with the `exclude_from` files and those of the project root,
+/// and the matcher built from them.
+struct IgnoreRules {
+    builder: GitignoreBuilder,
+    /// Last matcher that could be built, kept if adding some ignore file breaks the build.
+    matcher: Gitignore,
+    build_error_reported: bool,
+}
+
+impl IgnoreRules {
+    fn new(project_root: &Path, options: &CollectOptions) -> Result<Self> {
+        let mut builder = GitignoreBuilder::new(project_root);
+        add_ignore_file(&mut builder, &project_root.join(".gitignore"));
+        add_ignore_file(&mut builder, &project_root.join(".arianaignore"));
+        add_exclude_files(&mut builder, options)?;
+        let mut rules = IgnoreRules {
+            builder,
+            matcher: Gitignore::empty(),
+            build_error_reported: false,
+        };
+        rules.build();
+        Ok(rules)
+    }
+
+    /// Adds the ignore files of `dir`, if any.
+    fn add_dir(&mut self, dir: &Path) {
+        add_ignore_file(&mut self.builder, &dir.join(".gitignore"));
+        add_ignore_file(&mut self.builder, &dir.join(".arianaignore"));
+        self.build();
+    }
+
+    fn build(&mut self) {
+        match self.builder.build() {
+            Ok(matcher) => self.matcher = matcher,
+            Err(e) if !self.build_error_reported => {
+                eprintln!(
+                    "[Ariana] Could not apply some ignore patterns, the previous ones are kept: {}",
+                    e
+                );
+                self.build_error_reported = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Adds the patterns of an ignore file, if it exists. Invalid patterns are reported and
+/// skipped, the valid ones of the same file still apply.
+fn add_ignore_file(ignore_builder: &mut GitignoreBuilder, path: &Path) {
+    if !path.is_file() {
+        return;
+    }
+    if let Some(e) = ignore_builder.add(path) {
+        eprintln!("[Ariana] Skipping invalid ignore patterns: {}", e);
+    }
+}
+
+/// Adds the patterns of the `exclude_from` files. Unlike the ignore files found in the project,
+/// these were asked for, so a missing one is an error.
+fn add_exclude_files(
+    ignore_builder: &mut GitignoreBuilder,
+    options: &CollectOptions,
+) -> Result<()> {
+    for path in &options.exclude_from {
+        if !path.is_file() {
+            return Err(CliError::Config(format!(
+                "Exclude file {} does not exist",
+                path.display()
+            )));
+        }
+        add_ignore_file(ignore_builder, path);
+    }
+    Ok(())
+}
+
+/// Whether one of the ancestors of `path` (up to `project_root`, excluded) is in `dirs`.
+fn has_ancestor_in(path: &Path, dirs: &HashSet<PathBuf>, project_root: &Path) -> bool {
+    path.ancestors()
+        .skip(1)
+        .take_while(|ancestor| *ancestor != project_root)
+        .any(|ancestor| dirs.contains(ancestor))
+}
+
+fn should_instrument_file(project_root: &Path, path: &Path, options: &CollectOptions) -> bool {
+    if !is_source_file(path, options)
+        || skip_reason(path, options).is_some()
+        || options.is_test_file(project_root, path)
+    {
+        return false;
+    }
     if let Ok(metadata) = fs::metadata(path) {
-        if metadata.len() >= 4 * 1024 * 1024 {
-            // 4MB
-            return false;
+        if metadata.len() >= MAX_INSTRUMENTED_FILE_BYTES {
+            eprintln!(
+                "[Ariana] WARNING: instrumenting {} although it is {:.1} MB, it may be slow to instrument and run, or rejected by the server",
+                path.display(),
+                metadata.len() as f64 / (1024.0 * 1024.0)
+            );
         }
-    } else {
-        return false; // If metadata fails, skip instrumentation
+    }
+    true
+}
+
+/// Whether `path` is a source file of an enabled language, that gets instrumented unless
+/// `skip_reason` gives a reason not to.
+fn is_source_file(path: &Path, options: &CollectOptions) -> bool {
+    if options.lang_map.language_of(path).is_some() {
+        return true;
     }
     if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
         let ext_lower = ext.to_lowercase();
-        if !valid_extensions.contains(&ext_lower.as_str()) {
+        if !options.is_source_extension(&ext_lower) || !options.is_language_enabled(&ext_lower) {
             return false;
         }
         let filename = path.file_name().unwrap().to_str().unwrap_or("");
-        if filename.ends_with(".config.js") || filename.ends_with(".config.ts") {
-            return false;
-        }
-        true
+        !(filename.ends_with(".config.js") || filename.ends_with(".config.ts"))
     } else {
         false // No extension or extension reading fails
     }
 }
+
+/// Why the source file at `path` can't be instrumented, if it can't.
+fn skip_reason(path: &Path, options: &CollectOptions) -> Option<String> {
+    match fs::metadata(path) {
+        Ok(metadata)
+            if metadata.len() >= MAX_INSTRUMENTED_FILE_BYTES
+                && !options.is_size_cap_lifted(path) =>
+        {
+            Some(format!(
+                "too large ({:.1} MB), see --allow-large-files",
+                metadata.len() as f64 / (1024.0 * 1024.0)
+            ))
+        }
+        Ok(_) => None,
+        Err(e) => Some(format!("unreadable: {}", e)),
+    }
+}
+
+/// Whether `text` matches `pattern`, where `*` stands for any sequence of characters.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        // No `*`, the pattern must match exactly
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A project made of `files`, empty, under the system temp directory.
+    fn project(name: &str, files: &[&str]) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("ariana-collector-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for file in files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn invalid_ignore_patterns_are_skipped() {
+        let root = project("invalid-ignore", &["src/app.js", "generated/gen.js"]);
+        fs::write(root.join(".gitignore"), "src/{unclosed\ngenerated/\n").unwrap();
+
+        let collected =
+            collect_items(&root, &root.join(".ariana"), &CollectOptions::default()).unwrap();
+        let _ = fs::remove_dir_all(&root);
+
+        let instrumented = collected
+            .files_to_instrument
+            .iter()
+            .map(|(src, _)| src.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(instrumented, [root.join("src/app.js")]);
+        assert!(collected
+            .directories_to_link_or_copy
+            .iter()
+            .any(|(src, _)| *src == root.join("generated")));
+    }
+
+    #[test]
+    fn single_paths_are_classified_despite_invalid_ignore_patterns() {
+        let root = project(
+            "classify-invalid-ignore",
+            &["src/app.js", "generated/gen.js"],
+        );
+        fs::write(root.join(".gitignore"), "src/{unclosed\ngenerated/\n").unwrap();
+        let options = CollectOptions::default();
+
+        let app = classify_path(&root, &root.join("src/app.js"), &options).unwrap();
+        let generated = classify_path(&root, &root.join("generated/gen.js"), &options).unwrap();
+        let _ = fs::remove_dir_all(&root);
+
+        assert_eq!(app, PathAction::Instrument);
+        assert_eq!(generated, PathAction::LinkOrCopy);
+    }
+
+    #[test]
+    fn single_paths_are_classified_with_the_ignore_rules() {
+        let files = [
+            "src/app.js",
+            "generated/gen.js",
+            "lib/util.js",
+            "lib/vendor/dep.js",
+            "node_modules/pkg/index.js",
+            "README.md",
+            ".git/config",
+        ];
+        let root = project("classify", &files);
+        fs::write(root.join(".gitignore"), "generated/\n").unwrap();
+        fs::write(root.join("lib/.arianaignore"), "vendor/\n").unwrap();
+        let options = CollectOptions::default();
+
+        let actions = files
+            .iter()
+            .map(|file| classify_path(&root, &root.join(file), &options).unwrap())
+            .collect::<Vec<_>>();
+        let outside = classify_path(&root, &std::env::temp_dir().join("app.js"), &options).unwrap();
+        let _ = fs::remove_dir_all(&root);
+
+        assert_eq!(
+            actions,
+            [
+                PathAction::Instrument,
+                PathAction::LinkOrCopy,
+                PathAction::Instrument,
+                PathAction::LinkOrCopy,
+                PathAction::LinkOrCopy,
+                PathAction::LinkOrCopy,
+                PathAction::Skip,
+            ]
+        );
+        assert_eq!(outside, PathAction::Skip);
+    }
+}
@@ -1,35 +1,45 @@
-use anyhow::Result;
 use dirs;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::PathBuf;
 
+use crate::error::{CliError, Result};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub jwt: Option<String>,
+    /// Directory the config was loaded from, and is saved to.
+    #[serde(skip)]
+    dir: PathBuf,
 }
 
 impl Config {
-    pub fn load() -> Result<Self> {
-        let config_dir = get_config_dir()?;
+    pub fn load(overrides: &ConfigOverrides) -> Result<Self> {
+        let config_dir = overrides.config_dir()?;
         let config_file = config_dir.join("config.json");
 
         if !config_file.exists() {
-            return Ok(Config { jwt: None });
+            return Ok(Config {
+                jwt: None,
+                dir: config_dir,
+            });
         }
 
-        let config_str = fs::read_to_string(config_file)?;
-        let config = serde_json::from_str(&config_str)?;
-        Ok(config)
+        let config_str =
+            fs::read_to_string(&config_file).map_err(|e| CliError::path(&config_file, e))?;
+        let config: Config = serde_json::from_str(&config_str)?;
+        Ok(Config {
+            dir: config_dir,
+            ..config
+        })
     }
 
     pub fn save(&self) -> Result<()> {
-        let config_dir = get_config_dir()?;
-        fs::create_dir_all(&config_dir)?;
-        
-        let config_file = config_dir.join("config.json");
+        fs::create_dir_all(&self.dir).map_err(|e| CliError::path(&self.dir, e))?;
+
+        let config_file = self.dir.join("config.json");
         let config_str = serde_json::to_string_pretty(self)?;
-        fs::write(config_file, config_str)?;
+        fs::write(&config_file, config_str).map_err(|e| CliError::path(&config_file, e))?;
         Ok(())
     }
 
@@ -44,9 +54,66 @@ impl Config {
     }
 }
 
-fn get_config_dir() -> Result<PathBuf> {
-    let config_dir = dirs::config_dir()
-        .ok_or_else(|| anyhow::anyhow!("Could not determine config directory"))?
-        .join("ariana");
-    Ok(config_dir)
+/// The token of the account logged in with `ariana --login`, `None` if not logged in or the
+/// config can't be read.
+pub fn stored_auth_token(overrides: &ConfigOverrides) -> Option<String> {
+    Config::load(overrides).ok().and_then(|config| config.jwt)
+}
+
+/// Environment variable overriding the directory Ariana keeps its configuration and machine
+/// id in, e.g. for isolated runs in CI.
+pub const CONFIG_DIR_ENV: &str = "ARIANA_CONFIG_DIR";
+
+/// Environment variable setting the machine id runs are tied to, instead of the one stored or
+/// the system's, e.g. for a stable identity across CI runs.
+pub const MACHINE_ID_ENV: &str = "ARIANA_MACHINE_ID";
+
+/// Where Ariana keeps its configuration and machine id, and the machine id runs are tied to,
+/// when not the default ones.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    pub config_dir: Option<PathBuf>,
+    pub machine_id: Option<String>,
+}
+
+impl ConfigOverrides {
+    /// The given overrides, e.g. from flags, falling back to `CONFIG_DIR_ENV` and
+    /// `MACHINE_ID_ENV`.
+    pub fn new(config_dir: Option<PathBuf>, machine_id: Option<String>) -> Self {
+        ConfigOverrides {
+            config_dir: config_dir.or_else(|| {
+                std::env::var_os(CONFIG_DIR_ENV)
+                    .filter(|dir| !dir.is_empty())
+                    .map(PathBuf::from)
+            }),
+            machine_id: machine_id
+                .or_else(|| std::env::var(MACHINE_ID_ENV).ok())
+                .filter(|id| !id.trim().is_empty()),
+        }
+    }
+
+    /// Creates the overriding config directory if needed, failing early if it can't be.
+    pub fn ensure_config_dir(&self) -> Result<()> {
+        if let Some(config_dir) = &self.config_dir {
+            fs::create_dir_all(config_dir).map_err(|e| {
+                CliError::Config(format!(
+                    "Could not create the config directory {} set with --config-dir or {}: {}",
+                    config_dir.display(),
+                    CONFIG_DIR_ENV,
+                    e
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn config_dir(&self) -> Result<PathBuf> {
+        if let Some(config_dir) = &self.config_dir {
+            return Ok(config_dir.clone());
+        }
+        let config_dir = dirs::config_dir()
+            .ok_or_else(|| CliError::Config("Could not determine config directory".to_string()))?
+            .join("ariana");
+        Ok(config_dir)
+    }
 }
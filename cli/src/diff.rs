@@ -0,0 +1,126 @@
+use similar::{ChangeTag, TextDiff};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use zip::ZipArchive;
+
+use crate::collector::CollectedItems;
+use crate::error::{CliError, Result};
+use crate::processor::BACKUP_FILE;
+use crate::utils::from_zip_entry_name;
+
+/// Builds a unified diff of the original vs instrumented content of every instrumented file,
+/// followed by a summary of the lines added and removed. Must be called after instrumentation:
+/// originals are read from the project (or from the backup zip in `ariana_dir` with
+/// `is_inplace`) and instrumented files from `.ariana` (or from the project with `is_inplace`).
+pub fn instrumentation_diff(
+    items: &CollectedItems,
+    project_root: &Path,
+    ariana_dir: &Path,
+    is_inplace: bool,
+) -> Result<String> {
+    let backed_up_originals = if is_inplace {
+        read_backup_originals(project_root, ariana_dir)?
+    } else {
+        HashMap::new()
+    };
+
+    let mut diff = String::new();
+    let mut summary = vec![];
+    for (src, dest) in &items.files_to_instrument {
+        let (original, instrumented) = if is_inplace {
+            match backed_up_originals.get(src) {
+                Some(original) => (original.clone(), fs::read_to_string(src)?),
+                // Not in the backup, so it was left untouched
+                None => continue,
+            }
+        } else {
+            match fs::read_to_string(dest) {
+                Ok(instrumented) => (fs::read_to_string(src)?, instrumented),
+                Err(_) => continue,
+            }
+        };
+        if original == instrumented {
+            continue;
+        }
+
+        let display_path = src
+            .strip_prefix(project_root)
+            .unwrap_or(src)
+            .to_string_lossy()
+            .replace('\\', "/");
+        let text_diff = TextDiff::from_lines(&original, &instrumented);
+        let mut added = 0;
+        let mut removed = 0;
+        for change in text_diff.iter_all_changes() {
+            match change.tag() {
+                ChangeTag::Insert => added += 1,
+                ChangeTag::Delete => removed += 1,
+                ChangeTag::Equal => {}
+            }
+        }
+        diff.push_str(
+            &text_diff
+                .unified_diff()
+                .header(
+                    &format!("a/{}", display_path),
+                    &format!("b/{}", display_path),
+                )
+                .to_string(),
+        );
+        summary.push(format!("{} | +{} -{}", display_path, added, removed));
+    }
+
+    diff.push_str(&format!(
+        "\n{} of {} files changed by instrumentation\n",
+        summary.len(),
+        items.files_to_instrument.len()
+    ));
+    for line in summary {
+        diff.push_str(&line);
+        diff.push('\n');
+    }
+    Ok(diff)
+}
+
+fn read_backup_originals(
+    project_root: &Path,
+    ariana_dir: &Path,
+) -> Result<HashMap<PathBuf, String>> {
+    let zip_path = ariana_dir.join(BACKUP_FILE);
+    let zip_file = File::open(&zip_path).map_err(|e| CliError::path(&zip_path, e))?;
+    let mut archive = ZipArchive::new(zip_file)?;
+
+    let mut originals = HashMap::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let path = from_zip_entry_name(file.name(), project_root)?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        originals.insert(path, content);
+    }
+    Ok(originals)
+}
+
+/// Line by line diff of two recaps, unchanged lines kept for context.
+pub fn recap_diff(before: &str, after: &str) -> String {
+    if before == after {
+        return "No difference between the two recaps\n".to_string();
+    }
+    let diff = TextDiff::from_lines(before, after);
+    let mut output = String::new();
+    for change in diff.iter_all_changes() {
+        let sign = match change.tag() {
+            ChangeTag::Delete => "-",
+            ChangeTag::Insert => "+",
+            ChangeTag::Equal => " ",
+        };
+        output.push_str(sign);
+        output.push_str(change.value());
+        if change.missing_newline() {
+            output.push('\n');
+        }
+    }
+    output
+}
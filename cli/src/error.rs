@@ -0,0 +1,92 @@
+use std::path::PathBuf;
+
+use reqwest::StatusCode;
+use thiserror::Error;
+
+use crate::trace_watcher::TracePushError;
+
+/// Errors returned by the library, so that embedders can tell failures apart without parsing
+/// messages.
+#[derive(Debug, Error)]
+pub enum CliError {
+    /// The server could not be reached, or the connection broke.
+    #[error("network error: {0}")]
+    Network(#[from] reqwest::Error),
+    /// The server refused the vault key or machine hash.
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    /// The server answered with an error status to the request `message` describes.
+    #[error("{message}: HTTP {status}")]
+    Server { status: StatusCode, message: String },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// An I/O error on a given file or directory.
+    #[error("could not access {}: {source}", path.display())]
+    Path {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// Files could not be instrumented, or the server's answer could not be used.
+    #[error("{0}")]
+    Instrumentation(String),
+    /// The project or the CLI is set up in a way that can't work, e.g. an invalid package.json.
+    #[error("{0}")]
+    Config(String),
+    #[error(transparent)]
+    TracePush(#[from] TracePushError),
+    /// Nothing more is sent, the run's `UploadBudget` is used up.
+    #[error("the upload budget is exhausted")]
+    UploadBudgetExhausted,
+    /// Pushing pending traces stopped midway, those not delivered are still in `path`.
+    #[error("{source}, delivered {delivered} of {total} traces, the others are still in {}", path.display())]
+    PendingTracesLeft {
+        #[source]
+        source: Box<CliError>,
+        delivered: usize,
+        total: usize,
+        path: PathBuf,
+    },
+    /// Another run holds what this one needs, e.g. the `.ariana` directory or the pid file.
+    #[error("{0}")]
+    AlreadyRunning(String),
+    /// Copying the project to the remote, or running the command there, failed.
+    #[error("{0}")]
+    Remote(String),
+    /// A step of `InstrumentationSession` was called before those it depends on.
+    #[error("{0}")]
+    OutOfOrder(&'static str),
+    /// The `--inplace` backup is missing or holds entries that can't be restored.
+    #[error("{0}")]
+    Backup(String),
+    /// Restoring the `--inplace` backup stopped midway. Files restored so far are listed in
+    /// `progress_file`, running the restore again resumes from there.
+    #[error(
+        "{source}, restored {} of {} files, not restored yet: {}. Run `ariana --restore` again to resume",
+        restored.len(),
+        restored.len() + not_restored.len(),
+        not_restored.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    RestoreIncomplete {
+        #[source]
+        source: Box<CliError>,
+        restored: Vec<PathBuf>,
+        not_restored: Vec<PathBuf>,
+        progress_file: PathBuf,
+    },
+    #[error("invalid backup zip: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl CliError {
+    pub(crate) fn path(path: impl Into<PathBuf>, source: std::io::Error) -> Self {
+        CliError::Path {
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+pub type Result<T, E = CliError> = std::result::Result<T, E>;
@@ -0,0 +1,68 @@
+use serde::Serialize;
+
+/// Machine readable event about a run, for editor integrations driving their UI from it rather
+/// than parsing the human `[Ariana]` lines.
+#[derive(Debug, Serialize)]
+#[serde(tag = "ariana_event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    PhaseStarted {
+        phase: &'a str,
+    },
+    PhaseFinished {
+        phase: &'a str,
+        duration_ms: u128,
+    },
+    VaultCreated {
+        vault_key: &'a str,
+    },
+    FilesCollected {
+        files_to_instrument: usize,
+        files_to_link_or_copy: usize,
+        directories_to_link_or_copy: usize,
+    },
+    FilesInstrumented {
+        skipped_files: usize,
+    },
+    CommandStarted {
+        command: &'a [String],
+    },
+    CommandFinished {
+        command: &'a [String],
+        exit_code: Option<i32>,
+    },
+    RunFinished {
+        exit_code: i32,
+        traces_pushed: usize,
+        lines_streamed: usize,
+        lost_lines: usize,
+    },
+    Error {
+        message: &'a str,
+    },
+}
+
+/// Prints events as newline-delimited JSON on stderr when enabled, keeping stdout for the
+/// command's own output. Each line is a JSON object with an `ariana_event` field.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EventLog {
+    enabled: bool,
+}
+
+impl EventLog {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn emit(&self, event: Event) {
+        if !self.enabled {
+            return;
+        }
+        if let Ok(json) = serde_json::to_string(&event) {
+            eprintln!("{}", json);
+        }
+    }
+}
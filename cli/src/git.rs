@@ -0,0 +1,45 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+
+/// Runs `git` in `project_root` and returns its trimmed stdout, or `None` if it failed
+/// (not a git repository, git not installed...).
+fn git(project_root: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(project_root)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Vault tags describing the git checkout of `project_root`: `git_commit`, `git_branch`
+/// (absent on a detached HEAD) and `git_dirty`. Empty outside of a git repository.
+pub fn detect_git_metadata(project_root: &Path) -> BTreeMap<String, String> {
+    let mut tags = BTreeMap::new();
+
+    // Also fails in a repository without any commit yet, in which case there is nothing to record
+    let Some(commit) = git(project_root, &["rev-parse", "HEAD"]) else {
+        return tags;
+    };
+    tags.insert("git_commit".to_string(), commit);
+
+    // --abbrev-ref gives back "HEAD" when it is detached
+    if let Some(branch) = git(project_root, &["rev-parse", "--abbrev-ref", "HEAD"]) {
+        if branch != "HEAD" && !branch.is_empty() {
+            tags.insert("git_branch".to_string(), branch);
+        }
+    }
+
+    if let Some(status) = git(
+        project_root,
+        &["status", "--porcelain", "--untracked-files=no"],
+    ) {
+        tags.insert("git_dirty".to_string(), (!status.is_empty()).to_string());
+    }
+
+    tags
+}
@@ -1,38 +1,87 @@
-use anyhow::{anyhow, Result};
 use ariana_server::traces::instrumentation::ecma::EcmaImportStyle;
 use ariana_server::web::traces::instrument::{
     CodeInstrumentationBatchRequest, CodeInstrumentationBatchResponse,
 };
-use ariana_server::web::vaults::{VaultPublicData, CreateVaultRequestPayload};
+use ariana_server::web::vaults::{CreateVaultRequestPayload, VaultPublicData};
 use reqwest::blocking::Client;
-use std::path::PathBuf;
-use std::time::Duration;
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::path::{Component, Path, PathBuf};
+use std::str::Chars;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::task;
 
-use crate::utils::generate_machine_id;
+use crate::config::ConfigOverrides;
+use crate::error::{CliError, Result};
+use crate::trace_parser::TRACE_SCHEMA_VERSION;
+use crate::upload_budget::UploadBudget;
+use crate::utils::{api_endpoint, generate_machine_id};
 
+/// Module path aliases of a TypeScript project, from `compilerOptions.baseUrl` and
+/// `compilerOptions.paths` of its tsconfig.json, so that imports injected by instrumentation
+/// resolve like the project's own.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TsPathAliases {
+    /// Absolute directory the `paths` targets are relative to: `baseUrl` if set, else the
+    /// directory of the tsconfig defining `paths`.
+    pub base_url: String,
+    /// Alias patterns such as `@/*`, and the paths they map to, e.g. `["src/*"]`.
+    pub paths: BTreeMap<String, Vec<String>>,
+}
+
+/// `CodeInstrumentationBatchRequest` with what was added to it since.
+#[derive(Serialize)]
+struct InstrumentationBatchPayload {
+    #[serde(flatten)]
+    request: CodeInstrumentationBatchRequest,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ts_path_aliases: Option<TsPathAliases>,
+    /// Language of each file, `None` for those whose extension tells it. Only sent when a file
+    /// has one, see `LangMap`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files_languages: Option<Vec<Option<String>>>,
+}
+
+/// What every batch of a run is instrumented with.
+pub struct BatchContext<'a> {
+    pub api_url: &'a str,
+    pub vault_key: &'a str,
+    pub import_style: &'a EcmaImportStyle,
+    pub ts_path_aliases: Option<&'a TsPathAliases>,
+    pub upload_budget: &'a UploadBudget,
+    pub trace_marker: &'a str,
+    pub auth_token: Option<&'a str>,
+}
+
+/// Sends `files_paths` with their contents to be instrumented, returning the instrumented
+/// content of each, `None` for those the server declined. See `LangMap` for `files_languages`.
 pub async fn instrument_files_batch(
-    files_paths: &Vec<PathBuf>,
+    context: &BatchContext<'_>,
+    files_paths: &[PathBuf],
     files_contents: Vec<String>,
-    api_url: String,
-    vault_key: String,
-    import_style: &EcmaImportStyle,
+    files_languages: Option<Vec<Option<String>>>,
 ) -> Result<Vec<Option<String>>> {
     if files_paths.is_empty() {
         // If files_paths is empty, there's nothing to instrument.
         // The original code would panic on files_paths[0] if it were empty.
-        return Ok(vec![]); 
+        return Ok(vec![]);
     }
 
     let project_root_str = files_paths
-        .get(0)
-        .ok_or_else(|| anyhow!("Cannot determine project root: files_paths list is empty."))?
+        .first()
+        .ok_or_else(|| {
+            CliError::Instrumentation(
+                "Cannot determine project root: files_paths list is empty.".to_string(),
+            )
+        })?
         .parent()
         .ok_or_else(|| {
-            anyhow!(
+            CliError::Instrumentation(format!(
                 "Cannot determine project root: path {} has no parent.",
                 files_paths[0].display()
-            )
+            ))
         })?
         .to_string_lossy()
         .into_owned();
@@ -42,26 +91,47 @@ pub async fn instrument_files_batch(
         .map(|p| p.to_string_lossy().into_owned())
         .collect();
 
-    let import_style_owned = import_style.clone();
+    let import_style_owned = context.import_style.clone();
 
-    let request_payload = CodeInstrumentationBatchRequest {
-        files_contents, // Consumes files_contents
-        files_paths: files_paths_str,
-        project_root: project_root_str,
-        project_import_style: Some(import_style_owned),
+    let request_payload = InstrumentationBatchPayload {
+        request: CodeInstrumentationBatchRequest {
+            files_contents, // Consumes files_contents
+            files_paths: files_paths_str,
+            project_root: project_root_str,
+            project_import_style: Some(import_style_owned),
+        },
+        ts_path_aliases: context.ts_path_aliases.cloned(),
+        files_languages,
     };
 
-    // api_url and vault_key are owned Strings, they will be moved into the closure.
-    // request_payload is also moved.
+    let body = serde_json::to_vec(&request_payload)?;
+    if !context.upload_budget.try_spend(body.len()) {
+        return Err(CliError::UploadBudgetExhausted);
+    }
+
+    // Moved into the closure, along with body
+    let api_url = context.api_url.to_string();
+    let vault_key = context.vault_key.to_string();
+    let trace_marker = context.trace_marker.to_string();
+    let auth_token = context.auth_token.map(str::to_string);
     task::spawn_blocking(move || {
-        let client = Client::new(); 
-        let response_result = client
-            .post(&format!(
-                "{}/vaults/traces/{}/instrument-batched",
-                api_url, vault_key
+        let client = Client::new();
+        let mut request = client
+            .post(api_endpoint(
+                &api_url,
+                &format!("vaults/traces/{}/instrument-batched", vault_key),
             ))
             .header("Content-Type", "application/json")
-            .json(&request_payload)
+            .header(
+                "X-Ariana-Trace-Schema-Version",
+                TRACE_SCHEMA_VERSION.to_string(),
+            )
+            .header("X-Ariana-Trace-Marker", trace_marker);
+        if let Some(auth_token) = auth_token {
+            request = request.bearer_auth(auth_token);
+        }
+        let response_result = request
+            .body(body)
             .timeout(Duration::from_secs(10000))
             .send();
 
@@ -69,50 +139,90 @@ pub async fn instrument_files_batch(
             Ok(resp) => {
                 let status = resp.status();
                 if !status.is_success() {
-                    let body = resp.text().unwrap_or_else(|_| "Failed to read response body".to_string());
-                    Err(anyhow!(
+                    let body = resp
+                        .text()
+                        .unwrap_or_else(|_| "Failed to read response body".to_string());
+                    if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+                        return Err(CliError::Auth(format!(
+                            "the server refused to instrument files for this vault (HTTP {}): {}",
+                            status, body
+                        )));
+                    }
+                    Err(CliError::Instrumentation(format!(
                         "Failed to instrument file batch (HTTP {}): {}",
                         status, body
-                    ))
+                    )))
                 } else {
                     resp.json::<CodeInstrumentationBatchResponse>()
                         .map_err(|e| {
-                            anyhow!("Failed to parse instrument batch response JSON: {}", e)
+                            CliError::Instrumentation(format!(
+                                "Failed to parse instrument batch response JSON: {}",
+                                e
+                            ))
                         })
                         .map(|data| data.instrumented_contents)
                 }
             }
-            Err(e) => Err(anyhow!("Instrument batch HTTP request failed: {}", e)),
+            Err(e) => Err(CliError::Network(e)),
         }
     })
     .await
-    .map_err(|e| anyhow!("Task for instrumenting batch panicked or was cancelled: {}", e))? // Handles JoinError from spawn_blocking (e.g. if the spawned task panics)
-    // The final '?' propagates the Result from the closure (inner Result)
+    .map_err(|e| {
+        CliError::Instrumentation(format!(
+            "Task for instrumenting batch panicked or was cancelled: {}",
+            e
+        ))
+    })? // Handles JoinError from spawn_blocking (e.g. if the spawned task panics)
+        // The final '?' propagates the Result from the closure (inner Result)
+}
+
+/// Vault creation request, extending the server payload with Ariana CLI metadata.
+#[derive(Serialize)]
+struct CreateVaultRequest {
+    #[serde(flatten)]
+    payload: CreateVaultRequestPayload,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    tags: BTreeMap<String, String>,
 }
 
-pub async fn create_vault(api_url: &str, command_str: Option<&str>, cwd_str: Option<&str>) -> Result<String> {
+/// Creates a vault, owned by the account of `auth_token` if given, else only tied to this
+/// machine.
+pub async fn create_vault(
+    api_url: &str,
+    command_str: Option<&str>,
+    cwd_str: Option<&str>,
+    tags: &BTreeMap<String, String>,
+    auth_token: Option<&str>,
+    overrides: &ConfigOverrides,
+) -> Result<String> {
     // Generate a machine hash (just a random ID in this case)
-    let machine_hash = generate_machine_id().await?;
+    let machine_hash = generate_machine_id(overrides)
+        .await
+        .map_err(|e| CliError::Config(format!("Could not get the machine id: {}", e)))?;
 
     // Call the server API to create a vault
     let client = reqwest::Client::new();
-    let payload = CreateVaultRequestPayload {
-        command: command_str.map(|s| s.to_string()),
-        cwd: cwd_str.map(|s| s.to_string()),
+    let payload = CreateVaultRequest {
+        payload: CreateVaultRequestPayload {
+            command: command_str.map(|s| s.to_string()),
+            cwd: cwd_str.map(|s| s.to_string()),
+        },
+        tags: tags.clone(),
     };
 
-    let response = client
-        .post(&format!("{}/unauthenticated/vaults/create", api_url))
-        .header("X-Machine-Hash", machine_hash)
-        .json(&payload)
-        .send()
-        .await?;
+    let mut request = client
+        .post(api_endpoint(api_url, "unauthenticated/vaults/create"))
+        .header("X-Machine-Hash", machine_hash);
+    if let Some(auth_token) = auth_token {
+        request = request.bearer_auth(auth_token);
+    }
+    let response = request.json(&payload).send().await?;
 
     if !response.status().is_success() {
-        return Err(anyhow!(
-            "Failed to create vault: HTTP {}",
-            response.status()
-        ));
+        return Err(CliError::Server {
+            status: response.status(),
+            message: "Failed to create vault".to_string(),
+        });
     }
 
     // Parse the response to get the vault key
@@ -121,11 +231,56 @@ pub async fn create_vault(api_url: &str, command_str: Option<&str>, cwd_str: Opt
     Ok(vault_data.secret_key)
 }
 
-pub fn detect_project_import_style(project_root: &PathBuf) -> Result<EcmaImportStyle> {
+/// Signals the end of a run to the server.
+#[derive(Serialize)]
+struct FinishVaultRequest {
+    exit_code: i32,
+    /// Milliseconds since the Unix epoch.
+    ended_at: u128,
+}
+
+/// Tells the server the run is over, so viewers can show it as finished or failed right away.
+/// Servers that don't know this endpoint yet answer 404, which is not an error.
+pub async fn finish_vault(api_url: &str, vault_key: &str, exit_code: i32) -> Result<()> {
+    let ended_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post(api_endpoint(
+            api_url,
+            &format!("vaults/{}/finish", vault_key),
+        ))
+        .json(&FinishVaultRequest {
+            exit_code,
+            ended_at,
+        })
+        .send()
+        .await?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(());
+    }
+    if !response.status().is_success() {
+        return Err(CliError::Server {
+            status: response.status(),
+            message: "Failed to mark vault as finished".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+pub fn detect_project_import_style(project_root: &Path) -> Result<EcmaImportStyle> {
     let package_json_path = project_root.join("package.json");
     if package_json_path.exists() {
-        let content = std::fs::read_to_string(&package_json_path)?;
-        let json: serde_json::Value = serde_json::from_str(&content)?;
+        let content = std::fs::read_to_string(&package_json_path)
+            .map_err(|e| CliError::path(&package_json_path, e))?;
+        let json: serde_json::Value = serde_json::from_str(&content).map_err(|e| {
+            CliError::Config(format!("Invalid {}: {}", package_json_path.display(), e))
+        })?;
         if let Some(type_field) = json.get("type") {
             if type_field.as_str() == Some("module") {
                 return Ok(EcmaImportStyle::ESM);
@@ -137,3 +292,190 @@ pub fn detect_project_import_style(project_root: &PathBuf) -> Result<EcmaImportS
     }
     Ok(EcmaImportStyle::CJS)
 }
+
+/// Longest chain of tsconfig `extends` followed, beyond it the chain is assumed to loop.
+const MAX_TSCONFIG_EXTENDS_DEPTH: usize = 32;
+
+/// Path aliases of the project's tsconfig.json, following its `extends` chain: options of a
+/// tsconfig override those of the ones it extends. `None` if there is no tsconfig.json or it
+/// defines no `paths`.
+pub fn detect_ts_path_aliases(project_root: &Path) -> Result<Option<TsPathAliases>> {
+    let tsconfig_path = project_root.join("tsconfig.json");
+    if !tsconfig_path.is_file() {
+        return Ok(None);
+    }
+
+    // From the project's tsconfig to the last one it extends
+    let mut chain: Vec<(PathBuf, serde_json::Value)> = vec![];
+    let mut next = Some(tsconfig_path);
+    while let Some(path) = next.take() {
+        if chain.len() >= MAX_TSCONFIG_EXTENDS_DEPTH || chain.iter().any(|(p, _)| p == &path) {
+            return Err(CliError::Config(format!(
+                "{} extends itself, through {}",
+                chain[0].0.display(),
+                path.display()
+            )));
+        }
+        let json = read_tsconfig(&path)?;
+        let dir = path.parent().unwrap_or(project_root).to_path_buf();
+        // Since TypeScript 5.0 several can be extended, the last one wins for paths
+        let extends = match json.get("extends") {
+            Some(serde_json::Value::String(extends)) => Some(extends.clone()),
+            Some(serde_json::Value::Array(extends)) => extends
+                .iter()
+                .rev()
+                .find_map(|e| e.as_str().map(str::to_string)),
+            _ => None,
+        };
+        next = match extends {
+            Some(extends) => Some(
+                resolve_tsconfig_extends(&dir, &extends, project_root).ok_or_else(|| {
+                    CliError::Config(format!(
+                        "Could not find `{}`, extended by {}",
+                        extends,
+                        path.display()
+                    ))
+                })?,
+            ),
+            None => None,
+        };
+        chain.push((path, json));
+    }
+
+    // The first tsconfig of the chain that sets an option wins, relative paths being resolved
+    // from the directory of the tsconfig setting them
+    let option = |name: &str| {
+        chain.iter().find_map(|(path, json)| {
+            let value = json.get("compilerOptions")?.get(name)?;
+            Some((
+                path.parent().unwrap_or(project_root).to_path_buf(),
+                value.clone(),
+            ))
+        })
+    };
+    let base_url = option("baseUrl").and_then(|(dir, base_url)| Some(dir.join(base_url.as_str()?)));
+    let Some((paths_dir, paths)) = option("paths") else {
+        return Ok(None);
+    };
+    let paths = serde_json::from_value::<BTreeMap<String, Vec<String>>>(paths).map_err(|e| {
+        CliError::Config(format!(
+            "Invalid compilerOptions.paths in tsconfig.json: {}",
+            e
+        ))
+    })?;
+    if paths.is_empty() {
+        return Ok(None);
+    }
+    let base_url = base_url.unwrap_or(paths_dir);
+    Ok(Some(TsPathAliases {
+        base_url: normalize_path(&base_url).to_string_lossy().into_owned(),
+        paths,
+    }))
+}
+
+/// Path of the tsconfig `extends` refers to, from a tsconfig in `dir`: a relative or absolute
+/// path, or a package in node_modules, with or without its `.json` extension.
+fn resolve_tsconfig_extends(dir: &Path, extends: &str, project_root: &Path) -> Option<PathBuf> {
+    let bases = if extends.starts_with('.') || Path::new(extends).is_absolute() {
+        vec![dir.join(extends)]
+    } else {
+        // Looked up in the node_modules of each directory up to the project root
+        dir.ancestors()
+            .take_while(|ancestor| ancestor.starts_with(project_root))
+            .map(|ancestor| ancestor.join("node_modules").join(extends))
+            .collect()
+    };
+    bases.into_iter().find_map(|base| {
+        let mut with_extension = base.clone().into_os_string();
+        with_extension.push(".json");
+        [
+            base.clone(),
+            PathBuf::from(with_extension),
+            base.join("tsconfig.json"),
+        ]
+        .into_iter()
+        .find(|candidate| candidate.is_file())
+        .map(|candidate| normalize_path(&candidate))
+    })
+}
+
+fn read_tsconfig(path: &Path) -> Result<serde_json::Value> {
+    let content = std::fs::read_to_string(path).map_err(|e| CliError::path(path, e))?;
+    serde_json::from_str(&strip_json_comments(&content))
+        .map_err(|e| CliError::Config(format!("Invalid {}: {}", path.display(), e)))
+}
+
+/// `content` without the comments and trailing commas tsconfig files may have, which JSON
+/// doesn't allow.
+fn strip_json_comments(content: &str) -> String {
+    let mut stripped = String::with_capacity(content.len());
+    let mut chars = content.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            stripped.push(c);
+            match c {
+                '\\' => stripped.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                stripped.push(c);
+            }
+            ('/', Some('/' | '*')) => skip_comment(&mut chars),
+            (',', _) => {
+                // Trailing if only whitespace and comments stand before the closing bracket
+                if !matches!(next_significant_char(chars.clone()), Some('}' | ']')) {
+                    stripped.push(c);
+                }
+            }
+            _ => stripped.push(c),
+        }
+    }
+    stripped
+}
+
+/// Skips the comment whose leading `/` was just read.
+fn skip_comment(chars: &mut Peekable<Chars>) {
+    if chars.next() == Some('/') {
+        while chars.next_if(|&c| c != '\n').is_some() {}
+        return;
+    }
+    let mut previous = ' ';
+    for c in chars.by_ref() {
+        if previous == '*' && c == '/' {
+            break;
+        }
+        previous = c;
+    }
+}
+
+/// The next character that isn't whitespace or in a comment.
+fn next_significant_char(mut chars: Peekable<Chars>) -> Option<char> {
+    loop {
+        match chars.next()? {
+            c if c.is_whitespace() => {}
+            '/' if matches!(chars.peek(), Some('/' | '*')) => skip_comment(&mut chars),
+            c => return Some(c),
+        }
+    }
+}
+
+/// `path` without its `.` and `..` components, without touching the filesystem.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
@@ -0,0 +1,89 @@
+//! Ctrl+C and SIGTERM while a command runs. The first one stops the command, then Ariana
+//! still sends what was collected and restores `--inplace` files, which repeated presses must
+//! not cut short: the second one only asks to wait, and the third exits right away.
+
+use std::sync::OnceLock;
+
+use tokio::signal;
+use tokio::sync::watch;
+
+/// The first signal received, with the exit code a shell gives a process it stopped.
+type Interrupt = Option<(&'static str, i32)>;
+
+static INTERRUPTS: OnceLock<watch::Receiver<Interrupt>> = OnceLock::new();
+
+/// Waits for Ctrl+C or, on Unix, SIGTERM, the way a supervisor asks Ariana to stop. Returns
+/// the name of the signal and the exit code a shell gives a process it stopped.
+///
+/// The first call starts listening for the rest of the process: from then on, signals no
+/// longer kill Ariana, only the third one exits immediately, with the code of the first.
+pub async fn interrupted() -> (&'static str, i32) {
+    let mut interrupts = INTERRUPTS.get_or_init(listen).clone();
+    let interrupt = interrupts
+        .wait_for(Option::is_some)
+        .await
+        .map(|interrupt| *interrupt);
+    match interrupt {
+        Ok(interrupt) => interrupt.unwrap_or(("Ctrl+C", 130)),
+        // The runtime listening was shut down, nothing will come
+        Err(_) => std::future::pending().await,
+    }
+}
+
+fn listen() -> watch::Receiver<Interrupt> {
+    let (interrupts_tx, interrupts_rx) = watch::channel(None);
+    // Registered right away, so that signals no longer kill Ariana once this returns
+    let mut signals = Signals::new();
+    tokio::spawn(async move {
+        let mut received = 0;
+        loop {
+            let (signal_name, code) = signals.next().await;
+            received += 1;
+            match received {
+                1 => {
+                    let _ = interrupts_tx.send(Some((signal_name, code)));
+                }
+                2 => eprintln!(
+                    "[Ariana] Shutting down, please wait. Hit Ctrl+C again to exit right away"
+                ),
+                _ => {
+                    eprintln!("[Ariana] Exiting without finishing to shut down. With --inplace, run `ariana --restore` to put your original files back");
+                    let code = interrupts_tx.borrow().map_or(code, |(_, code)| code);
+                    std::process::exit(code);
+                }
+            }
+        }
+    });
+    interrupts_rx
+}
+
+/// Listeners kept across signals, so that one hit while the previous is handled isn't missed.
+struct Signals {
+    #[cfg(unix)]
+    interrupt: Option<signal::unix::Signal>,
+    #[cfg(unix)]
+    terminate: Option<signal::unix::Signal>,
+}
+
+impl Signals {
+    fn new() -> Self {
+        Signals {
+            #[cfg(unix)]
+            interrupt: signal::unix::signal(signal::unix::SignalKind::interrupt()).ok(),
+            #[cfg(unix)]
+            terminate: signal::unix::signal(signal::unix::SignalKind::terminate()).ok(),
+        }
+    }
+
+    async fn next(&mut self) -> (&'static str, i32) {
+        #[cfg(unix)]
+        if let (Some(interrupt), Some(terminate)) = (&mut self.interrupt, &mut self.terminate) {
+            tokio::select! {
+                _ = interrupt.recv() => return ("Ctrl+C", 130),
+                _ = terminate.recv() => return ("SIGTERM", 143),
+            }
+        }
+        let _ = signal::ctrl_c().await;
+        ("Ctrl+C", 130)
+    }
+}
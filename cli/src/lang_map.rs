@@ -0,0 +1,104 @@
+//! `--lang-map`: instrumenting files as another language than their extension tells, e.g. a
+//! `.flow` file as JavaScript.
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::capabilities::SupportedLanguage;
+use crate::error::{CliError, Result};
+
+/// Files matching `pattern` are instrumented as `language`, written `glob=language`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LangMapping {
+    /// Matched like a line of a `.gitignore` at the project root, e.g. `*.flow` or `legacy/*.js`.
+    pub pattern: String,
+    /// Name of a language the server can instrument, whatever its case, e.g. `javascript`.
+    pub language: String,
+}
+
+impl FromStr for LangMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.rsplit_once('=') {
+            Some((pattern, language)) if !pattern.is_empty() && !language.is_empty() => {
+                Ok(LangMapping {
+                    pattern: pattern.to_string(),
+                    language: language.to_string(),
+                })
+            }
+            _ => Err(format!("expected glob=language, got `{}`", s)),
+        }
+    }
+}
+
+/// The `--lang-map` mappings of a project, checked against the languages of the server.
+#[derive(Debug, Clone, Default)]
+pub struct LangMap {
+    /// Each matcher with the name the server gives its language. The first matching applies.
+    mappings: Vec<(Gitignore, String)>,
+}
+
+impl LangMap {
+    /// Fails if a mapping names a language missing from `languages`, or has an invalid glob.
+    pub fn new(
+        project_root: &Path,
+        mappings: &[LangMapping],
+        languages: &[SupportedLanguage],
+    ) -> Result<Self> {
+        let mappings = mappings
+            .iter()
+            .map(|mapping| {
+                let language = languages
+                    .iter()
+                    .find(|language| language.name.eq_ignore_ascii_case(&mapping.language))
+                    .ok_or_else(|| {
+                        CliError::Config(format!(
+                            "--lang-map {}={}: the server can't instrument {}, it supports {}",
+                            mapping.pattern,
+                            mapping.language,
+                            mapping.language,
+                            languages
+                                .iter()
+                                .map(|language| language.name.as_str())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ))
+                    })?;
+                let mut builder = GitignoreBuilder::new(project_root);
+                builder.add_line(None, &mapping.pattern).map_err(|e| {
+                    CliError::Config(format!(
+                        "--lang-map {}: invalid glob: {}",
+                        mapping.pattern, e
+                    ))
+                })?;
+                let matcher = builder.build().map_err(|e| {
+                    CliError::Config(format!("--lang-map {}: {}", mapping.pattern, e))
+                })?;
+                Ok((matcher, language.name.clone()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(LangMap { mappings })
+    }
+
+    /// The language `path` is mapped to, `None` to let the server tell it from its extension.
+    pub fn language_of(&self, path: &Path) -> Option<&str> {
+        self.mappings
+            .iter()
+            .find(|(matcher, _)| {
+                path.starts_with(matcher.path())
+                    && matcher.matched_path_or_any_parents(path, false).is_ignore()
+            })
+            .map(|(_, language)| language.as_str())
+    }
+
+    /// The languages of `paths`, in order, `None` if none of them is mapped.
+    pub fn languages_of(&self, paths: &[PathBuf]) -> Option<Vec<Option<String>>> {
+        let languages = paths
+            .iter()
+            .map(|path| self.language_of(path).map(str::to_string))
+            .collect::<Vec<_>>();
+        languages.iter().any(Option::is_some).then_some(languages)
+    }
+}
@@ -0,0 +1,53 @@
+//! Ariana CLI as a library, to embed Ariana's instrumentation into your own tooling.
+//!
+//! [`InstrumentationSession`] drives a whole run the same way the `ariana` binary does:
+//! configure it with a [`SessionConfig`], then [`prepare`](InstrumentationSession::prepare)
+//! the `.ariana` directory, [`create_vault`](InstrumentationSession::create_vault),
+//! [`collect`](InstrumentationSession::collect) the project files,
+//! [`instrument`](InstrumentationSession::instrument) them and finally
+//! [`run`](InstrumentationSession::run) the command while its traces and output are streamed
+//! to the vault.
+//!
+//! The building blocks are also usable on their own: [`collector::collect_items`],
+//! [`processor::process_items`], the instrumentation server client in [`instrumentation`],
+//! and the trace and output watchers.
+
+pub mod archive;
+pub mod auth;
+pub mod baseline;
+pub mod config;
+
+pub mod capabilities;
+pub mod collector;
+pub mod diff;
+pub mod error;
+pub mod events;
+pub mod git;
+pub mod instrumentation;
+pub mod interrupts;
+pub mod lang_map;
+pub mod manifest;
+pub mod metrics;
+pub mod pid_file;
+pub mod process_tree;
+pub mod processor;
+pub mod progress;
+pub mod recap_cache;
+pub mod redaction;
+pub mod remote;
+pub mod server_status;
+pub mod session;
+pub mod subprocess_stdout_watcher;
+pub mod syntax_check;
+pub mod trace_parser;
+pub mod trace_watcher;
+pub mod transcript;
+pub mod transport;
+pub mod upload_budget;
+pub mod utils;
+
+pub use error::CliError;
+pub use session::{InstrumentationSession, SessionConfig};
+
+/// Directory, relative to the project root, holding the instrumented copy, backups and vault key.
+pub const ARIANA_DIR: &str = ".ariana";
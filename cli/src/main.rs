@@ -1,32 +1,44 @@
 use anyhow::{anyhow, Result};
-use ariana_server::traces::Trace;
+use ariana_cli::archive::{write_archive, ExtractedArchive};
+use ariana_cli::auth;
+use ariana_cli::baseline::BaselineMode;
+use ariana_cli::capabilities::{load_capabilities, CapabilitiesSource};
+use ariana_cli::collector::{SkipCause, SkippedFile};
+use ariana_cli::config::{stored_auth_token, ConfigOverrides, MACHINE_ID_ENV};
+use ariana_cli::diff::recap_diff;
+use ariana_cli::events::{Event, EventLog};
+use ariana_cli::lang_map::LangMapping;
+use ariana_cli::manifest::{read_run_manifest, write_run_manifest};
+use ariana_cli::metrics::write_metrics_file;
+use ariana_cli::pid_file::PidFile;
+use ariana_cli::processor::{
+    default_read_concurrency, restore_backup, BackupCompression, DEFAULT_BATCH_FILE_COUNT,
+};
+use ariana_cli::recap_cache::{
+    invalidate_cached_recap, load_cached_recap, store_cached_recap, CachedRecap,
+};
+use ariana_cli::remote::RemoteTarget;
+use ariana_cli::subprocess_stdout_watcher::OutputTagging;
+use ariana_cli::trace_parser::{is_valid_trace_marker, DEFAULT_TRACE_MARKER};
+use ariana_cli::trace_watcher::{
+    flush_pending_traces, TraceWatcherOptions, DEFAULT_MAX_PUSH_BYTES, PENDING_TRACES_DIR,
+    PENDING_TRACES_FILE, UNKNOWN_VAULT_PENDING_TRACES,
+};
+use ariana_cli::upload_budget::UploadBudget;
+use ariana_cli::utils::{
+    api_endpoint, can_create_symlinks, generate_machine_id, is_valid_copy_extension,
+    normalize_api_url, reset_machine_id, resolve_package_script, DEFAULT_COPY_EXTENSIONS,
+};
+use ariana_cli::{InstrumentationSession, SessionConfig, ARIANA_DIR};
 use clap::Parser;
-use processor::restore_backup;
-use utils::generate_machine_id;
+use reqwest::StatusCode;
 use std::env;
 use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use tokio::io::AsyncBufReadExt;
-use tokio::spawn;
-use tokio::signal;
-use tokio::sync::mpsc;
-
-mod auth;
-mod config;
-
-mod collector;
-mod instrumentation;
-mod processor;
-mod subprocess_stdout_watcher;
-mod trace_watcher;
-mod utils;
-
-use collector::collect_items;
-use instrumentation::{create_vault, detect_project_import_style};
-use processor::process_items;
-use subprocess_stdout_watcher::{watch_subprocess_output, OutputSource};
-use trace_watcher::watch_traces;
-use utils::{add_to_gitignore, can_create_symlinks};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Parser)]
 #[command(version, about = "Ariana CLI")]
@@ -35,6 +47,18 @@ struct Cli {
     #[arg(long)]
     recap: bool,
 
+    /// With --recap, compares the recap of the last run with the one of the vault with this secret key, e.g. a run from before a regression
+    #[arg(long, value_name = "VAULT_KEY", requires = "recap")]
+    compare: Option<String>,
+
+    /// With --recap, the machine hash to authenticate with instead of this machine's, to get the recap of a vault created on another machine. The hash sent is shown when the server refuses it
+    #[arg(long, value_name = "HASH", requires = "recap")]
+    machine_hash: Option<String>,
+
+    /// With --recap, fetches the recap from the server even if one fetched less than an hour ago is cached in .ariana/recap-cache
+    #[arg(long, requires = "recap")]
+    refresh: bool,
+
     /// Ignores normal behavior and just restores original files from backup. Can be useful if you just ran --inplace and the backup was not restored
     #[arg(long)]
     restore: bool,
@@ -43,6 +67,38 @@ struct Cli {
     #[arg(long)]
     login: bool,
 
+    /// Ignores normal behavior and just lists the languages and file extensions the server can instrument
+    #[arg(long)]
+    list_supported: bool,
+
+    /// Ignores normal behavior and just pushes the traces a previous run saved to .ariana/pending_traces.jsonl because it couldn't reach the server, and those of earlier vaults set aside in .ariana/pending_traces/ by the runs that followed
+    #[arg(long)]
+    flush_pending: bool,
+
+    /// Vault to send traces to instead of creating a new one, e.g. the one an --instrument-only run wrote to .ariana/.vault_secret_key. With --flush-pending, defaults to the vault of the last run
+    #[arg(long, value_name = "KEY")]
+    vault_key: Option<String>,
+
+    /// Instruments your project (into .ariana, or your files with --inplace) and writes the vault key, then exits without running a command, so you can inspect the result or run it yourself later. Undo --inplace edits with --restore
+    #[arg(long)]
+    instrument_only: bool,
+
+    /// Runs the command again in the project a previous run instrumented into .ariana, sending the traces to the same vault, without collecting and instrumenting it again. Warns when source files changed since, run without it to instrument them again
+    #[arg(long, conflicts_with_all = ["inplace", "vault_key", "instrument_only", "keep_ariana_dir"])]
+    append_run: bool,
+
+    /// Directory to keep the login and machine id in, instead of the user's config directory. Can also be set with the ARIANA_CONFIG_DIR environment variable
+    #[arg(long, value_name = "PATH")]
+    config_dir: Option<PathBuf>,
+
+    /// Machine id to tie runs to instead of the one of this machine, e.g. for a stable identity across CI runs. Only its hash is sent. Can also be set with the ARIANA_MACHINE_ID environment variable
+    #[arg(long, value_name = "ID")]
+    machine_id: Option<String>,
+
+    /// Ignores normal behavior and just replaces the machine id anonymous runs are tied together by with a new random one, then prints its hash
+    #[arg(long, conflicts_with = "machine_id")]
+    reset_machine_id: bool,
+
     /// API URL for Ariana server
     #[arg(long, default_value_t = if cfg!(debug_assertions) { "http://localhost:8080/".to_string() } else { "https://api.ariana.dev/".to_string() })]
     api_url: String,
@@ -51,33 +107,418 @@ struct Cli {
     #[arg(long)]
     inplace: bool,
 
+    /// Instruments nothing and runs your command in your project as it is, only collecting the trace tags it prints and sending them to a new vault. For programs already instrumented, e.g. by hand, or to test the trace pipeline without the instrumentation server
+    #[arg(long, conflicts_with_all = ["inplace", "append_run", "instrument_only", "remote", "prewarm", "to_archive", "diff", "diff_output"])]
+    no_instrument: bool,
+
+    /// With --inplace, also instruments source files that are symlinks to files outside the project, modifying those files until the run ends
+    #[arg(long)]
+    instrument_external_symlinks: bool,
+
+    /// Looks for files to instrument in symlinked directories leading outside of your project, e.g. a shared library linked into it. By default they are only linked into .ariana, left uninstrumented
+    #[arg(long, overrides_with = "no_follow_symlinks")]
+    follow_symlinks: bool,
+
+    /// Only links symlinked directories into .ariana, without instrumenting their files. The default, overrides an earlier --follow-symlinks
+    #[arg(long)]
+    no_follow_symlinks: bool,
+
+    /// Waits for another ariana run in the same directory to finish instead of failing right away
+    #[arg(long)]
+    wait: bool,
+
+    /// Compression of the backup of original files made with --inplace
+    #[arg(long, value_enum, default_value_t = BackupCompression::Deflate)]
+    backup_compression: BackupCompression,
+
+    /// Maximum number of instrumentation requests sent to the server at once
+    #[arg(
+        long,
+        visible_alias = "instrument-concurrency-uploads",
+        default_value_t = 4
+    )]
+    concurrency: usize,
+
+    /// Number of threads reading the files to instrument, one per CPU by default. Raise it on a fast disk with a slow network, lower it on a slow disk, independently of --concurrency which limits the requests in flight
+    #[arg(long, value_name = "THREADS", value_parser = clap::value_parser!(u32).range(1..))]
+    instrument_concurrency_reads: Option<u32>,
+
+    /// Maximum number of files sent in a single instrumentation request. Lower it if the server rejects big batches, raise it for projects made of many small files
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_BATCH_FILE_COUNT as u32, value_parser = clap::value_parser!(u32).range(1..))]
+    batch_file_count: u32,
+
+    /// Maximum cumulated size of the files sent in a single instrumentation request, bigger batches are split
+    #[arg(long, default_value_t = 32 * 1024 * 1024)]
+    max_upload_bytes: u64,
+
+    /// Maximum number of files copied into .ariana at once. Lower it if copying a big directory like node_modules fails with "too many open files"
+    #[arg(long, default_value_t = 256, value_parser = clap::value_parser!(u32).range(1..))]
+    max_concurrent_files: u32,
+
+    /// Directory to create .ariana in instead of the current directory, e.g. when it is read-only, to keep it out of your repository or to put it on a faster disk. Holds the instrumented copy, the vault key, or with --inplace the backup of your files. Pass it again to --recap, --restore and --flush-pending
+    #[arg(long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+
+    /// Reuses the .ariana directory of the previous run, so files that didn't change since are not copied again
+    #[arg(long)]
+    keep_ariana_dir: bool,
+
+    /// Copies all files into .ariana again, even those that look unchanged since the previous run (with --keep-ariana-dir)
+    #[arg(long)]
+    force_copy_all: bool,
+
+    /// Copies everything into .ariana instead of linking to your original files, so .ariana can be zipped and run on another machine. Slower and uses more disk space, especially with big dependency directories like node_modules
+    #[arg(long)]
+    portable: bool,
+
+    /// Copies the instrumented project to a directory of another machine over SSH, e.g. user@host:/tmp/myapp, runs your command there and reads its traces and output here. The directory must not exist or be empty, it is removed at the end of the run. Needs key-based SSH access, and tar on both machines
+    #[arg(long, value_name = "[USER@]HOST:PATH", conflicts_with_all = ["inplace", "instrument_only"])]
+    remote: Option<RemoteTarget>,
+
+    /// Instruments the project held in this zip archive instead of the current directory, e.g. when the code isn't checked out locally. It is extracted to a temporary directory removed at the end of the run, entries that would be written outside of it are refused
+    #[arg(long, value_name = "ZIP", conflicts_with = "inplace")]
+    from_archive: Option<PathBuf>,
+
+    /// Writes the instrumented project to this zip archive once instrumented, e.g. to run it in a sandbox or on a build machine. Linked files and directories are archived too
+    #[arg(long, value_name = "ZIP", conflicts_with = "inplace")]
+    to_archive: Option<PathBuf>,
+
+    /// Extension of files to always copy into .ariana rather than link, whatever their size, on top of html, htm, css, sass, scss, vue and svelte, e.g. --copy-extension astro (repeatable). Useful when your tooling rewrites or watches them
+    #[arg(long = "copy-extension", value_name = "EXT", value_parser = parse_copy_extension)]
+    copy_extensions: Vec<String>,
+
+    /// Extension to remove from the files always copied rather than linked, e.g. --no-copy-extension css (repeatable)
+    #[arg(long = "no-copy-extension", value_name = "EXT", value_parser = parse_copy_extension)]
+    no_copy_extensions: Vec<String>,
+
+    /// Also writes your command's stdout and stderr, with timestamps, to a local file (e.g. .ariana/transcript.log)
+    #[arg(long)]
+    transcript: Option<PathBuf>,
+
+    /// Records your command's stdout, without traces, to .ariana/baseline.txt when it succeeds, for later runs with --compare-baseline
+    #[arg(long, conflicts_with = "compare_baseline")]
+    baseline: bool,
+
+    /// Compares your command's stdout, without traces, with the one recorded with --baseline, printing the differences and exiting with 1 when there are some, for CI smoke tests
+    #[arg(long)]
+    compare_baseline: bool,
+
+    /// Regex whose matches are ignored when recording or comparing the baseline, e.g. for ids or durations that change between runs (repeatable). Timestamps are ignored when none is given
+    #[arg(long, value_name = "REGEX")]
+    baseline_normalize: Vec<String>,
+
+    /// Regex whose matches are replaced by *** in the output sent to the server and the transcript (repeatable)
+    #[arg(long = "redact", value_name = "REGEX")]
+    redact_patterns: Vec<String>,
+
+    /// Disables the built-in redaction of common secrets (API keys, bearer tokens) in the captured output
+    #[arg(long)]
+    no_default_redaction: bool,
+
+    /// Doesn't send your command's stdout and stderr to the server, only its traces. Output is still printed locally
+    #[arg(long)]
+    no_subprocess_stream: bool,
+
+    /// Spools your command's output to a file under .ariana before streaming it to the server, so that a very verbose command with a slow connection uses disk rather than memory
+    #[arg(long)]
+    buffer_output_file: bool,
+
+    /// Cuts lines of your command's output longer than this many bytes before sending them to the server. They are still printed in full locally
+    #[arg(long, value_name = "N")]
+    max_line_length: Option<usize>,
+
+    /// Tags each line your command prints with the stream it comes from, to tell them apart when stdout and stderr end up in the same file: `prefix` starts lines with [stdout] or [stderr], `color` dims stderr lines
+    #[arg(long, value_enum, value_name = "STYLE")]
+    tag_output: Option<OutputTagging>,
+
+    /// Never colors output. `--tag-output color` then prefixes lines instead. Implied by a non-empty NO_COLOR environment variable
+    #[arg(long)]
+    no_color: bool,
+
+    /// Maximum megabytes sent to the server over the whole run (instrumentation, traces and output). Once reached, nothing more is sent but your command keeps running
+    #[arg(long, value_name = "MB")]
+    max_upload_mb: Option<u64>,
+
+    /// Makes sure the server gets the traces of each line of output before the line itself. Slows down output that contains traces, as each such line waits for its traces to be sent
+    #[arg(long)]
+    keep_output_order: bool,
+
+    /// Doesn't show the running count of traces and output lines sent while your command runs
+    #[arg(long)]
+    quiet: bool,
+
+    /// Prints machine readable events (phases, counts, vault key, errors, exit code) as one JSON object per line on stderr, for editor integrations. Also hides the status line
+    #[arg(long)]
+    json_logs: bool,
+
+    /// Doesn't print the feedback and Discord lines at the end of the run. Implied by --quiet and --json-logs, and when stdout isn't a terminal
+    #[arg(long)]
+    no_footer: bool,
+
+    /// Sends traces to the server uncompressed instead of gzipped
+    #[arg(long)]
+    no_trace_compression: bool,
+
+    /// Maximum size in bytes of the traces sent in a single request, before compression. Bigger pushes are split into several requests, each retried on its own, e.g. if the server rejects big requests with HTTP 413
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_PUSH_BYTES)]
+    trace_endpoint_batch_max: u64,
+
+    /// Every few seconds while your command runs, prints how many traces the server received against how many were sent, to tell whether traces missing from the viewer were lost on your side or the server's
+    #[arg(long, alias = "tail-server-logs")]
+    debug_server: bool,
+
+    /// Prints more details about what Ariana is doing
+    #[arg(long)]
+    verbose: bool,
+
+    /// Prints a one-line summary of each trace (location, duration, value) to stderr as your command runs
+    #[arg(long, alias = "pretty-traces")]
+    show_traces: bool,
+
+    /// Name of the tag your instrumented code prints traces in, e.g. `ariana_trace` to read `<ariana_trace id="...">` rather than `<trace id="...">` tags, so that output of your own containing those isn't mistaken for traces. Announced to the server when instrumenting, which must support it
+    #[arg(long, value_name = "NAME", default_value = DEFAULT_TRACE_MARKER, value_parser = parse_trace_marker)]
+    trace_output_marker: String,
+
+    /// Prints how long each phase (collection, copying, instrumentation, your command, trace flushing) took
+    #[arg(long)]
+    profile: bool,
+
+    /// When Ctrl+C or SIGTERM stops your command, still prints what the run did once the traces collected so far are sent: time elapsed, files instrumented, traces sent and how long each phase took, even with --quiet. --metrics-file is written either way
+    #[arg(long)]
+    summary_on_signal: bool,
+
+    /// Writes OpenMetrics (Prometheus text format) gauges about the run to a file when it ends, for CI dashboards: files_instrumented, files_skipped, bytes_uploaded, traces_pushed, duration_seconds and exit_code
+    #[arg(long, value_name = "FILE")]
+    metrics_file: Option<PathBuf>,
+
+    /// Writes the PID of Ariana to a file, removed once the run is over. Sending SIGTERM to that PID stops the command like Ctrl+C does, then the traces collected so far are still sent
+    #[arg(long, value_name = "FILE")]
+    pid_file: Option<PathBuf>,
+
+    /// Fails the run if any source file could not be instrumented (too large, unreadable, not UTF-8, failed batch...), listing them. The command still runs first, see --fail-on-instrument-error to not run it
+    #[arg(long)]
+    strict: bool,
+
+    /// Aborts before running the command if an instrumentation request to the server failed, restoring the original files with --inplace. Unlike --strict, files skipped for other reasons (too large, not UTF-8...) don't abort, and the two can be combined
+    #[arg(long)]
+    fail_on_instrument_error: bool,
+
+    /// Checks that each instrumented file still parses before writing it, leaving it uninstrumented with a warning otherwise. JavaScript files are checked with `node --check`, Python files with python3, TypeScript and JSX files are not checked. Slows down instrumentation
+    #[arg(long)]
+    verify_syntax: bool,
+
+    /// Sends a single small file to be instrumented first and aborts if it doesn't come back instrumented and valid, e.g. with the wrong import style or an outdated server, before sending the rest of your project
+    #[arg(long, conflicts_with = "append_run")]
+    prewarm: bool,
+
+    /// Labels the vault with a key=value tag, e.g. --tag ticket=ABC-123 (repeatable). Git commit, branch and dirty state are added automatically
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    tags: Vec<(String, String)>,
+
+    /// Doesn't attach the git commit, branch and dirty state of your project to the vault
+    #[arg(long)]
+    no_git_metadata: bool,
+
+    /// Fraction of traces sent to the server, between 0.0 and 1.0. Sampling is per call, so traces of sampled out calls are lost entirely
+    #[arg(long, default_value_t = 1.0, value_parser = parse_sample_rate)]
+    sample_rate: f64,
+
+    /// Sends traces the instrumented code emits twice (e.g. on retries) only once, among the latest 100,000 traces
+    #[arg(long)]
+    dedup_traces: bool,
+
+    /// Also appends every trace sent to FILE, one JSON trace per line, to keep a copy of them whatever the server keeps
+    #[arg(long, value_name = "FILE")]
+    save_traces: Option<PathBuf>,
+
+    /// Runs the command through npm/pnpm/yarn if it is the name of a script from your package.json, e.g. `ariana --npm-script test`
+    #[arg(long)]
+    npm_script: bool,
+
+    /// Name of a directory not to look into for files to instrument, e.g. --exclude-dir coverage (repeatable)
+    #[arg(long = "exclude-dir", value_name = "NAME")]
+    exclude_dirs: Vec<String>,
+
+    /// Name of a directory skipped by default (like dist or build) to look into anyway (repeatable)
+    #[arg(long = "include-dir", value_name = "NAME")]
+    include_dirs: Vec<String>,
+
+    /// Reads more patterns to exclude from a file in .gitignore format, applied as if it were a .arianaignore at the project root, e.g. a list generated in CI (repeatable)
+    #[arg(long, value_name = "FILE")]
+    exclude_from: Vec<PathBuf>,
+
+    /// Prints a diff of what instrumentation changed in each file before running your command
+    #[arg(long)]
+    diff: bool,
+
+    /// Writes the diff of what instrumentation changed to a file instead of printing it (implies --diff)
+    #[arg(long, value_name = "FILE")]
+    diff_output: Option<PathBuf>,
+
+    /// Doesn't instrument JavaScript and TypeScript files
+    #[arg(long)]
+    no_js: bool,
+
+    /// Doesn't instrument Python files
+    #[arg(long)]
+    no_python: bool,
+
+    /// Instruments the files matching a glob as another language than their extension tells, e.g. --lang-map '*.flow=javascript' (repeatable, the first matching applies). Globs are matched like .gitignore lines at the project root, the language must be one the server supports (see --list-supported)
+    #[arg(long = "lang-map", value_name = "GLOB=LANG")]
+    lang_map: Vec<LangMapping>,
+
+    /// Doesn't instrument test files, to trace your app rather than the test harness. Matches files named *.test.*, *.spec.*, test_*.py or *_test.py and files in a __tests__ directory, or the patterns of --test-pattern
+    #[arg(long)]
+    skip_tests: bool,
+
+    /// Pattern of test files not to instrument with --skip-tests, replacing the default ones (repeatable). Matched against file names, or with a trailing / against the directories they are in, * standing for any characters, e.g. --test-pattern '*.e2e.ts' --test-pattern 'fixtures/'
+    #[arg(long = "test-pattern", value_name = "PATTERN", requires = "skip_tests")]
+    test_patterns: Vec<String>,
+
+    /// Instruments files of any size. By default files of 4 MB or more are left uninstrumented, as they may be slow to instrument and run or be rejected by the server
+    #[arg(long)]
+    allow_large_files: bool,
+
+    /// File to instrument even if it is 4 MB or more, relative to your project (repeatable)
+    #[arg(long = "allow-file", value_name = "PATH")]
+    allowed_large_files: Vec<PathBuf>,
+
+    /// Files to instrument of this size or more are listed at the end of the run, and with --verbose warned about before being sent, to spot generated files slowing instrumentation down
+    #[arg(long, value_name = "KB", default_value_t = 1024)]
+    large_file_warning_kb: u64,
+
+    /// Runs your command from this subdirectory of your project, e.g. --cwd packages/app. Still uses the instrumented files
+    #[arg(long, value_name = "PATH")]
+    cwd: Option<PathBuf>,
+
+    /// Command to run after the main one under the same vault, e.g. --then 'npm test' (repeatable, run in order)
+    #[arg(long = "then", value_name = "COMMAND", value_parser = parse_command)]
+    then_commands: Vec<Vec<String>>,
+
+    /// Runs the commands given with --then even if a previous one fails
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Seconds to wait for a command that closed its stdout and stderr but keeps running, as commands that daemonize do, before killing it and everything it started. The run then ends with exit code 0, as if the command had succeeded. Without it, Ariana waits for the command to exit however long it takes
+    #[arg(long, value_name = "SECS")]
+    output_closed_timeout: Option<u64>,
+
     /// The command to execute in the instrumented code directory (not required if --recap, --restore, or --login is used)
     #[arg(trailing_var_arg = true)]
     command: Vec<String>,
 }
 
-const ARIANA_DIR: &str = ".ariana";
+fn parse_sample_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|_| format!("`{}` is not a number", s))?;
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(format!(
+            "expected a number between 0.0 and 1.0, got {}",
+            rate
+        ));
+    }
+    Ok(rate)
+}
+
+fn parse_trace_marker(s: &str) -> Result<String, String> {
+    if !is_valid_trace_marker(s) {
+        return Err(format!(
+            "`{}` is not a valid tag name, use letters, digits, - and _",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
+fn parse_command(s: &str) -> Result<Vec<String>, String> {
+    match shlex::split(s) {
+        Some(command) if !command.is_empty() => Ok(command),
+        Some(_) => Err("expected a command".to_string()),
+        None => Err(format!(
+            "could not parse `{}` as a command, check its quotes",
+            s
+        )),
+    }
+}
+
+fn parse_copy_extension(s: &str) -> Result<String, String> {
+    if !is_valid_copy_extension(s) {
+        return Err(format!(
+            "`{}` is not a valid extension, write it in lowercase and without dot, e.g. `astro`",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("expected KEY=VALUE, got `{}`", s)),
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env::set_var("RUST_BACKTRACE", "1");
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
+    cli.api_url = normalize_api_url(&cli.api_url);
+    let config_overrides = ConfigOverrides::new(cli.config_dir.clone(), cli.machine_id.clone());
+    config_overrides.ensure_config_dir()?;
 
     if cli.login {
-        auth::ensure_authenticated(&cli.api_url).await
+        auth::ensure_authenticated(&cli.api_url, &config_overrides)
+            .await
+            .map_err(Into::into)
     } else if cli.recap {
-        run_recap(&cli.api_url).await
+        run_recap(
+            &cli.api_url,
+            &ariana_dir(&cli)?,
+            cli.compare.as_deref(),
+            cli.machine_hash.as_deref(),
+            cli.refresh,
+            &config_overrides,
+        )
+        .await
     } else if cli.restore {
-        restore_backup()
+        restore_backup(&env::current_dir()?, &ariana_dir(&cli)?).map_err(Into::into)
+    } else if cli.flush_pending {
+        run_flush_pending(&cli, &config_overrides).await
+    } else if cli.reset_machine_id {
+        let machine_hash = reset_machine_id(&config_overrides).await?;
+        println!(
+            "[Ariana] New machine id generated, its hash is {}",
+            machine_hash
+        );
+        if config_overrides.machine_id.is_some() {
+            println!(
+                "[Ariana] Runs keep using the machine id set with --machine-id or {} while it is set",
+                MACHINE_ID_ENV
+            );
+        }
+        Ok(())
+    } else if cli.list_supported {
+        run_list_supported(&cli.api_url, &config_overrides).await;
+        Ok(())
     } else {
         // // Ensure authenticated before running any command
         // auth::ensure_authenticated(&cli.api_url).await?;
-        main_command(cli).await
+        let events = EventLog::new(cli.json_logs);
+        let result = main_command(cli, config_overrides).await;
+        if let Err(e) = &result {
+            events.emit(Event::Error {
+                message: &e.to_string(),
+            });
+        }
+        result
     }
 }
 
-async fn main_command(cli: Cli) -> Result<()> {
-    if cli.command.is_empty() && !cli.login {
+async fn main_command(mut cli: Cli, config_overrides: ConfigOverrides) -> Result<()> {
+    let start = Instant::now();
+    let started_at = SystemTime::now();
+    if cli.command.is_empty() && !cli.login && !cli.instrument_only {
         eprintln!("Error: A command is required when not using --recap");
         eprintln!("Usage: ariana [args...] <command>");
         eprintln!("       ariana --recap");
@@ -85,7 +526,139 @@ async fn main_command(cli: Cli) -> Result<()> {
     }
 
     let current_dir = env::current_dir()?;
-    let ariana_dir = current_dir.join(ARIANA_DIR);
+    let pid_file = match &cli.pid_file {
+        Some(path) => Some(PidFile::create(&current_dir.join(path))?),
+        None => None,
+    };
+
+    let extracted_archive = match &cli.from_archive {
+        Some(archive) => {
+            let extracted = ExtractedArchive::extract(&current_dir.join(archive))?;
+            println!(
+                "[Ariana] Extracted {} to {}",
+                archive.display(),
+                extracted.path().display()
+            );
+            Some(extracted)
+        }
+        None => None,
+    };
+    let project_root = extracted_archive.as_ref().map_or_else(
+        || current_dir.clone(),
+        |extracted| extracted.path().to_path_buf(),
+    );
+
+    if cli.npm_script && !cli.command.is_empty() {
+        match resolve_package_script(&project_root, &cli.command)? {
+            Some(command) => {
+                println!(
+                    "[Ariana] `{}` is a package.json script, running `{}`",
+                    cli.command[0],
+                    command.join(" ")
+                );
+                cli.command = command;
+            }
+            None => println!(
+                "[Ariana] `{}` is not a package.json script, running it as is",
+                cli.command[0]
+            ),
+        }
+    }
+
+    let diff_output = cli.diff_output.map(|path| current_dir.join(path));
+    let metrics_file = cli.metrics_file.map(|path| current_dir.join(path));
+    let to_archive = cli.to_archive.map(|path| current_dir.join(path));
+    let show_diff = cli.diff || diff_output.is_some();
+    let no_color = cli.no_color || env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+
+    let mut session = InstrumentationSession::new(SessionConfig {
+        cwd: cli.cwd,
+        then_commands: cli.then_commands,
+        keep_going: cli.keep_going,
+        inplace: cli.inplace,
+        no_instrument: cli.no_instrument,
+        backup_compression: cli.backup_compression,
+        concurrency: cli.concurrency,
+        read_concurrency: cli
+            .instrument_concurrency_reads
+            .map_or_else(default_read_concurrency, |threads| threads as usize),
+        batch_file_count: cli.batch_file_count as usize,
+        max_upload_bytes: cli.max_upload_bytes,
+        max_concurrent_files: cli.max_concurrent_files as usize,
+        keep_ariana_dir: cli.keep_ariana_dir,
+        output_dir: cli.output_dir.map(|path| current_dir.join(path)),
+        force_copy_all: cli.force_copy_all,
+        portable: cli.portable,
+        remote: cli.remote,
+        copy_extensions: DEFAULT_COPY_EXTENSIONS
+            .iter()
+            .map(|ext| ext.to_string())
+            .chain(cli.copy_extensions)
+            .filter(|ext| !cli.no_copy_extensions.contains(ext))
+            .collect(),
+        transcript: cli.transcript.map(|path| current_dir.join(path)),
+        baseline: if cli.baseline {
+            Some(BaselineMode::Record)
+        } else if cli.compare_baseline {
+            Some(BaselineMode::Compare)
+        } else {
+            None
+        },
+        baseline_normalize: cli.baseline_normalize,
+        redact_patterns: cli.redact_patterns,
+        default_redaction: !cli.no_default_redaction,
+        stream_output: !cli.no_subprocess_stream,
+        buffer_output_file: cli.buffer_output_file,
+        max_line_length: cli.max_line_length,
+        output_tagging: cli.tag_output.map(|tagging| match tagging {
+            OutputTagging::Color if no_color => OutputTagging::Prefix,
+            tagging => tagging,
+        }),
+        upload_budget_bytes: cli.max_upload_mb.map(|mb| mb.saturating_mul(1024 * 1024)),
+        keep_output_order: cli.keep_output_order,
+        quiet: cli.quiet,
+        json_logs: cli.json_logs,
+        trace_compression: !cli.no_trace_compression,
+        max_trace_push_bytes: cli.trace_endpoint_batch_max,
+        debug_server: cli.debug_server,
+        verbose: cli.verbose,
+        show_traces: cli.show_traces,
+        trace_marker: cli.trace_output_marker,
+        tags: cli.tags.into_iter().collect(),
+        git_metadata: !cli.no_git_metadata,
+        sample_rate: cli.sample_rate,
+        dedup_traces: cli.dedup_traces,
+        save_traces: cli.save_traces.map(|path| current_dir.join(path)),
+        exclude_dirs: cli.exclude_dirs,
+        include_dirs: cli.include_dirs,
+        exclude_from: cli
+            .exclude_from
+            .iter()
+            .map(|path| current_dir.join(path))
+            .collect(),
+        skip_js: cli.no_js,
+        skip_python: cli.no_python,
+        lang_map: cli.lang_map,
+        skip_tests: cli.skip_tests,
+        test_patterns: cli.test_patterns,
+        allow_large_files: cli.allow_large_files,
+        allowed_large_files: cli.allowed_large_files,
+        instrument_external_symlinks: cli.instrument_external_symlinks,
+        follow_symlinks: cli.follow_symlinks && !cli.no_follow_symlinks,
+        fail_on_instrument_error: cli.fail_on_instrument_error,
+        wait_for_lock: cli.wait,
+        verify_syntax: cli.verify_syntax,
+        prewarm: cli.prewarm,
+        vault_key: cli.vault_key,
+        auth_token: stored_auth_token(&config_overrides),
+        config_overrides,
+        large_file_warning_bytes: cli.large_file_warning_kb.saturating_mul(1024),
+        output_closed_timeout: cli
+            .output_closed_timeout
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+        ..SessionConfig::new(&cli.api_url, project_root, cli.command)
+    })?;
 
     // Check symlink capability on Windows
     if cfg!(windows) && !can_create_symlinks().await {
@@ -96,300 +669,580 @@ async fn main_command(cli: Cli) -> Result<()> {
         println!("For more info: https://docs.microsoft.com/en-us/windows/win32/fileio/creating-symbolic-links");
     }
 
-    // Create or clean .ariana directory
-    if !cli.inplace {
-        if ariana_dir.exists() {
-            println!("[Ariana] Removing previous .ariana directory");
-            fs_extra::dir::remove(&ariana_dir)?;
+    // Create or clean .ariana directory and add it to .gitignore
+    if cli.append_run {
+        session.reuse_previous_run().await?;
+    } else {
+        session.prepare().await?;
+        session.create_vault().await?;
+        if !cli.no_instrument {
+            session.collect()?;
+            session.instrument().await?;
         }
-        fs::create_dir_all(&ariana_dir)?;
+    }
+    if show_diff {
+        let diff = session.diff()?;
+        match &diff_output {
+            Some(path) => {
+                fs::write(path, diff)?;
+                println!(
+                    "[Ariana] Instrumentation diff written to {}",
+                    path.display()
+                );
+            }
+            None => println!("{}", diff),
+        }
+    }
+    if let Some(archive) = &to_archive {
+        let archived = write_archive(session.ariana_dir(), archive)?;
+        println!(
+            "[Ariana] Instrumented project ({} files) archived to {}",
+            archived,
+            archive.display()
+        );
+    }
+    if cli.instrument_only {
+        let vault_key = session.vault_key().unwrap_or_default();
+        if cli.inplace {
+            println!("[Ariana] Your files are instrumented, run `ariana --restore` to put the originals back");
+        } else if to_archive.is_none() {
+            println!(
+                "[Ariana] Instrumented project written to {}",
+                session.working_dir().display()
+            );
+        }
+        println!(
+            "[Ariana] Traces go to vault {}, pass --vault-key {} to attach a later run to it",
+            vault_key, vault_key
+        );
+        write_manifest(&session, started_at, None);
+        return Ok(());
+    }
+    let exit_code = session.run().await?;
+
+    // Counts are final here, what was collected before the signal has been sent
+    let interrupted_by = session.interrupted_by().filter(|_| cli.summary_on_signal);
+    if let Some(signal_name) = interrupted_by {
+        let metrics = session.metrics(start.elapsed(), exit_code);
+        println!(
+            "[Ariana] Stopped by {} after {:.1}s: {} files instrumented, {} skipped, {} traces sent",
+            signal_name,
+            metrics.duration.as_secs_f64(),
+            metrics.files_instrumented,
+            metrics.files_skipped,
+            metrics.traces_pushed
+        );
+    }
+    if cli.profile || interrupted_by.is_some() {
+        println!("[Ariana] Time spent per phase:");
+        for (phase, duration) in session.phase_timings() {
+            println!("[Ariana]   {:<16} {} ms", phase, duration.as_millis());
+        }
+    }
+
+    let skipped_files = session.skipped_files();
+    if cli.strict && !skipped_files.is_empty() {
+        eprintln!(
+            "[Ariana] {} files could not be instrumented ({}):",
+            skipped_files.len(),
+            skip_causes_summary(skipped_files)
+        );
+        for file in skipped_files {
+            let path = file
+                .path
+                .strip_prefix(&session.config().project_root)
+                .unwrap_or(&file.path);
+            eprintln!("[Ariana]   {}: {}", path.display(), file.reason);
+        }
+    } else if !skipped_files.is_empty() {
+        println!(
+            "[Ariana] {} files could not be instrumented ({}), use --strict to list them and fail the run",
+            skipped_files.len(),
+            skip_causes_summary(skipped_files)
+        );
     }
 
-    // Add .ariana to .gitignore
-    add_to_gitignore(&current_dir).await?;
+    let large_files = session.large_files();
+    if !large_files.is_empty() {
+        println!(
+            "[Ariana] {} files of {} KB or more were sent to be instrumented, the largest:",
+            large_files.len(),
+            cli.large_file_warning_kb
+        );
+        for (path, size) in large_files.iter().take(5) {
+            let path = path
+                .strip_prefix(&session.config().project_root)
+                .unwrap_or(path);
+            println!(
+                "[Ariana]   {} ({:.1} MB)",
+                path.display(),
+                *size as f64 / (1024.0 * 1024.0)
+            );
+        }
+    }
 
-    // Create vault
-    println!("[Ariana] Creating a new vault for your traces");
-    let current_cwd_str = env::current_dir()?.to_string_lossy().into_owned();
-    let vault_command_str = if cli.command.is_empty() { None } else { Some(cli.command.join(" ")) };
-    let vault_key = create_vault(&cli.api_url, vault_command_str.as_deref(), Some(&current_cwd_str)).await?;
-    let import_style = detect_project_import_style(&current_dir)?;
+    if !cli.no_footer && !cli.quiet && !cli.json_logs && io::stdout().is_terminal() {
+        println!("[Ariana] ❓ Use the Ariana IDE extension to view the traces.");
+        println!("[Ariana] 🙏 Thanks for using Ariana! We are looking for your feedback, suggestions & bugs so we can make Ariana super awesome for you!");
+        println!("[Ariana] ➡️  Join the Discord: https://discord.gg/Y3TFTmE89g");
+    }
 
-    // Process files
-    let working_dir = if cli.inplace {
-        current_dir.clone()
+    let exit_code = if exit_code == 0 && cli.strict && !session.skipped_files().is_empty() {
+        1
     } else {
-        ariana_dir.clone()
+        exit_code
     };
+    let exit_code = if exit_code == 0 && session.baseline_diff().is_some() {
+        1
+    } else {
+        exit_code
+    };
+    if let Some(path) = &metrics_file {
+        match write_metrics_file(path, &session.metrics(start.elapsed(), exit_code)) {
+            Ok(()) => println!("[Ariana] Metrics written to {}", path.display()),
+            Err(e) => eprintln!("[Ariana] {}", e),
+        }
+    }
+    write_manifest(&session, started_at, Some(exit_code));
 
-    let collected_items = collect_items(&current_dir, &ariana_dir)?;
-    println!("[Ariana] Instrumenting code files");
-    process_items(
-        &collected_items,
-        &cli.api_url,
-        &vault_key,
-        &import_style,
-        cli.inplace,
-    )
-    .await
-    .map_err(|s| anyhow!(s))?;
-
-    // Write vault secret key
-    let vault_secret_key_path = ariana_dir.join(".vault_secret_key");
-    fs::write(
-        &vault_secret_key_path,
-        format!("{}\nDO NOT SHARE THE ABOVE KEY WITH ANYONE", vault_key),
-    )?;
-
-    let (trace_tx, mut trace_rx) = mpsc::channel::<Trace>(1);
-    let (output_tx, output_rx) = mpsc::channel::<(String, OutputSource)>(100);
-    let (stop_tx, mut stop_rx) = mpsc::channel::<()>(1);
-    let (subprocess_stop_tx, subprocess_stop_rx) = mpsc::channel::<()>(1);
-
-    let api_url = cli.api_url.clone();
-    let trace_watcher_vault_key = vault_key.clone();
-    let trace_watcher = spawn(async move {
-        let _ = watch_traces(&mut trace_rx, &api_url, &trace_watcher_vault_key, &mut stop_rx).await;
-    });
-    
-    // Start the subprocess output watcher
-    let subprocess_api_url = cli.api_url.clone();
-    let subprocess_vault_key = vault_key.clone();
-    let subprocess_watcher = spawn(async move {
-        watch_subprocess_output(output_rx, &subprocess_api_url, &subprocess_vault_key, subprocess_stop_rx).await
-    });
-    // Prepare the command to run
-    let command_to_run = cli.command[0].clone(); // Assuming cli.command is not empty, checked earlier
-    let command_args = cli.command[1..].to_vec();
+    if exit_code != 0 {
+        // Exiting skips destructors
+        drop(pid_file);
+        drop(extracted_archive);
+        exit(exit_code);
+    }
+    Ok(())
+}
 
-    println!(
-        "[Ariana] Running `{} {}` in {}/",
-        command_to_run,
-        command_args.join(" "),
-        working_dir.file_name().unwrap_or_default().to_str().unwrap_or_default()
-    );
-    println!("\n\n\n");
-
-    let mut child = if cfg!(windows) {
-        tokio::process::Command::new("cmd")
-            .args(&["/C", &command_to_run])
-            .args(&command_args)
-            .current_dir(&working_dir)
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .spawn()?
-    } else {
-        tokio::process::Command::new(&command_to_run)
-            .args(&command_args)
-            .current_dir(&working_dir)
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .spawn()?
+/// Writes `.ariana/run.json`, only warning if it can't: the run itself went fine.
+fn write_manifest(
+    session: &InstrumentationSession,
+    started_at: SystemTime,
+    exit_code: Option<i32>,
+) {
+    let manifest = session.manifest(env::args().collect(), started_at, exit_code);
+    if let Err(e) = write_run_manifest(session.ariana_dir(), &manifest) {
+        eprintln!("[Ariana] {}", e);
+    }
+}
+
+async fn run_recap(
+    api_url: &str,
+    ariana_dir: &Path,
+    compare_vault_key: Option<&str>,
+    machine_hash: Option<&str>,
+    refresh: bool,
+    config_overrides: &ConfigOverrides,
+) -> Result<()> {
+    println!("[Ariana] Reading vault secret key...");
+    let vault_key = read_vault_secret_key(ariana_dir).await?;
+    match read_run_manifest(ariana_dir) {
+        Ok(Some(manifest)) if manifest.vault_key.as_deref() == Some(vault_key.as_str()) => {
+            let commands = manifest
+                .commands
+                .iter()
+                .map(|command| command.join(" "))
+                .collect::<Vec<_>>();
+            let outcome = match manifest.exit_code {
+                Some(exit_code) => format!("exited with {}", exit_code),
+                None => "instrumented only".to_string(),
+            };
+            println!(
+                "[Ariana] Last run: `{}`, {}, {} traces sent, {} files instrumented and {} skipped",
+                commands.join("` then `"),
+                outcome,
+                manifest.traces_pushed,
+                manifest.files_instrumented,
+                manifest.skipped_files.len()
+            );
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("[Ariana] {}", e),
+    }
+
+    // Generate a machine hash for the request
+    let machine_hash = match machine_hash {
+        Some(machine_hash) => machine_hash.to_string(),
+        None => generate_machine_id(config_overrides).await?,
     };
 
-    let child_stdout = child.stdout.take().expect("Failed to capture stdout");
-    let mut stdout_reader = tokio::io::BufReader::new(child_stdout).lines();
-
-    let child_stderr = child.stderr.take().expect("Failed to capture stderr");
-    let mut stderr_reader = tokio::io::BufReader::new(child_stderr).lines();
-
-    let stdout_output_tx = output_tx.clone();
-    let stderr_output_tx_clone = output_tx.clone(); 
-    let trace_tx_for_stdout = trace_tx.clone();
-    
-    let perf_now = std::time::Instant::now();
-
-    let stdout_processing_task = tokio::spawn(async move {
-        loop {
-            match stdout_reader.next_line().await {
-                Ok(Some(line)) => {
-                    let mut processed_line = String::new();
-                    let mut current_pos = 0;
-                    while let Some(start_idx) = line[current_pos..].find("<trace id=") {
-                        let absolute_start = current_pos + start_idx;
-                        processed_line.push_str(&line[current_pos..absolute_start]);
-                        if let Some(end_idx) = line[absolute_start..].find("</trace>") {
-                            let absolute_end = absolute_start + end_idx + 8; 
-                            if let Some(id_start_offset) = line[absolute_start..absolute_end].find('"') {
-                                let id_start_abs = absolute_start + id_start_offset + 1;
-                                if let Some(id_end_offset) = line[id_start_abs..absolute_end].find('"') {
-                                    let content_start = id_start_abs + id_end_offset + 2;
-                                    let content_end = absolute_start + end_idx;
-                                    if content_start <= content_end && content_end <= line.len() {
-                                        let trace_content = &line[content_start..content_end];
-                                        match serde_json::from_str::<Trace>(trace_content) {
-                                            Ok(trace) => {
-                                                if trace_tx_for_stdout.send(trace).await.is_err() {
-                                                    eprintln!("[Ariana] Trace channel closed. Cannot send more traces.");
-                                                }
-                                            }
-                                            Err(e) => {
-                                                eprintln!("[Ariana] Failed to deserialize trace content: {}, content: '{}'", e, trace_content);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            current_pos = absolute_end;
-                        } else {
-                            processed_line.push_str(&line[absolute_start..]);
-                            current_pos = line.len();
-                            break;
-                        }
-                    }
-                    if current_pos < line.len() {
-                        processed_line.push_str(&line[current_pos..]);
-                    }
-                    if !processed_line.trim_matches(|c| c == ' ' || c == '\n' || c == '\t' || c == '\r' || c == '\x08').is_empty() {
-                        println!("{}", processed_line);
-                        if stdout_output_tx.send((processed_line.clone(), OutputSource::Stdout)).await.is_err() {
-                            eprintln!("[Ariana] Stdout channel closed. Stopping stdout processing.");
-                            break;
-                        }
-                    }
-                }
-                Ok(None) => break, 
-                Err(e) => {
-                    eprintln!("[Ariana] Error reading stdout from subprocess: {}", e);
-                    break;
-                }
+    let recap = get_recap(api_url, ariana_dir, &vault_key, &machine_hash, refresh).await?;
+    let Some(other_vault_key) = compare_vault_key else {
+        match recap {
+            Some(recap) => {
+                println!("\n[Ariana] Trace Recap:\n");
+                println!("{}", recap);
             }
+            None => println!("[Ariana] No recap available yet for the last run"),
         }
-    });
-
-    let stderr_processing_task = tokio::spawn(async move {
-        loop {
-            match stderr_reader.next_line().await {
-                Ok(Some(line)) => {
-                    eprintln!("{}", line);
-                    if stderr_output_tx_clone.send((line, OutputSource::Stderr)).await.is_err() {
-                        eprintln!("[Ariana] Stderr channel closed. Stopping stderr processing.");
-                        break;
-                    }
-                }
-                Ok(None) => break, 
-                Err(e) => {
-                    eprintln!("[Ariana] Error reading stderr from subprocess: {}", e);
-                    break;
-                }
-            }
+        return Ok(());
+    };
+
+    println!(
+        "[Ariana] Getting the recap of vault {} to compare with...",
+        other_vault_key
+    );
+    let other_recap =
+        get_recap(api_url, ariana_dir, other_vault_key, &machine_hash, refresh).await?;
+    match (other_recap, recap) {
+        (Some(other_recap), Some(recap)) => {
+            println!(
+                "\n[Ariana] Trace Recap Comparison (- vault {}, + last run):\n",
+                other_vault_key
+            );
+            print!("{}", recap_diff(&other_recap, &recap));
         }
-    });
-    
-    tokio::select! {
-        biased; 
-        _ = signal::ctrl_c() => {
-            println!("[Ariana] Received Ctrl+C, stopping your command...");
-            if cli.inplace {
-                if let Err(e) = processor::restore_backup() {
-                    eprintln!("[Ariana] Error restoring backup during Ctrl+C: {}", e);
-                } else {
-                    println!("[Ariana] Backup restored due to Ctrl+C (if applicable).");
-                }
-            }
-            if let Err(e) = child.kill().await {
-                eprintln!("[Ariana] Failed to kill subprocess: {}. It might have already exited.", e);
-            } else {
-                println!("[Ariana] Subprocess signalled to terminate.");
-            }
-            // Child will be waited for outside the select block if killed.
+        (None, Some(recap)) => {
+            println!("[Ariana] No recap available for vault {}, nothing to compare with. Recap of the last run:\n", other_vault_key);
+            println!("{}", recap);
         }
-        result = child.wait() => {
-            match result {
-                Ok(status) => {
-                    if !status.success() {
-                        eprintln!("[Ariana] Subprocess exited with status: {}", status);
-                    }
-                }
-                Err(e) => {
-                    eprintln!("[Ariana] Error waiting for subprocess: {}", e);
-                }
-            }
+        (Some(other_recap), None) => {
+            println!("[Ariana] No recap available yet for the last run, nothing to compare with. Recap of vault {}:\n", other_vault_key);
+            println!("{}", other_recap);
         }
+        (None, None) => println!(
+            "[Ariana] No recap available yet for either the last run or vault {}",
+            other_vault_key
+        ),
     }
 
-    if let Err(e) = stdout_processing_task.await {
-        eprintln!("[Ariana] Error joining stdout processing task: {:?}", e);
+    Ok(())
+}
+
+/// The recap of the vault, `None` if the server has none yet. Recaps are cached in `ariana_dir`:
+/// one fetched less than `RECAP_CACHE_TTL` ago is used as is unless `refresh`, an older one is
+/// used if the server says it didn't change, or if the server can't be reached.
+async fn get_recap(
+    api_url: &str,
+    ariana_dir: &Path,
+    vault_key: &str,
+    machine_hash: &str,
+    refresh: bool,
+) -> Result<Option<String>> {
+    let cached = load_cached_recap(ariana_dir, vault_key);
+    if let Some(cached) = cached
+        .as_ref()
+        .filter(|cached| !refresh && cached.is_fresh())
+    {
+        println!(
+            "[Ariana] Using the recap fetched {} min ago, pass --refresh to fetch it again",
+            cached.age().as_secs() / 60
+        );
+        return Ok(Some(cached.recap.clone()));
     }
-    if let Err(e) = stderr_processing_task.await {
-        eprintln!("[Ariana] Error joining stderr processing task: {:?}", e);
+
+    println!("[Ariana] Fetching recap from server...");
+    let response = fetch_recap(
+        api_url,
+        vault_key,
+        machine_hash,
+        cached.as_ref().filter(|_| !refresh),
+    )
+    .await;
+    match (response, cached) {
+        (Ok(RecapResponse::NotModified), Some(mut cached)) => {
+            cached.touch();
+            store_cached_recap(ariana_dir, vault_key, &cached).unwrap_or_else(|e| eprintln!("[Ariana] Could not cache the recap: {}", e));
+            Ok(Some(cached.recap))
+        }
+        (Ok(RecapResponse::NotModified), None) => Err(anyhow!("Failed to get trace tree: the server answered HTTP 304 to a request that wasn't conditional")),
+        (Ok(RecapResponse::Recap(Some(cached))), _) => {
+            store_cached_recap(ariana_dir, vault_key, &cached).unwrap_or_else(|e| eprintln!("[Ariana] Could not cache the recap: {}", e));
+            Ok(Some(cached.recap))
+        }
+        (Ok(RecapResponse::Recap(None)), _) => {
+            invalidate_cached_recap(ariana_dir, vault_key);
+            Ok(None)
+        }
+        (Err(e), Some(cached)) if e.downcast_ref::<reqwest::Error>().is_some_and(|e| e.is_connect() || e.is_timeout()) => {
+            eprintln!("[Ariana] Could not reach the server ({}), using the recap fetched {} min ago", e, cached.age().as_secs() / 60);
+            Ok(Some(cached.recap))
+        }
+        (Err(e), _) => Err(e),
     }
+}
 
-    let perf_end = std::time::Instant::now();
-    println!(
-        "[Ariana] Command finished, took {} ms. Waiting to finish sending collected traces and output...",
-        perf_end.duration_since(perf_now).as_millis()
-    );
+/// What the server answered to a recap request.
+enum RecapResponse {
+    /// The recap, `None` if the server has none yet.
+    Recap(Option<CachedRecap>),
+    /// The recap didn't change since `cached` was fetched.
+    NotModified,
+}
 
-    drop(stop_tx); 
-    drop(subprocess_stop_tx);
-    drop(output_tx);
+/// Fetches the recap of the vault, conditionally if `cached` is given.
+async fn fetch_recap(
+    api_url: &str,
+    vault_key: &str,
+    machine_hash: &str,
+    cached: Option<&CachedRecap>,
+) -> Result<RecapResponse> {
+    // Call the server API to get the trace tree, retrying when the server is momentarily unavailable
+    let client = reqwest::Client::new();
+    let mut attempt = 1;
+    let response = loop {
+        let mut request = client
+            .post(api_endpoint(
+                api_url,
+                &format!("vaults/{}/get-trace-tree", vault_key),
+            ))
+            .header("X-Machine-Hash", machine_hash);
+        if let Some(etag) = cached.and_then(|cached| cached.etag.as_deref()) {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = cached.and_then(|cached| cached.last_modified.as_deref()) {
+            request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+        let result = request.send().await;
+        let retry_reason = match &result {
+            Ok(response) if response.status().is_server_error() => {
+                Some(format!("HTTP {}", response.status()))
+            }
+            Err(e) if e.is_connect() || e.is_timeout() => Some(e.to_string()),
+            _ => None,
+        };
+        match retry_reason {
+            Some(reason) if attempt < MAX_RECAP_ATTEMPTS => {
+                println!("[Ariana] Could not get the recap ({}), retrying...", reason);
+                tokio::time::sleep(Duration::from_millis(500 * 2u64.pow(attempt - 1))).await;
+                attempt += 1;
+            }
+            _ => break result?,
+        }
+    };
 
-    if let Err(e) = trace_watcher.await {
-         eprintln!("[Ariana CLI Main] Failed to join trace_watcher task: {:?}", e);
+    let status = response.status();
+    if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+        return Err(anyhow!(
+            "The server refused the recap of vault {} for this machine (HTTP {}). A recap can only be fetched from the machine that created the vault: run it there, or pass that machine's hash with --machine-hash. The machine hash sent was {}",
+            vault_key, status, machine_hash
+        ));
+    }
+    if status == StatusCode::NOT_MODIFIED {
+        return Ok(RecapResponse::NotModified);
     }
-    match subprocess_watcher.await {
-        Ok(Ok(_)) => {}
-        Ok(Err(e)) => eprintln!("[Ariana CLI Main] Subprocess_watcher completed with error: {}", e),
-        Err(e) => eprintln!("[Ariana CLI Main] Failed to join subprocess_watcher task: {:?}", e),
+    let header = |name: reqwest::header::HeaderName| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let etag = header(reqwest::header::ETAG);
+    let last_modified = header(reqwest::header::LAST_MODIFIED);
+    if response
+        .content_length()
+        .is_some_and(|length| length > MAX_RECAP_BYTES)
+    {
+        return Err(anyhow!(
+            "Failed to get trace tree: the response is too large ({} bytes)",
+            response.content_length().unwrap_or_default()
+        ));
     }
+    let is_json = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.contains("json"));
+    let body = read_recap_body(response).await?;
 
-    if cli.inplace {
-        if let Err(e) = processor::restore_backup() {
-            eprintln!("[Ariana] Error restoring backup at end of command: {}", e);
-        } else {
-            println!("[Ariana] Backup restored at end of command (if applicable).");
+    if !status.is_success() {
+        return Err(anyhow!(
+            "Failed to get trace tree: HTTP {}: {}",
+            status,
+            body_excerpt(&body)
+        ));
+    }
+    // A proxy in the way may answer with an HTML error page
+    if !is_json {
+        return Err(anyhow!(
+            "Failed to get trace tree: expected JSON from the server, got: {}",
+            body_excerpt(&body)
+        ));
+    }
+
+    // Parse the response
+    let value: serde_json::Value = serde_json::from_str(&body)
+        .map_err(|e| anyhow!("Failed to parse the recap ({}): {}", e, body_excerpt(&body)))?;
+    if value.get("answer").is_none_or(serde_json::Value::is_null) {
+        return Ok(RecapResponse::Recap(None));
+    }
+    let trace_tree_response: ariana_server::web::vaults::GetTraceTreeLLMResponse =
+        serde_json::from_value(value)
+            .map_err(|e| anyhow!("Failed to parse the recap ({}): {}", e, body_excerpt(&body)))?;
+    if trace_tree_response.answer.trim().is_empty() {
+        return Ok(RecapResponse::Recap(None));
+    }
+    Ok(RecapResponse::Recap(Some(CachedRecap::new(
+        trace_tree_response.answer,
+        etag,
+        last_modified,
+    ))))
+}
+
+/// Reads the body of a recap response, failing once it passes `MAX_RECAP_BYTES` whether or
+/// not the server announced its length.
+async fn read_recap_body(mut response: reqwest::Response) -> Result<String> {
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if (body.len() + chunk.len()) as u64 > MAX_RECAP_BYTES {
+            return Err(anyhow!(
+                "Failed to get trace tree: the response is larger than {} bytes",
+                MAX_RECAP_BYTES
+            ));
         }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Attempts at fetching a recap when the server errors or can't be reached.
+const MAX_RECAP_ATTEMPTS: u32 = 3;
+
+/// Recap responses bigger than this are refused.
+const MAX_RECAP_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Start of a response body, to show what the server or a proxy actually answered.
+fn body_excerpt(body: &str) -> String {
+    const MAX_CHARS: usize = 500;
+    let body = body.trim();
+    if body.is_empty() {
+        return "(empty body)".to_string();
+    }
+    let mut excerpt: String = body.chars().take(MAX_CHARS).collect();
+    if excerpt.len() < body.len() {
+        excerpt.push_str("...");
     }
+    excerpt
+}
 
-    println!("[Ariana] ❓ Use the Ariana IDE extension to view the traces.");
-    println!("[Ariana] 🙏 Thanks for using Ariana! We are looking for your feedback, suggestions & bugs so we can make Ariana super awesome for you!");
-    println!("[Ariana] ➡️  Join the Discord: https://discord.gg/Y3TFTmE89g");
+async fn run_flush_pending(cli: &Cli, config_overrides: &ConfigOverrides) -> Result<()> {
+    let ariana_dir = ariana_dir(cli)?;
+    let mut pending = vec![];
+    let pending_traces_path = ariana_dir.join(PENDING_TRACES_FILE);
+    if pending_traces_path.exists() {
+        let vault_key = match &cli.vault_key {
+            Some(vault_key) => vault_key.clone(),
+            None => read_vault_secret_key(&ariana_dir).await?,
+        };
+        pending.push((pending_traces_path, vault_key));
+    }
+    // Set aside by the runs that followed, named after their vault
+    if let Ok(entries) = fs::read_dir(ariana_dir.join(PENDING_TRACES_DIR)) {
+        for entry in entries {
+            let path = entry?.path();
+            if path
+                .file_name()
+                .is_some_and(|name| name == UNKNOWN_VAULT_PENDING_TRACES)
+            {
+                match &cli.vault_key {
+                    Some(vault_key) => pending.push((path, vault_key.clone())),
+                    None => eprintln!("[Ariana] The vault of the traces in {} is unknown, pass --vault-key to push them", path.display()),
+                }
+            } else if let Some(vault_key) = path.file_stem().and_then(|stem| stem.to_str()) {
+                pending.push((path.clone(), vault_key.to_string()));
+            }
+        }
+    }
+    if pending.is_empty() {
+        println!("[Ariana] No pending traces to push");
+        return Ok(());
+    }
 
+    let options = TraceWatcherOptions {
+        compress: !cli.no_trace_compression,
+        verbose: cli.verbose,
+        sample_rate: 1.0,
+        dedup_traces: false,
+        pending_traces_path: None,
+        save_traces_path: None,
+        upload_budget: Arc::new(UploadBudget::default()),
+        max_push_bytes: cli.trace_endpoint_batch_max,
+        auth_token: stored_auth_token(config_overrides),
+    };
+    for (path, vault_key) in pending {
+        println!(
+            "[Ariana] Pushing pending traces of vault {} from {}...",
+            vault_key,
+            path.display()
+        );
+        invalidate_cached_recap(&ariana_dir, &vault_key);
+        let delivered = flush_pending_traces(&path, &cli.api_url, &vault_key, &options).await?;
+        println!("[Ariana] Pushed {} pending traces", delivered);
+    }
     Ok(())
 }
 
-async fn run_recap(api_url: &str) -> Result<()> {
-    println!("[Ariana] Reading vault secret key...");
-    let vault_key = read_vault_secret_key().await?;
-    
-    println!("[Ariana] Fetching recap from server...");
-    
-    // Generate a machine hash for the request
-    let machine_hash = generate_machine_id().await?;
-    
-    // Call the server API to get the trace tree
-    let client = reqwest::Client::new();
-    let response = client
-        .post(&format!("{}/vaults/{}/get-trace-tree", api_url, vault_key))
-        .header("X-Machine-Hash", machine_hash)
-        .send()
-        .await?;
-    
-    if !response.status().is_success() {
-        return Err(anyhow!("Failed to get trace tree: HTTP {}", response.status()));
-    }
-    
-    // Parse and print the response
-    let trace_tree_response: ariana_server::web::vaults::GetTraceTreeLLMResponse = response.json().await?;
-    
-    println!("\n[Ariana] Trace Recap:\n");
-    println!("{}", trace_tree_response.answer);
-    
-    Ok(())
+async fn run_list_supported(api_url: &str, config_overrides: &ConfigOverrides) {
+    let (capabilities, source) = load_capabilities(api_url, true, config_overrides).await;
+    match source {
+        CapabilitiesSource::Server => {
+            println!("[Ariana] The server at {} can instrument:", api_url)
+        }
+        CapabilitiesSource::Cache => println!(
+            "[Ariana] Could not ask the server at {}, it could instrument last time:",
+            api_url
+        ),
+        CapabilitiesSource::Builtin => println!(
+            "[Ariana] The server at {} doesn't tell what it can instrument, it should support:",
+            api_url
+        ),
+    }
+    for language in &capabilities.languages {
+        let extensions = language
+            .extensions
+            .iter()
+            .map(|ext| format!(".{}", ext.trim_start_matches('.')))
+            .collect::<Vec<_>>();
+        println!("[Ariana]   {}: {}", language.name, extensions.join(", "));
+    }
 }
 
-/// Read the first line of the .ariana/.vault_secret_key file to get the vault secret key
-async fn read_vault_secret_key() -> Result<String> {
+/// How many files were left uninstrumented for each cause, e.g. `2 declined by the server, 1 failed`.
+fn skip_causes_summary(skipped_files: &[SkippedFile]) -> String {
+    [
+        (SkipCause::Declined, "declined by the server"),
+        (SkipCause::Failed, "failed"),
+        (SkipCause::Local, "left out by Ariana"),
+    ]
+    .iter()
+    .filter_map(|(cause, label)| {
+        let count = skipped_files
+            .iter()
+            .filter(|file| file.cause == *cause)
+            .count();
+        (count > 0).then(|| format!("{} {}", count, label))
+    })
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+/// The .ariana directory of the last run, in --output-dir if given or else in the current directory
+fn ariana_dir(cli: &Cli) -> Result<PathBuf> {
     let current_dir = env::current_dir()?;
-    let vault_key_path = current_dir.join(ARIANA_DIR).join(".vault_secret_key");
-    
+    let parent_dir = match &cli.output_dir {
+        Some(output_dir) => current_dir.join(output_dir),
+        None => current_dir,
+    };
+    Ok(parent_dir.join(ARIANA_DIR))
+}
+
+/// Read the first line of the .ariana/.vault_secret_key file to get the vault secret key
+async fn read_vault_secret_key(ariana_dir: &Path) -> Result<String> {
+    let vault_key_path = ariana_dir.join(".vault_secret_key");
+
     if !vault_key_path.exists() {
-        return Err(anyhow!("Vault secret key file not found at {}. Have you run 'ariana run' first?", vault_key_path.display()));
+        return Err(anyhow!(
+            "Vault secret key file not found at {}. Have you run 'ariana run' first?",
+            vault_key_path.display()
+        ));
     }
-    
+
     let content = tokio::fs::read_to_string(&vault_key_path).await?;
-    let vault_key = content.lines().next().ok_or_else(|| anyhow!("Vault secret key file is empty"))?;
-    
+    let vault_key = content
+        .lines()
+        .next()
+        .ok_or_else(|| anyhow!("Vault secret key file is empty"))?;
+
     Ok(vault_key.to_string())
-}
\ No newline at end of file
+}
@@ -0,0 +1,112 @@
+use ariana_server::traces::instrumentation::ecma::EcmaImportStyle;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::collector::SkipCause;
+use crate::error::{CliError, Result};
+
+/// Name of the file in `.ariana` describing the last run, see [`RunManifest`].
+pub const RUN_MANIFEST_FILE: &str = "run.json";
+
+/// Bumped when fields of [`RunManifest`] change meaning or are removed, not when some are added.
+pub const RUN_MANIFEST_VERSION: u32 = 1;
+
+/// How a run was configured and how it went, written to `.ariana/run.json` at its end for the
+/// IDE extension, `--recap`, and whoever needs to know after the fact what a run did.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunManifest {
+    pub version: u32,
+    /// Version of the CLI that made the run.
+    pub ariana_version: String,
+    /// Arguments the CLI was started with, its own flags included, identifying values and
+    /// secrets redacted.
+    pub args: Vec<String>,
+    /// Milliseconds since the epoch.
+    pub started_at: u64,
+    /// Milliseconds since the epoch.
+    pub finished_at: u64,
+    pub project_root: PathBuf,
+    /// Directory the commands ran in.
+    pub working_dir: PathBuf,
+    /// The command, then those of `--then`, in order.
+    pub commands: Vec<Vec<String>>,
+    pub api_url: String,
+    pub vault_key: Option<String>,
+    pub inplace: bool,
+    /// Import style of the project's JavaScript, `None` if the project wasn't instrumented by
+    /// this run, e.g. with `--append-run`.
+    pub import_style: Option<EcmaImportStyle>,
+    pub files_instrumented: usize,
+    pub skipped_files: Vec<ManifestSkippedFile>,
+    /// Files of `--large-file-warning-kb` or more sent to be instrumented, largest first.
+    pub large_files: Vec<ManifestLargeFile>,
+    /// Duration of each phase, in the order they ran.
+    pub timings: Vec<ManifestTiming>,
+    pub bytes_uploaded: u64,
+    pub traces_pushed: usize,
+    /// Whether the output differed from the baseline it was compared with.
+    pub baseline_differs: bool,
+    /// Exit code of the run, `None` if no command ran, e.g. with `--instrument-only`.
+    pub exit_code: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestSkippedFile {
+    /// Relative to the project root.
+    pub path: PathBuf,
+    pub reason: String,
+    /// `local`, `declined` or `failed`, see [`SkipCause`].
+    pub cause: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestLargeFile {
+    /// Relative to the project root.
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestTiming {
+    pub phase: String,
+    pub milliseconds: u64,
+}
+
+/// Name of `cause` in the manifest.
+pub fn skip_cause_name(cause: SkipCause) -> &'static str {
+    match cause {
+        SkipCause::Local => "local",
+        SkipCause::Declined => "declined",
+        SkipCause::Failed => "failed",
+    }
+}
+
+/// Writes `manifest` to `.ariana/run.json`, replacing it at once so that a reader never sees
+/// a partial file.
+pub fn write_run_manifest(ariana_dir: &Path, manifest: &RunManifest) -> Result<()> {
+    let path = ariana_dir.join(RUN_MANIFEST_FILE);
+    let tmp_path = ariana_dir.join(format!("{}.tmp", RUN_MANIFEST_FILE));
+    fs::write(&tmp_path, serde_json::to_vec_pretty(manifest)?)
+        .map_err(|e| CliError::path(&tmp_path, e))?;
+    fs::rename(&tmp_path, &path).map_err(|e| CliError::path(&path, e))?;
+    Ok(())
+}
+
+/// The manifest of the last run made in `ariana_dir`, `None` if there is none.
+pub fn read_run_manifest(ariana_dir: &Path) -> Result<Option<RunManifest>> {
+    let path = ariana_dir.join(RUN_MANIFEST_FILE);
+    let content = match fs::read(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(CliError::path(path, e)),
+    };
+    let manifest = serde_json::from_slice(&content).map_err(|e| {
+        CliError::Config(format!(
+            "{} is not a valid run manifest: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    Ok(Some(manifest))
+}
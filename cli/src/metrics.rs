@@ -0,0 +1,138 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::error::{CliError, Result};
+
+/// Figures about a finished run, written with `--metrics-file` for CI dashboards to track
+/// instrumentation coverage and timing over time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunMetrics {
+    pub files_instrumented: usize,
+    pub files_skipped: usize,
+    pub bytes_uploaded: u64,
+    pub traces_pushed: usize,
+    pub duration: Duration,
+    pub exit_code: i32,
+}
+
+impl RunMetrics {
+    /// The metrics as OpenMetrics gauges, which Prometheus' text format also accepts.
+    pub fn to_openmetrics(&self) -> String {
+        let gauges: [(&str, &str, String); 6] = [
+            (
+                "files_instrumented",
+                "Source files instrumented.",
+                self.files_instrumented.to_string(),
+            ),
+            (
+                "files_skipped",
+                "Source files left uninstrumented.",
+                self.files_skipped.to_string(),
+            ),
+            (
+                "bytes_uploaded",
+                "Bytes sent to the server.",
+                self.bytes_uploaded.to_string(),
+            ),
+            (
+                "traces_pushed",
+                "Traces sent to the server.",
+                self.traces_pushed.to_string(),
+            ),
+            (
+                "duration_seconds",
+                "Duration of the whole run.",
+                format!("{:.3}", self.duration.as_secs_f64()),
+            ),
+            (
+                "exit_code",
+                "Exit code of the run.",
+                self.exit_code.to_string(),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, help, value) in gauges {
+            let _ = writeln!(text, "# TYPE ariana_{} gauge", name);
+            let _ = writeln!(text, "# HELP ariana_{} {}", name, help);
+            let _ = writeln!(text, "ariana_{} {}", name, value);
+        }
+        text.push_str("# EOF\n");
+        text
+    }
+}
+
+/// Writes `metrics` to `path`, replacing it at once so that a scraper never reads a partial
+/// file. Refuses to write anything that isn't valid OpenMetrics text.
+pub fn write_metrics_file(path: &Path, metrics: &RunMetrics) -> Result<()> {
+    let text = metrics.to_openmetrics();
+    validate_openmetrics(&text)?;
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    fs::write(&tmp_path, text).map_err(|e| CliError::path(&tmp_path, e))?;
+    fs::rename(&tmp_path, path).map_err(|e| CliError::path(path, e))?;
+    Ok(())
+}
+
+/// Checks the subset of the OpenMetrics text format we emit: `# TYPE`, `# HELP` and sample
+/// lines with a valid metric name and a numeric value, ending with `# EOF`.
+pub fn validate_openmetrics(text: &str) -> Result<()> {
+    let mut lines = text.lines().peekable();
+    let mut terminated = false;
+    while let Some(line) = lines.next() {
+        if line == "# EOF" {
+            if lines.peek().is_some() {
+                return Err(CliError::Config(
+                    "Invalid metrics: lines after `# EOF`".to_string(),
+                ));
+            }
+            terminated = true;
+        } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+            match rest.split_once(' ') {
+                Some((name, "gauge" | "counter")) if is_valid_metric_name(name) => {}
+                _ => {
+                    return Err(CliError::Config(format!(
+                        "Invalid metrics line: `{}`",
+                        line
+                    )))
+                }
+            }
+        } else if let Some(rest) = line.strip_prefix("# HELP ") {
+            let name = rest.split(' ').next().unwrap_or_default();
+            if !is_valid_metric_name(name) {
+                return Err(CliError::Config(format!(
+                    "Invalid metrics line: `{}`",
+                    line
+                )));
+            }
+        } else {
+            match line.split_once(' ') {
+                Some((name, value))
+                    if is_valid_metric_name(name)
+                        && value.parse::<f64>().is_ok_and(f64::is_finite) => {}
+                _ => {
+                    return Err(CliError::Config(format!(
+                        "Invalid metrics line: `{}`",
+                        line
+                    )))
+                }
+            }
+        }
+    }
+    if !terminated {
+        return Err(CliError::Config(
+            "Invalid metrics: missing `# EOF`".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
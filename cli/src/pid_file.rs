@@ -0,0 +1,109 @@
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::{CliError, Result};
+
+/// A file holding the PID of this process, written with `--pid-file` so that a supervisor can
+/// send it SIGTERM and wait for the file to disappear to know the run is over. The file is
+/// removed when the `PidFile` is dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the PID of this process to `path`. A pid file left by a run that crashed is
+    /// replaced, but one of a process still running is an error, so that two runs never share
+    /// it.
+    pub fn create(path: &Path) -> Result<Self> {
+        let pid = std::process::id();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(path) {
+                Ok(mut file) => {
+                    writeln!(file, "{}", pid).map_err(|e| {
+                        let _ = fs::remove_file(path);
+                        CliError::path(path, e)
+                    })?;
+                    return Ok(PidFile {
+                        path: path.to_path_buf(),
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(CliError::path(path, e)),
+            }
+
+            match read_pid(path) {
+                Some(other_pid) if other_pid != pid && is_running(other_pid) => {
+                    return Err(CliError::AlreadyRunning(format!(
+                        "Pid file {} belongs to process {} which is still running, stop it or pass another --pid-file",
+                        path.display(),
+                        other_pid
+                    )));
+                }
+                Some(other_pid) => println!(
+                    "[Ariana] Replacing pid file {} left by process {} which is no longer running",
+                    path.display(),
+                    other_pid
+                ),
+                None => println!(
+                    "[Ariana] Replacing pid file {} which doesn't hold a PID",
+                    path.display()
+                ),
+            }
+            match fs::remove_file(path) {
+                // Another run may have removed it first, creating it again tells who wins
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(CliError::path(path, e)),
+            }
+        }
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Leave the file alone if another run replaced it in the meantime
+        if read_pid(&self.path) == Some(std::process::id()) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Whether a process with this PID exists. When that can't be told, it is assumed to exist.
+fn is_running(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        // Signal 0 only checks that the process exists and may be signalled
+        if unsafe { libc::kill(pid as i32, 0) } == 0 {
+            return true;
+        }
+        std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+    }
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::Foundation::{CloseHandle, ERROR_ACCESS_DENIED, STILL_ACTIVE};
+        use windows_sys::Win32::System::Threading::{
+            GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+        };
+
+        let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+        if handle.is_null() {
+            // A process of another user can't be opened
+            return std::io::Error::last_os_error().raw_os_error()
+                == Some(ERROR_ACCESS_DENIED as i32);
+        }
+        let mut exit_code = 0u32;
+        let queried = unsafe { GetExitCodeProcess(handle, &mut exit_code) };
+        unsafe { CloseHandle(handle) };
+        queried == 0 || exit_code == STILL_ACTIVE as u32
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = pid;
+        true
+    }
+}
@@ -0,0 +1,243 @@
+use std::io;
+use std::process::ExitStatus;
+
+use tokio::process::{Child, Command};
+
+/// The processes a command starts, kept together so that stopping the command also stops
+/// what it spawned, e.g. `node` started by `npm` or by `cmd /C` on Windows. On Unix the
+/// command runs in its own process group, given the terminal Ariana runs in if any, on
+/// Windows in a Job Object that kills its processes once closed.
+pub struct ProcessTree {
+    #[cfg(unix)]
+    group_id: Option<i32>,
+    /// Whether the group is given the terminal Ariana is in the foreground of, until dropped.
+    #[cfg(unix)]
+    holds_terminal: bool,
+    #[cfg(windows)]
+    job: Option<windows::Job>,
+}
+
+impl ProcessTree {
+    /// Sets up `command` so that the processes it starts can be grouped, to call before
+    /// spawning it. On Unix this puts it in a new process group, so it no longer receives the
+    /// signals sent to Ariana itself: stopping it is up to [`kill`](Self::kill). When
+    /// `inherits_stdin` and stdin is a terminal Ariana is in the foreground of, the group
+    /// becomes the foreground one instead, so that the command can read from the terminal and
+    /// gets the Ctrl+C hit in it, until the tree is dropped.
+    pub fn prepare(command: &mut Command, inherits_stdin: bool) -> Self {
+        #[cfg(unix)]
+        {
+            command.process_group(0);
+            let holds_terminal = inherits_stdin && terminal::in_foreground();
+            if holds_terminal {
+                // Also done by the parent in `attach`, whichever runs first wins the race
+                // against the command reading the terminal, which would stop it otherwise
+                unsafe {
+                    command.pre_exec(|| {
+                        terminal::set_foreground(libc::getpid());
+                        Ok(())
+                    });
+                }
+            }
+            ProcessTree {
+                group_id: None,
+                holds_terminal,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (command, inherits_stdin);
+            ProcessTree {
+                #[cfg(windows)]
+                job: None,
+            }
+        }
+    }
+
+    /// Groups the processes of `child`, spawned from the command given to `prepare`. If that
+    /// fails, a warning is printed and only `child` itself will be killed.
+    pub fn attach(&mut self, child: &Child) {
+        #[cfg(unix)]
+        {
+            self.group_id = child.id().map(|id| id as i32);
+            if let Some(group_id) = self.group_id.filter(|_| self.holds_terminal) {
+                terminal::set_foreground(group_id);
+            }
+        }
+        #[cfg(windows)]
+        {
+            self.job = windows::Job::attach(child);
+        }
+        #[cfg(not(any(unix, windows)))]
+        let _ = child;
+    }
+
+    /// Whether the command, exiting with `status`, was stopped by a Ctrl+C hit in the
+    /// terminal it was given. Only the command received it, so it is then sent to Ariana too,
+    /// to be handled like the ones hit while Ariana holds the terminal.
+    pub fn forward_terminal_interrupt(&mut self, status: ExitStatus) -> bool {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+
+            if !self.holds_terminal || status.signal() != Some(libc::SIGINT) {
+                return false;
+            }
+            self.release_terminal();
+            unsafe { libc::kill(libc::getpid(), libc::SIGINT) };
+            true
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = status;
+            false
+        }
+    }
+
+    /// Kills every process of the tree still running.
+    pub fn kill(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        if let Some(group_id) = self.group_id {
+            // A negative pid targets the whole process group
+            if unsafe { libc::kill(-group_id, libc::SIGKILL) } != 0 {
+                let error = io::Error::last_os_error();
+                // Everyone already exited
+                if error.raw_os_error() != Some(libc::ESRCH) {
+                    return Err(error);
+                }
+            }
+        }
+        #[cfg(windows)]
+        if let Some(job) = &self.job {
+            job.terminate()?;
+        }
+        Ok(())
+    }
+
+    /// Gives the terminal back to Ariana's process group.
+    #[cfg(unix)]
+    fn release_terminal(&mut self) {
+        if std::mem::take(&mut self.holds_terminal) {
+            terminal::set_foreground(unsafe { libc::getpgrp() });
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ProcessTree {
+    fn drop(&mut self) {
+        self.release_terminal();
+    }
+}
+
+#[cfg(unix)]
+mod terminal {
+    use std::mem;
+    use std::ptr;
+
+    /// Whether stdin is a terminal whose foreground process group is Ariana's.
+    pub fn in_foreground() -> bool {
+        unsafe {
+            libc::isatty(libc::STDIN_FILENO) == 1
+                && libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp()
+        }
+    }
+
+    /// Makes `group_id` the foreground process group of the terminal on stdin, failing
+    /// silently. SIGTTOU, which stops processes outside of the foreground group doing so, is
+    /// blocked meanwhile. Only async-signal-safe calls, as it also runs before `exec`.
+    pub fn set_foreground(group_id: i32) {
+        unsafe {
+            let mut blocked: libc::sigset_t = mem::zeroed();
+            let mut previous: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut blocked);
+            libc::sigaddset(&mut blocked, libc::SIGTTOU);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &blocked, &mut previous);
+            libc::tcsetpgrp(libc::STDIN_FILENO, group_id);
+            libc::pthread_sigmask(libc::SIG_SETMASK, &previous, ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::ffi::c_void;
+    use std::io;
+    use std::mem;
+
+    use tokio::process::Child;
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows_sys::Win32::System::JobObjects::{
+        AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+        SetInformationJobObject, TerminateJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+        JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+    };
+
+    /// A Job Object killing its processes when closed, so that they also die if Ariana exits
+    /// without stopping them.
+    pub struct Job(HANDLE);
+
+    // The handle is only used through the thread safe Job Object functions
+    unsafe impl Send for Job {}
+    unsafe impl Sync for Job {}
+
+    impl Job {
+        fn new() -> io::Result<Self> {
+            let handle = unsafe { CreateJobObjectW(std::ptr::null(), std::ptr::null()) };
+            if handle.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let job = Job(handle);
+            let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { mem::zeroed() };
+            info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+            let set = unsafe {
+                SetInformationJobObject(
+                    job.0,
+                    JobObjectExtendedLimitInformation,
+                    &info as *const _ as *const c_void,
+                    mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+                )
+            };
+            if set == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(job)
+        }
+
+        /// A job holding `child`, or `None` with a warning if it can't be created.
+        pub fn attach(child: &Child) -> Option<Self> {
+            let job = Job::new().and_then(|job| job.assign(child).map(|()| job));
+            if let Err(e) = &job {
+                eprintln!(
+                    "[Ariana] WARNING: could not track the processes started by your command, they may keep running once it is stopped: {}",
+                    e
+                );
+            }
+            job.ok()
+        }
+
+        /// Puts `child` in the job. Processes it starts from then on are in the job too.
+        fn assign(&self, child: &Child) -> io::Result<()> {
+            let process = child
+                .raw_handle()
+                .ok_or_else(|| io::Error::other("the process already exited"))?;
+            if unsafe { AssignProcessToJobObject(self.0, process as HANDLE) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        pub fn terminate(&self) -> io::Result<()> {
+            if unsafe { TerminateJobObject(self.0, 1) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for Job {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+}
@@ -1,29 +1,204 @@
-use crate::collector::CollectedItems;
-use crate::instrumentation::instrument_files_batch;
-use crate::utils::create_link_or_copy;
-use anyhow::{anyhow, Result};
+use crate::collector::{CollectedItems, SkipCause, SkippedFile};
+use crate::error::{CliError, Result};
+use crate::instrumentation::{instrument_files_batch, BatchContext, TsPathAliases};
+use crate::lang_map::LangMap;
+use crate::syntax_check::{check_syntax, SyntaxCheck};
+use crate::upload_budget::UploadBudget;
+use crate::utils::{
+    create_link_or_copy, from_zip_entry_name, link_fallback_reason, split_by_size,
+    to_zip_entry_name,
+};
 use ariana_server::traces::instrumentation::ecma::EcmaImportStyle;
-use futures_util::future;
+use futures_util::{future, stream, StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task;
 use zip::write::FileOptions;
-use zip::{ZipArchive, ZipWriter};
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
 
-/// Processes files_to_instrument in batches of up to 100 files in parallel.
+/// Name of the zip in `.ariana` the original files are backed up to with `--inplace`.
+pub const BACKUP_FILE: &str = "__ariana_backups.zip";
+
+/// Lists, in `.ariana`, the files of the backup already restored by an interrupted restore.
+pub const RESTORE_PROGRESS_FILE: &str = "restore_progress.txt";
+
+/// Number of files sent in a single instrumentation request unless told otherwise.
+pub const DEFAULT_BATCH_FILE_COUNT: usize = 300;
+
+/// Compression used for the original files stored in the `--inplace` backup zip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BackupCompression {
+    /// Store files as-is, fastest but as big as the originals
+    None,
+    /// Good balance between CPU usage and size
+    Deflate,
+    /// Smaller and usually faster than deflate
+    Zstd,
+}
+
+impl BackupCompression {
+    fn compression_method(self) -> CompressionMethod {
+        match self {
+            BackupCompression::None => CompressionMethod::Stored,
+            BackupCompression::Deflate => CompressionMethod::Deflated,
+            BackupCompression::Zstd => CompressionMethod::Zstd,
+        }
+    }
+}
+
+/// Tunables for `process_items`, mostly coming from the CLI flags.
+#[derive(Debug, Clone)]
+pub struct ProcessOptions {
+    pub project_root: PathBuf,
+    /// Where instrumented files go, or with `is_inplace` the backup of the originals. Usually
+    /// `.ariana` in the project root.
+    pub ariana_dir: PathBuf,
+    pub is_inplace: bool,
+    pub backup_compression: BackupCompression,
+    /// Maximum number of instrumentation batches in flight at once.
+    pub concurrency: usize,
+    /// Number of threads reading the files to instrument, see `default_read_concurrency`.
+    pub read_concurrency: usize,
+    /// Maximum number of files sent in a single instrumentation request, bigger batches are
+    /// then split by `max_upload_bytes` too.
+    pub batch_file_count: usize,
+    /// Batches whose cumulated files contents exceed this are sent as several requests.
+    pub max_upload_bytes: u64,
+    /// Maximum number of files copied into `.ariana` at once, across all copied directories.
+    pub max_concurrent_files: usize,
+    /// Copies files again even if a previous copy in `.ariana` looks up to date.
+    pub force_copy_all: bool,
+    /// Copies everything into `.ariana` instead of linking, so it doesn't depend on the
+    /// original project.
+    pub portable: bool,
+    /// Extensions of files copied rather than linked whatever their size, lowercase and
+    /// without dot, usually `DEFAULT_COPY_EXTENSIONS`.
+    pub copy_extensions: Vec<String>,
+    /// Shared with the rest of the run, instrumentation requests count towards it.
+    pub upload_budget: Arc<UploadBudget>,
+    /// Name of the tag the instrumented code prints traces in, see `TraceMarker`.
+    pub trace_marker: String,
+    /// Keeps the original of files whose instrumented code doesn't parse, see `check_syntax`.
+    pub verify_syntax: bool,
+    /// Sent along with each batch so that injected imports respect them.
+    pub ts_path_aliases: Option<TsPathAliases>,
+    /// Languages of the files instrumented as another language than their extension tells.
+    pub lang_map: LangMap,
+    /// Token of the logged in account, see `SessionConfig::auth_token`.
+    pub auth_token: Option<String>,
+    /// Files to instrument at least this big are reported in `ProcessReport::large_files`,
+    /// and with `verbose` warned about before being sent.
+    pub large_file_bytes: u64,
+    pub verbose: bool,
+}
+
+/// How long `process_items` spent on each kind of work. Copying and instrumentation run
+/// concurrently, each duration is measured from the start until that work is done.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessTimings {
+    pub copying: Duration,
+    pub instrumenting: Duration,
+}
+
+/// What `process_items` did.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessReport {
+    pub timings: ProcessTimings,
+    /// Files to instrument that were left as is, e.g. because they aren't valid UTF-8 or
+    /// their batch failed.
+    pub skipped_files: Vec<SkippedFile>,
+    /// Instrumentation requests that failed or whose results couldn't be used.
+    pub failed_batches: usize,
+    /// Files at least `ProcessOptions::large_file_bytes` big sent to be instrumented, with
+    /// their size, largest first. They often are generated files slowing instrumentation down.
+    pub large_files: Vec<(PathBuf, u64)>,
+}
+
+/// What `process_instrument_files_in_batches` did.
+struct BatchesOutcome {
+    skipped_files: Vec<SkippedFile>,
+    failed_batches: usize,
+    large_files: Vec<(PathBuf, u64)>,
+}
+
+fn skip_all(paths: &[PathBuf], reason: &str) -> Vec<SkippedFile> {
+    paths
+        .iter()
+        .map(|path| SkippedFile {
+            path: path.clone(),
+            reason: reason.to_string(),
+            cause: SkipCause::Failed,
+        })
+        .collect()
+}
+
+/// Puts back the original content of files whose instrumented content doesn't parse, unless
+/// the original doesn't either, which means the checker can't parse this file anyway. Returns
+/// the files put back.
+async fn revert_invalid_syntax(
+    src_paths: &[PathBuf],
+    original_contents: &[String],
+    instrumented_contents: &mut [Option<String>],
+) -> Vec<SkippedFile> {
+    let mut reverted = vec![];
+    for ((src_path, original_content), maybe_instrumented_content) in src_paths
+        .iter()
+        .zip(original_contents)
+        .zip(instrumented_contents.iter_mut())
+    {
+        let Some(instrumented_content) = maybe_instrumented_content else {
+            continue;
+        };
+        let SyntaxCheck::Invalid(error) = check_syntax(src_path, instrumented_content).await else {
+            continue;
+        };
+        if check_syntax(src_path, original_content).await != SyntaxCheck::Valid {
+            continue;
+        }
+        eprintln!(
+            "[Ariana] WARNING: the instrumented code of {} is not valid ({}), leaving it uninstrumented",
+            src_path.display(),
+            error
+        );
+        *maybe_instrumented_content = None;
+        reverted.push(SkippedFile {
+            path: src_path.clone(),
+            reason: format!("instrumented code is not valid: {}", error),
+            cause: SkipCause::Local,
+        });
+    }
+    reverted
+}
+
+/// Default of `ProcessOptions::read_concurrency`: one thread per CPU, like rayon's global pool.
+pub fn default_read_concurrency() -> usize {
+    std::thread::available_parallelism().map_or(4, |threads| threads.get())
+}
+
+/// A batch of files to instrument, with its index and the contents of its files.
+type ReadBatch = (usize, Vec<(PathBuf, PathBuf)>, Vec<String>);
+
+/// Processes files_to_instrument in batches of up to `options.batch_file_count` files, read by
+/// `options.read_concurrency` threads, with up to `options.concurrency` batches in flight at
+/// once. Returns the files left uninstrumented, or an error if a file could not be backed up or
+/// written.
 async fn process_instrument_files_in_batches(
     mut files: Vec<(PathBuf, PathBuf)>,
     api_url: &str,
     vault_key: &str,
     import_style: &EcmaImportStyle,
     pb: Arc<Mutex<ProgressBar>>,
-    is_inplace: bool,
     zip_writer: Option<Arc<std::sync::Mutex<ZipWriter<File>>>>,
-) {
+    options: &ProcessOptions,
+) -> Result<BatchesOutcome> {
     let mut paths_sizes = HashMap::new();
     files.sort_by(|a, b| {
         let a_size = fs::metadata(&a.0).unwrap().len();
@@ -33,68 +208,309 @@ async fn process_instrument_files_in_batches(
 
         a_size.cmp(&b_size)
     });
+    let large_files = files
+        .iter()
+        .rev()
+        .filter_map(|(src, _)| {
+            let size = fs::metadata(src).ok()?.len();
+            (size >= options.large_file_bytes).then(|| (src.clone(), size))
+        })
+        .collect::<Vec<_>>();
+    if options.verbose {
+        for (path, size) in &large_files {
+            eprintln!(
+                "[Ariana] WARNING: sending {} ({:.1} MB) to be instrumented, large files slow instrumentation down. Exclude it if it is generated",
+                path.display(),
+                *size as f64 / (1024.0 * 1024.0)
+            );
+        }
+    }
 
-    for (i, batch) in files.chunks(300).enumerate() {
-        let files_contents: Vec<String> = batch
-            .par_iter()
-            .map(|(src, _)| fs::read_to_string(&src).unwrap())
-            .collect();
-
-        let mut src_paths = vec![];
-        let mut dest_paths = vec![];
-        for (src, dest) in batch.into_iter() {
-            src_paths.push(src.clone());
-            dest_paths.push(dest.clone());
+    // Files of the next batches are read while the requests of the previous ones are in
+    // flight, up to `concurrency` batches ahead
+    let concurrency = options.concurrency.max(1);
+    let (read_tx, read_rx) = mpsc::channel::<ReadBatch>(concurrency);
+    let batches_to_read = files
+        .chunks(options.batch_file_count.max(1))
+        .map(|batch| batch.to_vec())
+        .collect::<Vec<_>>();
+    let is_inplace = options.is_inplace;
+    let read_concurrency = options.read_concurrency.max(1);
+    let reader_pb = pb.clone();
+    let reader = task::spawn_blocking(move || {
+        // Its own pool, so that reads don't take more threads than asked on a slow disk
+        let read_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(read_concurrency)
+            .thread_name(|i| format!("ariana-read-{}", i))
+            .build();
+        if let Err(e) = &read_pool {
+            eprintln!(
+                "[Ariana] Could not start {} threads to read files, using the default ones: {}",
+                read_concurrency, e
+            );
         }
-        let result = instrument_files_batch(
-            &src_paths,
-            files_contents.clone(),
-            api_url.to_string(),
-            vault_key.to_string(),
-            import_style,
-        )
-        .await;
-        let maybe_instrumented_contents = match result {
-            Ok(maybe_instrumented_contents) => maybe_instrumented_contents,
-            Err(e) => {
-                eprintln!("Could not process batch {} because of: {:?}", i, e.source());
-                continue;
+        let mut unreadable_files = vec![];
+        for (i, files) in batches_to_read.into_iter().enumerate() {
+            let read_files = || -> Vec<_> {
+                files
+                    .par_iter()
+                    .map(|(src, _)| fs::read_to_string(src))
+                    .collect()
+            };
+            let reads = match &read_pool {
+                Ok(read_pool) => read_pool.install(read_files),
+                Err(_) => read_files(),
+            };
+            let mut batch = vec![];
+            let mut batch_contents = vec![];
+            for ((src, dest), read) in files.into_iter().zip(reads) {
+                match read {
+                    Ok(content) => {
+                        batch.push((src, dest));
+                        batch_contents.push(content);
+                    }
+                    Err(e) => {
+                        let reason = if e.kind() == std::io::ErrorKind::InvalidData {
+                            "not valid UTF-8".to_string()
+                        } else {
+                            format!("unreadable: {}", e)
+                        };
+                        eprintln!(
+                            "[Ariana] Leaving {} uninstrumented: {}",
+                            src.display(),
+                            reason
+                        );
+                        // Still mirrored into .ariana, as is
+                        if !is_inplace {
+                            if let Some(parent) = dest.parent() {
+                                let _ = fs::create_dir_all(parent);
+                            }
+                            let _ = fs::copy(&src, &dest);
+                        }
+                        reader_pb.lock().unwrap().inc(1);
+                        unreadable_files.push(SkippedFile {
+                            path: src,
+                            reason,
+                            cause: SkipCause::Local,
+                        });
+                    }
+                }
+            }
+            if read_tx.blocking_send((i, batch, batch_contents)).is_err() {
+                break;
+            }
+        }
+        unreadable_files
+    });
+    let read_batches = stream::unfold(read_rx, |mut read_rx| async move {
+        read_rx.recv().await.map(|read_batch| (read_batch, read_rx))
+    });
+
+    let batch_context = &BatchContext {
+        api_url,
+        vault_key,
+        import_style,
+        ts_path_aliases: options.ts_path_aliases.as_ref(),
+        upload_budget: &options.upload_budget,
+        trace_marker: &options.trace_marker,
+        auth_token: options.auth_token.as_deref(),
+    };
+    let pb = &pb;
+    let zip_writer = &zip_writer;
+    let failed_batches = &AtomicUsize::new(0);
+    let batches_skipped_files = read_batches
+        .map(|(i, batch, batch_contents)| async move {
+            let mut skipped = vec![];
+            let sub_batches = split_by_size(
+                &batch_contents,
+                |content| content.len() as u64,
+                options.max_upload_bytes,
+            );
+            if sub_batches.len() > 1 {
+                println!(
+                    "[Ariana] Batch {} is bigger than {} bytes, splitting it into {} requests",
+                    i,
+                    options.max_upload_bytes,
+                    sub_batches.len()
+                );
             }
-        };
 
-        for (((src_path, dest_path), original_content), maybe_instrumented_content) in src_paths
-            .iter()
-            .zip(dest_paths.iter())
-            .zip(files_contents.iter())
-            .zip(maybe_instrumented_contents.iter())
-        {
-            let instrumented_content =
-                if let Some(instrumented_content) = maybe_instrumented_content {
-                    instrumented_content
-                } else {
-                    original_content
+            for range in sub_batches {
+                let files_contents = &batch_contents[range.clone()];
+                let mut src_paths = vec![];
+                let mut dest_paths = vec![];
+                for (src, dest) in batch[range].iter() {
+                    src_paths.push(src.clone());
+                    dest_paths.push(dest.clone());
+                }
+                let result = instrument_files_batch(
+                    batch_context,
+                    &src_paths,
+                    files_contents.to_vec(),
+                    options.lang_map.languages_of(&src_paths),
+                )
+                .await;
+                let mut maybe_instrumented_contents = match result {
+                    Ok(maybe_instrumented_contents)
+                        if maybe_instrumented_contents.len() == src_paths.len() =>
+                    {
+                        maybe_instrumented_contents
+                    }
+                    Ok(maybe_instrumented_contents) => {
+                        // Results can't be matched back to their files, keep all originals
+                        failed_batches.fetch_add(1, Ordering::Relaxed);
+                        eprintln!(
+                            "Could not process batch {}: the server returned {} results for {} files, leaving these files uninstrumented",
+                            i,
+                            maybe_instrumented_contents.len(),
+                            src_paths.len()
+                        );
+                        skipped.extend(skip_all(
+                            &src_paths,
+                            "the server returned results for another number of files",
+                        ));
+                        vec![None; src_paths.len()]
+                    }
+                    Err(e) => {
+                        eprintln!("Could not process batch {} because of: {}", i, e);
+                        failed_batches.fetch_add(1, Ordering::Relaxed);
+                        // Keep the originals, so the files are still there in .ariana
+                        skipped.extend(skip_all(
+                            &src_paths,
+                            &format!("instrumentation request failed: {}", e),
+                        ));
+                        vec![None; src_paths.len()]
+                    }
                 };
-            if is_inplace {
-                if let Some(ref zw) = zip_writer {
-                    let mut zw = zw.lock().unwrap();
-                    let path_str = src_path.to_string_lossy().to_string();
-                    zw.start_file(&path_str, FileOptions::<()>::default())
-                        .unwrap();
-                    zw.write_all(original_content.as_bytes()).unwrap();
-                    fs::write(src_path, instrumented_content).unwrap();
-                } else {
-                    panic!("No zip writer");
+                for (src_path, maybe_instrumented_content) in
+                    src_paths.iter().zip(maybe_instrumented_contents.iter())
+                {
+                    if maybe_instrumented_content.is_none()
+                        && !skipped.iter().any(|file: &SkippedFile| &file.path == src_path)
+                    {
+                        skipped.push(SkippedFile {
+                            path: src_path.clone(),
+                            reason: "declined by the server, which may not support its language"
+                                .to_string(),
+                            cause: SkipCause::Declined,
+                        });
+                    }
                 }
-            } else {
-                if let Some(parent) = dest_path.parent() {
-                    // println!("create dir all {:?}", parent);
-                    fs::create_dir_all(parent).unwrap();
+
+                if options.verify_syntax {
+                    skipped.extend(
+                        revert_invalid_syntax(
+                            &src_paths,
+                            files_contents,
+                            &mut maybe_instrumented_contents,
+                        )
+                        .await,
+                    );
+                }
+
+                for (((src_path, dest_path), original_content), maybe_instrumented_content) in
+                    src_paths
+                        .iter()
+                        .zip(dest_paths.iter())
+                        .zip(files_contents.iter())
+                        .zip(maybe_instrumented_contents.iter())
+                {
+                    let instrumented_content =
+                        if let Some(instrumented_content) = maybe_instrumented_content {
+                            instrumented_content
+                        } else {
+                            original_content
+                        };
+                    if options.is_inplace {
+                        if let Some(zw) = zip_writer {
+                            let mut zw = zw.lock().unwrap();
+                            let path_str = match to_zip_entry_name(src_path, &options.project_root)
+                            {
+                                Ok(path_str) => path_str,
+                                Err(e) => {
+                                    eprintln!(
+                                        "Could not back up {:?}, leaving it untouched: {}",
+                                        src_path, e
+                                    );
+                                    pb.lock().unwrap().inc(1);
+                                    continue;
+                                }
+                            };
+                            let file_options = FileOptions::<()>::default().compression_method(
+                                options.backup_compression.compression_method(),
+                            );
+                            // Left untouched unless its original is in the backup
+                            zw.start_file(&path_str, file_options)
+                                .map_err(|e| e.to_string())
+                                .and_then(|()| {
+                                    zw.write_all(original_content.as_bytes())
+                                        .map_err(|e| e.to_string())
+                                })
+                                .map_err(|e| {
+                                    CliError::Backup(format!(
+                                        "Could not back up {:?}: {}",
+                                        src_path, e
+                                    ))
+                                })?;
+                            fs::write(src_path, instrumented_content).map_err(|e| {
+                                CliError::Backup(format!(
+                                    "Could not write the instrumented {:?}, its original is in the backup: {}",
+                                    src_path, e
+                                ))
+                            })?;
+                        } else {
+                            panic!("No zip writer");
+                        }
+                    } else {
+                        if let Some(parent) = dest_path.parent() {
+                            // println!("create dir all {:?}", parent);
+                            fs::create_dir_all(parent).map_err(|e| CliError::path(parent, e))?;
+                        }
+                        fs::write(dest_path, instrumented_content)
+                            .map_err(|e| CliError::path(dest_path, e))?;
+                    }
+                    pb.lock().unwrap().inc(1);
                 }
-                fs::write(dest_path, instrumented_content).unwrap();
             }
-            pb.lock().unwrap().inc(1);
-        }
+            Ok(skipped)
+        })
+        .buffer_unordered(concurrency)
+        .collect::<Vec<Result<Vec<SkippedFile>>>>()
+        .await;
+    let unreadable_files = match reader.await {
+        Ok(unreadable_files) => unreadable_files,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(_) => vec![],
+    };
+    let mut skipped_files = batches_skipped_files
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    skipped_files.extend(unreadable_files);
+    let declined_files = skipped_files
+        .iter()
+        .filter(|file| file.cause == SkipCause::Declined)
+        .count();
+    if declined_files > 0 {
+        println!(
+            "[Ariana] The server declined to instrument {} files, they run as is and produce no traces",
+            declined_files
+        );
+    }
+    let failed_batches = failed_batches.load(Ordering::Relaxed);
+    if failed_batches > 0 {
+        eprintln!(
+            "[Ariana] {} instrumentation requests failed, their files run as is and produce no traces",
+            failed_batches
+        );
     }
+    Ok(BatchesOutcome {
+        skipped_files,
+        failed_batches,
+        large_files,
+    })
 }
 
 pub async fn process_items(
@@ -102,10 +518,10 @@ pub async fn process_items(
     api_url: &str,
     vault_key: &str,
     import_style: &EcmaImportStyle,
-    is_inplace: bool,
-) -> Result<(), String> {
+    options: &ProcessOptions,
+) -> Result<ProcessReport> {
     // Calculate total for progress bar
-    let total = if is_inplace {
+    let total = if options.is_inplace {
         items.files_to_instrument.len() as u64
     } else {
         (items.directories_to_link_or_copy.len()
@@ -122,23 +538,50 @@ pub async fn process_items(
             .progress_chars("##-"),
     );
 
+    let start = Instant::now();
+    let mut report = ProcessReport::default();
+
     // Process items based on is_inplace flag
-    if is_inplace {
-        fs::create_dir_all(".ariana").map_err(|_| format!("Couldn't create .ariana"))?;
-        let zip_file = File::create(".ariana/__ariana_backups.zip")
-            .map_err(|_| format!("Couldn't create .ariana/__ariana_backups.zip"))?;
+    if options.is_inplace {
+        fs::create_dir_all(&options.ariana_dir)
+            .map_err(|e| CliError::path(&options.ariana_dir, e))?;
+        let zip_path = options.ariana_dir.join(BACKUP_FILE);
+        // Left by an interrupted restore of a previous backup, it doesn't apply to this one
+        let _ = fs::remove_file(options.ariana_dir.join(RESTORE_PROGRESS_FILE));
+        let zip_file = File::create(&zip_path).map_err(|e| CliError::path(&zip_path, e))?;
         let zip_writer = Arc::new(std::sync::Mutex::new(ZipWriter::new(zip_file)));
-        process_instrument_files_in_batches(
+        let outcome = process_instrument_files_in_batches(
             items.files_to_instrument.to_vec(),
             api_url,
             vault_key,
             import_style,
             pb.clone(),
-            true,
             Some(zip_writer),
+            options,
         )
-        .await;
+        .await?;
+        report.skipped_files = outcome.skipped_files;
+        report.failed_batches = outcome.failed_batches;
+        report.large_files = outcome.large_files;
+        report.timings.instrumenting = start.elapsed();
     } else {
+        // Links that can't work would otherwise be attempted, and fail, once per file
+        let copy_everything = options.portable
+            || match link_fallback_reason(&options.project_root, &options.ariana_dir).await {
+                Some(reason) => {
+                    println!(
+                        "[Ariana] Copying files into .ariana instead of linking them: {}",
+                        reason
+                    );
+                    true
+                }
+                None => false,
+            };
+
+        // Shared by all copies, however deep, so that big directories don't exhaust file
+        // descriptors
+        let copy_permits = Arc::new(Semaphore::new(options.max_concurrent_files.max(1)));
+
         // Create futures for all tasks
         let mut tasks = Vec::new();
 
@@ -147,13 +590,26 @@ pub async fn process_items(
             let pb = pb.clone();
             let src = src.clone();
             let dest = dest.clone();
+            let force_copy_all = options.force_copy_all;
+            let portable = copy_everything;
+            let copy_permits = copy_permits.clone();
+            let copy_extensions = options.copy_extensions.clone();
             tasks.push(tokio::spawn(async move {
                 if let Some(parent) = dest.parent() {
                     if let Err(e) = tokio::fs::create_dir_all(parent).await {
                         eprintln!("Could not create {:?}: {}", parent, e);
                     }
                 }
-                if let Err(e) = create_link_or_copy(&src, &dest).await {
+                if let Err(e) = create_link_or_copy(
+                    &src,
+                    &dest,
+                    force_copy_all,
+                    portable,
+                    &copy_extensions,
+                    &copy_permits,
+                )
+                .await
+                {
                     eprintln!("Could not copy or link {:?}: {}", src, e);
                 }
                 pb.lock().unwrap().inc(1);
@@ -165,13 +621,26 @@ pub async fn process_items(
             let pb = pb.clone();
             let src = src.clone();
             let dest = dest.clone();
+            let force_copy_all = options.force_copy_all;
+            let portable = copy_everything;
+            let copy_permits = copy_permits.clone();
+            let copy_extensions = options.copy_extensions.clone();
             tasks.push(tokio::spawn(async move {
                 if let Some(parent) = dest.parent() {
                     if let Err(e) = tokio::fs::create_dir_all(parent).await {
                         eprintln!("Could not create {:?}: {}", parent, e);
                     }
                 }
-                if let Err(e) = create_link_or_copy(&src, &dest).await {
+                if let Err(e) = create_link_or_copy(
+                    &src,
+                    &dest,
+                    force_copy_all,
+                    portable,
+                    &copy_extensions,
+                    &copy_permits,
+                )
+                .await
+                {
                     eprintln!("Could not copy or link {:?}: {}", src, e);
                 }
                 pb.lock().unwrap().inc(1);
@@ -183,42 +652,99 @@ pub async fn process_items(
         let api_url = api_url.to_string();
         let vault_key = vault_key.to_string();
         let import_style = import_style.clone();
+        let options = options.clone();
 
         let pb_clone = pb.clone();
-        tasks.push(tokio::spawn(async move {
+        let instrument_task = tokio::spawn(async move {
             process_instrument_files_in_batches(
                 files_to_process,
                 &api_url,
                 &vault_key,
                 &import_style,
                 pb_clone.clone(),
-                false,
                 None,
+                &options,
             )
             .await
-        }));
+        });
 
-        // Wait for all tasks to complete
-        future::join_all(tasks).await;
+        // Wait for all tasks to complete, timing copying and instrumentation separately
+        let (copying, (outcome, instrumenting)) = tokio::join!(
+            async {
+                future::join_all(tasks).await;
+                start.elapsed()
+            },
+            async {
+                let outcome = match instrument_task.await {
+                    Ok(outcome) => outcome,
+                    Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                    Err(e) => Err(CliError::Instrumentation(format!(
+                        "Instrumentation was stopped: {}",
+                        e
+                    ))),
+                };
+                (outcome, start.elapsed())
+            },
+        );
+        let outcome = outcome?;
+        report.skipped_files = outcome.skipped_files;
+        report.failed_batches = outcome.failed_batches;
+        report.large_files = outcome.large_files;
+        report.timings = ProcessTimings {
+            copying,
+            instrumenting,
+        };
     }
 
     // Finalize progress bar and message thread
     pb.lock().unwrap().finish();
 
-    Ok(())
+    Ok(report)
 }
 
-pub fn restore_backup() -> Result<()> {
-    let zip_path = Path::new(".ariana/__ariana_backups.zip");
+/// Puts back the original files of `project_root` backed up in `ariana_dir` by an `--inplace` run.
+/// Each file is replaced at once, so none is left half written, and recorded in `RESTORE_PROGRESS_FILE` once restored. If the
+/// restore fails midway, e.g. because the disk is full, running it again resumes where it
+/// stopped, and the error lists the files not restored yet.
+pub fn restore_backup(project_root: &Path, ariana_dir: &Path) -> Result<()> {
+    let zip_path = ariana_dir.join(BACKUP_FILE);
     if !zip_path.exists() {
-        return Err(anyhow!("Backup not found, could not restore."));
+        return Err(CliError::Backup(format!(
+            "Backup not found at {}, could not restore.",
+            zip_path.display()
+        )));
     }
 
-    let zip_file = File::open(zip_path)?;
+    let zip_file = File::open(&zip_path).map_err(|e| CliError::path(&zip_path, e))?;
     let mut archive = ZipArchive::new(zip_file)?;
 
-    let total = archive.len() as u64;
-    let pb = ProgressBar::new(total);
+    // Checked before restoring anything, so that a bad entry doesn't stop the restore midway
+    let mut entries = Vec::with_capacity(archive.len());
+    for name in archive.file_names() {
+        let path =
+            from_zip_entry_name(name, project_root).map_err(|e| CliError::Backup(e.to_string()))?;
+        entries.push((name.to_string(), path));
+    }
+
+    let progress_path = ariana_dir.join(RESTORE_PROGRESS_FILE);
+    let already_restored: HashSet<String> = match fs::read_to_string(&progress_path) {
+        Ok(content) => content.lines().map(str::to_string).collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+        Err(e) => return Err(CliError::path(&progress_path, e)),
+    };
+    if !already_restored.is_empty() {
+        println!(
+            "[Ariana] Resuming the restore of the backup, {} files were already restored",
+            already_restored.len()
+        );
+    }
+    let mut progress = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&progress_path)
+        .map_err(|e| CliError::path(&progress_path, e))?;
+
+    let pb = ProgressBar::new(entries.len() as u64);
     pb.set_style(
         ProgressStyle::default_bar()
             .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} Restoring backups")
@@ -226,25 +752,162 @@ pub fn restore_backup() -> Result<()> {
             .progress_chars("##-"),
     );
 
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
-        let filename = file.name().to_string();
-        let outpath = Path::new(&filename);
-
-        if let Some(parent) = outpath.parent() {
-            if !parent.exists() {
-                std::fs::create_dir_all(parent)?;
+    for (i, (name, outpath)) in entries.iter().enumerate() {
+        if !already_restored.contains(name) {
+            let restored = restore_entry(&mut archive, name, outpath).and_then(|()| {
+                writeln!(progress, "{}", name)?;
+                progress.flush()?;
+                Ok(())
+            });
+            if let Err(e) = restored {
+                pb.abandon();
+                let (restored, not_restored): (Vec<_>, Vec<_>) = entries
+                    .iter()
+                    .enumerate()
+                    .partition(|(j, (name, _))| *j < i || already_restored.contains(name));
+                return Err(CliError::RestoreIncomplete {
+                    source: Box::new(e),
+                    restored: restored
+                        .into_iter()
+                        .map(|(_, (_, path))| path.clone())
+                        .collect(),
+                    not_restored: not_restored
+                        .into_iter()
+                        .map(|(_, (_, path))| path.clone())
+                        .collect(),
+                    progress_file: progress_path,
+                });
             }
         }
-
-        let mut content = Vec::new();
-        file.read_to_end(&mut content)?;
-        std::fs::write(outpath, content)?;
         pb.inc(1);
     }
 
-    drop(archive);
-
+    drop(progress);
+    let _ = fs::remove_file(&progress_path);
     pb.finish_with_message("Backup restoration complete");
     Ok(())
 }
+
+/// Writes the backup entry `name` to a temporary file next to `outpath`, then renames it over
+/// `outpath`, keeping the permissions of the file it replaces.
+fn restore_entry(archive: &mut ZipArchive<File>, name: &str, outpath: &Path) -> Result<()> {
+    let mut content = Vec::new();
+    archive.by_name(name)?.read_to_end(&mut content)?;
+
+    if let Some(parent) = outpath.parent() {
+        if !parent.exists() {
+            fs::create_dir_all(parent).map_err(|e| CliError::path(parent, e))?;
+        }
+    }
+    let mut tmp_name = outpath.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".ariana-restore");
+    let tmp_path = outpath.with_file_name(tmp_name);
+    let written = fs::write(&tmp_path, &content).and_then(|()| {
+        if let Ok(metadata) = fs::metadata(outpath) {
+            fs::set_permissions(&tmp_path, metadata.permissions())?;
+        }
+        fs::rename(&tmp_path, outpath)
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(CliError::path(outpath, e));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ariana-processor-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(".ariana")).unwrap();
+        dir
+    }
+
+    fn write_backup(ariana_dir: &Path, entries: &[(&str, &str)]) {
+        let mut zip_writer = ZipWriter::new(File::create(ariana_dir.join(BACKUP_FILE)).unwrap());
+        for (name, content) in entries {
+            zip_writer
+                .start_file(*name, FileOptions::<()>::default())
+                .unwrap();
+            zip_writer.write_all(content.as_bytes()).unwrap();
+        }
+        zip_writer.finish().unwrap();
+    }
+
+    #[test]
+    fn restore_backup_restores_every_file() {
+        let root = test_dir("restore");
+        let ariana_dir = root.join(".ariana");
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/app.js"), "instrumented").unwrap();
+        write_backup(
+            &ariana_dir,
+            &[("src/app.js", "app"), ("src/gone.js", "gone")],
+        );
+
+        restore_backup(&root, &ariana_dir).unwrap();
+
+        assert_eq!(fs::read_to_string(root.join("src/app.js")).unwrap(), "app");
+        assert_eq!(
+            fs::read_to_string(root.join("src/gone.js")).unwrap(),
+            "gone"
+        );
+        assert!(!ariana_dir.join(RESTORE_PROGRESS_FILE).exists());
+    }
+
+    #[test]
+    fn restore_backup_failing_midway_can_be_resumed() {
+        let root = test_dir("restore-midway");
+        let ariana_dir = root.join(".ariana");
+        fs::create_dir_all(root.join("src")).unwrap();
+        for name in ["a.js", "c.js"] {
+            fs::write(root.join("src").join(name), "instrumented").unwrap();
+        }
+        // A file where a directory is expected makes the second file fail to be written
+        fs::write(root.join("blocked"), "").unwrap();
+        write_backup(
+            &ariana_dir,
+            &[("src/a.js", "a"), ("blocked/b.js", "b"), ("src/c.js", "c")],
+        );
+
+        let error = restore_backup(&root, &ariana_dir).unwrap_err();
+
+        let CliError::RestoreIncomplete {
+            restored,
+            not_restored,
+            progress_file,
+            ..
+        } = error
+        else {
+            panic!("expected an incomplete restore, got {:?}", error);
+        };
+        assert_eq!(restored, [root.join("src/a.js")]);
+        assert_eq!(
+            not_restored,
+            [root.join("blocked/b.js"), root.join("src/c.js")]
+        );
+        assert_eq!(fs::read_to_string(&progress_file).unwrap(), "src/a.js\n");
+        assert_eq!(fs::read_to_string(root.join("src/a.js")).unwrap(), "a");
+        assert_eq!(
+            fs::read_to_string(root.join("src/c.js")).unwrap(),
+            "instrumented"
+        );
+
+        // Restored files are left alone when resuming
+        fs::write(root.join("src/a.js"), "edited since").unwrap();
+        fs::remove_file(root.join("blocked")).unwrap();
+        restore_backup(&root, &ariana_dir).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(root.join("src/a.js")).unwrap(),
+            "edited since"
+        );
+        assert_eq!(fs::read_to_string(root.join("blocked/b.js")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(root.join("src/c.js")).unwrap(), "c");
+        assert!(!progress_file.exists());
+    }
+}
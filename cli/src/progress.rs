@@ -0,0 +1,83 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Running totals of what was sent to the server while the command runs, shown on a status
+/// line on stderr. The line is only drawn when stderr is a terminal.
+pub struct RunProgress {
+    traces_pushed: AtomicUsize,
+    lines_streamed: AtomicUsize,
+    /// Output lines that could not be sent, not shown on the status line.
+    lines_lost: AtomicUsize,
+    bar: ProgressBar,
+}
+
+impl RunProgress {
+    /// With `visible` false, totals are still counted but no status line is drawn.
+    pub fn new(visible: bool) -> Self {
+        let bar = if visible {
+            let bar = ProgressBar::new_spinner();
+            bar.set_style(
+                ProgressStyle::default_spinner()
+                    .template("[Ariana] {spinner} {msg}")
+                    .unwrap(),
+            );
+            bar.enable_steady_tick(Duration::from_millis(200));
+            bar
+        } else {
+            ProgressBar::hidden()
+        };
+        let progress = Self {
+            traces_pushed: AtomicUsize::new(0),
+            lines_streamed: AtomicUsize::new(0),
+            lines_lost: AtomicUsize::new(0),
+            bar,
+        };
+        progress.update_message();
+        progress
+    }
+
+    pub fn add_traces_pushed(&self, count: usize) {
+        self.traces_pushed.fetch_add(count, Ordering::Relaxed);
+        self.update_message();
+    }
+
+    pub fn add_lines_streamed(&self, count: usize) {
+        self.lines_streamed.fetch_add(count, Ordering::Relaxed);
+        self.update_message();
+    }
+
+    pub fn add_lines_lost(&self, count: usize) {
+        self.lines_lost.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn traces_pushed(&self) -> usize {
+        self.traces_pushed.load(Ordering::Relaxed)
+    }
+
+    pub fn lines_streamed(&self) -> usize {
+        self.lines_streamed.load(Ordering::Relaxed)
+    }
+
+    pub fn lines_lost(&self) -> usize {
+        self.lines_lost.load(Ordering::Relaxed)
+    }
+
+    /// Hides the status line while `f` prints, so the output isn't mixed with it.
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.bar.suspend(f)
+    }
+
+    /// Removes the status line for good.
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+
+    fn update_message(&self) {
+        self.bar.set_message(format!(
+            "{} traces pushed, {} output lines streamed",
+            self.traces_pushed(),
+            self.lines_streamed()
+        ));
+    }
+}
@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::Result;
+
+/// Directory in `.ariana` holding the recaps fetched from the server, one file per vault.
+pub const RECAP_CACHE_DIR: &str = "recap-cache";
+
+/// How long a cached recap is used without asking the server whether it changed.
+pub const RECAP_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A recap fetched from the server, with what is needed to ask the server whether it changed
+/// since.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedRecap {
+    pub recap: String,
+    /// When the recap was fetched or last confirmed unchanged, in seconds since the epoch.
+    pub fetched_at: u64,
+    /// `ETag` header of the response, sent back as `If-None-Match`.
+    pub etag: Option<String>,
+    /// `Last-Modified` header of the response, sent back as `If-Modified-Since`.
+    pub last_modified: Option<String>,
+}
+
+impl CachedRecap {
+    pub fn new(recap: String, etag: Option<String>, last_modified: Option<String>) -> Self {
+        CachedRecap {
+            recap,
+            fetched_at: now_secs(),
+            etag,
+            last_modified,
+        }
+    }
+
+    /// How long ago the recap was fetched or confirmed unchanged.
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(now_secs().saturating_sub(self.fetched_at))
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.age() < RECAP_CACHE_TTL
+    }
+
+    /// Records that the server confirmed the recap is unchanged.
+    pub fn touch(&mut self) {
+        self.fetched_at = now_secs();
+    }
+}
+
+/// The cached recap of the vault with `vault_key`, if any. Files are named after a hash of the
+/// key, so that the key doesn't show in file listings.
+fn cache_path(ariana_dir: &Path, vault_key: &str) -> PathBuf {
+    let hash = Sha256::digest(vault_key.as_bytes());
+    let name = hash
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    ariana_dir
+        .join(RECAP_CACHE_DIR)
+        .join(format!("{}.json", name))
+}
+
+/// The cached recap of the vault, `None` if there is none or it can't be read.
+pub fn load_cached_recap(ariana_dir: &Path, vault_key: &str) -> Option<CachedRecap> {
+    let content = fs::read_to_string(cache_path(ariana_dir, vault_key)).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+pub fn store_cached_recap(ariana_dir: &Path, vault_key: &str, cached: &CachedRecap) -> Result<()> {
+    let path = cache_path(ariana_dir, vault_key);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_vec(cached)?)?;
+    Ok(())
+}
+
+/// Forgets the cached recap of the vault, once new traces were pushed to it.
+pub fn invalidate_cached_recap(ariana_dir: &Path, vault_key: &str) {
+    let _ = fs::remove_file(cache_path(ariana_dir, vault_key));
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
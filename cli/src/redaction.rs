@@ -0,0 +1,136 @@
+use regex::Regex;
+use std::borrow::Cow;
+
+use crate::error::{CliError, Result};
+
+/// Patterns scrubbed from the captured output unless `--no-default-redaction` is passed.
+const DEFAULT_PATTERNS: &[&str] = &[
+    // Authorization headers and bearer tokens
+    r"(?i)bearer\s+[A-Za-z0-9\-._~+/]+=*",
+    // AWS access key ids
+    r"\b(AKIA|ASIA)[0-9A-Z]{16}\b",
+    // GitHub tokens
+    r"\bgh[pousr]_[A-Za-z0-9]{36,}\b",
+    // Slack tokens
+    r"\bxox[abprs]-[A-Za-z0-9-]{10,}",
+    // Google API keys
+    r"\bAIza[0-9A-Za-z_\-]{35}",
+    // OpenAI, Anthropic, Stripe style secret keys
+    r"\b(sk|rk)[-_][A-Za-z0-9_\-]{20,}",
+];
+
+const REDACTED: &str = "***";
+
+/// Flags whose value identifies the user, a machine or a vault, redacted by `redact_args`.
+const IDENTIFYING_FLAGS: &[&str] = &["--machine-id", "--machine-hash", "--vault-key", "--compare"];
+
+/// Replaces secrets in lines of output before they leave the machine.
+pub struct Redactor {
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    pub fn new(extra_patterns: &[String], use_defaults: bool) -> Result<Self> {
+        let defaults = if use_defaults { DEFAULT_PATTERNS } else { &[] };
+        let patterns = defaults
+            .iter()
+            .copied()
+            .chain(extra_patterns.iter().map(String::as_str))
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|e| {
+                    CliError::Config(format!("Invalid redaction pattern `{}`: {}", pattern, e))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Redactor { patterns })
+    }
+
+    pub fn redact<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        let mut line = Cow::Borrowed(line);
+        for pattern in &self.patterns {
+            if let Cow::Owned(redacted) = pattern.replace_all(&line, REDACTED) {
+                line = Cow::Owned(redacted);
+            }
+        }
+        line
+    }
+
+    /// Redacts command line `args` before they are written to disk: the values of
+    /// `IDENTIFYING_FLAGS`, given as `--flag value` or `--flag=value`, and the patterns.
+    pub fn redact_args(&self, args: &[String]) -> Vec<String> {
+        let mut redacted = Vec::with_capacity(args.len());
+        let mut redact_next = false;
+        for arg in args {
+            if redact_next {
+                redacted.push(REDACTED.to_string());
+                redact_next = false;
+                continue;
+            }
+            match arg.split_once('=') {
+                Some((flag, _)) if IDENTIFYING_FLAGS.contains(&flag) => {
+                    redacted.push(format!("{}={}", flag, REDACTED));
+                }
+                None if IDENTIFYING_FLAGS.contains(&arg.as_str()) => {
+                    redacted.push(arg.clone());
+                    redact_next = true;
+                }
+                _ => redacted.push(self.redact(arg).into_owned()),
+            }
+        }
+        redacted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn identifying_flag_values_are_redacted() {
+        let redactor = Redactor::new(&[], true).unwrap();
+
+        let redacted = redactor.redact_args(&args(&[
+            "ariana",
+            "--machine-id",
+            "my-laptop",
+            "--vault-key=secret",
+            "--inplace",
+            "npm",
+            "test",
+        ]));
+
+        assert_eq!(
+            redacted,
+            [
+                "ariana",
+                "--machine-id",
+                "***",
+                "--vault-key=***",
+                "--inplace",
+                "npm",
+                "test"
+            ]
+        );
+    }
+
+    #[test]
+    fn secrets_in_args_are_redacted() {
+        let redactor = Redactor::new(&["hunter2".to_string()], true).unwrap();
+
+        let redacted = redactor.redact_args(&args(&[
+            "curl",
+            "-H",
+            "Authorization: Bearer abc.def",
+            "--password=hunter2",
+        ]));
+
+        assert_eq!(
+            redacted,
+            ["curl", "-H", "Authorization: ***", "--password=***"]
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Running the command on another machine over SSH, see `SessionConfig::remote`. Uses the
+//! `ssh` and `tar` programs installed locally, and a POSIX shell and `tar` on the remote.
+
+use std::fmt;
+use std::path::{Component, Path};
+use std::process::Stdio;
+use std::str::FromStr;
+use tokio::process::Command;
+
+use crate::error::{CliError, Result};
+use crate::subprocess_stdout_watcher::OUTPUT_SPOOL_FILE;
+use crate::trace_watcher::{PENDING_TRACES_DIR, PENDING_TRACES_FILE};
+use crate::utils::LOCK_FILE;
+
+/// Environment variable naming a program to use instead of `ssh`, called with the same
+/// arguments.
+pub const SSH_PROGRAM_ENV: &str = "ARIANA_SSH";
+
+/// Files of `.ariana` that only matter to Ariana itself, left out when the instrumented project
+/// is copied to the remote or to an archive.
+pub(crate) const LOCAL_ONLY_FILES: &[&str] = &[
+    LOCK_FILE,
+    PENDING_TRACES_FILE,
+    PENDING_TRACES_DIR,
+    OUTPUT_SPOOL_FILE,
+    ".vault_secret_key",
+];
+
+/// Exit code of the upload script when the remote directory is already in use.
+const DIRECTORY_NOT_EMPTY: i32 = 3;
+
+/// A directory on another machine to copy the instrumented project to and run the command in,
+/// written `[user@]host:path`. It is removed at the end of the run, so it must not hold
+/// anything beforehand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteTarget {
+    /// `[user@]host`, or anything else `ssh` accepts, e.g. a host of `~/.ssh/config`.
+    pub destination: String,
+    /// Directory on the remote, relative to the home of the remote user unless absolute.
+    pub path: String,
+}
+
+impl FromStr for RemoteTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let Some((destination, path)) = s.split_once(':') else {
+            return Err(format!("expected [user@]host:path, got `{}`", s));
+        };
+        let path = path
+            .strip_prefix("~/")
+            .unwrap_or(path)
+            .trim_end_matches('/');
+        if destination.is_empty() {
+            return Err(format!("expected [user@]host:path, got `{}`", s));
+        }
+        // Removed at the end of the run, better not be the home or root directory
+        if !Path::new(path)
+            .components()
+            .any(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(format!(
+                "`{}` can't be used as the remote directory, it is removed at the end of the run, name a directory of its own",
+                path
+            ));
+        }
+        Ok(RemoteTarget {
+            destination: destination.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for RemoteTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.destination, self.path)
+    }
+}
+
+impl RemoteTarget {
+    /// Copies the content of `local_dir` into the remote directory, following links so that
+    /// linked files and directories are copied too. Fails if the remote directory already
+    /// holds something.
+    pub async fn upload(&self, local_dir: &Path) -> Result<()> {
+        let mut tar = Command::new("tar");
+        tar.args(["-c", "-h", "-f", "-"]);
+        for file in LOCAL_ONLY_FILES {
+            tar.arg(format!("--exclude=./{}", file));
+        }
+        let mut tar = tar
+            .arg("-C")
+            .arg(local_dir)
+            .arg(".")
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                CliError::Remote(format!("Could not run tar to copy the project: {}", e))
+            })?;
+        let archive: Stdio = tar
+            .stdout
+            .take()
+            .expect("Failed to capture the output of tar")
+            .try_into()?;
+
+        let path = quote(&self.path)?;
+        let script = format!(
+            "if [ -d {path} ] && [ -n \"$(ls -A {path})\" ]; then exit {not_empty}; fi\nmkdir -p {path} && tar -x -f - -C {path}",
+            path = path,
+            not_empty = DIRECTORY_NOT_EMPTY
+        );
+        let upload = self.ssh(&script)?.stdin(archive).status().await;
+        let tar_status = tar.wait().await?;
+        match upload
+            .map_err(|e| CliError::Remote(format!("Could not run ssh: {}", e)))?
+            .code()
+        {
+            Some(0) if tar_status.success() => Ok(()),
+            Some(0) => Err(CliError::Remote(format!(
+                "tar failed to archive the project ({})",
+                tar_status
+            ))),
+            Some(DIRECTORY_NOT_EMPTY) => Err(CliError::Remote(format!(
+                "{} is not empty, pick another directory: it is removed at the end of the run",
+                self
+            ))),
+            code => Err(CliError::Remote(format!(
+                "Could not copy the project to {} (ssh exited with {})",
+                self,
+                code.map_or("a signal".to_string(), |code| code.to_string())
+            ))),
+        }
+    }
+
+    /// The local command running `command` in `relative_cwd` of the copied project. Its stdin
+    /// is piped and must be kept open while it runs: the remote command is stopped once it
+    /// closes, e.g. because the command was killed or the connection was lost.
+    pub fn command(&self, relative_cwd: &Path, command: &[String]) -> Result<Command> {
+        let mut dir = self.path.clone();
+        for component in relative_cwd.components() {
+            dir.push('/');
+            dir.push_str(&component.as_os_str().to_string_lossy());
+        }
+        let command = shlex::try_join(command.iter().map(String::as_str)).map_err(|e| {
+            CliError::Remote(format!(
+                "Could not quote the command to run remotely: {}",
+                e
+            ))
+        })?;
+        // Background commands of a script get /dev/null as stdin, so the connection's is kept
+        // as fd 3 for the watcher killing the command once it closes
+        let script = format!(
+            "cd {} || exit 1\nexec 3<&0\n{} </dev/null 3<&- &\npid=$!\n{{ cat >/dev/null; kill $pid; }} <&3 >/dev/null 2>&1 &\nexec 3<&-\nwait $pid",
+            quote(&dir)?,
+            command
+        );
+        let mut ssh = self.ssh(&script)?;
+        ssh.stdin(Stdio::piped());
+        Ok(ssh)
+    }
+
+    /// Removes the remote directory.
+    pub async fn cleanup(&self) -> Result<()> {
+        let status = self
+            .ssh(&format!("rm -rf {}", quote(&self.path)?))?
+            .stdin(Stdio::null())
+            .status()
+            .await?;
+        if !status.success() {
+            return Err(CliError::Remote(format!("ssh exited with {}", status)));
+        }
+        Ok(())
+    }
+
+    /// Runs `script` with `sh` on the remote, whatever the login shell of the remote user.
+    fn ssh(&self, script: &str) -> Result<Command> {
+        let program = std::env::var_os(SSH_PROGRAM_ENV).unwrap_or_else(|| "ssh".into());
+        let mut ssh = Command::new(program);
+        ssh.args([
+            "-T",
+            // Fails rather than waiting for a password nobody is asked for
+            "-o",
+            "BatchMode=yes",
+            // Notices a lost connection within 45 seconds
+            "-o",
+            "ServerAliveInterval=15",
+            "-o",
+            "ServerAliveCountMax=3",
+        ])
+        .arg(&self.destination)
+        .arg(format!("sh -c {}", quote(script)?));
+        Ok(ssh)
+    }
+}
+
+fn quote(s: &str) -> Result<String> {
+    shlex::try_quote(s)
+        .map(|quoted| quoted.into_owned())
+        .map_err(|e| {
+            CliError::Remote(format!(
+                "Could not quote `{}` for the remote shell: {}",
+                s, e
+            ))
+        })
+}
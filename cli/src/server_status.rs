@@ -0,0 +1,84 @@
+//! `--debug-server`: asks the server how many traces of the vault it received while the
+//! command runs, to tell apart traces the CLI fails to send from traces the server loses.
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::error::{CliError, Result};
+use crate::progress::RunProgress;
+use crate::utils::api_endpoint;
+
+/// How often the server is asked while the command runs.
+pub const SERVER_STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What the `status` endpoint of a vault answers.
+#[derive(Debug, Clone, Deserialize)]
+pub struct VaultStatus {
+    pub traces_received: u64,
+}
+
+/// The status of the vault, `None` if the server has no `status` endpoint.
+pub async fn fetch_vault_status(
+    api_url: &str,
+    vault_key: &str,
+    auth_token: Option<&str>,
+) -> Result<Option<VaultStatus>> {
+    let mut request = reqwest::Client::new()
+        .get(api_endpoint(
+            api_url,
+            &format!("vaults/{}/status", vault_key),
+        ))
+        .timeout(Duration::from_secs(5));
+    if let Some(auth_token) = auth_token {
+        request = request.bearer_auth(auth_token);
+    }
+    let response = request.send().await?;
+    match response.status() {
+        StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED => Ok(None),
+        status if status.is_success() => Ok(Some(response.json().await?)),
+        status => Err(CliError::Server {
+            status,
+            message: "Failed to get the vault status".to_string(),
+        }),
+    }
+}
+
+/// Prints what the server received against what was pushed every `interval`, and a last time
+/// once `stop` changes, which should be after the traces were flushed. Gives up for the rest
+/// of the run if the server has no `status` endpoint.
+pub async fn watch_server_status(
+    api_url: String,
+    vault_key: String,
+    auth_token: Option<String>,
+    interval: Duration,
+    progress: Arc<RunProgress>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        let last = tokio::select! {
+            _ = ticks.tick() => false,
+            _ = stop.changed() => true,
+        };
+        let status = fetch_vault_status(&api_url, &vault_key, auth_token.as_deref()).await;
+        let pushed = progress.traces_pushed();
+        progress.suspend(|| match &status {
+            Ok(Some(status)) => println!(
+                "[Ariana] Server has received {} traces, {} were pushed{}",
+                status.traces_received,
+                pushed,
+                if last { "" } else { " so far" }
+            ),
+            Ok(None) => eprintln!(
+                "[Ariana] The server doesn't tell how many traces it received, --debug-server is disabled for this run"
+            ),
+            Err(e) => eprintln!("[Ariana] Could not get the vault status from the server: {}", e),
+        });
+        if last || matches!(status, Ok(None)) {
+            return;
+        }
+    }
+}
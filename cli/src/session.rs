@@ -0,0 +1,1805 @@
+use ariana_server::traces::instrumentation::ecma::EcmaImportStyle;
+use ariana_server::traces::Trace;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tokio::spawn;
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::baseline::{Baseline, BaselineMode, BASELINE_FILE};
+use crate::capabilities::{load_capabilities, CapabilitiesSource};
+use crate::collector::{
+    collect_items, CollectOptions, CollectedItems, SkippedFile, DEFAULT_TEST_PATTERNS,
+};
+use crate::config::ConfigOverrides;
+use crate::diff::instrumentation_diff;
+use crate::error::{CliError, Result};
+use crate::events::{Event, EventLog};
+use crate::git::detect_git_metadata;
+use crate::instrumentation::{
+    create_vault, detect_project_import_style, detect_ts_path_aliases, finish_vault,
+    instrument_files_batch, BatchContext, TsPathAliases,
+};
+use crate::interrupts::interrupted;
+use crate::lang_map::{LangMap, LangMapping};
+use crate::manifest::{
+    skip_cause_name, ManifestLargeFile, ManifestSkippedFile, ManifestTiming, RunManifest,
+    RUN_MANIFEST_VERSION,
+};
+use crate::metrics::RunMetrics;
+use crate::process_tree::ProcessTree;
+use crate::processor::{
+    default_read_concurrency, process_items, restore_backup, BackupCompression, ProcessOptions,
+    DEFAULT_BATCH_FILE_COUNT,
+};
+use crate::progress::RunProgress;
+use crate::recap_cache::invalidate_cached_recap;
+use crate::redaction::Redactor;
+use crate::remote::RemoteTarget;
+use crate::server_status::{watch_server_status, SERVER_STATUS_INTERVAL};
+use crate::subprocess_stdout_watcher::{
+    tag_output_line, watch_subprocess_output, OutputSource, OutputTagging, OUTPUT_SPOOL_FILE,
+};
+use crate::syntax_check::{check_syntax, SyntaxCheck};
+use crate::trace_parser::{
+    parse_trace_tags, summarize_trace, TraceMarker, DEFAULT_TRACE_MARKER, TRACE_SCHEMA_VERSION,
+};
+use crate::trace_watcher::{
+    watch_traces, TraceWatcherOptions, DEFAULT_MAX_PUSH_BYTES, PENDING_TRACES_DIR,
+    PENDING_TRACES_FILE, UNKNOWN_VAULT_PENDING_TRACES,
+};
+use crate::transcript::write_transcript;
+use crate::transport::Transport;
+use crate::upload_budget::UploadBudget;
+use crate::utils::{
+    add_to_gitignore, clear_dir_except, find_executable, is_cmd_builtin, is_dir_writable,
+    lock_ariana_dir, remove_links, DEFAULT_COPY_EXTENSIONS, LOCK_FILE,
+};
+use crate::ARIANA_DIR;
+
+/// Everything an [`InstrumentationSession`] can be configured with. Start from
+/// [`SessionConfig::new`], which has the same defaults as the `ariana` binary.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// URL of the Ariana server, without trailing slash.
+    pub api_url: String,
+    /// Root of the project to instrument, `.ariana` is created under it.
+    pub project_root: PathBuf,
+    /// The command to run and its arguments.
+    pub command: Vec<String>,
+    /// Directory to run the commands in, relative to the project root. In `.ariana` unless
+    /// `inplace`, so that the instrumented files are used.
+    pub cwd: Option<PathBuf>,
+    /// Commands run after `command`, in order, under the same vault.
+    pub then_commands: Vec<Vec<String>>,
+    /// Runs the next commands even if one fails.
+    pub keep_going: bool,
+    /// Instruments the original files instead of a copy of them under `.ariana`.
+    pub inplace: bool,
+    /// Instruments nothing: the commands run in the project as it is, expected to print trace
+    /// tags on their own, and [`instrument`](InstrumentationSession::instrument) does nothing.
+    pub no_instrument: bool,
+    pub backup_compression: BackupCompression,
+    /// Maximum number of instrumentation requests in flight at once.
+    pub concurrency: usize,
+    /// Number of threads reading the files to instrument, independently of `concurrency`.
+    pub read_concurrency: usize,
+    /// Maximum number of files sent in a single instrumentation request.
+    pub batch_file_count: usize,
+    /// Maximum cumulated size of the files sent in a single instrumentation request.
+    pub max_upload_bytes: u64,
+    /// Maximum number of files copied into `.ariana` at once, so that copying big directories
+    /// doesn't run out of file descriptors.
+    pub max_concurrent_files: usize,
+    /// Reuses the `.ariana` directory of the previous run instead of recreating it.
+    pub keep_ariana_dir: bool,
+    /// Copies files again even if a previous copy in a kept `.ariana` looks up to date.
+    pub force_copy_all: bool,
+    /// Copies everything into `.ariana` instead of linking to the original files, so it can
+    /// be moved to another machine. Slower and bigger on disk, especially with `node_modules`.
+    pub portable: bool,
+    /// Copies the instrumented project to this directory of another machine and runs the
+    /// commands there over SSH, reading their traces and output locally. The directory is
+    /// removed at the end of the run.
+    pub remote: Option<RemoteTarget>,
+    /// Extensions of files always copied rather than linked, lowercase and without dot.
+    pub copy_extensions: Vec<String>,
+    /// Local file receiving a transcript of the command's output, relative to `project_root`
+    /// unless absolute.
+    pub transcript: Option<PathBuf>,
+    /// Records the stdout of the commands, traces stripped, as the baseline of `.ariana`, or
+    /// compares it with the baseline recorded earlier, see [`baseline_diff`](InstrumentationSession::baseline_diff).
+    pub baseline: Option<BaselineMode>,
+    /// Regexes whose matches are replaced before recording or comparing the baseline, for
+    /// content that changes between runs. Timestamps when empty.
+    pub baseline_normalize: Vec<String>,
+    /// Regexes scrubbed from the output sent to the server and the transcript.
+    pub redact_patterns: Vec<String>,
+    /// Also scrubs common secrets from the output.
+    pub default_redaction: bool,
+    /// Streams the command's stdout and stderr to the vault. Traces are sent either way.
+    pub stream_output: bool,
+    /// Spools the streamed output to a file in `.ariana` rather than memory, so that a slow
+    /// server never holds back the command nor makes memory grow.
+    pub buffer_output_file: bool,
+    /// Hides the running count of traces and output lines sent while the command runs.
+    pub quiet: bool,
+    /// Prints machine readable events about the run on stderr, see [`Event`].
+    pub json_logs: bool,
+    /// Makes the server receive the traces of a line before the line itself, by waiting for
+    /// them to be pushed before streaming a line that had traces. Slows down traced output.
+    pub keep_output_order: bool,
+    /// Bytes the whole run may send to the server, instrumentation requests, traces and
+    /// output included. Nothing more is sent once reached. Unlimited with `None`.
+    pub upload_budget_bytes: Option<u64>,
+    /// Lines streamed to the vault are cut to this many bytes, they are printed in full locally.
+    pub max_line_length: Option<usize>,
+    /// Tags each line of output printed locally with the stream it comes from, so that they
+    /// can be told apart once both streams end up in the same file.
+    pub output_tagging: Option<OutputTagging>,
+    /// Gzips trace pushes.
+    pub trace_compression: bool,
+    /// Trace pushes bigger than this, before compression, are split into several requests.
+    pub max_trace_push_bytes: u64,
+    /// Regularly prints how many traces the server received against how many were pushed,
+    /// see `watch_server_status`.
+    pub debug_server: bool,
+    pub verbose: bool,
+    /// Prints a summary of each trace to stderr as it is parsed from the command's output.
+    pub show_traces: bool,
+    /// User tags attached to the vault.
+    pub tags: BTreeMap<String, String>,
+    /// Attaches the git commit, branch and dirty state to the vault.
+    pub git_metadata: bool,
+    /// Fraction of traces sent to the server.
+    pub sample_rate: f64,
+    /// Pushes traces emitted twice by the instrumentation runtime only once.
+    pub dedup_traces: bool,
+    /// File every pushed trace is also appended to, one JSON trace per line.
+    pub save_traces: Option<PathBuf>,
+    pub exclude_dirs: Vec<String>,
+    pub include_dirs: Vec<String>,
+    /// Ignore files applied like a `.arianaignore` at the project root.
+    pub exclude_from: Vec<PathBuf>,
+    /// Leaves JavaScript and TypeScript files uninstrumented.
+    pub skip_js: bool,
+    /// Leaves Python files uninstrumented.
+    pub skip_python: bool,
+    /// Files instrumented as another language than their extension tells, see [`LangMap`].
+    pub lang_map: Vec<LangMapping>,
+    /// Leaves test files uninstrumented, those matching `test_patterns` or by default
+    /// [`DEFAULT_TEST_PATTERNS`].
+    pub skip_tests: bool,
+    pub test_patterns: Vec<String>,
+    /// Instruments files whatever their size, see `collector::MAX_INSTRUMENTED_FILE_BYTES`.
+    pub allow_large_files: bool,
+    /// Files instrumented whatever their size, relative to the project root.
+    pub allowed_large_files: Vec<PathBuf>,
+    /// Instruments source files symlinked from outside the project even with `inplace`, which
+    /// then modifies them.
+    pub instrument_external_symlinks: bool,
+    /// Looks for files to instrument in symlinked directories leading outside of the project,
+    /// instead of only linking them into `.ariana`.
+    pub follow_symlinks: bool,
+    /// Makes [`instrument`](InstrumentationSession::instrument) fail, restoring the original
+    /// files with `inplace`, when an instrumentation request failed.
+    pub fail_on_instrument_error: bool,
+    /// Waits for another run using `.ariana` to finish instead of failing.
+    pub wait_for_lock: bool,
+    /// Leaves uninstrumented the files whose instrumented code doesn't parse.
+    pub verify_syntax: bool,
+    /// Makes [`instrument`](InstrumentationSession::instrument) first send a single small file
+    /// and fail if it doesn't come back instrumented and parsing, before sending the others.
+    pub prewarm: bool,
+    /// Name of the tag traces are printed in by the instrumented code, see [`TraceMarker`].
+    pub trace_marker: String,
+    /// Directory `.ariana` is created in instead of the project root, e.g. for read-only
+    /// projects or to keep it out of the repository. With `inplace`, only the backup of the
+    /// original files goes there.
+    pub output_dir: Option<PathBuf>,
+    /// Vault to send traces to instead of creating one, e.g. one created by an earlier run that
+    /// only instrumented the project.
+    pub vault_key: Option<String>,
+    /// How long to wait for a command that closed both its stdout and stderr but keeps
+    /// running, e.g. one that daemonizes, before stopping it as if it had finished. Its traces
+    /// can't be read anymore either way. Waits for it to exit however long it takes with `None`,
+    /// the default, since a command still busy without printing anything is no daemon.
+    pub output_closed_timeout: Option<Duration>,
+    /// Token of the logged in account, see [`crate::config::stored_auth_token`], sent with
+    /// the requests creating the vault, instrumenting files and pushing traces so that the run
+    /// counts against that account. Without it runs are anonymous, tied to this machine only.
+    pub auth_token: Option<String>,
+    /// Where the machine id runs are tied to and the cached server capabilities are kept, and
+    /// the machine id to use instead of the stored one.
+    pub config_overrides: ConfigOverrides,
+    /// Files to instrument at least this big are listed in the summary of the run, see
+    /// [`large_files`](InstrumentationSession::large_files).
+    pub large_file_warning_bytes: u64,
+}
+
+impl SessionConfig {
+    pub fn new(api_url: &str, project_root: PathBuf, command: Vec<String>) -> Self {
+        SessionConfig {
+            api_url: api_url.trim_end_matches('/').to_string(),
+            project_root,
+            command,
+            cwd: None,
+            then_commands: vec![],
+            keep_going: false,
+            inplace: false,
+            no_instrument: false,
+            backup_compression: BackupCompression::Deflate,
+            concurrency: 4,
+            read_concurrency: default_read_concurrency(),
+            batch_file_count: DEFAULT_BATCH_FILE_COUNT,
+            max_upload_bytes: 32 * 1024 * 1024,
+            max_concurrent_files: 256,
+            keep_ariana_dir: false,
+            force_copy_all: false,
+            portable: false,
+            remote: None,
+            copy_extensions: DEFAULT_COPY_EXTENSIONS
+                .iter()
+                .map(|ext| ext.to_string())
+                .collect(),
+            transcript: None,
+            baseline: None,
+            baseline_normalize: vec![],
+            redact_patterns: vec![],
+            default_redaction: true,
+            stream_output: true,
+            buffer_output_file: false,
+            max_line_length: None,
+            output_tagging: None,
+            upload_budget_bytes: None,
+            keep_output_order: false,
+            quiet: false,
+            json_logs: false,
+            trace_compression: true,
+            max_trace_push_bytes: DEFAULT_MAX_PUSH_BYTES,
+            debug_server: false,
+            verbose: false,
+            show_traces: false,
+            tags: BTreeMap::new(),
+            git_metadata: true,
+            sample_rate: 1.0,
+            dedup_traces: false,
+            save_traces: None,
+            exclude_dirs: vec![],
+            include_dirs: vec![],
+            exclude_from: vec![],
+            lang_map: vec![],
+            skip_js: false,
+            skip_python: false,
+            skip_tests: false,
+            test_patterns: vec![],
+            allow_large_files: false,
+            allowed_large_files: vec![],
+            instrument_external_symlinks: false,
+            follow_symlinks: false,
+            fail_on_instrument_error: false,
+            wait_for_lock: false,
+            verify_syntax: false,
+            prewarm: false,
+            trace_marker: DEFAULT_TRACE_MARKER.to_string(),
+            output_closed_timeout: None,
+            auth_token: None,
+            config_overrides: ConfigOverrides::default(),
+            large_file_warning_bytes: 1024 * 1024,
+            output_dir: None,
+            vault_key: None,
+        }
+    }
+}
+
+/// Reads the next line of `reader` without its line ending, using `buf` as scratch space.
+/// Bytes that aren't valid UTF-8, e.g. from programs printing Latin-1, become replacement
+/// characters rather than failing the read.
+async fn next_lossy_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> std::io::Result<Option<String>> {
+    buf.clear();
+    if reader.read_until(b'\n', buf).await? == 0 {
+        return Ok(None);
+    }
+    if buf.ends_with(b"\n") {
+        buf.pop();
+        if buf.ends_with(b"\r") {
+            buf.pop();
+        }
+    }
+    Ok(Some(String::from_utf8_lossy(buf).into_owned()))
+}
+
+/// Cuts `line` to at most `max_length` bytes (on a char boundary), marking how much was cut.
+fn truncate_line(mut line: String, max_length: Option<usize>) -> String {
+    let Some(max_length) = max_length else {
+        return line;
+    };
+    if line.len() <= max_length {
+        return line;
+    }
+    let mut end = max_length;
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    let truncated_bytes = line.len() - end;
+    line.truncate(end);
+    line.push_str(&format!("... [{} bytes truncated]", truncated_bytes));
+    line
+}
+
+enum CommandOutcome {
+    Exited(i32),
+    /// Stopped on Ctrl+C or SIGTERM, named, with the exit code Ariana should then exit with.
+    Interrupted(&'static str, i32),
+}
+
+/// Where `run_command` forwards what a command prints, shared by all the commands of a run.
+struct CommandSinks {
+    trace_tx: mpsc::Sender<Trace>,
+    /// Asks the trace watcher to push the traces it received so far, see `keep_output_order`.
+    flush_tx: mpsc::Sender<oneshot::Sender<()>>,
+    output_tx: Option<mpsc::Sender<(String, OutputSource)>>,
+    transcript_tx: Option<mpsc::Sender<(String, OutputSource)>>,
+    baseline_tx: Option<mpsc::Sender<String>>,
+    progress: Arc<RunProgress>,
+}
+
+/// A single Ariana run: one vault, one instrumentation of the project, one command.
+pub struct InstrumentationSession {
+    config: SessionConfig,
+    ariana_dir: PathBuf,
+    /// `cwd` relative to the project root.
+    relative_cwd: PathBuf,
+    redactor: Arc<Redactor>,
+    vault_key: Option<String>,
+    collected_items: Option<CollectedItems>,
+    phase_timings: Vec<(&'static str, Duration)>,
+    skipped_files: Vec<SkippedFile>,
+    large_files: Vec<(PathBuf, u64)>,
+    files_instrumented: usize,
+    traces_pushed: usize,
+    /// Ctrl+C or SIGTERM, if one stopped the commands.
+    interrupted_by: Option<&'static str>,
+    upload_budget: Arc<UploadBudget>,
+    events: EventLog,
+    /// Held from [`prepare`](Self::prepare) on, see [`lock_ariana_dir`].
+    ariana_dir_lock: Option<fs::File>,
+    /// Extensions of the files the server can instrument, known from
+    /// [`prepare`](Self::prepare) on.
+    source_extensions: Option<Vec<String>>,
+    /// `config.lang_map` checked against the languages of the server, in
+    /// [`prepare`](Self::prepare).
+    lang_map: LangMap,
+    baseline: Option<Arc<Baseline>>,
+    /// Known once [`instrument`](Self::instrument) detected it.
+    import_style: Option<EcmaImportStyle>,
+    /// Set by [`run`](Self::run) when comparing with a baseline the output differs from.
+    baseline_diff: Option<String>,
+}
+
+impl InstrumentationSession {
+    /// Fails if the configuration is invalid, e.g. a redaction pattern doesn't compile or a
+    /// command can't be found, before any work is done.
+    pub fn new(config: SessionConfig) -> Result<Self> {
+        let redactor = Redactor::new(&config.redact_patterns, config.default_redaction)?;
+        let baseline = config
+            .baseline
+            .map(|mode| Baseline::new(mode, &config.baseline_normalize))
+            .transpose()?;
+        if config.inplace && config.remote.is_some() {
+            return Err(CliError::Config(
+                "Commands can't run remotely with inplace, which instruments the local files"
+                    .to_string(),
+            ));
+        }
+        let relative_cwd = match &config.cwd {
+            Some(cwd) => {
+                let cwd = config.project_root.join(cwd);
+                if !cwd.is_dir() {
+                    return Err(CliError::Config(format!(
+                        "Directory {} does not exist",
+                        cwd.display()
+                    )));
+                }
+                // Canonicalized so that `..` can't escape the project
+                cwd.canonicalize()?
+                    .strip_prefix(config.project_root.canonicalize()?)
+                    .map_err(|_| {
+                        CliError::Config(format!(
+                            "Directory {} is outside of the project {}",
+                            cwd.display(),
+                            config.project_root.display()
+                        ))
+                    })?
+                    .to_path_buf()
+            }
+            None => PathBuf::new(),
+        };
+        // Remote commands are looked up on the remote
+        if config.remote.is_none() {
+            let working_dir = config.project_root.join(&relative_cwd);
+            for command in std::iter::once(&config.command).chain(&config.then_commands) {
+                if let Some(program) = command.first() {
+                    // On Windows commands go through `cmd /C`, which has a few built in
+                    if cfg!(windows) && is_cmd_builtin(program) {
+                        continue;
+                    }
+                    if find_executable(program, &working_dir).is_none() {
+                        return Err(CliError::Config(format!(
+                            "Command not found: `{}`, check that it is installed and in your PATH",
+                            program
+                        )));
+                    }
+                }
+            }
+        }
+        let upload_budget = Arc::new(UploadBudget::new(config.upload_budget_bytes));
+        let json_logs = config.json_logs;
+        Ok(InstrumentationSession {
+            relative_cwd,
+            ariana_dir: config
+                .output_dir
+                .as_ref()
+                .unwrap_or(&config.project_root)
+                .join(ARIANA_DIR),
+            config,
+            redactor: Arc::new(redactor),
+            vault_key: None,
+            collected_items: None,
+            phase_timings: vec![],
+            skipped_files: vec![],
+            large_files: vec![],
+            files_instrumented: 0,
+            traces_pushed: 0,
+            interrupted_by: None,
+            upload_budget,
+            events: EventLog::new(json_logs),
+            ariana_dir_lock: None,
+            source_extensions: None,
+            lang_map: LangMap::default(),
+            baseline: baseline.map(Arc::new),
+            baseline_diff: None,
+            import_style: None,
+        })
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    pub fn ariana_dir(&self) -> &Path {
+        &self.ariana_dir
+    }
+
+    /// The key of the vault, once created.
+    pub fn vault_key(&self) -> Option<&str> {
+        self.vault_key.as_deref()
+    }
+
+    /// Records how long `phase` took, and tells `--json-logs` consumers it finished.
+    fn record_phase(&mut self, phase: &'static str, duration: Duration) {
+        self.phase_timings.push((phase, duration));
+        self.events.emit(Event::PhaseFinished {
+            phase,
+            duration_ms: duration.as_millis(),
+        });
+    }
+
+    /// How long each phase of the session took so far, in the order they ran.
+    pub fn phase_timings(&self) -> &[(&'static str, Duration)] {
+        &self.phase_timings
+    }
+
+    /// Source files left uninstrumented by collection and instrumentation so far.
+    pub fn skipped_files(&self) -> &[SkippedFile] {
+        &self.skipped_files
+    }
+
+    /// Files at least `large_file_warning_bytes` big sent to be instrumented, with their size,
+    /// largest first.
+    pub fn large_files(&self) -> &[(PathBuf, u64)] {
+        &self.large_files
+    }
+
+    /// The signal that stopped the commands of [`run`](Self::run), `None` if they ran to the end.
+    pub fn interrupted_by(&self) -> Option<&'static str> {
+        self.interrupted_by
+    }
+
+    /// How the output of the run differs from the baseline it was compared with, `None` if it
+    /// matches or wasn't compared.
+    pub fn baseline_diff(&self) -> Option<&str> {
+        self.baseline_diff.as_deref()
+    }
+
+    /// Figures about the run so far, for `--metrics-file`. `duration` and `exit_code` are the
+    /// caller's, which knows when the run started and how it ends.
+    pub fn metrics(&self, duration: Duration, exit_code: i32) -> RunMetrics {
+        RunMetrics {
+            files_instrumented: self.files_instrumented,
+            files_skipped: self.skipped_files.len(),
+            bytes_uploaded: self.upload_budget.spent(),
+            traces_pushed: self.traces_pushed,
+            duration,
+            exit_code,
+        }
+    }
+
+    /// What the run did so far, for `.ariana/run.json`. `args` are those Ariana was started
+    /// with, recorded redacted, and `exit_code` the one it exits with, `None` if no command ran.
+    pub fn manifest(
+        &self,
+        args: Vec<String>,
+        started_at: SystemTime,
+        exit_code: Option<i32>,
+    ) -> RunManifest {
+        let relative = |path: &Path| {
+            path.strip_prefix(&self.config.project_root)
+                .unwrap_or(path)
+                .to_path_buf()
+        };
+        let millis_since_epoch = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_millis() as u64)
+        };
+        RunManifest {
+            version: RUN_MANIFEST_VERSION,
+            ariana_version: env!("CARGO_PKG_VERSION").to_string(),
+            args: self.redactor.redact_args(&args),
+            started_at: millis_since_epoch(started_at),
+            finished_at: millis_since_epoch(SystemTime::now()),
+            project_root: self.config.project_root.clone(),
+            working_dir: self.working_dir(),
+            commands: std::iter::once(self.config.command.clone())
+                .chain(self.config.then_commands.iter().cloned())
+                .collect(),
+            api_url: self.config.api_url.clone(),
+            vault_key: self.vault_key.clone(),
+            inplace: self.config.inplace,
+            import_style: self.import_style.clone(),
+            files_instrumented: self.files_instrumented,
+            skipped_files: self
+                .skipped_files
+                .iter()
+                .map(|file| ManifestSkippedFile {
+                    path: relative(&file.path),
+                    reason: file.reason.clone(),
+                    cause: skip_cause_name(file.cause).to_string(),
+                })
+                .collect(),
+            large_files: self
+                .large_files
+                .iter()
+                .map(|(path, bytes)| ManifestLargeFile {
+                    path: relative(path),
+                    bytes: *bytes,
+                })
+                .collect(),
+            timings: self
+                .phase_timings
+                .iter()
+                .map(|(phase, duration)| ManifestTiming {
+                    phase: phase.to_string(),
+                    milliseconds: duration.as_millis() as u64,
+                })
+                .collect(),
+            bytes_uploaded: self.upload_budget.spent(),
+            traces_pushed: self.traces_pushed,
+            baseline_differs: self.baseline_diff.is_some(),
+            exit_code,
+        }
+    }
+
+    /// Directory the command runs in: `cwd` under the project root with `inplace` or
+    /// `no_instrument`, under `.ariana` otherwise.
+    pub fn working_dir(&self) -> PathBuf {
+        let root = if self.config.inplace || self.config.no_instrument {
+            &self.config.project_root
+        } else {
+            &self.ariana_dir
+        };
+        root.join(&self.relative_cwd)
+    }
+
+    /// Locks `.ariana` for this run, starts from a clean one (unless `inplace` or
+    /// `keep_ariana_dir`) and makes sure git ignores it.
+    pub async fn prepare(&mut self) -> Result<()> {
+        self.events.emit(Event::PhaseStarted {
+            phase: "preparation",
+        });
+        let start = Instant::now();
+        self.check_writable()?;
+        self.check_baseline_recorded()?;
+        let ariana_dir_existed = self.ariana_dir.exists();
+        self.ariana_dir_lock =
+            Some(lock_ariana_dir(&self.ariana_dir, self.config.wait_for_lock).await?);
+        self.set_aside_pending_traces()?;
+        if !self.config.inplace {
+            if self.config.keep_ariana_dir && ariana_dir_existed {
+                println!("[Ariana] Reusing previous .ariana directory");
+                remove_links(&self.ariana_dir)?;
+            } else if ariana_dir_existed {
+                println!("[Ariana] Removing previous .ariana directory");
+                // Its lock file stays, another run locking a new one would not see this one's lock,
+                // and so does the baseline recorded by an earlier run
+                clear_dir_except(
+                    &self.ariana_dir,
+                    &[LOCK_FILE, BASELINE_FILE, PENDING_TRACES_DIR],
+                )?;
+            }
+            fs::create_dir_all(&self.ariana_dir)?;
+        }
+
+        if self.ariana_dir.starts_with(&self.config.project_root) {
+            add_to_gitignore(&self.config.project_root).await?;
+        }
+        self.load_source_extensions().await?;
+        self.record_phase("preparation", start.elapsed());
+        Ok(())
+    }
+
+    /// Fails when comparing with a baseline that was never recorded, before anything is
+    /// instrumented or run for nothing. Checked by `prepare` and `reuse_previous_run`.
+    fn check_baseline_recorded(&self) -> Result<()> {
+        if let Some(baseline) = &self.baseline {
+            let baseline_path = self.ariana_dir.join(BASELINE_FILE);
+            if baseline.mode == BaselineMode::Compare && !baseline_path.exists() {
+                return Err(CliError::Config(format!(
+                    "No baseline to compare with in {}, record one first with --baseline",
+                    baseline_path.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the traces the previous run couldn't push to `PENDING_TRACES_DIR`, in a file
+    /// named after their vault, so that they aren't pushed to this run's vault. They are still
+    /// pushed by `--flush-pending`.
+    fn set_aside_pending_traces(&self) -> Result<()> {
+        let pending_traces_path = self.ariana_dir.join(PENDING_TRACES_FILE);
+        if !pending_traces_path.exists() {
+            return Ok(());
+        }
+        let file_name = self.previous_vault_key().map_or_else(
+            || UNKNOWN_VAULT_PENDING_TRACES.to_string(),
+            |vault_key| format!("{}.jsonl", vault_key),
+        );
+        let set_aside_dir = self.ariana_dir.join(PENDING_TRACES_DIR);
+        fs::create_dir_all(&set_aside_dir)?;
+        let set_aside_path = set_aside_dir.join(file_name);
+        // Added to what an earlier run set aside for the same vault, if anything
+        let traces = fs::read(&pending_traces_path)?;
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&set_aside_path)?
+            .write_all(&traces)?;
+        fs::remove_file(&pending_traces_path)?;
+        eprintln!(
+            "[Ariana] WARNING: the previous run could not push all its traces, they are kept in {}. Push them with `ariana --flush-pending`",
+            set_aside_path.display()
+        );
+        Ok(())
+    }
+
+    /// Key of the vault of the previous run, from `.ariana/.vault_secret_key`.
+    fn previous_vault_key(&self) -> Option<String> {
+        fs::read_to_string(self.ariana_dir.join(".vault_secret_key"))
+            .ok()
+            .and_then(|content| content.lines().next().map(|line| line.trim().to_string()))
+            .filter(|vault_key| !vault_key.is_empty())
+    }
+
+    /// Asks the server which files it can instrument, see [`load_capabilities`], and fails if
+    /// `lang_map` maps files to a language it can't.
+    async fn load_source_extensions(&mut self) -> Result<()> {
+        let (capabilities, source) =
+            load_capabilities(&self.config.api_url, false, &self.config.config_overrides).await;
+        if self.config.verbose && source == CapabilitiesSource::Builtin {
+            println!("[Ariana] The server didn't tell which languages it can instrument, assuming JavaScript, TypeScript and Python");
+        }
+        self.source_extensions = Some(capabilities.extensions());
+        self.lang_map = LangMap::new(
+            &self.config.project_root,
+            &self.config.lang_map,
+            &capabilities.languages,
+        )?;
+        Ok(())
+    }
+
+    /// Fails early with a helpful message when `.ariana` can't be created, rather than deep
+    /// into the run with a generic error.
+    fn check_writable(&self) -> Result<()> {
+        let project_root = &self.config.project_root;
+        if self.config.inplace && !is_dir_writable(project_root) {
+            return Err(CliError::Config(format!(
+                "{} is read-only, --inplace can't instrument its files. Run without --inplace and with --output-dir <DIR> to instrument a copy in a writable directory",
+                project_root.display()
+            )));
+        }
+        match &self.config.output_dir {
+            Some(output_dir) => {
+                fs::create_dir_all(output_dir).map_err(|e| {
+                    CliError::Config(format!(
+                        "Could not create --output-dir {}: {}",
+                        output_dir.display(),
+                        e
+                    ))
+                })?;
+                if !is_dir_writable(output_dir) {
+                    return Err(CliError::Config(format!(
+                        "--output-dir {} is not writable",
+                        output_dir.display()
+                    )));
+                }
+            }
+            None if !is_dir_writable(project_root) => {
+                return Err(CliError::Config(format!(
+                    "{} is read-only, so .ariana can't be created in it. Pass --output-dir <DIR> to create it in a writable directory, e.g. --output-dir {}",
+                    project_root.display(),
+                    std::env::temp_dir().join("ariana").display()
+                )));
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Creates the vault receiving the traces, or uses `vault_key` from the config if set, and
+    /// writes its key to `.ariana/.vault_secret_key`.
+    pub async fn create_vault(&mut self) -> Result<String> {
+        self.events.emit(Event::PhaseStarted {
+            phase: "vault creation",
+        });
+        let start = Instant::now();
+        let vault_key = match &self.config.vault_key {
+            Some(vault_key) => {
+                println!(
+                    "[Ariana] Sending traces to the existing vault {}",
+                    vault_key
+                );
+                vault_key.clone()
+            }
+            None => {
+                println!("[Ariana] Creating a new vault for your traces");
+                self.new_vault().await?
+            }
+        };
+
+        // Write vault secret key right away, so the vault can be found even if instrumentation fails
+        fs::create_dir_all(&self.ariana_dir)?;
+        let vault_secret_key_path = self.ariana_dir.join(".vault_secret_key");
+        fs::write(
+            &vault_secret_key_path,
+            format!("{}\nDO NOT SHARE THE ABOVE KEY WITH ANYONE", vault_key),
+        )?;
+
+        self.vault_key = Some(vault_key.clone());
+        self.record_phase("vault creation", start.elapsed());
+        self.events.emit(Event::VaultCreated {
+            vault_key: &vault_key,
+        });
+        Ok(vault_key)
+    }
+
+    /// Asks the server for a new vault, labelled with the command, project and tags.
+    async fn new_vault(&self) -> Result<String> {
+        let cwd_str = self.config.project_root.to_string_lossy().into_owned();
+        let command_str = if self.config.command.is_empty() {
+            None
+        } else {
+            Some(
+                std::iter::once(&self.config.command)
+                    .chain(&self.config.then_commands)
+                    .map(|command| command.join(" "))
+                    .collect::<Vec<_>>()
+                    .join(" && "),
+            )
+        };
+        let mut tags = if self.config.git_metadata {
+            detect_git_metadata(&self.config.project_root)
+        } else {
+            BTreeMap::new()
+        };
+        tags.extend(self.config.tags.clone());
+        let vault_key = create_vault(
+            &self.config.api_url,
+            command_str.as_deref(),
+            Some(&cwd_str),
+            &tags,
+            self.config.auth_token.as_deref(),
+            &self.config.config_overrides,
+        )
+        .await?;
+        Ok(vault_key)
+    }
+
+    /// Walks the project to find the files to instrument and the ones to link or copy.
+    pub fn collect(&mut self) -> Result<&CollectedItems> {
+        self.events.emit(Event::PhaseStarted {
+            phase: "collection",
+        });
+        let start = Instant::now();
+        let collect_options = CollectOptions {
+            exclude_dirs: self.config.exclude_dirs.clone(),
+            include_dirs: self.config.include_dirs.clone(),
+            exclude_from: self.config.exclude_from.clone(),
+            source_extensions: self.source_extensions.clone(),
+            lang_map: self.lang_map.clone(),
+            skip_js: self.config.skip_js,
+            skip_python: self.config.skip_python,
+            allow_large_files: self.config.allow_large_files,
+            allowed_large_files: self
+                .config
+                .allowed_large_files
+                .iter()
+                .map(|path| self.config.project_root.join(path))
+                .collect(),
+            inplace: self.config.inplace,
+            instrument_external_symlinks: self.config.instrument_external_symlinks,
+            follow_symlinks: self.config.follow_symlinks,
+            test_patterns: match (self.config.skip_tests, self.config.test_patterns.is_empty()) {
+                (false, _) => vec![],
+                (true, true) => DEFAULT_TEST_PATTERNS
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect(),
+                (true, false) => self.config.test_patterns.clone(),
+            },
+        };
+        for path in &collect_options.allowed_large_files {
+            if !path.is_file() {
+                eprintln!("[Ariana] --allow-file {} is not a file", path.display());
+            }
+        }
+        let collected_items = collect_items(
+            &self.config.project_root,
+            &self.ariana_dir,
+            &collect_options,
+        )?;
+        self.record_phase("collection", start.elapsed());
+        self.events.emit(Event::FilesCollected {
+            files_to_instrument: collected_items.files_to_instrument.len(),
+            files_to_link_or_copy: collected_items.files_to_link_or_copy.len(),
+            directories_to_link_or_copy: collected_items.directories_to_link_or_copy.len(),
+        });
+        self.skipped_files = collected_items.skipped_files.clone();
+        Ok(self.collected_items.insert(collected_items))
+    }
+
+    /// Instruments the collected files, collecting them first if needed. Requires a vault.
+    /// Does nothing with `no_instrument`.
+    pub async fn instrument(&mut self) -> Result<()> {
+        let vault_key = self
+            .vault_key
+            .clone()
+            .ok_or_else(|| CliError::OutOfOrder("A vault must be created before instrumenting"))?;
+        if self.config.no_instrument {
+            return Ok(());
+        }
+        if self.collected_items.is_none() {
+            self.collect()?;
+        }
+        let collected_items = self.collected_items.as_ref().unwrap();
+        let files_to_instrument = collected_items.files_to_instrument.len();
+        let import_style: EcmaImportStyle = detect_project_import_style(&self.config.project_root)?;
+        self.import_style = Some(import_style.clone());
+        let ts_path_aliases =
+            detect_ts_path_aliases(&self.config.project_root).unwrap_or_else(|e| {
+                eprintln!(
+                    "[Ariana] WARNING: ignoring the path aliases of tsconfig.json: {}",
+                    e
+                );
+                None
+            });
+
+        if self.config.prewarm {
+            self.prewarm(&vault_key, &import_style, ts_path_aliases.as_ref())
+                .await?;
+        }
+        let collected_items = self.collected_items.as_ref().unwrap();
+
+        println!("[Ariana] Instrumenting code files");
+        self.events.emit(Event::PhaseStarted {
+            phase: "instrumentation",
+        });
+        let report = process_items(
+            collected_items,
+            &self.config.api_url,
+            &vault_key,
+            &import_style,
+            &ProcessOptions {
+                project_root: self.config.project_root.clone(),
+                ariana_dir: self.ariana_dir.clone(),
+                is_inplace: self.config.inplace,
+                backup_compression: self.config.backup_compression,
+                concurrency: self.config.concurrency,
+                read_concurrency: self.config.read_concurrency,
+                batch_file_count: self.config.batch_file_count,
+                max_upload_bytes: self.config.max_upload_bytes,
+                max_concurrent_files: self.config.max_concurrent_files,
+                force_copy_all: self.config.force_copy_all,
+                portable: self.config.portable,
+                copy_extensions: self.config.copy_extensions.clone(),
+                upload_budget: self.upload_budget.clone(),
+                trace_marker: self.config.trace_marker.clone(),
+                verify_syntax: self.config.verify_syntax,
+                ts_path_aliases,
+                lang_map: self.lang_map.clone(),
+                auth_token: self.config.auth_token.clone(),
+                large_file_bytes: self.config.large_file_warning_bytes,
+                verbose: self.config.verbose,
+            },
+        )
+        .await;
+        let report = match report {
+            Ok(report) => report,
+            Err(e) if self.config.inplace => {
+                // Files already instrumented have their original in the backup
+                restore_backup(&self.config.project_root, &self.ariana_dir)?;
+                println!("[Ariana] Backup restored, your files are back to their original state");
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        if !self.config.inplace {
+            self.record_phase("copying", report.timings.copying);
+        }
+        self.record_phase("instrumentation", report.timings.instrumenting);
+        self.files_instrumented = files_to_instrument.saturating_sub(report.skipped_files.len());
+        self.skipped_files.extend(report.skipped_files);
+        self.large_files = report.large_files;
+        self.events.emit(Event::FilesInstrumented {
+            skipped_files: self.skipped_files.len(),
+        });
+        if self.config.fail_on_instrument_error && report.failed_batches > 0 {
+            if self.config.inplace {
+                restore_backup(&self.config.project_root, &self.ariana_dir)?;
+                println!("[Ariana] Backup restored, your files are back to their original state");
+            }
+            return Err(CliError::Instrumentation(format!(
+                "{} instrumentation requests failed, not running the command (--fail-on-instrument-error)",
+                report.failed_batches
+            )));
+        }
+        Ok(())
+    }
+
+    /// Sends the smallest file to instrument alone, preferring one whose syntax can be checked,
+    /// and fails if the server doesn't instrument it or its instrumented code doesn't parse
+    /// while the original does: the rest would most likely go the same way.
+    async fn prewarm(
+        &self,
+        vault_key: &str,
+        import_style: &EcmaImportStyle,
+        ts_path_aliases: Option<&TsPathAliases>,
+    ) -> Result<()> {
+        let collected_items = self.collected_items.as_ref().unwrap();
+        let Some((path, content)) = collected_items
+            .files_to_instrument
+            .iter()
+            .filter_map(|(src, _)| {
+                let content = fs::read_to_string(src).ok()?;
+                (!content.trim().is_empty()).then(|| (src.clone(), content))
+            })
+            .min_by_key(|(path, content)| {
+                let checkable = matches!(
+                    path.extension().and_then(|ext| ext.to_str()),
+                    Some("js" | "mjs" | "cjs" | "py")
+                );
+                (!checkable, content.len())
+            })
+        else {
+            return Ok(());
+        };
+
+        let relative_path = path
+            .strip_prefix(&self.config.project_root)
+            .unwrap_or(&path);
+        println!(
+            "[Ariana] Checking instrumentation on {} first (--prewarm)",
+            relative_path.display()
+        );
+        let context = BatchContext {
+            api_url: &self.config.api_url,
+            vault_key,
+            import_style,
+            ts_path_aliases,
+            upload_budget: &self.upload_budget,
+            trace_marker: &self.config.trace_marker,
+            auth_token: self.config.auth_token.as_deref(),
+        };
+        let instrumented = instrument_files_batch(
+            &context,
+            std::slice::from_ref(&path),
+            vec![content.clone()],
+            self.lang_map.languages_of(std::slice::from_ref(&path)),
+        )
+        .await
+        .map_err(|e| {
+            CliError::Instrumentation(format!(
+                "Prewarm failed, not instrumenting the other files: {}",
+                e
+            ))
+        })?;
+        let Some(Some(instrumented)) = instrumented.into_iter().next() else {
+            return Err(CliError::Instrumentation(format!(
+                "Prewarm failed, the server didn't instrument {}, not instrumenting the other files. Check that the server supports its language and is up to date",
+                relative_path.display()
+            )));
+        };
+        if let SyntaxCheck::Invalid(error) = check_syntax(&path, &instrumented).await {
+            if check_syntax(&path, &content).await == SyntaxCheck::Valid {
+                return Err(CliError::Instrumentation(format!(
+                    "Prewarm failed, the instrumented code of {} is not valid ({}), not instrumenting the other files. Check the import style of your project and that the server is up to date",
+                    relative_path.display(),
+                    error
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the place of [`prepare`](Self::prepare), [`create_vault`](Self::create_vault) and
+    /// [`instrument`](Self::instrument) to run the command again in the `.ariana` a previous
+    /// run instrumented, sending its traces to the same vault. Fails if there is no such
+    /// `.ariana`, and warns about source files changed since, whose old version would run.
+    pub async fn reuse_previous_run(&mut self) -> Result<()> {
+        if self.config.inplace {
+            return Err(CliError::Config(
+                "An --inplace run restores the original files when it ends, there is no instrumented project to run again"
+                    .to_string(),
+            ));
+        }
+        self.events.emit(Event::PhaseStarted {
+            phase: "preparation",
+        });
+        let start = Instant::now();
+        if !self.ariana_dir.is_dir() {
+            return Err(CliError::Config(format!(
+                "{} does not exist, run without --append-run first to instrument the project",
+                self.ariana_dir.display()
+            )));
+        }
+        self.check_baseline_recorded()?;
+        self.ariana_dir_lock =
+            Some(lock_ariana_dir(&self.ariana_dir, self.config.wait_for_lock).await?);
+        // Same vault, so this run's traces that can't be pushed are added to them
+        let pending_traces_path = self.ariana_dir.join(PENDING_TRACES_FILE);
+        if pending_traces_path.exists() {
+            eprintln!(
+                "[Ariana] WARNING: the previous run could not push all its traces, they are kept in {}. Push them with `ariana --flush-pending`",
+                pending_traces_path.display()
+            );
+        }
+        let vault_key_path = self.ariana_dir.join(".vault_secret_key");
+        let vault_key = self.previous_vault_key().ok_or_else(|| {
+            CliError::Config(format!(
+                "No vault key in {}, run without --append-run first to create a vault",
+                vault_key_path.display()
+            ))
+        })?;
+        self.load_source_extensions().await?;
+        self.record_phase("preparation", start.elapsed());
+
+        // Only to compare the sources with their instrumented copies, nothing is written
+        self.collect()?;
+        let files_to_instrument = &self.collected_items.as_ref().unwrap().files_to_instrument;
+        let mut missing = vec![];
+        let mut stale = vec![];
+        for (src, dest) in files_to_instrument {
+            let modified =
+                |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+            match (modified(src), modified(dest)) {
+                (_, Err(_)) => missing.push(src),
+                (Ok(src_modified), Ok(dest_modified)) if src_modified > dest_modified => {
+                    stale.push(src)
+                }
+                _ => {}
+            }
+        }
+        if !files_to_instrument.is_empty() && missing.len() == files_to_instrument.len() {
+            return Err(CliError::Config(format!(
+                "{} holds no instrumented project, run without --append-run first to instrument it",
+                self.ariana_dir.display()
+            )));
+        }
+        if !missing.is_empty() || !stale.is_empty() {
+            eprintln!(
+                "[Ariana] WARNING: .ariana is out of date, {} source files changed and {} are new since it was instrumented, run without --append-run to instrument them again:",
+                stale.len(),
+                missing.len()
+            );
+            for path in stale.iter().chain(&missing).take(10) {
+                let path = path.strip_prefix(&self.config.project_root).unwrap_or(path);
+                eprintln!("[Ariana]   {}", path.display());
+            }
+            if stale.len() + missing.len() > 10 {
+                eprintln!("[Ariana]   ...");
+            }
+        }
+        self.files_instrumented = files_to_instrument.len() - missing.len();
+
+        println!(
+            "[Ariana] Running again in the instrumented project, sending traces to vault {}",
+            vault_key
+        );
+        self.events.emit(Event::VaultCreated {
+            vault_key: &vault_key,
+        });
+        self.vault_key = Some(vault_key);
+        Ok(())
+    }
+
+    /// Unified diff of what instrumentation changed in each file, see [`instrumentation_diff`].
+    /// With `inplace`, call it before [`run`](Self::run) which restores the original files.
+    pub fn diff(&self) -> Result<String> {
+        let collected_items = self.collected_items.as_ref().ok_or_else(|| {
+            CliError::OutOfOrder("Files must be instrumented before diffing them")
+        })?;
+        instrumentation_diff(
+            collected_items,
+            &self.config.project_root,
+            &self.ariana_dir,
+            self.config.inplace,
+        )
+    }
+
+    /// Runs the command, then the `then_commands`, in the working directory, streaming their
+    /// traces and output to the vault. Stops at the first command that fails (unless
+    /// `keep_going`) or when Ctrl+C is hit. With `inplace`, the original files are restored after,
+    /// also when this fails.
+    ///
+    /// Returns the exit code of the first command that failed, or else of the last one.
+    pub async fn run(&mut self) -> Result<i32> {
+        let result = self.run_commands().await;
+        if result.is_err() && self.config.inplace {
+            match restore_backup(&self.config.project_root, &self.ariana_dir) {
+                Ok(()) => println!(
+                    "[Ariana] Backup restored, your files are back to their original state"
+                ),
+                Err(e) => eprintln!("[Ariana] Error restoring backup: {}", e),
+            }
+        }
+        result
+    }
+
+    /// Everything `run` does but restoring the original files when it fails. Once the trace and
+    /// output watchers are started, errors only get returned after they are shut down.
+    async fn run_commands(&mut self) -> Result<i32> {
+        let vault_key = self.vault_key.clone().ok_or_else(|| {
+            CliError::OutOfOrder("A vault must be created before running the command")
+        })?;
+        if self.config.command.is_empty() {
+            return Err(CliError::Config("No command to run".to_string()));
+        }
+        let working_dir = self.working_dir();
+        if !working_dir.is_dir() {
+            return Err(CliError::Config(format!(
+                "Working directory {} does not exist",
+                working_dir.display()
+            )));
+        }
+
+        if let Some(remote) = &self.config.remote {
+            println!("[Ariana] Copying the instrumented project to {}", remote);
+            let upload_start = Instant::now();
+            remote.upload(&self.ariana_dir).await?;
+            self.record_phase("remote upload", upload_start.elapsed());
+        }
+
+        let (trace_tx, trace_rx) = mpsc::channel::<Trace>(1);
+        let (flush_tx, flush_rx) = mpsc::channel::<oneshot::Sender<()>>(1);
+        // The status line would garble the events printed on stderr
+        let progress = Arc::new(RunProgress::new(
+            !self.config.quiet && !self.events.is_enabled(),
+        ));
+
+        let mut transport = Transport::new(&self.config.api_url, &vault_key, self.events);
+        let trace_watcher_options = TraceWatcherOptions {
+            compress: self.config.trace_compression,
+            verbose: self.config.verbose,
+            sample_rate: self.config.sample_rate,
+            dedup_traces: self.config.dedup_traces,
+            pending_traces_path: Some(self.ariana_dir.join(PENDING_TRACES_FILE)),
+            save_traces_path: self.config.save_traces.clone(),
+            upload_budget: self.upload_budget.clone(),
+            max_push_bytes: self.config.max_trace_push_bytes,
+            auth_token: self.config.auth_token.clone(),
+        };
+        let server_status_watcher = self.config.debug_server.then(|| {
+            let (stop_tx, stop_rx) = watch::channel(false);
+            let watcher = spawn(watch_server_status(
+                self.config.api_url.clone(),
+                vault_key.clone(),
+                self.config.auth_token.clone(),
+                SERVER_STATUS_INTERVAL,
+                progress.clone(),
+                stop_rx,
+            ));
+            (stop_tx, watcher)
+        });
+        let trace_watcher_connection = transport.connection();
+        let trace_watcher_progress = progress.clone();
+        transport.spawn("traces", async move {
+            watch_traces(
+                trace_rx,
+                trace_watcher_connection,
+                flush_rx,
+                &trace_watcher_options,
+                &trace_watcher_progress,
+            )
+            .await
+        });
+
+        // Start the subprocess output watcher, unless output streaming is disabled
+        let output_tx = if self.config.stream_output {
+            let (output_tx, output_rx) = mpsc::channel::<(String, OutputSource)>(100);
+            let subprocess_connection = transport.connection();
+            let subprocess_progress = progress.clone();
+            let subprocess_upload_budget = self.upload_budget.clone();
+            let spool_path = self
+                .config
+                .buffer_output_file
+                .then(|| self.ariana_dir.join(OUTPUT_SPOOL_FILE));
+            transport.spawn("output", async move {
+                watch_subprocess_output(
+                    output_rx,
+                    subprocess_connection,
+                    &subprocess_progress,
+                    &subprocess_upload_budget,
+                    spool_path,
+                )
+                .await
+            });
+            Some(output_tx)
+        } else {
+            None
+        };
+        // Start the local transcript writer if requested
+        let (transcript_tx, transcript_writer) = match &self.config.transcript {
+            Some(transcript_path) => {
+                let (transcript_tx, transcript_rx) =
+                    mpsc::channel::<(String, OutputSource)>(10_000);
+                let transcript_path = self.config.project_root.join(transcript_path);
+                let transcript_writer =
+                    spawn(async move { write_transcript(transcript_rx, &transcript_path).await });
+                (Some(transcript_tx), Some(transcript_writer))
+            }
+            None => (None, None),
+        };
+        let (baseline_tx, baseline_collector) = match &self.baseline {
+            Some(baseline) => {
+                let (baseline_tx, mut baseline_rx) = mpsc::channel::<String>(10_000);
+                let baseline = baseline.clone();
+                let baseline_collector = spawn(async move {
+                    let mut lines = vec![];
+                    while let Some(line) = baseline_rx.recv().await {
+                        lines.push(baseline.normalize(&line));
+                    }
+                    lines
+                });
+                (Some(baseline_tx), Some(baseline_collector))
+            }
+            None => (None, None),
+        };
+
+        let sinks = CommandSinks {
+            trace_tx,
+            flush_tx,
+            output_tx,
+            transcript_tx,
+            baseline_tx,
+            progress: progress.clone(),
+        };
+        let commands = std::iter::once(self.config.command.clone())
+            .chain(self.config.then_commands.iter().cloned())
+            .collect::<Vec<_>>();
+        let mut exit_code = 0;
+        let mut command_error = None;
+        let mut commands_duration = Duration::ZERO;
+        self.events.emit(Event::PhaseStarted { phase: "command" });
+        for (i, command) in commands.iter().enumerate() {
+            let command_start = Instant::now();
+            self.events.emit(Event::CommandStarted { command });
+            let outcome = match self.run_command(command, &working_dir, &sinks).await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    command_error = Some(e);
+                    break;
+                }
+            };
+            commands_duration += command_start.elapsed();
+            self.events.emit(Event::CommandFinished {
+                command,
+                exit_code: match outcome {
+                    CommandOutcome::Exited(code) => Some(code),
+                    CommandOutcome::Interrupted(..) => None,
+                },
+            });
+            let code = match outcome {
+                // What ssh exits with when it can't connect or loses the connection
+                CommandOutcome::Exited(255) if self.config.remote.is_some() => {
+                    eprintln!("[Ariana] Your command stopped with exit code 255, the connection to the remote was probably lost or couldn't be established");
+                    255
+                }
+                CommandOutcome::Exited(code) => code,
+                CommandOutcome::Interrupted(signal_name, code) => {
+                    self.interrupted_by = Some(signal_name);
+                    exit_code = code;
+                    break;
+                }
+            };
+            if exit_code == 0 {
+                exit_code = code;
+            }
+            if code != 0 && !self.config.keep_going && i + 1 < commands.len() {
+                eprintln!("[Ariana] Command failed, skipping the next ones");
+                break;
+            }
+        }
+        if let Some(remote) = &self.config.remote {
+            if let Err(e) = remote.cleanup().await {
+                eprintln!("[Ariana] Could not remove {}: {}", remote, e);
+            }
+        }
+        let CommandSinks {
+            output_tx,
+            transcript_tx,
+            baseline_tx,
+            ..
+        } = sinks;
+        drop(transcript_tx);
+        drop(baseline_tx);
+        if let Some(baseline_collector) = baseline_collector {
+            match baseline_collector.await {
+                Ok(lines) if command_error.is_none() => match self.interrupted_by {
+                    // Its output is cut short, neither worth recording nor comparing
+                    Some(signal_name) => eprintln!(
+                        "[Ariana] Your command was stopped by {}, its output was not checked against the baseline",
+                        signal_name
+                    ),
+                    None => self.check_baseline(&lines, exit_code),
+                },
+                Ok(_) => {}
+                Err(e) => eprintln!(
+                    "[Ariana] Could not collect the output for the baseline: {}",
+                    e
+                ),
+            }
+        }
+
+        progress.suspend(|| {
+            println!("[Ariana] Waiting to finish sending collected traces and output...")
+        });
+        self.record_phase("command", commands_duration);
+        self.events.emit(Event::PhaseStarted {
+            phase: "trace flushing",
+        });
+        let flush_start = Instant::now();
+
+        drop(output_tx);
+        transport.shutdown().await;
+        if let Some((stop_tx, watcher)) = server_status_watcher {
+            let _ = stop_tx.send(true);
+            let _ = watcher.await;
+        }
+        let lost_lines = progress.lines_lost();
+        if lost_lines > 0 {
+            eprintln!(
+                "[Ariana] {} lines of output could not be sent to the server",
+                lost_lines
+            );
+        }
+
+        if let Some(transcript_writer) = transcript_writer {
+            match transcript_writer.await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => eprintln!("[Ariana] Could not write transcript: {}", e),
+                Err(e) => eprintln!(
+                    "[Ariana CLI Main] Failed to join transcript writer task: {:?}",
+                    e
+                ),
+            }
+        }
+        self.record_phase("trace flushing", flush_start.elapsed());
+        progress.finish();
+        if let Some(e) = command_error {
+            return Err(e);
+        }
+        if !self.config.quiet {
+            println!(
+                "[Ariana] Sent {} traces and {} lines of output",
+                progress.traces_pushed(),
+                progress.lines_streamed()
+            );
+        }
+        if self.upload_budget.is_exhausted() {
+            eprintln!(
+                "[Ariana] The upload budget was reached after sending {} bytes, {} traces were not sent",
+                self.upload_budget.spent(),
+                self.upload_budget.dropped_traces()
+            );
+        }
+
+        if let Err(e) = finish_vault(&self.config.api_url, &vault_key, exit_code).await {
+            eprintln!("[Ariana] Could not tell the server the run is over: {}", e);
+        }
+
+        if self.config.inplace {
+            if let Err(e) = restore_backup(&self.config.project_root, &self.ariana_dir) {
+                eprintln!("[Ariana] Error restoring backup at end of command: {}", e);
+            } else {
+                println!("[Ariana] Backup restored at end of command (if applicable).");
+            }
+        }
+
+        self.traces_pushed = progress.traces_pushed();
+        if self.traces_pushed > 0 {
+            invalidate_cached_recap(&self.ariana_dir, &vault_key);
+        }
+        self.events.emit(Event::RunFinished {
+            exit_code,
+            traces_pushed: progress.traces_pushed(),
+            lines_streamed: progress.lines_streamed(),
+            lost_lines,
+        });
+        Ok(exit_code)
+    }
+
+    /// Records `lines` as the baseline, or compares them with it, depending on its mode. The
+    /// output of commands exiting with `exit_code` other than 0 is not recorded.
+    fn check_baseline(&mut self, lines: &[String], exit_code: i32) {
+        let Some(baseline) = self.baseline.clone() else {
+            return;
+        };
+        let baseline_path = self.ariana_dir.join(BASELINE_FILE);
+        match baseline.mode {
+            BaselineMode::Record if exit_code != 0 => eprintln!(
+                "[Ariana] Your command failed with exit code {}, its output was not recorded as the baseline",
+                exit_code
+            ),
+            BaselineMode::Record => match baseline.record(&self.ariana_dir, lines) {
+                Ok(()) => println!(
+                    "[Ariana] Recorded {} lines of output as the baseline in {}",
+                    lines.len(),
+                    baseline_path.display()
+                ),
+                Err(e) => eprintln!("[Ariana] Could not record the baseline: {}", e),
+            },
+            BaselineMode::Compare => match baseline.compare(&self.ariana_dir, lines) {
+                Ok(None) => println!("[Ariana] Output matches the baseline"),
+                Ok(Some(diff)) => {
+                    eprintln!("[Ariana] Output differs from the baseline:\n{}", diff);
+                    self.baseline_diff = Some(diff);
+                }
+                Err(e) => {
+                    eprintln!("[Ariana] Could not compare with the baseline: {}", e);
+                    self.baseline_diff = Some(e.to_string());
+                }
+            },
+        }
+    }
+
+    /// Runs a single command until it exits or Ctrl+C is hit, forwarding its traces and output.
+    async fn run_command(
+        &self,
+        command: &[String],
+        working_dir: &Path,
+        sinks: &CommandSinks,
+    ) -> Result<CommandOutcome> {
+        let (command_to_run, command_args) = command
+            .split_first()
+            .ok_or_else(|| CliError::Config("Empty command".to_string()))?;
+
+        let place = match &self.config.remote {
+            Some(remote) => format!("on {}", remote),
+            None => format!(
+                "in {}/",
+                working_dir
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_str()
+                    .unwrap_or_default()
+            ),
+        };
+        println!(
+            "[Ariana] Running `{} {}` {}",
+            command_to_run,
+            command_args.join(" "),
+            place
+        );
+        println!("\n\n\n");
+
+        let mut child_command = match &self.config.remote {
+            Some(remote) => remote.command(&self.relative_cwd, command)?,
+            None => {
+                let mut child_command = if cfg!(windows) {
+                    let mut child_command = tokio::process::Command::new("cmd");
+                    child_command.args(["/C", command_to_run.as_str()]);
+                    child_command
+                } else {
+                    tokio::process::Command::new(command_to_run)
+                };
+                child_command.args(command_args);
+                child_command
+            }
+        };
+        child_command
+            .current_dir(working_dir)
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped());
+        // Remote commands read the connection's stdin rather than the terminal
+        let mut process_tree =
+            ProcessTree::prepare(&mut child_command, self.config.remote.is_none());
+        let mut child = child_command.spawn()?;
+        // Kept until the command is over, dropping it gives the terminal back and, on Windows,
+        // kills what is left of the tree
+        process_tree.attach(&child);
+        // Kept open until the command is over, a remote command is stopped once it closes
+        let _remote_stdin = child.stdin.take();
+
+        let child_stdout = child.stdout.take().expect("Failed to capture stdout");
+        let mut stdout_reader = tokio::io::BufReader::new(child_stdout);
+
+        let child_stderr = child.stderr.take().expect("Failed to capture stderr");
+        let mut stderr_reader = tokio::io::BufReader::new(child_stderr);
+
+        let stdout_output_tx = sinks.output_tx.clone();
+        let stderr_output_tx_clone = sinks.output_tx.clone();
+        let trace_tx_for_stdout = sinks.trace_tx.clone();
+        let stdout_transcript_tx = sinks.transcript_tx.clone();
+        let stdout_baseline_tx = sinks.baseline_tx.clone();
+        let stderr_transcript_tx = sinks.transcript_tx.clone();
+        let stdout_redactor = self.redactor.clone();
+        let stderr_redactor = self.redactor.clone();
+
+        let show_traces = self.config.show_traces;
+        let trace_marker = TraceMarker::new(&self.config.trace_marker);
+        let max_line_length = self.config.max_line_length;
+        let output_tagging = self.config.output_tagging;
+        let keep_output_order = self.config.keep_output_order;
+        let flush_tx = sinks.flush_tx.clone();
+        let stdout_progress = sinks.progress.clone();
+        let stderr_progress = sinks.progress.clone();
+
+        let perf_now = std::time::Instant::now();
+
+        let stdout_processing_task = tokio::spawn(async move {
+            let mut warned_about_schema = false;
+            let mut buf = Vec::new();
+            loop {
+                match next_lossy_line(&mut stdout_reader, &mut buf).await {
+                    Ok(Some(line)) => {
+                        let parsed_line = parse_trace_tags(&line, &trace_marker);
+                        if let Some(schema_version) = parsed_line.schema_version {
+                            if schema_version > TRACE_SCHEMA_VERSION && !warned_about_schema {
+                                eprintln!("[Ariana] Traces use schema version {}, newer than the version {} this CLI knows, consider updating ariana", schema_version, TRACE_SCHEMA_VERSION);
+                                warned_about_schema = true;
+                            }
+                        }
+                        for error in &parsed_line.errors {
+                            eprintln!("[Ariana] Failed to deserialize trace content: {}", error);
+                        }
+                        let had_traces = !parsed_line.traces.is_empty();
+                        for trace in parsed_line.traces {
+                            if show_traces {
+                                eprintln!("[Ariana] [trace] {}", summarize_trace(&trace));
+                            }
+                            if trace_tx_for_stdout.send(trace).await.is_err() {
+                                eprintln!(
+                                    "[Ariana] Trace channel closed. Cannot send more traces."
+                                );
+                            }
+                        }
+                        if keep_output_order && stdout_output_tx.is_some() && had_traces {
+                            // Wait for this line's traces to be pushed before streaming it
+                            let (ack_tx, ack_rx) = oneshot::channel();
+                            if flush_tx.send(ack_tx).await.is_ok() {
+                                let _ = ack_rx.await;
+                            }
+                        }
+                        let processed_line = parsed_line.text;
+                        if !processed_line
+                            .trim_matches(|c| {
+                                c == ' ' || c == '\n' || c == '\t' || c == '\r' || c == '\x08'
+                            })
+                            .is_empty()
+                        {
+                            stdout_progress.suspend(|| {
+                                println!(
+                                    "{}",
+                                    tag_output_line(
+                                        &processed_line,
+                                        OutputSource::Stdout,
+                                        output_tagging
+                                    )
+                                )
+                            });
+                            let redacted_line =
+                                stdout_redactor.redact(&processed_line).into_owned();
+                            if let Some(transcript_tx) = &stdout_transcript_tx {
+                                let _ = transcript_tx
+                                    .send((redacted_line.clone(), OutputSource::Stdout))
+                                    .await;
+                            }
+                            if let Some(baseline_tx) = &stdout_baseline_tx {
+                                let _ = baseline_tx.send(redacted_line.clone()).await;
+                            }
+                            if let Some(stdout_output_tx) = &stdout_output_tx {
+                                if stdout_output_tx
+                                    .send((
+                                        truncate_line(redacted_line, max_line_length),
+                                        OutputSource::Stdout,
+                                    ))
+                                    .await
+                                    .is_err()
+                                {
+                                    eprintln!(
+                                        "[Ariana] Stdout channel closed. Stopping stdout processing."
+                                    );
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("[Ariana] Error reading stdout from subprocess: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        let stderr_processing_task = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            loop {
+                match next_lossy_line(&mut stderr_reader, &mut buf).await {
+                    Ok(Some(line)) => {
+                        stderr_progress.suspend(|| {
+                            eprintln!(
+                                "{}",
+                                tag_output_line(&line, OutputSource::Stderr, output_tagging)
+                            )
+                        });
+                        let redacted_line = stderr_redactor.redact(&line).into_owned();
+                        if let Some(transcript_tx) = &stderr_transcript_tx {
+                            let _ = transcript_tx
+                                .send((redacted_line.clone(), OutputSource::Stderr))
+                                .await;
+                        }
+                        if let Some(stderr_output_tx) = &stderr_output_tx_clone {
+                            if stderr_output_tx
+                                .send((
+                                    truncate_line(redacted_line, max_line_length),
+                                    OutputSource::Stderr,
+                                ))
+                                .await
+                                .is_err()
+                            {
+                                eprintln!(
+                                    "[Ariana] Stderr channel closed. Stopping stderr processing."
+                                );
+                                break;
+                            }
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("[Ariana] Error reading stderr from subprocess: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        // Resolves once the command closed its stdout and stderr, usually because it exited
+        let mut output_tasks =
+            tokio::spawn(
+                async move { (stdout_processing_task.await, stderr_processing_task.await) },
+            );
+        let mut output_tasks_results = None;
+        let mut output_closed_at = None;
+        let mut interrupted_in_terminal = false;
+        let outcome = loop {
+            tokio::select! {
+                biased;
+                (signal_name, code) = interrupted() => {
+                    println!("[Ariana] Received {}, stopping your command...", signal_name);
+                    if self.config.inplace {
+                        if let Err(e) = restore_backup(&self.config.project_root, &self.ariana_dir) {
+                            eprintln!("[Ariana] Error restoring backup during {}: {}", signal_name, e);
+                        } else {
+                            println!("[Ariana] Backup restored due to {} (if applicable).", signal_name);
+                        }
+                    }
+                    if let Err(e) = process_tree.kill() {
+                        eprintln!("[Ariana] Failed to kill the processes started by your command: {}", e);
+                    }
+                    if interrupted_in_terminal {
+                        // Already exited
+                    } else if let Err(e) = child.kill().await {
+                        eprintln!("[Ariana] Failed to kill subprocess: {}. It might have already exited.", e);
+                    } else {
+                        println!("[Ariana] Subprocess signalled to terminate.");
+                    }
+                    break CommandOutcome::Interrupted(signal_name, code);
+                }
+                result = child.wait(), if !interrupted_in_terminal => {
+                    break match result {
+                        Ok(status) if process_tree.forward_terminal_interrupt(status) => {
+                            // Stopped like with a Ctrl+C received by Ariana, which comes next
+                            interrupted_in_terminal = true;
+                            continue;
+                        }
+                        Ok(status) => {
+                            if !status.success() {
+                                eprintln!("[Ariana] Subprocess exited with status: {}", status);
+                            }
+                            // Killed by a signal on unix
+                            CommandOutcome::Exited(status.code().unwrap_or(1))
+                        }
+                        Err(e) => {
+                            eprintln!("[Ariana] Error waiting for subprocess: {}", e);
+                            CommandOutcome::Exited(1)
+                        }
+                    };
+                }
+                results = &mut output_tasks, if output_tasks_results.is_none() => {
+                    output_tasks_results = Some(results);
+                    output_closed_at = Some(Instant::now());
+                }
+                _ = tokio::time::sleep_until(
+                    output_closed_at
+                        .zip(self.config.output_closed_timeout)
+                        .map_or_else(Instant::now, |(closed_at, timeout)| closed_at + timeout)
+                        .into(),
+                ), if output_closed_at.is_some() && self.config.output_closed_timeout.is_some() => {
+                    // A command that daemonizes leaves a process running detached from its output
+                    println!(
+                        "[Ariana] Your command closed its output {} s ago but is still running, stopping it (--output-closed-timeout)",
+                        self.config.output_closed_timeout.unwrap_or_default().as_secs()
+                    );
+                    if let Err(e) = process_tree.kill() {
+                        eprintln!("[Ariana] Failed to kill the processes started by your command: {}", e);
+                    }
+                    let _ = child.kill().await;
+                    break CommandOutcome::Exited(0);
+                }
+            }
+        };
+
+        let output_tasks_results = match output_tasks_results {
+            Some(results) => results,
+            None => output_tasks.await,
+        };
+        match output_tasks_results {
+            Ok((stdout_result, stderr_result)) => {
+                if let Err(e) = stdout_result {
+                    eprintln!("[Ariana] Error joining stdout processing task: {:?}", e);
+                }
+                if let Err(e) = stderr_result {
+                    eprintln!("[Ariana] Error joining stderr processing task: {:?}", e);
+                }
+            }
+            Err(e) => eprintln!("[Ariana] Error joining output processing tasks: {:?}", e),
+        }
+
+        let perf_end = std::time::Instant::now();
+        println!(
+            "[Ariana] Command finished, took {} ms.",
+            perf_end.duration_since(perf_now).as_millis()
+        );
+
+        Ok(outcome)
+    }
+}
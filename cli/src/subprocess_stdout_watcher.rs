@@ -1,10 +1,18 @@
-use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
-use tokio_tungstenite::tungstenite::protocol::Message;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use std::time::{SystemTime, UNIX_EPOCH};
-use futures_util::SinkExt;
-use tokio_tungstenite::connect_async;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::{mpsc, Notify};
+
+use crate::error::{CliError, Result};
+use crate::progress::RunProgress;
+use crate::transport::{Connection, ReconnectingWebSocket};
+use crate::upload_budget::UploadBudget;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum OutputSource {
@@ -12,6 +20,35 @@ pub enum OutputSource {
     Stderr,
 }
 
+/// How lines of the command's output printed locally tell which stream they come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputTagging {
+    /// Prefix each line with `[stdout]` or `[stderr]`
+    Prefix,
+    /// Dim stderr lines, leaving stdout ones as-is
+    Color,
+}
+
+/// `line` as printed locally with `tagging`, unchanged without.
+pub fn tag_output_line(
+    line: &str,
+    source: OutputSource,
+    tagging: Option<OutputTagging>,
+) -> Cow<'_, str> {
+    match (tagging, source) {
+        (None, _) | (Some(OutputTagging::Color), OutputSource::Stdout) => Cow::Borrowed(line),
+        (Some(OutputTagging::Prefix), OutputSource::Stdout) => {
+            Cow::Owned(format!("[stdout] {}", line))
+        }
+        (Some(OutputTagging::Prefix), OutputSource::Stderr) => {
+            Cow::Owned(format!("[stderr] {}", line))
+        }
+        (Some(OutputTagging::Color), OutputSource::Stderr) => {
+            Cow::Owned(format!("\x1b[2m{}\x1b[0m", line))
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct SubprocessOutput {
     pub line: String,
@@ -19,109 +56,220 @@ struct SubprocessOutput {
     pub source: OutputSource,
 }
 
+/// Lines kept for retrying while the connection is down, the oldest are dropped beyond that.
+const MAX_PENDING_LINES: usize = 10_000;
+
+/// Name of the file in `.ariana` output is spooled to with `--buffer-output-file`.
+pub const OUTPUT_SPOOL_FILE: &str = "output_spool.jsonl";
+
+/// Streams the lines received on `output_rx` to the vault over a WebSocket, reconnecting when
+/// the connection drops, until the channel closes or `connection` is shut down; lines already
+/// queued are still sent then. Lines that could not be sent are counted in `progress`.
+///
+/// With a `spool_path`, lines go through that file instead of memory, see `watch_through_spool`.
 pub async fn watch_subprocess_output(
     mut output_rx: mpsc::Receiver<(String, OutputSource)>,
-    api_url: &str,
-    vault_key: &str,
-    mut stop_rx: mpsc::Receiver<()>,
+    mut connection: Connection,
+    progress: &RunProgress,
+    upload_budget: &UploadBudget,
+    spool_path: Option<PathBuf>,
 ) -> Result<()> {
-    let url = format!(
-        "{}vaults/{}/subprocess-stdout/stream",
-        api_url.replace("http", "ws").replace("https", "wss"),
-        vault_key
-    );
+    let mut socket = connection
+        .websocket(&format!(
+            "vaults/{}/subprocess-stdout/stream",
+            connection.vault_key()
+        ))
+        .await;
 
-    let (mut ws_stream, _) = connect_async(&url).await?;
-    // println!("[Ariana] Connected to subprocess stdout stream");
+    if let Some(spool_path) = spool_path {
+        return watch_through_spool(
+            output_rx,
+            socket,
+            connection,
+            spool_path,
+            progress,
+            upload_budget,
+        )
+        .await;
+    }
 
-    let (internal_tx, mut internal_rx) = mpsc::channel::<(String, OutputSource)>(10_000);
-    let (task_stop_tx, mut task_stop_rx) = mpsc::channel::<()>(1);
+    let mut shutting_down = false;
+    // Serialized lines not sent yet, in order, kept across reconnections
+    let mut pending = VecDeque::new();
 
-    tokio::spawn(async move {
-        loop {
-            tokio::select! {
-                biased; // Prioritize stop signal
-                _ = task_stop_rx.recv() => {
-                    // println!("[Ariana CLI Watcher] Forwarder task: Received stop signal. Breaking loop.");
+    loop {
+        tokio::select! {
+            biased;
+            _ = connection.shutdown_requested(), if !shutting_down => {
+                // Lines already queued are still sent
+                shutting_down = true;
+                output_rx.close();
+            }
+            output = output_rx.recv() => {
+                let Some((line, source)) = output else {
                     break;
-                }
-                output_opt = output_rx.recv() => {
-                    if let Some(output) = output_opt {
-                        // println!("[Ariana CLI Watcher] Forwarder task: Received from output_rx: {:?}", output);
-                        if internal_tx.send(output.clone()).await.is_err() { // Cloned for logging if send fails
-                            // println!("[Ariana CLI Watcher] Forwarder task: Failed to send to internal_tx (receiver dropped). Breaking loop.");
-                            break; // internal_rx dropped
-                        }
-                        // println!("[Ariana CLI Watcher] Forwarder task: Sent to internal_tx: {:?}", output);
-                    } else {
-                        // println!("[Ariana CLI Watcher] Forwarder task: output_rx channel closed. Breaking loop.");
-                        break; // output_rx closed
+                };
+                let output_payload = SubprocessOutput {
+                    line,
+                    timestamp: timestamp_ms(),
+                    source,
+                };
+                if let Ok(json) = serde_json::to_string(&output_payload) {
+                    pending.push_back(json);
+                    if pending.len() > MAX_PENDING_LINES {
+                        pending.pop_front();
+                        progress.add_lines_lost(1);
                     }
+                    flush_pending(&mut socket, &mut pending, progress, upload_budget).await;
+                }
+            }
+        }
+    }
+
+    // Last chance for lines still pending after a connection loss
+    socket.retry_now();
+    flush_pending(&mut socket, &mut pending, progress, upload_budget).await;
+    progress.add_lines_lost(pending.len());
+    socket.close().await;
+    Ok(())
+}
+
+/// Spools the lines received on `output_rx` to `spool_path` as fast as they come, while a
+/// reader sends them from the file at the pace of the connection. The command never waits on
+/// the network and memory stays bounded however far behind the server falls, at the cost of
+/// disk space. While the connection is down, lines wait in the file rather than being dropped.
+async fn watch_through_spool(
+    mut output_rx: mpsc::Receiver<(String, OutputSource)>,
+    mut socket: ReconnectingWebSocket,
+    mut connection: Connection,
+    spool_path: PathBuf,
+    progress: &RunProgress,
+    upload_budget: &UploadBudget,
+) -> Result<()> {
+    let mut spool = BufWriter::new(File::create(&spool_path).await?);
+    let mut reader = BufReader::new(File::open(&spool_path).await?);
+    let spooled = Arc::new(Notify::new());
+
+    let writer_spooled = spooled.clone();
+    let writer = tokio::spawn(async move {
+        let mut stopping = false;
+        loop {
+            let output = tokio::select! {
+                biased;
+                _ = connection.shutdown_requested(), if !stopping => {
+                    // Lines already queued are still spooled
+                    stopping = true;
+                    output_rx.close();
+                    continue;
                 }
+                output = output_rx.recv() => output,
+            };
+            let Some((line, source)) = output else {
+                break;
+            };
+            let mut json = serde_json::to_vec(&SubprocessOutput {
+                line,
+                timestamp: timestamp_ms(),
+                source,
+            })?;
+            json.push(b'\n');
+            spool.write_all(&json).await?;
+            if output_rx.is_empty() {
+                spool.flush().await?;
             }
+            writer_spooled.notify_one();
         }
-        // println!("[Ariana CLI Watcher] Forwarder task: Exited loop.");
+        spool.flush().await?;
+        writer_spooled.notify_one();
+        Ok::<_, CliError>(())
     });
 
-    let mut shutting_down = false;
+    let mut pending = VecDeque::new();
+    // Line being read, the writer may not have flushed all of it yet
+    let mut line = String::new();
+    loop {
+        let writer_done = writer.is_finished();
+        let mut caught_up = false;
+        while pending.len() < MAX_PENDING_LINES {
+            if reader.read_line(&mut line).await? == 0 || !line.ends_with('\n') {
+                caught_up = true;
+                break;
+            }
+            pending.push_back(line.trim_end_matches('\n').to_owned());
+            line.clear();
+        }
+        if writer_done {
+            // Last chance for lines still pending after a connection loss
+            socket.retry_now();
+        }
+        flush_pending(&mut socket, &mut pending, progress, upload_budget).await;
 
-    'main_loop: loop {
-        tokio::select! {
-            biased;
-            _ = stop_rx.recv(), if !shutting_down => {
-                // println!("[Ariana CLI Watcher] Main loop: Received global stop signal.");
-                shutting_down = true;
-                let _ = task_stop_tx.send(()).await; // Signal forwarder task to stop
-                // println!("[Ariana CLI Watcher] Main loop: Signaled forwarder task to stop. Will continue to drain internal_rx.");
-                // Continue to drain internal_rx
-            },
-            internal_output_opt = internal_rx.recv() => {
-                if let Some((line, source)) = internal_output_opt {
-                    // println!("[Ariana CLI Watcher] Main loop: Received from internal_rx: line='{}', source={:?}", line, source);
-                    let output_payload = SubprocessOutput {
-                        line: line.clone(), // Clone for potential retry
-                        timestamp: SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap_or_else(|_| SystemTime::UNIX_EPOCH.duration_since(UNIX_EPOCH).unwrap())
-                            .as_millis() as u64,
-                        source,
-                    };
-
-                    if let Ok(json) = serde_json::to_string(&output_payload) {
-                        // println!("[Ariana CLI Watcher] Main loop: Sending JSON to WebSocket: {}", json);
-                        if ws_stream.send(Message::Text(json.clone().into())).await.is_err() {
-                            // eprintln!("[Ariana CLI Watcher] Main loop: Error sending subprocess output, attempting reconnect...");
-                            match connect_async(&url).await {
-                                Ok((new_stream, _)) => {
-                                    ws_stream = new_stream;
-                                    // println!("[Ariana CLI Watcher] Main loop: Reconnected to subprocess stdout stream");
-                                    if ws_stream.send(Message::Text(json.into())).await.is_err() {
-                                        // eprintln!("[Ariana CLI Watcher] Main loop: Error resending after reconnect. Message lost: {:?}", output_payload);
-                                    }
-                                }
-                                Err(_e_connect) => {
-                                    // eprintln!("[Ariana CLI Watcher] Main loop: Failed to reconnect: {}. Exiting watcher.", e_connect);
-                                    break 'main_loop; // Cannot send, so exit
-                                }
-                            }
-                        }
-                    } else {
-                        // eprintln!("[Ariana CLI Watcher] Main loop: Failed to serialize SubprocessOutput to JSON: line='{}', source={:?}", output_payload.line, output_payload.source);
-                    }
-                } else {
-                    // internal_rx is closed. This means internal_tx (from forwarder task) was dropped.
-                    // This happens when the forwarder task finishes (either subprocess ended or was stopped).
-                    // println!("[Ariana CLI Watcher] Main loop: internal_rx channel closed. All messages processed or forwarder stopped. Breaking loop.");
-                    break 'main_loop;
+        if !pending.is_empty() {
+            // The connection is down
+            if writer_done {
+                progress.add_lines_lost(pending.len());
+                while reader.read_line(&mut line).await? > 0 {
+                    progress.add_lines_lost(1);
+                    line.clear();
                 }
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        } else if caught_up {
+            if writer_done {
+                break;
             }
+            spooled.notified().await;
         }
     }
 
-    // println!("[Ariana CLI Watcher] Main loop: Draining complete or loop exited. Closing WebSocket.");
-    if let Err(_e) = ws_stream.close(None).await {
-        // eprintln!("[Ariana CLI Watcher] Error closing WebSocket connection: {}", e);
-    }
-    // println!("[Ariana CLI Watcher] Subprocess stdout watcher finished.");
+    socket.close().await;
+    let _ = tokio::fs::remove_file(&spool_path).await;
+    writer.await.map_err(|e| CliError::Io(e.into()))??;
     Ok(())
 }
+
+fn timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| SystemTime::UNIX_EPOCH.duration_since(UNIX_EPOCH).unwrap())
+        .as_millis() as u64
+}
+
+/// Sends the pending lines in order. Lines that can't be sent stay pending, to be retried on
+/// the next call, while those going over the upload budget are dropped, and all of them once
+/// the socket gave up connecting.
+async fn flush_pending(
+    socket: &mut ReconnectingWebSocket,
+    pending: &mut VecDeque<String>,
+    progress: &RunProgress,
+    upload_budget: &UploadBudget,
+) {
+    while let Some(json) = pending.front() {
+        if socket.gave_up() {
+            // Not worth keeping, they would never be sent
+            progress.add_lines_lost(pending.len());
+            pending.clear();
+            return;
+        }
+        if !upload_budget.try_spend(json.len()) {
+            pending.pop_front();
+            progress.add_lines_lost(1);
+            continue;
+        }
+        if !socket.send(json).await {
+            // Not sent, it is counted again when retried
+            upload_budget.refund(json.len());
+            if socket.gave_up() {
+                progress.suspend(|| {
+                    eprintln!("[Ariana] Could not connect to the server to stream the output of your command, giving up: it is only printed here from now on. Traces are still sent");
+                });
+                progress.add_lines_lost(pending.len());
+                pending.clear();
+            }
+            return;
+        }
+        pending.pop_front();
+        progress.add_lines_streamed(1);
+    }
+}
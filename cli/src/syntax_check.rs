@@ -0,0 +1,88 @@
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+
+use tokio::process::Command;
+
+/// Outcome of checking that a file parses, see `check_syntax`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyntaxCheck {
+    Valid,
+    /// The checker rejected the file, with its error message.
+    Invalid(String),
+    /// The file wasn't checked: no checker for its language, or the checker isn't installed.
+    Unchecked,
+}
+
+/// Checks that `content`, the content of the file at `path`, parses. JavaScript is checked
+/// with `node --check` and Python with `python3` (`python` on Windows) compiling it without
+/// running it. Other files, including TypeScript and JSX that node can't parse, are not
+/// checked.
+pub async fn check_syntax(path: &Path, content: &str) -> SyntaxCheck {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_lowercase)
+        .unwrap_or_default();
+    static NODE_MISSING: Once = Once::new();
+    static PYTHON_MISSING: Once = Once::new();
+    let (program, args, missing_warning): (&str, &[&str], &Once) = match extension.as_str() {
+        "js" | "mjs" | "cjs" => ("node", &["--check"], &NODE_MISSING),
+        "py" if cfg!(windows) => ("python", PYTHON_CHECK_ARGS, &PYTHON_MISSING),
+        "py" => ("python3", PYTHON_CHECK_ARGS, &PYTHON_MISSING),
+        _ => return SyntaxCheck::Unchecked,
+    };
+
+    // Checked under the same file name, node tells ES modules from CommonJS by the extension
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "ariana-syntax-check-{}-{}",
+        std::process::id(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = dir.join(path.file_name().unwrap_or_default());
+    if tokio::fs::create_dir_all(&dir).await.is_err()
+        || tokio::fs::write(&file, content).await.is_err()
+    {
+        return SyntaxCheck::Unchecked;
+    }
+    let output = Command::new(program)
+        .args(args)
+        .arg(&file)
+        .stdin(Stdio::null())
+        .output()
+        .await;
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+
+    match output {
+        Ok(output) if output.status.success() => SyntaxCheck::Valid,
+        Ok(output) => SyntaxCheck::Invalid(first_error_line(&output.stderr)),
+        Err(e) => {
+            missing_warning.call_once(|| {
+                eprintln!(
+                    "[Ariana] WARNING: could not run `{}` to verify the syntax of instrumented files, they are not verified: {}",
+                    program, e
+                )
+            });
+            SyntaxCheck::Unchecked
+        }
+    }
+}
+
+const PYTHON_CHECK_ARGS: &[&str] = &[
+    "-c",
+    "import sys; compile(open(sys.argv[1], encoding='utf-8').read(), sys.argv[1], 'exec')",
+];
+
+/// The line of a checker's output that describes the error, e.g. `SyntaxError: ...`.
+fn first_error_line(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    stderr
+        .lines()
+        .find(|line| line.contains("Error"))
+        .or_else(|| stderr.lines().find(|line| !line.trim().is_empty()))
+        .unwrap_or("invalid syntax")
+        .trim()
+        .to_string()
+}
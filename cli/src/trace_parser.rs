@@ -0,0 +1,443 @@
+use ariana_server::traces::{Trace, TraceType};
+use serde::de::IgnoredAny;
+use serde_json::Value;
+
+/// Name of the tag traces are printed in, unless configured otherwise.
+pub const DEFAULT_TRACE_MARKER: &str = "trace";
+
+/// Version of the trace schema this CLI decodes, announced to the server when instrumenting.
+/// Traces without a `schema_version` field are version 1.
+pub const TRACE_SCHEMA_VERSION: u64 = 1;
+
+/// Fields of the trace schema this CLI knows about, any other one is ignored.
+const TRACE_FIELDS: [&str; 6] = [
+    "trace_id",
+    "start_pos",
+    "end_pos",
+    "parent_id",
+    "timestamp",
+    "trace_type",
+];
+
+/// The `<name id="...">` and `</name>` delimiters of the tags traces are printed in. A custom
+/// name keeps output that happens to contain `<trace id="` from being mistaken for traces.
+#[derive(Debug, Clone)]
+pub struct TraceMarker {
+    open: String,
+    close: String,
+}
+
+impl TraceMarker {
+    pub fn new(name: &str) -> Self {
+        Self {
+            open: format!("<{} id=\"", name),
+            close: format!("</{}>", name),
+        }
+    }
+}
+
+impl Default for TraceMarker {
+    fn default() -> Self {
+        Self::new(DEFAULT_TRACE_MARKER)
+    }
+}
+
+/// Whether `name` can be used as a trace marker: a non empty tag name made of ASCII letters,
+/// digits, `-` and `_`.
+pub fn is_valid_trace_marker(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// A line of subprocess output split between the traces it embeds and the remaining text.
+#[derive(Debug, Default)]
+pub struct ParsedLine {
+    /// The line with every well-formed `<trace>` tag removed.
+    pub text: String,
+    pub traces: Vec<Trace>,
+    /// Tags that were terminated but couldn't be decoded, they are dropped from `text`.
+    pub errors: Vec<String>,
+    /// Highest schema version among `traces`.
+    pub schema_version: Option<u64>,
+}
+
+enum TagError {
+    /// No end to the tag on this line, it is kept as plain text.
+    Unterminated,
+    /// The tag ends `consumed` bytes after its opening but doesn't hold a valid trace.
+    Invalid { message: String, consumed: usize },
+    /// The tag is broken and another one starts `consumed` bytes after its opening, before
+    /// any end. What comes before that one is kept as plain text.
+    Broken { consumed: usize },
+}
+
+/// Extracts the `<trace id="...">{json}</trace>` tags from a line of output, `trace` being
+/// the name of the `marker`.
+///
+/// The JSON payload is delimited by actually parsing it, so `</trace>` or quotes showing up
+/// inside its strings don't cut it short.
+pub fn parse_trace_tags(line: &str, marker: &TraceMarker) -> ParsedLine {
+    let mut parsed = ParsedLine::default();
+    let mut rest = line;
+
+    while let Some(start) = rest.find(&marker.open) {
+        parsed.text.push_str(&rest[..start]);
+        let tag_body = &rest[start + marker.open.len()..];
+        match parse_tag_body(tag_body, marker) {
+            Ok((trace, schema_version, consumed)) => {
+                parsed.traces.push(trace);
+                parsed.schema_version = parsed.schema_version.max(Some(schema_version));
+                rest = &tag_body[consumed..];
+            }
+            Err(TagError::Invalid { message, consumed }) => {
+                parsed.errors.push(message);
+                rest = &tag_body[consumed..];
+            }
+            Err(TagError::Broken { consumed }) => {
+                parsed
+                    .text
+                    .push_str(&rest[start..start + marker.open.len() + consumed]);
+                rest = &tag_body[consumed..];
+            }
+            Err(TagError::Unterminated) => {
+                parsed.text.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    parsed.text.push_str(rest);
+
+    parsed
+}
+
+/// Parses what follows `<trace id="`, returning the trace, its schema version and how many
+/// bytes the rest of the tag spans, `</trace>` included.
+fn parse_tag_body(tag_body: &str, marker: &TraceMarker) -> Result<(Trace, u64, usize), TagError> {
+    let id_end = tag_body.find('"').ok_or(TagError::Unterminated)?;
+    // The quote found may be the one of the next tag if this one has none
+    if tag_body[..id_end].contains('<') {
+        return Err(resync(tag_body, 0, "malformed trace tag", marker));
+    }
+    let content_start = id_end + 1;
+    if !tag_body[content_start..].starts_with('>') {
+        return Err(resync(
+            tag_body,
+            content_start,
+            "malformed trace tag",
+            marker,
+        ));
+    }
+    let content_start = content_start + 1;
+    let content = &tag_body[content_start..];
+
+    // Find where the JSON value ends, whatever its shape
+    let mut stream = serde_json::Deserializer::from_str(content).into_iter::<IgnoredAny>();
+    match stream.next() {
+        Some(Ok(_)) => {}
+        Some(Err(e)) if !e.is_eof() => {
+            return Err(resync(
+                tag_body,
+                content_start,
+                &format!("invalid trace JSON: {}", e),
+                marker,
+            ))
+        }
+        _ => return Err(TagError::Unterminated),
+    }
+    let json_end = stream.byte_offset();
+    let json = &content[..json_end];
+
+    let after_json = &content[json_end..];
+    let close_offset = after_json.len() - after_json.trim_start().len();
+    if !after_json[close_offset..].starts_with(&marker.close) {
+        return Err(resync(
+            tag_body,
+            content_start + json_end,
+            &format!("expected {} after the trace JSON", marker.close),
+            marker,
+        ));
+    }
+    let consumed = content_start + json_end + close_offset + marker.close.len();
+
+    match decode_trace(json) {
+        Ok((trace, schema_version)) => Ok((trace, schema_version, consumed)),
+        Err(e) => Err(TagError::Invalid {
+            message: format!("{}, content: '{}'", e, json),
+            consumed,
+        }),
+    }
+}
+
+/// Decodes a trace payload, tolerating small schema changes: unknown fields are ignored and a
+/// missing `parent_id` is taken as a root trace.
+fn decode_trace(json: &str) -> Result<(Trace, u64), serde_json::Error> {
+    let mut value: Value = serde_json::from_str(json)?;
+    let mut schema_version = 1;
+    if let Some(object) = value.as_object_mut() {
+        schema_version = object
+            .get("schema_version")
+            .and_then(Value::as_u64)
+            .unwrap_or(1);
+        object.retain(|key, _| TRACE_FIELDS.contains(&key.as_str()));
+        object
+            .entry("parent_id")
+            .or_insert_with(|| Value::String(String::new()));
+    }
+    let trace = serde_json::from_value(value)?;
+    Ok((trace, schema_version))
+}
+
+/// Error for a broken tag, resuming parsing after the next `</trace>` found from `from`, or
+/// at the next `<trace id="` if it comes first so that a valid tag following is still parsed.
+fn resync(tag_body: &str, from: usize, message: &str, marker: &TraceMarker) -> TagError {
+    let close = tag_body[from..].find(&marker.close).map(|i| from + i);
+    let next_open = tag_body[from..].find(&marker.open).map(|i| from + i);
+    match (close, next_open) {
+        (Some(close), next_open) if next_open.is_none_or(|next_open| close < next_open) => {
+            TagError::Invalid {
+                message: format!("{}, content: '{}'", message, &tag_body[..close]),
+                consumed: close + marker.close.len(),
+            }
+        }
+        (_, Some(next_open)) => TagError::Broken {
+            consumed: next_open,
+        },
+        _ => TagError::Unterminated,
+    }
+}
+
+/// One line, human readable summary of a trace, e.g. `exit src/app.ts:12:4 (1.25ms) -> 42`.
+pub fn summarize_trace(trace: &Trace) -> String {
+    let position = format!(
+        "{}:{}:{}",
+        trace.start_pos.filepath, trace.start_pos.line, trace.start_pos.column
+    );
+    match &trace.trace_type {
+        TraceType::Enter => format!("enter {}", position),
+        TraceType::Exit {
+            duration_ns,
+            return_value,
+        } => format!(
+            "exit {} ({}) -> {}",
+            position,
+            format_duration(*duration_ns),
+            preview(return_value.as_deref().unwrap_or("undefined"))
+        ),
+        TraceType::Error {
+            duration_ns,
+            error_message,
+        } => format!(
+            "error {} ({}) !! {}",
+            position,
+            format_duration(*duration_ns),
+            preview(error_message)
+        ),
+        TraceType::Legacy => format!("trace {}", position),
+    }
+}
+
+fn format_duration(duration_ns: u128) -> String {
+    format!("{:.2}ms", duration_ns as f64 / 1_000_000.0)
+}
+
+/// First line of `value`, cut to a readable length.
+fn preview(value: &str) -> String {
+    const MAX_CHARS: usize = 80;
+    let first_line = value.lines().next().unwrap_or_default();
+    let mut preview: String = first_line.chars().take(MAX_CHARS).collect();
+    if preview.len() < value.len() {
+        preview.push_str("...");
+    }
+    preview
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ariana_server::traces::Position;
+
+    fn trace(id: &str, return_value: &str) -> Trace {
+        let position = Position {
+            filepath: "src/app.js".to_string(),
+            line: 1,
+            column: 1,
+        };
+        Trace {
+            trace_id: id.to_string(),
+            start_pos: position.clone(),
+            end_pos: position,
+            parent_id: String::new(),
+            timestamp: 0,
+            trace_type: TraceType::Exit {
+                duration_ns: 0,
+                return_value: Some(return_value.to_string()),
+            },
+        }
+    }
+
+    fn tag(id: &str, return_value: &str) -> String {
+        format!(
+            "<trace id=\"{}\">{}</trace>",
+            id,
+            serde_json::to_string(&trace(id, return_value)).unwrap()
+        )
+    }
+
+    fn parse(line: &str) -> ParsedLine {
+        parse_trace_tags(line, &TraceMarker::default())
+    }
+
+    fn ids(parsed: &ParsedLine) -> Vec<&str> {
+        parsed.traces.iter().map(|t| t.trace_id.as_str()).collect()
+    }
+
+    fn return_value(trace: &Trace) -> Option<&str> {
+        match &trace.trace_type {
+            TraceType::Exit { return_value, .. } => return_value.as_deref(),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn quotes_inside_the_json_are_kept() {
+        let parsed = parse(&tag("t1", r#"say "hi" and \"bye\""#));
+        assert_eq!(ids(&parsed), ["t1"]);
+        assert_eq!(
+            return_value(&parsed.traces[0]),
+            Some(r#"say "hi" and \"bye\""#)
+        );
+        assert_eq!(parsed.text, "");
+    }
+
+    #[test]
+    fn closing_tags_inside_the_json_dont_end_the_trace() {
+        let line = format!("before {} after", tag("t1", "</trace><trace id=\"t2\">"));
+        let parsed = parse(&line);
+        assert_eq!(ids(&parsed), ["t1"]);
+        assert_eq!(
+            return_value(&parsed.traces[0]),
+            Some("</trace><trace id=\"t2\">")
+        );
+        assert_eq!(parsed.text, "before  after");
+        assert!(parsed.errors.is_empty());
+    }
+
+    #[test]
+    fn several_tags_on_a_line_keep_the_text_between_them() {
+        let line = format!(
+            "a{}b {} c{}",
+            tag("t1", "1"),
+            tag("t2", "2"),
+            tag("t3", "3")
+        );
+        let parsed = parse(&line);
+        assert_eq!(ids(&parsed), ["t1", "t2", "t3"]);
+        assert_eq!(parsed.text, "ab  c");
+    }
+
+    #[test]
+    fn invalid_json_is_dropped_up_to_its_closing_tag() {
+        let line = format!("a<trace id=\"t1\">{{oops</trace>b{}c", tag("t2", "2"));
+        let parsed = parse(&line);
+        assert_eq!(ids(&parsed), ["t2"]);
+        assert_eq!(parsed.errors.len(), 1);
+        assert_eq!(parsed.text, "abc");
+    }
+
+    #[test]
+    fn broken_tags_dont_swallow_the_next_one() {
+        for broken in [
+            "<trace id=\"t1\">{oops ",
+            "<trace id=\"t1\" oops ",
+            "<trace id=t1> oops ",
+        ] {
+            let line = format!("{}user text {}end", broken, tag("t2", "2"));
+            let parsed = parse(&line);
+            assert_eq!(ids(&parsed), ["t2"], "{}", broken);
+            assert_eq!(parsed.text, format!("{}user text end", broken));
+        }
+    }
+
+    #[test]
+    fn unterminated_tags_are_kept_as_text() {
+        let line = "<trace id=\"t1\">{\"trace_id\": \"t1\"";
+        let parsed = parse(line);
+        assert!(parsed.traces.is_empty());
+        assert!(parsed.errors.is_empty());
+        assert_eq!(parsed.text, line);
+    }
+
+    /// `tag` with its JSON changed by `edit`.
+    fn edited_tag(id: &str, edit: impl FnOnce(&mut serde_json::Map<String, Value>)) -> String {
+        let mut json = serde_json::to_value(trace(id, "1")).unwrap();
+        edit(json.as_object_mut().unwrap());
+        format!("<trace id=\"{}\">{}</trace>", id, json)
+    }
+
+    #[test]
+    fn unknown_trace_fields_are_ignored() {
+        let parsed = parse(&edited_tag("t1", |json| {
+            json.insert("schema_version".to_string(), Value::from(2));
+            json.insert("thread_id".to_string(), Value::from("main"));
+        }));
+        assert_eq!(ids(&parsed), ["t1"]);
+        assert_eq!(return_value(&parsed.traces[0]), Some("1"));
+        assert_eq!(parsed.schema_version, Some(2));
+    }
+
+    #[test]
+    fn traces_without_a_schema_version_are_version_1() {
+        let parsed = parse(&tag("t1", "1"));
+        assert_eq!(parsed.schema_version, Some(1));
+    }
+
+    #[test]
+    fn a_missing_parent_id_makes_a_root_trace() {
+        let parsed = parse(&edited_tag("t1", |json| {
+            json.remove("parent_id");
+        }));
+        assert_eq!(ids(&parsed), ["t1"]);
+        assert_eq!(parsed.traces[0].parent_id, "");
+    }
+
+    #[test]
+    fn traces_missing_a_required_field_are_reported() {
+        let parsed = parse(&format!(
+            "a{}b",
+            edited_tag("t1", |json| {
+                json.remove("start_pos");
+            })
+        ));
+        assert!(parsed.traces.is_empty());
+        assert_eq!(parsed.errors.len(), 1);
+        assert_eq!(parsed.text, "ab");
+    }
+
+    #[test]
+    fn literal_trace_tags_in_user_output_are_kept() {
+        for line in [
+            "user prints <trace> and </trace> literally",
+            "<trace id=\"user\"> without an end",
+            "<trace>{\"trace_id\": \"t1\"}</trace>",
+        ] {
+            let parsed = parse(line);
+            assert!(parsed.traces.is_empty(), "{}", line);
+            assert!(parsed.errors.is_empty(), "{}", line);
+            assert_eq!(parsed.text, line);
+        }
+    }
+
+    #[test]
+    fn custom_markers_leave_default_tags_to_the_user() {
+        let marker = TraceMarker::new("ariana-1234");
+        let custom_tag = tag("t2", "2")
+            .replace("<trace ", "<ariana-1234 ")
+            .replace("</trace>", "</ariana-1234>");
+        let line = format!("{} {}", tag("t1", "1"), custom_tag);
+        let parsed = parse_trace_tags(&line, &marker);
+        assert_eq!(ids(&parsed), ["t2"]);
+        assert_eq!(parsed.text, format!("{} ", tag("t1", "1")));
+    }
+}
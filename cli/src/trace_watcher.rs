@@ -1,78 +1,692 @@
-use std::time::Duration;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
-use ariana_server::{traces::Trace, web::traces::PushTracesRequest};
-use tokio::{sync::mpsc, time::interval};
+use ariana_server::{
+    traces::{Trace, TraceType},
+    web::traces::PushTracesRequest,
+};
+use flate2::{write::GzEncoder, Compression};
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::StatusCode;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::interval,
+};
 
+use crate::error::{CliError, Result};
+use crate::progress::RunProgress;
+use crate::transport::{http_client, retry_delay, Connection};
+use crate::upload_budget::UploadBudget;
+use crate::utils::{api_endpoint, split_by_size};
+
+/// Tunables for `watch_traces`, mostly coming from the CLI flags.
+#[derive(Debug, Clone)]
+pub struct TraceWatcherOptions {
+    /// Gzip the body of trace pushes.
+    pub compress: bool,
+    pub verbose: bool,
+    /// Fraction of traces pushed, between 0 and 1.
+    pub sample_rate: f64,
+    /// Pushes a trace seen again within the last `DEDUP_WINDOW` traces only once.
+    pub dedup_traces: bool,
+    /// File traces that can't be pushed are appended to, one JSON trace per line, instead of
+    /// being dropped. See `flush_pending_traces`.
+    pub pending_traces_path: Option<PathBuf>,
+    /// File every trace pushed is also appended to, one JSON trace per line, whether the push
+    /// succeeds or not. See `--save-traces`.
+    pub save_traces_path: Option<PathBuf>,
+    /// Shared with the rest of the run, traces past it are dropped.
+    pub upload_budget: Arc<UploadBudget>,
+    /// Pushes whose traces serialize to more than this, before compression, are split into
+    /// several requests.
+    pub max_push_bytes: u64,
+    /// Token of the logged in account, pushes are only tied to the vault without it.
+    pub auth_token: Option<String>,
+}
+
+/// Name of the file in `.ariana` holding the traces a run couldn't push.
+pub const PENDING_TRACES_FILE: &str = "pending_traces.jsonl";
+
+/// Directory in `.ariana` the traces earlier runs couldn't push are set aside in when a new
+/// vault is used, one `{vault_key}.jsonl` file per vault.
+pub const PENDING_TRACES_DIR: &str = "pending_traces";
+
+/// Name of the file in `PENDING_TRACES_DIR` holding traces whose vault key was lost.
+pub const UNKNOWN_VAULT_PENDING_TRACES: &str = "unknown-vault.jsonl";
+
+/// Traces pushed per request, both while watching and when flushing pending traces. Requests
+/// are split further past `TraceWatcherOptions::max_push_bytes`.
+const BATCH_SIZE: usize = 50_000;
+
+/// Default of `TraceWatcherOptions::max_push_bytes`.
+pub const DEFAULT_MAX_PUSH_BYTES: u64 = 8 * 1024 * 1024;
+
+const MAX_PUSH_ATTEMPTS: u32 = 3;
+
+/// How many of the latest traces `--dedup-traces` remembers, bounding its memory on long runs.
+const DEDUP_WINDOW: usize = 100_000;
+
+/// Buffer of the `save_traces_path` file, only written to disk once full so that saving traces
+/// rarely waits on the disk before a push.
+const SAVED_TRACES_BUFFER_BYTES: usize = 1024 * 1024;
+
+/// The `save_traces_path` file traces are appended to before being pushed, flushed when
+/// dropped even if a push failed. Once writing to it fails, traces are only pushed for the
+/// rest of the run.
+struct SavedTraces {
+    path: PathBuf,
+    writer: Option<BufWriter<fs::File>>,
+}
+
+impl SavedTraces {
+    fn open(path: &Path) -> Result<Self> {
+        let error = |source| CliError::Path {
+            path: path.to_path_buf(),
+            source,
+        };
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent).map_err(error)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(error)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer: Some(BufWriter::with_capacity(SAVED_TRACES_BUFFER_BYTES, file)),
+        })
+    }
+
+    fn append(&mut self, traces: &[Trace]) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        let written = traces.iter().try_for_each(|trace| {
+            serde_json::to_writer(&mut *writer, trace)?;
+            writer.write_all(b"\n")
+        });
+        if let Err(e) = written {
+            self.give_up(e);
+        }
+    }
+
+    fn flush(&mut self) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        if let Err(e) = writer.flush() {
+            self.give_up(e);
+        }
+    }
+
+    fn give_up(&mut self, error: std::io::Error) {
+        eprintln!(
+            "[Ariana] Could not save traces to {}, they are only pushed from now on: {}",
+            self.path.display(),
+            error
+        );
+        self.writer = None;
+    }
+}
+
+/// Whether `trace` is kept when sampling at `sample_rate`. The decision only depends on the
+/// trace id, so all the traces of a same call (enter, exit or error) are kept or dropped together.
+fn is_sampled(trace: &Trace, sample_rate: f64) -> bool {
+    if sample_rate >= 1.0 {
+        return true;
+    }
+    // FNV-1a, stable across runs and platforms unlike the std hasher
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in trace.trace_id.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    (hash as f64 / u64::MAX as f64) < sample_rate
+}
+
+/// The latest traces received, to recognize those the runtime emits twice. The enter and exit
+/// traces of a call share their id, so the kind of trace is part of the key.
+struct RecentTraces {
+    seen: HashSet<(String, &'static str)>,
+    order: VecDeque<(String, &'static str)>,
+    capacity: usize,
+    duplicates: usize,
+}
+
+impl RecentTraces {
+    fn new(capacity: usize) -> Self {
+        Self {
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+            duplicates: 0,
+        }
+    }
+
+    /// Records `trace`, returning false if it was already among the latest traces.
+    fn insert(&mut self, trace: &Trace) -> bool {
+        let kind = match trace.trace_type {
+            TraceType::Enter => "enter",
+            TraceType::Exit { .. } => "exit",
+            TraceType::Error { .. } => "error",
+            TraceType::Legacy => "legacy",
+        };
+        let key = (trace.trace_id.clone(), kind);
+        if self.seen.contains(&key) {
+            self.duplicates += 1;
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(key.clone());
+        self.order.push_back(key);
+        true
+    }
+}
+
+/// Whether `trace` is to be pushed, according to sampling and deduplication.
+fn should_push(
+    trace: &Trace,
+    options: &TraceWatcherOptions,
+    recent: &mut Option<RecentTraces>,
+) -> bool {
+    if !is_sampled(trace, options.sample_rate) {
+        return false;
+    }
+    recent.as_mut().is_none_or(|recent| recent.insert(trace))
+}
+
+/// Pushes the traces received on `trace_rx` to the vault in batches, until `connection` is
+/// shut down; traces already queued are still pushed then. Each request received on
+/// `flush_rx` pushes the traces received so far right away, then gets acknowledged. Pushed
+/// traces are also appended to `options.save_traces_path` if set, flushed to disk along with
+/// each flush request and on shutdown.
 pub async fn watch_traces(
-    trace_rx: &mut mpsc::Receiver<Trace>,
-    api_url: &str,
-    vault_key: &str,
-    stop_rx: &mut mpsc::Receiver<()>,
+    mut trace_rx: mpsc::Receiver<Trace>,
+    mut connection: Connection,
+    mut flush_rx: mpsc::Receiver<oneshot::Sender<()>>,
+    options: &TraceWatcherOptions,
+    progress: &RunProgress,
 ) -> Result<()> {
+    let api_url = connection.api_url().to_string();
+    let api_url = api_url.as_str();
+    let vault_key = connection.vault_key().to_string();
+    let vault_key = vault_key.as_str();
     let mut traces = Vec::new();
-    let batch_size = 50_000;
+    let batch_size = BATCH_SIZE;
     let mut clear_start = std::time::Instant::now();
     let mut interval = interval(Duration::from_secs(3));
+    let mut recent = options
+        .dedup_traces
+        .then(|| RecentTraces::new(DEDUP_WINDOW));
+    let mut saved = match &options.save_traces_path {
+        Some(path) => Some(SavedTraces::open(path)?),
+        None => None,
+    };
 
     loop {
         tokio::select! {
             _ = interval.tick() => {
                 if !traces.is_empty() {
-                    process_traces(&traces, api_url, vault_key).await?;
+                    save_traces(&mut saved, &traces);
+                    push_traces(&traces, api_url, vault_key, options, progress).await?;
                     traces.clear();
                     clear_start = std::time::Instant::now();
                 }
             }
             trace = trace_rx.recv() => {
                 if let Some(trace) = trace {
+                    if !should_push(&trace, options, &mut recent) {
+                        continue;
+                    }
                     traces.push(trace);
 
                     if traces.len() >= batch_size || clear_start.elapsed() > Duration::from_secs(3) {
-                        process_traces(&traces, api_url, vault_key).await?;
+                        save_traces(&mut saved, &traces);
+                        push_traces(&traces, api_url, vault_key, options, progress).await?;
                         traces.clear();
                         clear_start = std::time::Instant::now();
                     }
                 }
             }
-            _ = stop_rx.recv() => {
-                if !traces.is_empty() {
-                    let mut chunks = Vec::new();
-                    for i in 0..(traces.len() / batch_size) + 1 {
-                        let start = i * batch_size;
-                        let end = ((i + 1) * batch_size).min(traces.len());
-                        chunks.push(&traces[start..end]);
+            Some(ack) = flush_rx.recv() => {
+                // Traces sent before the flush request may still be queued
+                while let Ok(trace) = trace_rx.try_recv() {
+                    if should_push(&trace, options, &mut recent) {
+                        traces.push(trace);
                     }
-                    for chunk in chunks {
-                        process_traces(chunk, api_url, vault_key).await?;
+                }
+                if !traces.is_empty() {
+                    save_traces(&mut saved, &traces);
+                    push_traces(&traces, api_url, vault_key, options, progress).await?;
+                    traces.clear();
+                    clear_start = std::time::Instant::now();
+                }
+                if let Some(saved) = &mut saved {
+                    saved.flush();
+                }
+                let _ = ack.send(());
+            }
+            _ = connection.shutdown_requested() => {
+                // Traces already queued are still pushed
+                trace_rx.close();
+                while let Some(trace) = trace_rx.recv().await {
+                    if should_push(&trace, options, &mut recent) {
+                        traces.push(trace);
                     }
                 }
+                save_traces(&mut saved, &traces);
+                if let Some(saved) = &mut saved {
+                    saved.flush();
+                }
+                for chunk in traces.chunks(batch_size) {
+                    push_traces(chunk, api_url, vault_key, options, progress).await?;
+                }
                 break;
             }
         }
     }
 
+    if let Some(recent) = recent.filter(|recent| options.verbose && recent.duplicates > 0) {
+        println!("[Ariana] Skipped {} duplicate traces", recent.duplicates);
+    }
+
     Ok(())
 }
 
-async fn process_traces(traces: &[Trace], api_url: &str, vault_key: &str) -> Result<()> {
+fn save_traces(saved: &mut Option<SavedTraces>, traces: &[Trace]) {
+    if let Some(saved) = saved {
+        saved.append(traces);
+    }
+}
+
+/// Error pushing traces, with the explanation sent by the server if any.
+#[derive(Debug)]
+pub struct TracePushError {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl TracePushError {
+    /// Errors that won't go away by retrying, like an invalid vault key or an exhausted quota.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self.status,
+            StatusCode::UNAUTHORIZED
+                | StatusCode::PAYMENT_REQUIRED
+                | StatusCode::FORBIDDEN
+                | StatusCode::NOT_FOUND
+                | StatusCode::PAYLOAD_TOO_LARGE
+        )
+    }
+}
+
+impl fmt::Display for TracePushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to process trace: {}", self.status)?;
+        if !self.body.trim().is_empty() {
+            write!(f, ": {}", self.body.trim())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TracePushError {}
+
+/// Pushes `traces` in requests of at most `options.max_push_bytes`, see `push_request`.
+async fn push_traces(
+    traces: &[Trace],
+    api_url: &str,
+    vault_key: &str,
+    options: &TraceWatcherOptions,
+    progress: &RunProgress,
+) -> Result<()> {
+    for range in split_traces(traces, options) {
+        push_request(&traces[range], api_url, vault_key, options, progress).await?;
+    }
+    Ok(())
+}
+
+/// Splits `traces` into ranges small enough to be pushed in one request each.
+fn split_traces(traces: &[Trace], options: &TraceWatcherOptions) -> Vec<Range<usize>> {
+    // What the request adds around the traces, and a comma between each
+    let envelope =
+        serde_json::to_vec(&PushTracesRequest { traces: vec![] }).map_or(0, |json| json.len());
+    let ranges = split_by_size(
+        traces,
+        |trace| serde_json::to_vec(trace).map_or(0, |json| json.len() as u64 + 1),
+        options.max_push_bytes.saturating_sub(envelope as u64),
+    );
+    if options.verbose && ranges.len() > 1 {
+        println!(
+            "[Ariana] {} traces are bigger than {} bytes, pushing them in {} requests",
+            traces.len(),
+            options.max_push_bytes,
+            ranges.len()
+        );
+    }
+    ranges
+}
+
+/// Pushes `traces` in one request, retrying transient failures. Fatal errors are returned so
+/// that the watcher stops, while traces that still can't be pushed after retrying are saved to
+/// `options.pending_traces_path`, or dropped without one.
+async fn push_request(
+    traces: &[Trace],
+    api_url: &str,
+    vault_key: &str,
+    options: &TraceWatcherOptions,
+    progress: &RunProgress,
+) -> Result<()> {
+    let error = match push_traces_with_retry(traces, api_url, vault_key, options).await {
+        Ok(()) => {
+            progress.add_traces_pushed(traces.len());
+            return Ok(());
+        }
+        Err(error) => error,
+    };
+    match &error {
+        CliError::UploadBudgetExhausted => {
+            options.upload_budget.add_dropped_traces(traces.len());
+            return Ok(());
+        }
+        CliError::TracePush(push_error) if push_error.is_fatal() => return Err(error),
+        _ => {}
+    }
+    if let Some(pending_traces_path) = &options.pending_traces_path {
+        match append_pending_traces(pending_traces_path, traces) {
+            Ok(()) => {
+                eprintln!(
+                    "[Ariana] Could not push {} traces after {} attempts ({}), saved them to {}, push them later with --flush-pending",
+                    traces.len(),
+                    MAX_PUSH_ATTEMPTS,
+                    error,
+                    pending_traces_path.display()
+                );
+                return Ok(());
+            }
+            Err(e) => eprintln!(
+                "[Ariana] Could not save traces to {}: {}",
+                pending_traces_path.display(),
+                e
+            ),
+        }
+    }
+    eprintln!(
+        "[Ariana] Dropping {} traces after {} attempts: {}",
+        traces.len(),
+        MAX_PUSH_ATTEMPTS,
+        error
+    );
+    Ok(())
+}
+
+/// Pushes `traces`, retrying transient failures with an exponential backoff. Returns the last
+/// error if they still can't be pushed, or right away for fatal errors.
+async fn push_traces_with_retry(
+    traces: &[Trace],
+    api_url: &str,
+    vault_key: &str,
+    options: &TraceWatcherOptions,
+) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        let error = match process_traces(traces, api_url, vault_key, options).await {
+            Ok(()) => return Ok(()),
+            Err(error) => error,
+        };
+        match &error {
+            CliError::UploadBudgetExhausted => return Err(error),
+            CliError::TracePush(push_error) if push_error.is_fatal() => return Err(error),
+            _ => {}
+        }
+        if attempt >= MAX_PUSH_ATTEMPTS {
+            return Err(error);
+        }
+        if options.verbose {
+            println!("[Ariana] Retrying trace push after error: {}", error);
+        }
+        tokio::time::sleep(retry_delay(attempt)).await;
+        attempt += 1;
+    }
+}
+
+fn trace_lines(traces: &[Trace]) -> Result<Vec<u8>> {
+    let mut lines = Vec::new();
+    for trace in traces {
+        serde_json::to_writer(&mut lines, trace)?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+fn append_pending_traces(path: &Path, traces: &[Trace]) -> Result<()> {
+    let lines = trace_lines(traces)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(&lines)?;
+    Ok(())
+}
+
+/// Replaces the content of the pending traces file `path` with `traces` at once, so that the
+/// traces it holds are never lost to a failure midway.
+fn rewrite_pending_traces(path: &Path, traces: &[Trace]) -> Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    fs::write(&tmp_path, trace_lines(traces)?).map_err(|e| CliError::path(&tmp_path, e))?;
+    fs::rename(&tmp_path, path).map_err(|e| CliError::path(path, e))?;
+    Ok(())
+}
+
+/// Pushes the traces saved to `path` by a run that couldn't push them, in batches and with
+/// retries. The file is removed once everything is delivered; if a batch still fails, the
+/// traces not delivered yet are written back to it. Returns how many traces were delivered.
+pub async fn flush_pending_traces(
+    path: &Path,
+    api_url: &str,
+    vault_key: &str,
+    options: &TraceWatcherOptions,
+) -> Result<usize> {
+    let content = fs::read_to_string(path).map_err(|e| CliError::path(path, e))?;
+    let mut traces = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Trace>(line) {
+            Ok(trace) => traces.push(trace),
+            Err(e) => eprintln!(
+                "[Ariana] Skipping invalid trace on line {} of {}: {}",
+                i + 1,
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    let mut delivered = 0;
+    let chunks = traces
+        .chunks(BATCH_SIZE)
+        .flat_map(|chunk| {
+            split_traces(chunk, options)
+                .into_iter()
+                .map(move |range| &chunk[range])
+        })
+        .collect::<Vec<_>>();
+    for chunk in chunks {
+        if let Err(e) = push_traces_with_retry(chunk, api_url, vault_key, options).await {
+            rewrite_pending_traces(path, &traces[delivered..])?;
+            return Err(CliError::PendingTracesLeft {
+                source: Box::new(e),
+                delivered,
+                total: traces.len(),
+                path: path.to_path_buf(),
+            });
+        }
+        delivered += chunk.len();
+    }
+    fs::remove_file(path)?;
+    Ok(delivered)
+}
+
+async fn process_traces(
+    traces: &[Trace],
+    api_url: &str,
+    vault_key: &str,
+    options: &TraceWatcherOptions,
+) -> Result<()> {
     // Create a properly typed request
     let request = PushTracesRequest {
         traces: traces.to_vec(),
     };
+    let body = serde_json::to_vec(&request)?;
 
     // Send the trace to the server
-    let client = reqwest::Client::new();
-    let response = client
-        .post(&format!("{}/vaults/traces/{}/push", api_url, vault_key))
-        .header("Content-Type", "application/json")
-        .json(&request)
-        .send()
-        .await?;
+    let mut request_builder = http_client()
+        .post(api_endpoint(
+            api_url,
+            &format!("vaults/traces/{}/push", vault_key),
+        ))
+        .header(CONTENT_TYPE, "application/json");
+    if let Some(auth_token) = &options.auth_token {
+        request_builder = request_builder.bearer_auth(auth_token);
+    }
+    let body = if options.compress {
+        let compressed_body = gzip(&body)?;
+        if options.verbose {
+            println!(
+                "[Ariana] Pushing {} traces, {} bytes compressed to {} ({:.1}x)",
+                traces.len(),
+                body.len(),
+                compressed_body.len(),
+                body.len() as f64 / compressed_body.len().max(1) as f64
+            );
+        }
+        request_builder = request_builder.header(CONTENT_ENCODING, "gzip");
+        compressed_body
+    } else {
+        body
+    };
+    let body_len = body.len();
+    if !options.upload_budget.try_spend(body_len) {
+        return Err(CliError::UploadBudgetExhausted);
+    }
+    request_builder = request_builder.body(body);
+    let push_start = Instant::now();
+    let response = match request_builder.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            // Not sent, it is counted again when retried
+            options.upload_budget.refund(body_len);
+            return Err(e.into());
+        }
+    };
+    if options.verbose {
+        println!(
+            "[Ariana] Pushed {} traces in {} ms over {:?}",
+            traces.len(),
+            push_start.elapsed().as_millis(),
+            response.version()
+        );
+    }
 
     if !response.status().is_success() {
-        return Err(anyhow!("Failed to process trace: {}", response.status()));
+        options.upload_budget.refund(body_len);
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(TracePushError { status, body }.into());
     }
 
     Ok(())
 }
+
+fn gzip(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ariana_server::traces::Position;
+
+    fn trace(id: &str, trace_type: TraceType) -> Trace {
+        let position = Position {
+            filepath: "src/app.js".to_string(),
+            line: 1,
+            column: 1,
+        };
+        Trace {
+            trace_id: id.to_string(),
+            start_pos: position.clone(),
+            end_pos: position,
+            parent_id: String::new(),
+            timestamp: 0,
+            trace_type,
+        }
+    }
+
+    fn enter(id: &str) -> Trace {
+        trace(id, TraceType::Enter)
+    }
+
+    #[test]
+    fn duplicates_within_the_window_are_kept_once() {
+        let mut recent = RecentTraces::new(10);
+        let kept = ["t1", "t2", "t1", "t3", "t2", "t1"]
+            .into_iter()
+            .filter(|id| recent.insert(&enter(id)))
+            .collect::<Vec<_>>();
+        assert_eq!(kept, ["t1", "t2", "t3"]);
+        assert_eq!(recent.duplicates, 3);
+    }
+
+    #[test]
+    fn traces_out_of_the_window_are_forgotten() {
+        let mut recent = RecentTraces::new(2);
+        assert!(recent.insert(&enter("t1")));
+        assert!(recent.insert(&enter("t2")));
+        assert!(recent.insert(&enter("t3")));
+        // t1 was evicted by t3, t3 is still remembered
+        assert!(recent.insert(&enter("t1")));
+        assert!(!recent.insert(&enter("t1")));
+        assert_eq!(recent.seen.len(), 2);
+        assert_eq!(recent.order.len(), 2);
+    }
+
+    #[test]
+    fn enter_and_exit_traces_of_a_call_are_both_kept() {
+        let mut recent = RecentTraces::new(10);
+        let exit = trace(
+            "t1",
+            TraceType::Exit {
+                duration_ns: 1,
+                return_value: None,
+            },
+        );
+        assert!(recent.insert(&enter("t1")));
+        assert!(recent.insert(&exit));
+        assert!(!recent.insert(&exit));
+        assert_eq!(recent.duplicates, 1);
+    }
+}
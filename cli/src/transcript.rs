@@ -0,0 +1,40 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+
+use crate::error::{CliError, Result};
+use crate::subprocess_stdout_watcher::OutputSource;
+
+/// Writes every line received on `output_rx` to the transcript file at `path`, prefixed
+/// with a millisecond timestamp and the stream it came from. Runs until the channel closes.
+pub async fn write_transcript(
+    mut output_rx: mpsc::Receiver<(String, OutputSource)>,
+    path: &Path,
+) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let file = File::create(path)
+        .await
+        .map_err(|e| CliError::path(path, e))?;
+    let mut writer = BufWriter::new(file);
+
+    while let Some((line, source)) = output_rx.recv().await {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let source = match source {
+            OutputSource::Stdout => "stdout",
+            OutputSource::Stderr => "stderr",
+        };
+        writer
+            .write_all(format!("{} [{}] {}\n", timestamp, source, line).as_bytes())
+            .await?;
+    }
+
+    writer.flush().await?;
+    Ok(())
+}
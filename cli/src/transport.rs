@@ -0,0 +1,233 @@
+use futures_util::SinkExt;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+use crate::error::Result;
+use crate::events::{Event, EventLog};
+use crate::utils::websocket_endpoint;
+
+/// Longest wait between two attempts to reach the server, see `retry_delay`.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How long a WebSocket that never connected keeps trying before giving up, see
+/// `ReconnectingWebSocket::gave_up`.
+pub const INITIAL_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Wait before the `attempt`th retry of something that failed to reach the server, doubling
+/// from 500 ms each time. Trace pushes and WebSocket reconnections back off the same way.
+pub fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_millis(500)
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Client shared by the HTTP requests of the watchers, so that they reuse pooled connections,
+/// multiplexed over HTTP/2 when the server supports it, rather than each opening its own.
+pub fn http_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .pool_idle_timeout(Duration::from_secs(90))
+            .tcp_keepalive(Duration::from_secs(30))
+            .http2_keep_alive_interval(Duration::from_secs(30))
+            .http2_keep_alive_while_idle(true)
+            .http2_adaptive_window(true)
+            .build()
+            .unwrap_or_default()
+    })
+}
+
+/// What the watchers sending a run's traces and output to the vault share: where the vault is
+/// and when to stop. The watchers are spawned with `spawn`, and `shutdown`
+/// then stops them all the same way: they stop taking new data, send what they still hold,
+/// and `shutdown` returns once every one of them is done.
+pub struct Transport {
+    connection: Connection,
+    shutdown_tx: watch::Sender<bool>,
+    tasks: Vec<(&'static str, JoinHandle<()>)>,
+    events: EventLog,
+}
+
+impl Transport {
+    pub fn new(api_url: &str, vault_key: &str, events: EventLog) -> Self {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        Transport {
+            connection: Connection {
+                endpoint: Arc::new((api_url.to_string(), vault_key.to_string())),
+                shutdown_rx,
+                initial_connect_timeout: INITIAL_CONNECT_TIMEOUT,
+            },
+            shutdown_tx,
+            tasks: Vec::new(),
+            events,
+        }
+    }
+
+    /// How long WebSockets opened through this transport keep trying to connect a first time,
+    /// `INITIAL_CONNECT_TIMEOUT` by default.
+    pub fn initial_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connection.initial_connect_timeout = timeout;
+        self
+    }
+
+    /// A handle for a watcher to reach the vault and learn when to stop.
+    pub fn connection(&self) -> Connection {
+        self.connection.clone()
+    }
+
+    /// Runs a watcher sending `name` (e.g. "traces") until it returns, which it should do once
+    /// it drained what it holds after `shutdown` is requested. An error is reported as soon as
+    /// the watcher stops on it, the rest of the run goes on without it.
+    pub fn spawn(
+        &mut self,
+        name: &'static str,
+        watcher: impl Future<Output = Result<()>> + Send + 'static,
+    ) {
+        let events = self.events;
+        let task = tokio::spawn(async move {
+            if let Err(e) = watcher.await {
+                events.emit(Event::Error {
+                    message: &format!("Stopped sending {}: {}", name, e),
+                });
+                eprintln!("[Ariana] Stopped sending {}: {}", name, e);
+            }
+        });
+        self.tasks.push((name, task));
+    }
+
+    /// Asks every watcher to stop, then waits until each one sent, or gave up on, everything
+    /// it was given.
+    pub async fn shutdown(self) {
+        let _ = self.shutdown_tx.send(true);
+        for (name, task) in self.tasks {
+            if let Err(e) = task.await {
+                eprintln!("[Ariana] The watcher sending {} crashed: {}", name, e);
+            }
+        }
+    }
+}
+
+/// A watcher's handle on the `Transport` it was spawned by.
+#[derive(Clone)]
+pub struct Connection {
+    /// API URL and vault key.
+    endpoint: Arc<(String, String)>,
+    shutdown_rx: watch::Receiver<bool>,
+    initial_connect_timeout: Duration,
+}
+
+impl Connection {
+    pub fn api_url(&self) -> &str {
+        &self.endpoint.0
+    }
+
+    pub fn vault_key(&self) -> &str {
+        &self.endpoint.1
+    }
+
+    /// Opens a WebSocket to the endpoint at `path`. Connecting is attempted right away, a
+    /// failure only means it is retried when sending, until the initial connect timeout.
+    pub async fn websocket(&self, path: &str) -> ReconnectingWebSocket {
+        let mut socket = ReconnectingWebSocket {
+            url: websocket_endpoint(self.api_url(), path),
+            stream: None,
+            failures: 0,
+            retry_at: None,
+            give_up_at: Some(Instant::now() + self.initial_connect_timeout),
+            gave_up: false,
+        };
+        socket.reconnect().await;
+        socket
+    }
+
+    /// Resolves once `Transport::shutdown` is called, or the `Transport` is dropped.
+    pub async fn shutdown_requested(&mut self) {
+        let _ = self.shutdown_rx.wait_for(|shutdown| *shutdown).await;
+    }
+}
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A WebSocket that reconnects when the connection drops. After a failed reconnection,
+/// sending fails right away until `retry_delay` has passed, so that a server that is down
+/// doesn't slow down every message. One that never connected gives up for good once its
+/// initial connect timeout passed, e.g. because the server doesn't accept WebSockets.
+pub struct ReconnectingWebSocket {
+    url: String,
+    stream: Option<WsStream>,
+    /// Reconnections failed in a row.
+    failures: u32,
+    retry_at: Option<Instant>,
+    /// Until the first connection, when to stop trying.
+    give_up_at: Option<Instant>,
+    gave_up: bool,
+}
+
+impl ReconnectingWebSocket {
+    /// Sends `text`, reconnecting first if the connection dropped. Returns whether it was sent.
+    pub async fn send(&mut self, text: &str) -> bool {
+        if self.gave_up {
+            return false;
+        }
+        if let Some(stream) = &mut self.stream {
+            if stream.send(Message::Text(text.into())).await.is_ok() {
+                return true;
+            }
+            self.stream = None;
+        }
+        if self
+            .retry_at
+            .is_some_and(|retry_at| Instant::now() < retry_at)
+            || !self.reconnect().await
+        {
+            return false;
+        }
+        match &mut self.stream {
+            Some(stream) => stream.send(Message::Text(text.into())).await.is_ok(),
+            None => false,
+        }
+    }
+
+    /// Lets the next `send` reconnect without waiting for the backoff, for a last attempt
+    /// before giving up on what is left to send.
+    pub fn retry_now(&mut self) {
+        self.retry_at = None;
+    }
+
+    /// Whether it never connected and stopped trying, sending then always fails.
+    pub fn gave_up(&self) -> bool {
+        self.gave_up
+    }
+
+    pub async fn close(self) {
+        if let Some(mut stream) = self.stream {
+            let _ = stream.close(None).await;
+        }
+    }
+
+    async fn reconnect(&mut self) -> bool {
+        match connect_async(&self.url).await {
+            Ok((stream, _)) => {
+                self.stream = Some(stream);
+                self.failures = 0;
+                self.retry_at = None;
+                self.give_up_at = None;
+                true
+            }
+            Err(_) => {
+                self.failures += 1;
+                self.retry_at = Some(Instant::now() + retry_delay(self.failures));
+                self.gave_up = self
+                    .give_up_at
+                    .is_some_and(|give_up_at| Instant::now() >= give_up_at);
+                false
+            }
+        }
+    }
+}
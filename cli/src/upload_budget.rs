@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// Cap on the bytes a run sends to the server, shared by instrumentation requests, trace
+/// pushes and output streaming. Once reached, nothing more is sent but the command keeps
+/// running.
+#[derive(Debug, Default)]
+pub struct UploadBudget {
+    limit: Option<u64>,
+    spent: AtomicU64,
+    exhausted: AtomicBool,
+    dropped_traces: AtomicUsize,
+}
+
+impl UploadBudget {
+    /// A budget of `limit` bytes, unlimited with `None`.
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+
+    /// Records `bytes` about to be sent. Returns false, recording nothing, if that would go
+    /// over the budget, warning the first time.
+    pub fn try_spend(&self, bytes: usize) -> bool {
+        let Some(limit) = self.limit else {
+            self.spent.fetch_add(bytes as u64, Ordering::Relaxed);
+            return true;
+        };
+        let spent = self
+            .spent
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |spent| {
+                let spent = spent + bytes as u64;
+                (spent <= limit).then_some(spent)
+            });
+        if spent.is_err() && !self.exhausted.swap(true, Ordering::Relaxed) {
+            eprintln!(
+                "[Ariana] WARNING: reached the upload budget of {:.1} MB, nothing more will be sent to the server. Your command keeps running",
+                limit as f64 / (1024.0 * 1024.0)
+            );
+        }
+        spent.is_ok()
+    }
+
+    /// Takes back bytes recorded with `try_spend` that were not sent after all.
+    pub fn refund(&self, bytes: usize) {
+        self.spent.fetch_sub(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes sent so far.
+    pub fn spent(&self) -> u64 {
+        self.spent.load(Ordering::Relaxed)
+    }
+
+    /// Whether something was refused for going over the budget.
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+
+    pub fn add_dropped_traces(&self, count: usize) {
+        self.dropped_traces.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Traces not sent because the budget was reached.
+    pub fn dropped_traces(&self) -> usize {
+        self.dropped_traces.load(Ordering::Relaxed)
+    }
+}
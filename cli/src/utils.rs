@@ -1,11 +1,14 @@
-use anyhow::{anyhow, Result};
 use rand::distributions::Alphanumeric;
 use rand::thread_rng;
 use rand::Rng;
 use sha2::{Digest, Sha256};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use tokio::fs;
+use tokio::sync::Semaphore;
+
+use crate::config::{ConfigOverrides, CONFIG_DIR_ENV};
+use crate::error::{CliError, Result};
 
 pub fn should_copy_or_link_directory(dir_name: &str) -> bool {
     let skip_list = [
@@ -37,28 +40,51 @@ pub fn should_explore_directory(dir_name: &str) -> bool {
     !skip_list.contains(&dir_name) && !dir_name.contains(".") && !dir_name.starts_with("_")
 }
 
-pub async fn should_copy_not_link(path: &Path) -> bool {
+/// Extensions of files always copied rather than linked, whatever their size, by default.
+pub const DEFAULT_COPY_EXTENSIONS: &[&str] =
+    &["html", "htm", "css", "sass", "scss", "vue", "svelte"];
+
+/// Whether `ext` can be in the list of extensions always copied: lowercase, without dot.
+pub fn is_valid_copy_extension(ext: &str) -> bool {
+    !ext.is_empty()
+        && ext
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Whether `path` is copied rather than linked: small files, and files with one of
+/// `copy_extensions` (lowercase, without dot) whatever their size.
+pub async fn should_copy_not_link(path: &Path, copy_extensions: &[String]) -> bool {
     // if file is less than 1mb copy it
     let metadata = fs::metadata(path).await.unwrap();
     if metadata.len() < 1024 * 1024 {
         return true;
     }
 
-    let unsafe_extensions = ["html", "htm", "css", "sass", "scss", "vue", "svelte"];
-
     if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
         let ext_lower = ext.to_lowercase();
-        if unsafe_extensions.contains(&ext_lower.as_str()) {
+        if copy_extensions.contains(&ext_lower) {
             return true;
         }
     }
     false
 }
 
-pub async fn create_link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+/// Links `src` at `dest`, or copies it when linking isn't possible or safe, or always with
+/// `portable`. Unless `force_copy`, files already copied by a previous run and unchanged since
+/// are not copied again. Copies hold one of `permits` per open file or directory. See
+/// `should_copy_not_link` for `copy_extensions`.
+pub async fn create_link_or_copy(
+    src: &Path,
+    dest: &Path,
+    force_copy: bool,
+    portable: bool,
+    copy_extensions: &[String],
+    permits: &Semaphore,
+) -> Result<()> {
     if src.is_dir() {
-        if should_copy_not_link(src).await {
-            copy_dir_all(src, dest).await?;
+        if portable || should_copy_not_link(src, copy_extensions).await {
+            copy_dir_all(src, dest, force_copy, permits).await?;
             return Ok(());
         }
 
@@ -67,7 +93,7 @@ pub async fn create_link_or_copy(src: &Path, dest: &Path) -> Result<()> {
             match tokio::fs::symlink(src, dest).await {
                 Ok(_) => return Ok(()),
                 Err(_) => {
-                    copy_dir_all(src, dest).await?;
+                    copy_dir_all(src, dest, force_copy, permits).await?;
                     return Ok(());
                 }
             }
@@ -78,14 +104,14 @@ pub async fn create_link_or_copy(src: &Path, dest: &Path) -> Result<()> {
             match tokio::fs::symlink_dir(src, dest).await {
                 Ok(_) => return Ok(()),
                 Err(_) => {
-                    copy_dir_all(src, dest).await?;
+                    copy_dir_all(src, dest, force_copy, permits).await?;
                     return Ok(());
                 }
             }
         }
     } else if src.is_file() {
-        if should_copy_not_link(src).await {
-            fs::copy(src, dest).await?;
+        if portable || should_copy_not_link(src, copy_extensions).await {
+            copy_file(src, dest, force_copy, permits).await?;
             return Ok(());
         }
 
@@ -94,7 +120,7 @@ pub async fn create_link_or_copy(src: &Path, dest: &Path) -> Result<()> {
             match tokio::fs::symlink(src, dest).await {
                 Ok(_) => return Ok(()),
                 Err(_) => {
-                    fs::copy(src, dest).await?;
+                    copy_file(src, dest, force_copy, permits).await?;
                     return Ok(());
                 }
             }
@@ -106,7 +132,7 @@ pub async fn create_link_or_copy(src: &Path, dest: &Path) -> Result<()> {
                 Ok(_) => return Ok(()),
                 Err(e) => {
                     eprintln!("cannot symlink: {:?}", e);
-                    fs::copy(src, dest).await?;
+                    copy_file(src, dest, force_copy, permits).await?;
                     return Ok(());
                 }
             }
@@ -116,9 +142,9 @@ pub async fn create_link_or_copy(src: &Path, dest: &Path) -> Result<()> {
     #[cfg(not(any(unix, windows)))]
     {
         if src.is_dir() {
-            copy_dir_all(src, dest).await?;
+            copy_dir_all(src, dest, force_copy, permits).await?;
         } else if src.is_file() {
-            fs::copy(src, dest).await?;
+            copy_file(src, dest, force_copy, permits).await?;
         }
     }
 
@@ -126,28 +152,32 @@ pub async fn create_link_or_copy(src: &Path, dest: &Path) -> Result<()> {
 }
 
 #[async_recursion::async_recursion]
-async fn copy_dir_all(src: &Path, dst: &Path) -> Result<()> {
-    fs::create_dir_all(&dst).await?;
-    let mut entries = fs::read_dir(src).await?;
-    let mut tasks = Vec::new();
-
-    while let Some(entry) = entries.next_entry().await? {
-        let ty = entry.file_type().await?;
-        let new_dst = dst.join(entry.file_name());
-        let task = async move {
-            if ty.is_dir() {
-                copy_dir_all(&entry.path(), &new_dst).await
-            } else {
-                Ok(if ty.is_file() {
-                    fs::copy(entry.path(), new_dst).await.map(|_| ())
-                } else {
-                    Ok(())
-                }?)
-            }
-        };
-        tasks.push(task);
+async fn copy_dir_all(src: &Path, dst: &Path, force_copy: bool, permits: &Semaphore) -> Result<()> {
+    let mut children = Vec::new();
+    {
+        // Released before recursing, a directory waiting on its children must not hold one
+        let _permit = permits
+            .acquire()
+            .await
+            .expect("copy permits are never closed");
+        fs::create_dir_all(&dst).await?;
+        let mut entries = fs::read_dir(src).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let ty = entry.file_type().await?;
+            children.push((entry.path(), dst.join(entry.file_name()), ty));
+        }
     }
 
+    let tasks = children.into_iter().map(|(path, new_dst, ty)| async move {
+        if ty.is_dir() {
+            copy_dir_all(&path, &new_dst, force_copy, permits).await
+        } else if ty.is_file() {
+            copy_file(&path, &new_dst, force_copy, permits).await
+        } else {
+            Ok(())
+        }
+    });
+
     futures_util::future::join_all(tasks)
         .await
         .into_iter()
@@ -156,6 +186,149 @@ async fn copy_dir_all(src: &Path, dst: &Path) -> Result<()> {
     Ok(())
 }
 
+async fn copy_file(src: &Path, dest: &Path, force_copy: bool, permits: &Semaphore) -> Result<()> {
+    let _permit = permits
+        .acquire()
+        .await
+        .expect("copy permits are never closed");
+    if !force_copy && is_unchanged_copy(src, dest).await {
+        return Ok(());
+    }
+    fs::copy(src, dest).await?;
+    Ok(())
+}
+
+/// Whether `dest` is a copy of `src` made after the last modification of `src`, judging by
+/// size and modification time.
+async fn is_unchanged_copy(src: &Path, dest: &Path) -> bool {
+    let (Ok(src_metadata), Ok(dest_metadata)) =
+        (fs::metadata(src).await, fs::symlink_metadata(dest).await)
+    else {
+        return false;
+    };
+    if !dest_metadata.is_file() || src_metadata.len() != dest_metadata.len() {
+        return false;
+    }
+    match (src_metadata.modified(), dest_metadata.modified()) {
+        (Ok(src_modified), Ok(dest_modified)) => dest_modified >= src_modified,
+        _ => false,
+    }
+}
+
+/// Splits `items` into consecutive ranges whose cumulated `size` stays under `max_bytes`, to
+/// send them in requests the server accepts. A single item bigger than `max_bytes` gets a
+/// range of its own.
+pub fn split_by_size<T>(
+    items: &[T],
+    size: impl Fn(&T) -> u64,
+    max_bytes: u64,
+) -> Vec<Range<usize>> {
+    let mut ranges = vec![];
+    let mut start = 0;
+    let mut total_size = 0;
+    for (i, item) in items.iter().enumerate() {
+        let size = size(item);
+        if i > start && total_size + size > max_bytes {
+            ranges.push(start..i);
+            start = i;
+            total_size = 0;
+        }
+        total_size += size;
+    }
+    if start < items.len() {
+        ranges.push(start..items.len());
+    }
+    ranges
+}
+
+/// Removes every symlink under `dir`, without following them. Used before reusing the
+/// `.ariana` directory of a previous run, so that links are recreated from scratch and
+/// nothing gets written through a stale link into the original project.
+pub fn remove_links(dir: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            let path = entry.path();
+            // Directory symlinks are directories to remove on Windows
+            if std::fs::remove_file(&path).is_err() {
+                std::fs::remove_dir(&path)?;
+            }
+        } else if file_type.is_dir() {
+            remove_links(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes everything in `dir` except the entries named in `kept`.
+pub fn clear_dir_except(dir: &Path, kept: &[&str]) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if kept.iter().any(|kept| entry.file_name() == *kept) {
+            continue;
+        }
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            fs_extra::dir::remove(&path)
+                .map_err(|e| CliError::path(&path, std::io::Error::other(e)))?;
+        } else if std::fs::remove_file(&path).is_err() {
+            // Directory symlinks are directories to remove on Windows
+            std::fs::remove_dir(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether files can be created in `dir`, checked by creating and removing one.
+pub fn is_dir_writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".ariana_write_test_{}", std::process::id()));
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+    {
+        Ok(_) => std::fs::remove_file(&probe).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Name of the file in `.ariana` locked by the run using the directory.
+pub const LOCK_FILE: &str = ".lock";
+
+/// Takes an advisory lock on `ariana_dir`, creating it if needed, so that two runs in the same
+/// project don't clobber each other's files. The lock is held until the returned file is
+/// dropped or the process exits. If another run holds it, fails, or waits for it with `wait`.
+pub async fn lock_ariana_dir(ariana_dir: &Path, wait: bool) -> Result<std::fs::File> {
+    std::fs::create_dir_all(ariana_dir)?;
+    let lock_path = ariana_dir.join(LOCK_FILE);
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|e| CliError::path(&lock_path, e))?;
+    match file.try_lock() {
+        Ok(()) => return Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) if wait => {}
+        Err(std::fs::TryLockError::WouldBlock) => {
+            return Err(CliError::AlreadyRunning(format!(
+                "Another ariana run is using {}, wait for it to finish or pass --wait",
+                ariana_dir.display()
+            )))
+        }
+        Err(std::fs::TryLockError::Error(e)) => return Err(CliError::path(&lock_path, e)),
+    }
+    println!(
+        "[Ariana] Another ariana run is using {}, waiting for it to finish...",
+        ariana_dir.display()
+    );
+    tokio::task::spawn_blocking(move || file.lock().map(|()| file))
+        .await
+        .map_err(|e| CliError::Io(e.into()))?
+        .map_err(|e| CliError::path(&lock_path, e))
+}
+
 pub async fn can_create_symlinks() -> bool {
     #[cfg(windows)]
     {
@@ -185,77 +358,375 @@ pub async fn can_create_symlinks() -> bool {
     }
 }
 
+/// Why files of `src_dir` can't be linked from `dest_dir`, if they can't: the directories are
+/// on different filesystems, or a test link can't be created or followed. Checked once per run
+/// so that a whole run falls back to copying.
+pub async fn link_fallback_reason(src_dir: &Path, dest_dir: &Path) -> Option<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let (Ok(src_metadata), Ok(dest_metadata)) =
+            (fs::metadata(src_dir).await, fs::metadata(dest_dir).await)
+        {
+            if src_metadata.dev() != dest_metadata.dev() {
+                return Some(format!(
+                    "{} is on a different filesystem than {}",
+                    dest_dir.display(),
+                    src_dir.display()
+                ));
+            }
+        }
+    }
+
+    let link = dest_dir.join(".ariana_link_test");
+    let _ = fs::remove_file(&link).await;
+    #[cfg(unix)]
+    let result = fs::symlink(src_dir, &link).await;
+    #[cfg(windows)]
+    let result = fs::symlink_dir(src_dir, &link).await;
+    #[cfg(not(any(unix, windows)))]
+    let result: std::io::Result<()> = Err(std::io::ErrorKind::Unsupported.into());
+    let reason = match result {
+        Err(e) => Some(format!(
+            "links can't be created in {}: {}",
+            dest_dir.display(),
+            e
+        )),
+        Ok(()) if fs::metadata(&link).await.is_err() => Some(format!(
+            "links created in {} can't be followed",
+            dest_dir.display()
+        )),
+        Ok(()) => None,
+    };
+    // Directory links are directories to remove on Windows
+    if fs::remove_file(&link).await.is_err() {
+        let _ = fs::remove_dir(&link).await;
+    }
+    reason
+}
+
+const GITIGNORE_BLOCK_START: &str = "# BEGIN ariana";
+const GITIGNORE_BLOCK_END: &str = "# END ariana";
+
+/// Makes sure the project's .gitignore holds Ariana's entries inside a single managed block,
+/// updated in place on every run. Entries left outside of it by older versions are moved in.
 pub async fn add_to_gitignore(project_root: &Path) -> Result<()> {
     let gitignore_path = project_root.join(".gitignore");
-    let entries = vec![
+    let entries = [
         ".ariana/",
         ".traces/",
         ".ariana_saved_traces/",
         ".vault_secret_key",
     ];
-    if !gitignore_path.exists() {
-        fs::write(&gitignore_path, entries.join("\n") + "\n").await?;
-        return Ok(());
+    let mut block = vec![GITIGNORE_BLOCK_START];
+    block.extend(entries.iter());
+    block.push(GITIGNORE_BLOCK_END);
+
+    let content = if gitignore_path.exists() {
+        fs::read_to_string(&gitignore_path).await?
+    } else {
+        String::new()
+    };
+    let lines: Vec<&str> = content.lines().collect();
+
+    // Only consider a block that is properly closed, so we never swallow user lines
+    let block_range = lines
+        .iter()
+        .position(|line| line.trim() == GITIGNORE_BLOCK_START)
+        .and_then(|start| {
+            lines[start..]
+                .iter()
+                .position(|line| line.trim() == GITIGNORE_BLOCK_END)
+                .map(|end| start..start + end + 1)
+        });
+
+    let mut new_lines: Vec<&str> = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        match &block_range {
+            Some(range) if i == range.start => new_lines.extend(block.iter()),
+            Some(range) if range.contains(&i) => {}
+            _ if entries.contains(&line.trim()) => {}
+            _ => new_lines.push(line),
+        }
     }
-    let content = fs::read_to_string(&gitignore_path).await?;
-    let mut lines: Vec<String> = content.lines().map(String::from).collect();
-    let mut modified = false;
-    for entry in entries {
-        if !lines.contains(&entry.to_string()) {
-            lines.push(entry.to_string());
-            modified = true;
+    if block_range.is_none() {
+        if new_lines.last().is_some_and(|line| !line.trim().is_empty()) {
+            new_lines.push("");
         }
+        new_lines.extend(block.iter());
     }
-    if modified {
-        fs::write(&gitignore_path, lines.join("\n") + "\n").await?;
+
+    let new_content = new_lines.join("\n") + "\n";
+    if new_content != content {
+        fs::write(&gitignore_path, new_content).await?;
     }
     Ok(())
 }
 
+/// If `command` is a single script name from the root package.json `scripts`, returns the
+/// command running it through the package manager detected from the project's lockfile.
+pub fn resolve_package_script(
+    project_root: &Path,
+    command: &[String],
+) -> Result<Option<Vec<String>>> {
+    let Some((script, args)) = command.split_first() else {
+        return Ok(None);
+    };
+    let package_json_path = project_root.join("package.json");
+    if !package_json_path.exists() {
+        return Ok(None);
+    }
+    let content = std::fs::read_to_string(&package_json_path)?;
+    let json: serde_json::Value = serde_json::from_str(&content)?;
+    if json
+        .get("scripts")
+        .and_then(|scripts| scripts.get(script))
+        .is_none()
+    {
+        return Ok(None);
+    }
+
+    let package_manager = if project_root.join("pnpm-lock.yaml").exists() {
+        "pnpm"
+    } else if project_root.join("yarn.lock").exists() {
+        "yarn"
+    } else {
+        "npm"
+    };
+    let mut resolved = vec![
+        package_manager.to_string(),
+        "run".to_string(),
+        script.clone(),
+    ];
+    if !args.is_empty() {
+        // npm only forwards arguments to the script after a `--`
+        if package_manager == "npm" {
+            resolved.push("--".to_string());
+        }
+        resolved.extend(args.iter().cloned());
+    }
+    Ok(Some(resolved))
+}
+
+/// Commands built into `cmd`, which runs them without any executable to find.
+const CMD_BUILTINS: &[&str] = &[
+    "assoc", "call", "cd", "chdir", "cls", "color", "copy", "date", "del", "dir", "echo",
+    "endlocal", "erase", "exit", "for", "ftype", "goto", "if", "md", "mkdir", "mklink", "move",
+    "path", "pause", "popd", "prompt", "pushd", "rd", "ren", "rename", "rmdir", "set", "setlocal",
+    "shift", "start", "time", "title", "type", "ver", "verify", "vol",
+];
+
+/// Extensions `cmd` tries when `PATHEXT` isn't set.
+const DEFAULT_PATHEXT: &str = ".COM;.EXE;.BAT;.CMD";
+
+/// Resolves `program` the way spawning it would: as a path relative to `working_dir` if it
+/// contains a separator, through the `PATH` directories otherwise. On Windows, where commands
+/// go through `cmd /C`, `working_dir` is searched before `PATH` and each extension of
+/// `PATHEXT` is tried if `program` has none. Returns `None` if no executable file is found.
+pub fn find_executable(program: &str, working_dir: &Path) -> Option<PathBuf> {
+    let pathext = cfg!(windows)
+        .then(|| std::env::var("PATHEXT").unwrap_or_else(|_| DEFAULT_PATHEXT.to_string()));
+    let names = executable_names(program, pathext.as_deref());
+    let dirs: Vec<PathBuf> = if program.contains('/') || program.contains('\\') {
+        vec![working_dir.to_path_buf()]
+    } else {
+        let path_var = std::env::var_os("PATH")?;
+        cfg!(windows)
+            .then(|| working_dir.to_path_buf())
+            .into_iter()
+            .chain(std::env::split_paths(&path_var))
+            .collect()
+    };
+    dirs.iter()
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .find(|candidate| is_executable(candidate))
+}
+
+/// Whether `cmd` runs `program` itself, like `echo` or `dir`.
+pub fn is_cmd_builtin(program: &str) -> bool {
+    CMD_BUILTINS.contains(&program.to_ascii_lowercase().as_str())
+}
+
+/// File names `program` may have: itself, and with `pathext` (`PATHEXT` on Windows) each of
+/// its extensions appended if `program` has none.
+fn executable_names(program: &str, pathext: Option<&str>) -> Vec<String> {
+    let mut names = vec![program.to_string()];
+    if let Some(pathext) = pathext.filter(|_| Path::new(program).extension().is_none()) {
+        names.extend(
+            pathext
+                .split(';')
+                .filter(|ext| !ext.is_empty())
+                .map(|ext| format!("{}{}", program, ext)),
+        );
+    }
+    names
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Strips trailing slashes from the API URL so endpoints can all be built as `{api_url}/path`.
+pub fn normalize_api_url(api_url: &str) -> String {
+    api_url.trim_end_matches('/').to_string()
+}
+
+/// URL of the server endpoint at `path`, relative to `api_url`. A base path in `api_url` (e.g.
+/// `https://host/ariana/` behind a reverse proxy) is kept, so this gives
+/// `https://host/ariana/vaults/...`. Every endpoint must be built through here.
+pub fn api_endpoint(api_url: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        api_url.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+/// Same as `api_endpoint`, with the scheme switched to `ws`/`wss` for WebSocket endpoints.
+pub fn websocket_endpoint(api_url: &str, path: &str) -> String {
+    let endpoint = api_endpoint(api_url, path);
+    if let Some(rest) = endpoint.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = endpoint.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        endpoint
+    }
+}
+
 pub fn compute_dest_path(src_path: &Path, project_root: &Path, ariana_dir: &Path) -> PathBuf {
     let relative_path = src_path.strip_prefix(project_root).unwrap();
-    let result = ariana_dir.join(relative_path);
-    result
+    ariana_dir.join(relative_path)
 }
 
-pub async fn generate_machine_id() -> Result<String> {
+/// Name under which `path` is stored in the backup zip: relative to `project_root` and
+/// with forward slashes, as zip entry names are expected to be on every platform.
+pub fn to_zip_entry_name(path: &Path, project_root: &Path) -> Result<String> {
+    let relative_path = path.strip_prefix(project_root).map_err(|_| {
+        CliError::Backup(format!(
+            "{} is not inside the project root {}",
+            path.display(),
+            project_root.display()
+        ))
+    })?;
+    let components = relative_path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    Ok(components.join("/"))
+}
+
+/// Path under `project_root` that the backup zip entry `name` should be restored to.
+/// Entries written by older versions of the CLI held absolute paths and are kept as is.
+pub fn from_zip_entry_name(name: &str, project_root: &Path) -> Result<PathBuf> {
+    let legacy_path = Path::new(name);
+    if legacy_path.is_absolute() {
+        return Ok(legacy_path.to_path_buf());
+    }
+
+    let mut path = project_root.to_path_buf();
+    for part in name.split(['/', '\\']) {
+        match part {
+            "" | "." => continue,
+            ".." => {
+                return Err(CliError::Backup(format!(
+                    "Refusing to restore {} outside of the project",
+                    name
+                )))
+            }
+            part if part.contains(':') => {
+                return Err(CliError::Backup(format!(
+                    "Refusing to restore {} outside of the project",
+                    name
+                )))
+            }
+            part => path.push(part),
+        }
+    }
+    Ok(path)
+}
+
+pub async fn generate_machine_id(overrides: &ConfigOverrides) -> Result<String> {
     // Try to get a stable machine ID if possible, otherwise generate a random one
-    let id = match get_stable_machine_id().await {
+    let id = match overrides.machine_id.clone() {
         Some(id) => id,
-        None => {
-            // Generate a random ID and save it for future use
-            let rng = thread_rng();
-            let random_id: String = rng
-                .sample_iter(&Alphanumeric)
-                .take(32)
-                .map(char::from)
-                .collect();
-
-            // Save this ID for future use
-            let ariana_dir = dirs::home_dir()
-                .ok_or_else(|| anyhow!("Could not determine home directory"))?
-                .join(".ariana");
-
-            tokio::fs::create_dir_all(&ariana_dir).await?;
-            tokio::fs::write(ariana_dir.join("machine-id"), &random_id).await?;
-
-            random_id
-        }
+        None => match get_stable_machine_id(overrides).await {
+            Some(id) => id,
+            None => {
+                // Generate a random ID and save it for future use
+                let random_id = random_machine_id();
+                if let Err(e) = store_machine_id(overrides, &random_id).await {
+                    eprintln!(
+                        "[Ariana] WARNING: could not save the machine id, this run won't be tied to the previous ones: {}",
+                        e
+                    );
+                }
+                random_id
+            }
+        },
     };
 
-    // Hash the ID for privacy
+    Ok(hash_machine_id(&id))
+}
+
+/// Replaces the stored machine id with a new random one, so that later runs are no longer tied
+/// to the previous ones. Returns the hash of the new id.
+pub async fn reset_machine_id(overrides: &ConfigOverrides) -> Result<String> {
+    let id = random_machine_id();
+    store_machine_id(overrides, &id).await?;
+    Ok(hash_machine_id(&id))
+}
+
+fn random_machine_id() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+async fn store_machine_id(overrides: &ConfigOverrides, id: &str) -> Result<()> {
+    let ariana_dir = machine_id_dir(overrides).ok_or_else(|| {
+        CliError::Config(format!(
+            "Could not determine the home directory to keep the machine id in, set --config-dir or {} to a directory to keep it in instead",
+            CONFIG_DIR_ENV
+        ))
+    })?;
+    fs::create_dir_all(&ariana_dir).await?;
+    fs::write(ariana_dir.join("machine-id"), id).await?;
+    Ok(())
+}
+
+/// Hashes the ID for privacy
+fn hash_machine_id(id: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(id.as_bytes());
-    let result = hasher.finalize();
+    format!("{:x}", hasher.finalize())
+}
 
-    Ok(format!("{:x}", result))
+/// Where the generated machine id is kept: the config directory when overridden,
+/// `~/.ariana` otherwise.
+fn machine_id_dir(overrides: &ConfigOverrides) -> Option<PathBuf> {
+    overrides
+        .config_dir
+        .clone()
+        .or_else(|| dirs::home_dir().map(|home_dir| home_dir.join(".ariana")))
 }
 
 /// Try to get a stable machine ID from the filesystem
-async fn get_stable_machine_id() -> Option<String> {
+async fn get_stable_machine_id(overrides: &ConfigOverrides) -> Option<String> {
     // First check if we've already created an ID
-    if let Some(home_dir) = dirs::home_dir() {
-        let ariana_id_path = home_dir.join(".ariana").join("machine-id");
+    if let Some(ariana_dir) = machine_id_dir(overrides) {
+        let ariana_id_path = ariana_dir.join("machine-id");
         if let Ok(id) = fs::read_to_string(&ariana_id_path).await {
             return Some(id);
         }
@@ -264,7 +735,7 @@ async fn get_stable_machine_id() -> Option<String> {
     // Try to get a system machine ID (Windows/Linux specific)
     #[cfg(windows)]
     {
-        if let Ok(output) = Command::new("wmic")
+        if let Ok(output) = std::process::Command::new("wmic")
             .args(["csproduct", "get", "UUID"])
             .output()
         {
@@ -289,3 +760,157 @@ async fn get_stable_machine_id() -> Option<String> {
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zip_entry_names_round_trip() {
+        let root = Path::new("project");
+        let path = root.join("src").join("lib").join("app.js");
+        let name = to_zip_entry_name(&path, root).unwrap();
+        assert_eq!(name, "src/lib/app.js");
+        assert_eq!(from_zip_entry_name(&name, root).unwrap(), path);
+    }
+
+    #[test]
+    fn zip_entry_names_with_windows_separators_are_restored_under_the_root() {
+        let root = Path::new("project");
+        assert_eq!(
+            from_zip_entry_name("src\\lib\\app.js", root).unwrap(),
+            root.join("src").join("lib").join("app.js")
+        );
+    }
+
+    #[test]
+    fn legacy_absolute_zip_entry_names_are_kept() {
+        let legacy = std::env::temp_dir().join("project").join("app.js");
+        let name = legacy.to_string_lossy();
+        assert_eq!(
+            from_zip_entry_name(&name, Path::new("elsewhere")).unwrap(),
+            legacy
+        );
+    }
+
+    #[test]
+    fn zip_entry_names_escaping_the_root_are_refused() {
+        let root = Path::new("project");
+        for name in [
+            "../app.js",
+            "src/../../app.js",
+            "src\\..\\..\\app.js",
+            "C:app.js",
+            "src/c:/app.js",
+        ] {
+            assert!(from_zip_entry_name(name, root).is_err(), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn machine_ids_set_explicitly_are_used_without_being_stored() {
+        let config_dir =
+            std::env::temp_dir().join(format!("ariana-machine-id-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&config_dir);
+        let overrides = ConfigOverrides {
+            config_dir: Some(config_dir.clone()),
+            machine_id: Some("ci-runner".to_string()),
+        };
+
+        let machine_hash = generate_machine_id(&overrides).await.unwrap();
+
+        assert_eq!(machine_hash, hash_machine_id("ci-runner"));
+        assert!(!config_dir.join("machine-id").exists());
+    }
+
+    #[tokio::test]
+    async fn reset_machine_ids_are_stored_in_the_config_directory() {
+        let config_dir =
+            std::env::temp_dir().join(format!("ariana-reset-machine-id-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&config_dir);
+        let overrides = ConfigOverrides {
+            config_dir: Some(config_dir.clone()),
+            machine_id: None,
+        };
+
+        let machine_hash = reset_machine_id(&overrides).await.unwrap();
+        let stored = std::fs::read_to_string(config_dir.join("machine-id")).unwrap();
+        let _ = std::fs::remove_dir_all(&config_dir);
+
+        assert_eq!(machine_hash, hash_machine_id(&stored));
+    }
+
+    #[tokio::test]
+    async fn gitignore_block_is_written_once_and_updated_in_place() {
+        let root = std::env::temp_dir().join(format!("ariana-gitignore-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let gitignore = root.join(".gitignore");
+        std::fs::write(&gitignore, "node_modules/\n.ariana/\ndist/\n.traces/\n").unwrap();
+
+        add_to_gitignore(&root).await.unwrap();
+        let first = std::fs::read_to_string(&gitignore).unwrap();
+        for _ in 0..3 {
+            add_to_gitignore(&root).await.unwrap();
+        }
+        let last = std::fs::read_to_string(&gitignore).unwrap();
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert_eq!(first, last);
+        assert_eq!(
+            last,
+            "node_modules/\ndist/\n\n# BEGIN ariana\n.ariana/\n.traces/\n.ariana_saved_traces/\n.vault_secret_key\n# END ariana\n"
+        );
+    }
+
+    #[test]
+    fn paths_outside_of_the_root_have_no_zip_entry_name() {
+        assert!(to_zip_entry_name(Path::new("other/app.js"), Path::new("project")).is_err());
+    }
+
+    #[test]
+    fn api_urls_are_normalized_with_or_without_a_trailing_slash() {
+        for api_url in [
+            "https://api.ariana.dev",
+            "https://api.ariana.dev/",
+            "https://api.ariana.dev//",
+        ] {
+            assert_eq!(normalize_api_url(api_url), "https://api.ariana.dev");
+            assert_eq!(
+                api_endpoint(api_url, "vaults/key/push"),
+                "https://api.ariana.dev/vaults/key/push"
+            );
+        }
+    }
+
+    #[test]
+    fn api_endpoints_keep_the_base_path_of_the_api_url() {
+        for api_url in ["http://host/ariana", "http://host/ariana/"] {
+            assert_eq!(
+                api_endpoint(api_url, "/unauthenticated/vaults/create"),
+                "http://host/ariana/unauthenticated/vaults/create"
+            );
+            assert_eq!(
+                websocket_endpoint(api_url, "vaults/key/stream"),
+                "ws://host/ariana/vaults/key/stream"
+            );
+        }
+    }
+
+    #[test]
+    fn executables_are_looked_up_with_each_pathext_extension() {
+        assert_eq!(
+            executable_names("npm", Some(".COM;.EXE;.CMD;")),
+            ["npm", "npm.COM", "npm.EXE", "npm.CMD"]
+        );
+        assert_eq!(executable_names("npm.cmd", Some(".EXE;.CMD")), ["npm.cmd"]);
+        assert_eq!(executable_names("npm", None), ["npm"]);
+    }
+
+    #[test]
+    fn cmd_builtins_are_recognized_whatever_their_case() {
+        assert!(is_cmd_builtin("echo"));
+        assert!(is_cmd_builtin("DIR"));
+        assert!(!is_cmd_builtin("node"));
+    }
+}
@@ -0,0 +1,1709 @@
+//! End-to-end tests running the CLI against `mock_server`, a local stand-in for the Ariana
+//! server, to check what goes over the network: the order of requests, retries, and that
+//! everything a run produced is sent before it ends.
+
+mod mock_server;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use ariana_cli::archive::{write_archive, ExtractedArchive};
+use ariana_cli::baseline::{BaselineMode, BASELINE_FILE};
+use ariana_cli::capabilities::{ServerCapabilities, SupportedLanguage};
+use ariana_cli::collector::SkipCause;
+use ariana_cli::config::{ConfigOverrides, CONFIG_DIR_ENV};
+use ariana_cli::manifest::read_run_manifest;
+use ariana_cli::progress::RunProgress;
+use ariana_cli::remote::SSH_PROGRAM_ENV;
+use ariana_cli::subprocess_stdout_watcher::{watch_subprocess_output, OutputSource};
+use ariana_cli::trace_watcher::{
+    flush_pending_traces, watch_traces, TraceWatcherOptions, DEFAULT_MAX_PUSH_BYTES,
+};
+use ariana_cli::transport::Transport;
+use ariana_cli::upload_budget::UploadBudget;
+use ariana_cli::{events::EventLog, InstrumentationSession, SessionConfig, ARIANA_DIR};
+use ariana_server::traces::{Position, Trace, TraceType};
+use mock_server::{MockServer, INSTRUMENTED_HEADER, VAULT_KEY};
+use tokio::sync::mpsc;
+
+/// An empty directory for a test, under the system temp directory.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ariana-e2e-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Config directory of the tests, keeping the machine id out of the home directory.
+fn config_overrides() -> ConfigOverrides {
+    ConfigOverrides {
+        config_dir: Some(std::env::temp_dir().join("ariana-e2e-config")),
+        machine_id: None,
+    }
+}
+
+/// `SessionConfig::new` with the config directory of the tests.
+fn session_config(server: &MockServer, root: PathBuf, command: Vec<String>) -> SessionConfig {
+    SessionConfig {
+        config_overrides: config_overrides(),
+        ..SessionConfig::new(&server.url, root, command)
+    }
+}
+
+/// The `ariana` binary, with the config directory of the tests.
+fn ariana() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_ariana"));
+    command.env(CONFIG_DIR_ENV, config_overrides().config_dir.unwrap());
+    command
+}
+
+fn trace(id: &str) -> Trace {
+    let position = Position {
+        filepath: "src/app.js".to_string(),
+        line: 1,
+        column: 1,
+    };
+    Trace {
+        trace_id: id.to_string(),
+        start_pos: position.clone(),
+        end_pos: position,
+        parent_id: String::new(),
+        timestamp: 0,
+        trace_type: TraceType::Enter,
+    }
+}
+
+/// A line of output the way instrumented code prints a trace.
+fn trace_line(id: &str) -> String {
+    format!(
+        "<trace id=\"{}\">{}</trace>",
+        id,
+        serde_json::to_string(&trace(id)).unwrap()
+    )
+}
+
+/// A project with a JavaScript file, running `script` with `sh`. Further arguments are
+/// available to the script as `$0`, `$1`...
+fn project(root: &Path, server: &MockServer, script: &str, args: &[String]) -> SessionConfig {
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(root.join("src/app.js"), "console.log('hello');\n").unwrap();
+    let command = ["sh", "-c", script]
+        .into_iter()
+        .map(str::to_string)
+        .chain(args.iter().cloned())
+        .collect();
+    SessionConfig {
+        quiet: true,
+        git_metadata: false,
+        ..session_config(server, root.to_path_buf(), command)
+    }
+}
+
+async fn run(config: SessionConfig) -> (InstrumentationSession, i32) {
+    let mut session = InstrumentationSession::new(config).unwrap();
+    session.prepare().await.unwrap();
+    session.create_vault().await.unwrap();
+    session.collect().unwrap();
+    session.instrument().await.unwrap();
+    let exit_code = session.run().await.unwrap();
+    (session, exit_code)
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn run_sends_instrumentation_traces_and_output_in_order() {
+    let server = MockServer::start().await;
+    let root = test_dir("run");
+    let config = project(
+        &root,
+        &server,
+        "echo hello; echo \"$0\"; echo \"$1\"",
+        &[trace_line("t1"), trace_line("t2")],
+    );
+
+    let (session, exit_code) = run(config).await;
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(session.vault_key(), Some(VAULT_KEY));
+    let instrumented = fs::read_to_string(root.join(ARIANA_DIR).join("src/app.js")).unwrap();
+    assert!(instrumented.starts_with(INSTRUMENTED_HEADER));
+
+    let state = server.state();
+    assert!(state
+        .instrumented_files
+        .iter()
+        .any(|path| path.ends_with("app.js")));
+    let trace_ids = state
+        .traces
+        .iter()
+        .map(|trace| trace.trace_id.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(trace_ids, ["t1", "t2"]);
+    assert!(state.output_lines.iter().any(|line| line == "hello"));
+    assert_eq!(state.finished, Some(0));
+
+    // The vault exists before anything is sent to it, and is finished once all is sent
+    let position = |suffix: &str| {
+        state
+            .requests
+            .iter()
+            .position(|request| request.ends_with(suffix))
+    };
+    assert!(position("/unauthenticated/vaults/create") < position("/instrument-batched"));
+    assert!(position("/instrument-batched") < position("/push"));
+    assert_eq!(position("/finish"), Some(state.requests.len() - 1));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn logged_in_runs_send_the_token() {
+    let server = MockServer::start().await;
+    let root = test_dir("logged-in");
+    let config = SessionConfig {
+        auth_token: Some("mock-token".to_string()),
+        ..project(&root, &server, "echo \"$0\"", &[trace_line("t1")])
+    };
+
+    run(config).await;
+
+    let state = server.state();
+    for endpoint in ["/vaults/create", "/instrument-batched", "/push"] {
+        let authorizations = state
+            .authorized_requests
+            .iter()
+            .filter(|(request, _)| request.ends_with(endpoint))
+            .map(|(_, authorization)| authorization.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(authorizations, ["Bearer mock-token"], "{}", endpoint);
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn anonymous_runs_send_no_token() {
+    let server = MockServer::start().await;
+    let root = test_dir("anonymous");
+    let config = project(&root, &server, "echo \"$0\"", &[trace_line("t1")]);
+
+    run(config).await;
+
+    let state = server.state();
+    assert!(state.authorized_requests.is_empty());
+    assert_eq!(state.traces.len(), 1);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn trace_pushes_are_retried_when_the_server_is_unavailable() {
+    let server = MockServer::start().await;
+    server.state().failing_pushes = 2;
+    let root = test_dir("retry");
+    let config = project(&root, &server, "echo \"$0\"", &[trace_line("t1")]);
+
+    let (_, exit_code) = run(config).await;
+
+    assert_eq!(exit_code, 0);
+    let state = server.state();
+    let pushes = state
+        .requests
+        .iter()
+        .filter(|request| request.ends_with("/push"))
+        .count();
+    assert_eq!(pushes, 3);
+    assert_eq!(state.traces.len(), 1);
+    assert!(!root.join(ARIANA_DIR).join("pending_traces.jsonl").exists());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn vault_key_is_kept_when_instrumentation_fails() {
+    let server = MockServer::start().await;
+    server.state().failing_instrumentation = true;
+    let root = test_dir("instrumentation-failure");
+    let config = SessionConfig {
+        inplace: true,
+        fail_on_instrument_error: true,
+        ..project(&root, &server, "echo \"$0\"", &[trace_line("t1")])
+    };
+
+    let mut session = InstrumentationSession::new(config).unwrap();
+    session.prepare().await.unwrap();
+    session.create_vault().await.unwrap();
+    session.collect().unwrap();
+    assert!(session.instrument().await.is_err());
+
+    let vault_key = fs::read_to_string(root.join(ARIANA_DIR).join(".vault_secret_key")).unwrap();
+    assert_eq!(vault_key.lines().next(), Some(VAULT_KEY));
+    assert_eq!(
+        fs::read_to_string(root.join("src/app.js")).unwrap(),
+        "console.log('hello');\n"
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn failing_runs_restore_the_sources_and_still_send_the_traces() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let server = MockServer::start().await;
+    let root = test_dir("failing-run");
+    let script = root.join("gone.sh");
+    fs::write(&script, "#!/bin/sh\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    let config = SessionConfig {
+        inplace: true,
+        then_commands: vec![vec!["./gone.sh".to_string()]],
+        ..project(&root, &server, "echo \"$0\"", &[trace_line("t1")])
+    };
+
+    let mut session = InstrumentationSession::new(config).unwrap();
+    session.prepare().await.unwrap();
+    session.create_vault().await.unwrap();
+    session.collect().unwrap();
+    session.instrument().await.unwrap();
+    assert!(fs::read_to_string(root.join("src/app.js"))
+        .unwrap()
+        .starts_with(INSTRUMENTED_HEADER));
+    // Found when the session was created, but can't be spawned anymore
+    fs::remove_file(&script).unwrap();
+
+    assert!(session.run().await.is_err());
+    assert_eq!(
+        fs::read_to_string(root.join("src/app.js")).unwrap(),
+        "console.log('hello');\n"
+    );
+    assert_eq!(server.state().traces.len(), 1);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn traces_a_run_could_not_push_are_set_aside_by_the_next_one() {
+    let server = MockServer::start().await;
+    server.state().failing_pushes = 3;
+    let root = test_dir("set-aside-pending");
+    let config = project(&root, &server, "echo \"$0\"", &[trace_line("t1")]);
+    let pending = root.join(ARIANA_DIR).join("pending_traces.jsonl");
+
+    run(config.clone()).await;
+    assert!(pending.exists());
+    assert!(server.state().traces.is_empty());
+
+    let (_, exit_code) = run(config).await;
+
+    assert_eq!(exit_code, 0);
+    assert!(!pending.exists());
+    let set_aside = fs::read_to_string(
+        root.join(ARIANA_DIR)
+            .join("pending_traces")
+            .join(format!("{}.jsonl", VAULT_KEY)),
+    )
+    .unwrap();
+    assert_eq!(set_aside.lines().count(), 1);
+    assert_eq!(server.state().traces.len(), 1);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn duplicate_traces_are_pushed_once_with_dedup_traces() {
+    let server = MockServer::start().await;
+    let root = test_dir("dedup-traces");
+    let config = project(
+        &root,
+        &server,
+        "echo \"$0\"; echo \"$1\"; echo \"$0$0\"",
+        &[trace_line("t1"), trace_line("t2")],
+    );
+
+    run(SessionConfig {
+        dedup_traces: true,
+        ..config
+    })
+    .await;
+
+    let state = server.state();
+    let trace_ids = state
+        .traces
+        .iter()
+        .map(|trace| trace.trace_id.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(trace_ids, ["t1", "t2"]);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn invalid_utf8_output_is_decoded_lossily() {
+    let server = MockServer::start().await;
+    let root = test_dir("invalid-utf8");
+    let config = project(
+        &root,
+        &server,
+        r#"printf 'latin-1 caf\351\n'; echo "$0"; printf 'bytes \377\376%s after\n' "$1""#,
+        &[trace_line("t1"), trace_line("t2")],
+    );
+
+    let (_, exit_code) = run(config).await;
+
+    assert_eq!(exit_code, 0);
+    let state = server.state();
+    let trace_ids = state
+        .traces
+        .iter()
+        .map(|trace| trace.trace_id.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(trace_ids, ["t1", "t2"]);
+    assert!(state
+        .output_lines
+        .iter()
+        .any(|line| line == "latin-1 caf\u{FFFD}"));
+    assert!(state
+        .output_lines
+        .iter()
+        .any(|line| line == "bytes \u{FFFD}\u{FFFD} after"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn big_trace_batches_are_split_and_fully_delivered() {
+    let server = MockServer::start().await;
+    server.state().failing_pushes = 1;
+    let root = test_dir("split-pushes");
+    let traces = (0..300)
+        .map(|i| trace_line(&i.to_string()))
+        .collect::<Vec<_>>();
+    let config = SessionConfig {
+        max_trace_push_bytes: 4096,
+        ..project(&root, &server, "printf '%s\\n' \"$0\" \"$@\"", &traces)
+    };
+
+    let (_, exit_code) = run(config).await;
+
+    assert_eq!(exit_code, 0);
+    let state = server.state();
+    let pushes = state
+        .requests
+        .iter()
+        .filter(|request| request.ends_with("/push"))
+        .count();
+    assert!(pushes > 10, "{} pushes", pushes);
+    let mut trace_ids = state
+        .traces
+        .iter()
+        .map(|trace| trace.trace_id.parse::<usize>().unwrap())
+        .collect::<Vec<_>>();
+    trace_ids.sort_unstable();
+    assert_eq!(trace_ids, (0..300).collect::<Vec<_>>());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn directories_of_thousands_of_files_are_copied_entirely() {
+    let server = MockServer::start().await;
+    let root = test_dir("many-files");
+    for package in 0..40 {
+        let package_dir = root.join(format!("node_modules/package-{}/lib", package));
+        fs::create_dir_all(&package_dir).unwrap();
+        for file in 0..100 {
+            fs::write(package_dir.join(format!("{}.txt", file)), file.to_string()).unwrap();
+        }
+    }
+    let config = SessionConfig {
+        portable: true,
+        max_concurrent_files: 8,
+        ..project(&root, &server, "exit 0", &[])
+    };
+
+    let (session, exit_code) = run(config).await;
+
+    assert_eq!(exit_code, 0);
+    let copies = session.ariana_dir().join("node_modules");
+    for package in 0..40 {
+        let package_dir = copies.join(format!("package-{}/lib", package));
+        for file in 0..100 {
+            let copy = package_dir.join(format!("{}.txt", file));
+            assert!(!copy.symlink_metadata().unwrap().is_symlink());
+            assert_eq!(fs::read_to_string(copy).unwrap(), file.to_string());
+        }
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn user_output_with_trace_tags_is_not_taken_for_traces() {
+    let server = MockServer::start().await;
+    let root = test_dir("literal-trace-tags");
+    let custom_trace = trace_line("t2")
+        .replace("<trace ", "<ariana-run ")
+        .replace("</trace>", "</ariana-run>");
+    let config = SessionConfig {
+        trace_marker: "ariana-run".to_string(),
+        ..project(
+            &root,
+            &server,
+            "echo \"html: <trace>$0</trace>\"; echo \"$0\"; echo \"$1\"",
+            &[trace_line("t1"), custom_trace],
+        )
+    };
+
+    let (_, exit_code) = run(config).await;
+
+    assert_eq!(exit_code, 0);
+    let state = server.state();
+    let trace_ids = state
+        .traces
+        .iter()
+        .map(|trace| trace.trace_id.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(trace_ids, ["t2"]);
+    assert!(state.output_lines.contains(&trace_line("t1")));
+    assert!(state
+        .output_lines
+        .contains(&format!("html: <trace>{}</trace>", trace_line("t1"))));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn append_run_reuses_the_instrumented_project_and_vault() {
+    let server = MockServer::start().await;
+    let root = test_dir("append-run");
+    let config = project(&root, &server, "echo \"$0\"", &[trace_line("t1")]);
+    run(config.clone()).await;
+    let requests_before = server.state().requests.len();
+    // Changed since it was instrumented, which only warns
+    fs::File::options()
+        .write(true)
+        .open(root.join("src/app.js"))
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+
+    let mut session = InstrumentationSession::new(config).unwrap();
+    session.reuse_previous_run().await.unwrap();
+    let exit_code = session.run().await.unwrap();
+
+    assert_eq!(exit_code, 0);
+    assert_eq!(session.vault_key(), Some(VAULT_KEY));
+    let state = server.state();
+    let requests = &state.requests[requests_before..];
+    assert!(requests
+        .iter()
+        .all(|request| !request.ends_with("/create") && !request.ends_with("/instrument-batched")));
+    assert_eq!(state.traces.len(), 2);
+}
+
+#[tokio::test]
+async fn append_run_needs_a_previous_run() {
+    let server = MockServer::start().await;
+    let root = test_dir("append-run-first");
+    let config = session_config(&server, root, vec![]);
+
+    let mut session = InstrumentationSession::new(config).unwrap();
+
+    assert!(session.reuse_previous_run().await.is_err());
+    assert!(server.state().requests.is_empty());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn exit_code_of_the_command_is_reported() {
+    let server = MockServer::start().await;
+    let root = test_dir("exit-code");
+    let config = project(&root, &server, "exit 3", &[]);
+
+    let (_, exit_code) = run(config).await;
+
+    assert_eq!(exit_code, 3);
+    assert_eq!(server.state().finished, Some(3));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn command_closing_its_output_is_stopped_after_the_timeout() {
+    let server = MockServer::start().await;
+    let root = test_dir("output-closed");
+    let config = SessionConfig {
+        output_closed_timeout: Some(Duration::from_secs(1)),
+        ..project(
+            &root,
+            &server,
+            "echo \"$0\"; exec >&- 2>&-; sleep 60",
+            &[trace_line("t1")],
+        )
+    };
+
+    let start = Instant::now();
+    let (_, exit_code) = run(config).await;
+
+    assert!(start.elapsed() < Duration::from_secs(30));
+    assert_eq!(exit_code, 0);
+    let state = server.state();
+    assert_eq!(state.traces.len(), 1);
+    assert_eq!(state.finished, Some(0));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn commands_closing_their_output_are_waited_for_by_default() {
+    let server = MockServer::start().await;
+    let root = test_dir("output-closed-default");
+    let config = project(
+        &root,
+        &server,
+        "exec >&- 2>&-; sleep 2; touch finished",
+        &[],
+    );
+
+    let (session, exit_code) = run(config).await;
+
+    assert_eq!(exit_code, 0);
+    assert!(session.ariana_dir().join("finished").exists());
+}
+
+/// Whether the process `pid` is still running, zombies aside.
+#[cfg(unix)]
+fn is_running(pid: &str) -> bool {
+    let output = std::process::Command::new("ps")
+        .args(["-o", "stat=", "-p", pid])
+        .output()
+        .unwrap();
+    let state = String::from_utf8_lossy(&output.stdout);
+    !state.trim().is_empty() && !state.trim().starts_with('Z')
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn stopping_a_command_also_stops_the_processes_it_started() {
+    let server = MockServer::start().await;
+    let root = test_dir("process-tree");
+    let config = SessionConfig {
+        output_closed_timeout: Some(Duration::from_secs(1)),
+        ..project(
+            &root,
+            &server,
+            "sleep 60 >/dev/null 2>&1 & echo $! > grandchild.pid; echo $$ > child.pid; \
+             exec >&- 2>&-; wait",
+            &[],
+        )
+    };
+
+    let start = Instant::now();
+    let (session, _) = run(config).await;
+
+    assert!(start.elapsed() < Duration::from_secs(30));
+    let cwd = session.ariana_dir().to_path_buf();
+    for pid_file in ["child.pid", "grandchild.pid"] {
+        let pid = fs::read_to_string(cwd.join(pid_file)).unwrap();
+        assert!(!is_running(pid.trim()), "{} is still running", pid_file);
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn large_files_sent_to_be_instrumented_are_reported() {
+    let server = MockServer::start().await;
+    let root = test_dir("large-files");
+    let config = SessionConfig {
+        large_file_warning_bytes: 1024,
+        ..project(&root, &server, "true", &[])
+    };
+    fs::write(root.join("src/bundle.js"), "// generated\n".repeat(200)).unwrap();
+
+    let (session, _) = run(config).await;
+
+    let large_files = session.large_files();
+    assert_eq!(large_files.len(), 1);
+    assert!(large_files[0].0.ends_with("bundle.js"));
+    assert_eq!(large_files[0].1, 2600);
+}
+
+fn javascript_only() -> ServerCapabilities {
+    ServerCapabilities {
+        languages: vec![SupportedLanguage {
+            name: "JavaScript".to_string(),
+            extensions: vec!["js".to_string()],
+        }],
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn only_languages_the_server_supports_are_instrumented() {
+    let server = MockServer::start().await;
+    server.state().capabilities = Some(javascript_only());
+    let root = test_dir("capabilities");
+    let config = project(&root, &server, "true", &[]);
+    fs::write(root.join("src/tool.py"), "print('tool')\n").unwrap();
+
+    run(config).await;
+
+    let state = server.state();
+    assert!(state
+        .instrumented_files
+        .iter()
+        .any(|path| path.ends_with("app.js")));
+    assert!(!state
+        .instrumented_files
+        .iter()
+        .any(|path| path.ends_with("tool.py")));
+}
+
+#[test]
+fn list_supported_prints_the_languages_of_the_server() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(MockServer::start());
+    server.state().capabilities = Some(javascript_only());
+    let root = test_dir("list-supported");
+
+    let output = ariana()
+        .args(["--api-url", &server.url, "--list-supported"])
+        .current_dir(&root)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("JavaScript: .js"));
+    assert!(!stdout.contains("Python"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn files_the_server_declines_are_told_apart() {
+    let server = MockServer::start().await;
+    server.state().declined_suffix = Some("declined.js".to_string());
+    let root = test_dir("declined");
+    let config = project(&root, &server, "true", &[]);
+    fs::write(root.join("src/declined.js"), "console.log('declined');\n").unwrap();
+
+    let (session, _) = run(config).await;
+
+    let skipped = session.skipped_files();
+    assert_eq!(skipped.len(), 1);
+    assert!(skipped[0].path.ends_with("declined.js"));
+    assert_eq!(skipped[0].cause, SkipCause::Declined);
+    let copied = fs::read_to_string(root.join(ARIANA_DIR).join("src/declined.js")).unwrap();
+    assert_eq!(copied, "console.log('declined');\n");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn prewarm_sends_the_smallest_file_first() {
+    let server = MockServer::start().await;
+    let root = test_dir("prewarm");
+    let config = project(&root, &server, "true", &[]);
+    fs::write(root.join("src/small.js"), "f();\n").unwrap();
+
+    let (_, exit_code) = run(SessionConfig {
+        prewarm: true,
+        ..config
+    })
+    .await;
+
+    assert_eq!(exit_code, 0);
+    let state = server.state();
+    assert!(state.instrumented_files[0].ends_with("small.js"));
+    assert_eq!(state.instrumented_files.len(), 3);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn failed_prewarm_sends_no_other_file() {
+    let server = MockServer::start().await;
+    server.state().declined_suffix = Some("small.js".to_string());
+    let root = test_dir("prewarm-failed");
+    let config = project(&root, &server, "true", &[]);
+    fs::write(root.join("src/small.js"), "f();\n").unwrap();
+
+    let mut session = InstrumentationSession::new(SessionConfig {
+        prewarm: true,
+        ..config
+    })
+    .unwrap();
+    session.prepare().await.unwrap();
+    session.create_vault().await.unwrap();
+    session.collect().unwrap();
+    let error = session.instrument().await.unwrap_err();
+
+    assert!(error.to_string().contains("Prewarm failed"));
+    assert_eq!(server.state().instrumented_files.len(), 1);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn batches_hold_at_most_the_configured_file_count() {
+    let server = MockServer::start().await;
+    let root = test_dir("batch-file-count");
+    let config = project(&root, &server, "true", &[]);
+    fs::write(root.join("src/other.js"), "g();\n").unwrap();
+    fs::write(root.join("src/last.js"), "h();\n").unwrap();
+
+    let (_, exit_code) = run(SessionConfig {
+        batch_file_count: 2,
+        ..config
+    })
+    .await;
+
+    assert_eq!(exit_code, 0);
+    let state = server.state();
+    // Batches are sent concurrently, in any order
+    let mut batch_sizes = state.batch_sizes.clone();
+    batch_sizes.sort();
+    assert_eq!(batch_sizes, vec![1, 2]);
+    assert_eq!(state.instrumented_files.len(), 3);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn debug_server_asks_the_vault_status_until_the_end() {
+    let server = MockServer::start().await;
+    server.state().vault_status = true;
+    let root = test_dir("debug-server");
+    let config = project(&root, &server, "true", &[]);
+
+    let (_, exit_code) = run(SessionConfig {
+        debug_server: true,
+        ..config
+    })
+    .await;
+
+    assert_eq!(exit_code, 0);
+    let status_requests = server
+        .state()
+        .requests
+        .iter()
+        .filter(|request| request.ends_with(&format!("vaults/{}/status", VAULT_KEY)))
+        .count();
+    // Right away, then once the traces are flushed
+    assert_eq!(status_requests, 2);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn debug_server_gives_up_without_a_status_endpoint() {
+    let server = MockServer::start().await;
+    let root = test_dir("debug-server-missing");
+    let config = project(&root, &server, "true", &[]);
+
+    let (_, exit_code) = run(SessionConfig {
+        debug_server: true,
+        ..config
+    })
+    .await;
+
+    assert_eq!(exit_code, 0);
+    let status_requests = server
+        .state()
+        .requests
+        .iter()
+        .filter(|request| request.ends_with("/status"))
+        .count();
+    assert_eq!(status_requests, 1);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn mapped_files_are_sent_with_their_language() {
+    let server = MockServer::start().await;
+    let root = test_dir("lang-map");
+    let config = project(&root, &server, "true", &[]);
+    fs::write(root.join("src/types.flow"), "f();\n").unwrap();
+
+    let (_, exit_code) = run(SessionConfig {
+        lang_map: vec!["*.flow=javascript".parse().unwrap()],
+        ..config
+    })
+    .await;
+
+    assert_eq!(exit_code, 0);
+    let state = server.state();
+    let languages = state
+        .instrumented_files
+        .iter()
+        .zip(&state.files_languages)
+        .map(|(path, language)| (path.rsplit('/').next().unwrap(), language.as_deref()))
+        .collect::<Vec<_>>();
+    assert!(languages.contains(&("types.flow", Some("JavaScript"))));
+    assert!(languages.contains(&("app.js", None)));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn languages_the_server_lacks_are_refused() {
+    let server = MockServer::start().await;
+    let root = test_dir("lang-map-unsupported");
+    let config = project(&root, &server, "true", &[]);
+
+    let mut session = InstrumentationSession::new(SessionConfig {
+        lang_map: vec!["*.js=cobol".parse().unwrap()],
+        ..config
+    })
+    .unwrap();
+    let error = session.prepare().await.unwrap_err();
+
+    assert!(error.to_string().contains("can't instrument cobol"));
+}
+
+#[tokio::test]
+async fn output_streaming_gives_up_when_the_server_never_accepts_it() {
+    let server = MockServer::start().await;
+    server.state().refuse_websockets = true;
+    test_dir("websocket-refused");
+    let progress = Arc::new(RunProgress::new(false));
+    let mut transport = Transport::new(&server.url, VAULT_KEY, EventLog::new(false))
+        .initial_connect_timeout(Duration::from_millis(500));
+    let (output_tx, output_rx) = mpsc::channel(100);
+    let (connection, watcher_progress) = (transport.connection(), progress.clone());
+    transport.spawn("output", async move {
+        watch_subprocess_output(
+            output_rx,
+            connection,
+            &watcher_progress,
+            &UploadBudget::default(),
+            None,
+        )
+        .await
+    });
+
+    for line in ["before", "after", "later"] {
+        output_tx
+            .send((line.to_string(), OutputSource::Stdout))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+    }
+    drop(output_tx);
+    transport.shutdown().await;
+
+    assert_eq!(progress.lines_lost(), 3);
+    assert_eq!(progress.lines_streamed(), 0);
+    let attempts = server
+        .state()
+        .requests
+        .iter()
+        .filter(|request| request.ends_with("/subprocess-stdout/stream"))
+        .count();
+    // Right away, then once the timeout passed, never again
+    assert_eq!(attempts, 2);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn no_instrument_only_forwards_the_printed_traces() {
+    let server = MockServer::start().await;
+    let root = test_dir("no-instrument");
+    let config = project(
+        &root,
+        &server,
+        "echo \"$0\"; pwd > ran_in.txt",
+        &[trace_line("t1")],
+    );
+
+    let (_, exit_code) = run(SessionConfig {
+        no_instrument: true,
+        ..config
+    })
+    .await;
+
+    assert_eq!(exit_code, 0);
+    let state = server.state();
+    assert!(state.instrumented_files.is_empty());
+    assert_eq!(state.traces.len(), 1);
+    assert!(root.join("ran_in.txt").exists());
+    assert!(!root.join(ARIANA_DIR).join("src").exists());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn pushed_traces_are_also_saved_with_save_traces() {
+    let server = MockServer::start().await;
+    let root = test_dir("save-traces");
+    let saved = root.join("saved").join("traces.jsonl");
+    let config = project(
+        &root,
+        &server,
+        "echo \"$0\"; echo \"$1\"",
+        &[trace_line("t1"), trace_line("t2")],
+    );
+
+    let (_, exit_code) = run(SessionConfig {
+        save_traces: Some(saved.clone()),
+        ..config
+    })
+    .await;
+
+    assert_eq!(exit_code, 0);
+    let saved = fs::read_to_string(saved).unwrap();
+    let saved = saved
+        .lines()
+        .map(|line| serde_json::from_str::<Trace>(line).unwrap().trace_id)
+        .collect::<Vec<_>>();
+    assert_eq!(saved, ["t1", "t2"]);
+    assert_eq!(server.state().traces.len(), 2);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn output_is_compared_with_the_recorded_baseline() {
+    let server = MockServer::start().await;
+    let root = test_dir("baseline");
+    let run_with = |mode, script: &str| {
+        let config = project(&root, &server, script, &[trace_line("t1")]);
+        run(SessionConfig {
+            baseline: Some(mode),
+            ..config
+        })
+    };
+    let script = "echo \"started at $(date +%H:%M:%S)\"; echo \"result $0\"";
+
+    // Sessions hold the lock of `.ariana` until dropped
+    let baseline_diff =
+        |(session, _): (InstrumentationSession, i32)| session.baseline_diff().map(str::to_string);
+
+    run_with(BaselineMode::Record, script).await;
+    let recorded = fs::read_to_string(root.join(ARIANA_DIR).join(BASELINE_FILE)).unwrap();
+    let same = baseline_diff(run_with(BaselineMode::Compare, script).await);
+    let changed = baseline_diff(run_with(BaselineMode::Compare, "echo \"result 42\"").await);
+
+    assert_eq!(recorded, "started at <normalized>\nresult \n");
+    assert_eq!(same, None);
+    let diff = changed.unwrap();
+    assert!(diff.contains("-started at <normalized>"));
+    assert!(diff.contains("+result 42"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn output_of_failed_commands_is_not_recorded_as_the_baseline() {
+    let server = MockServer::start().await;
+    let root = test_dir("failed-baseline");
+    let config = SessionConfig {
+        baseline: Some(BaselineMode::Record),
+        ..project(&root, &server, "echo result; exit 3", &[])
+    };
+
+    let (_session, exit_code) = run(config).await;
+
+    assert_eq!(exit_code, 3);
+    assert!(!root.join(ARIANA_DIR).join(BASELINE_FILE).exists());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn comparing_with_no_recorded_baseline_fails_before_touching_files() {
+    let server = MockServer::start().await;
+    let root = test_dir("no-baseline");
+    let config = SessionConfig {
+        baseline: Some(BaselineMode::Compare),
+        inplace: true,
+        ..project(&root, &server, "true", &[])
+    };
+
+    let mut session = InstrumentationSession::new(config).unwrap();
+    let error = session.prepare().await.unwrap_err();
+
+    assert!(error.to_string().contains("No baseline to compare with"));
+    assert!(!root.join(ARIANA_DIR).exists());
+    assert!(!root.join(".gitignore").exists());
+    assert!(server.state().requests.is_empty());
+}
+
+/// A project with a `shared` directory linked from outside of it, which links to itself.
+#[cfg(unix)]
+fn project_with_symlinked_directory(name: &str, server: &MockServer) -> (PathBuf, SessionConfig) {
+    let dir = test_dir(name);
+    let shared = dir.join("shared");
+    fs::create_dir_all(&shared).unwrap();
+    fs::write(shared.join("lib.js"), "module.exports = 1;\n").unwrap();
+    std::os::unix::fs::symlink(&shared, shared.join("loop")).unwrap();
+    let root = dir.join("project");
+    let config = project(&root, server, "true", &[]);
+    std::os::unix::fs::symlink(&shared, root.join("shared")).unwrap();
+    (root, config)
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn symlinked_directories_are_linked_as_they_are_by_default() {
+    let server = MockServer::start().await;
+    let (root, config) = project_with_symlinked_directory("symlinks", &server);
+
+    run(config).await;
+
+    let linked = fs::read_to_string(root.join(ARIANA_DIR).join("shared/lib.js")).unwrap();
+    assert_eq!(linked, "module.exports = 1;\n");
+    assert!(!server
+        .state()
+        .instrumented_files
+        .iter()
+        .any(|path| path.ends_with("lib.js")));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn followed_symlinked_directories_are_instrumented_once() {
+    let server = MockServer::start().await;
+    let (root, config) = project_with_symlinked_directory("follow-symlinks", &server);
+
+    run(SessionConfig {
+        follow_symlinks: true,
+        ..config
+    })
+    .await;
+
+    let instrumented = fs::read_to_string(root.join(ARIANA_DIR).join("shared/lib.js")).unwrap();
+    assert!(instrumented.starts_with(INSTRUMENTED_HEADER));
+    let lib_requests = server
+        .state()
+        .instrumented_files
+        .iter()
+        .filter(|path| path.ends_with("lib.js"))
+        .count();
+    assert_eq!(lib_requests, 1);
+}
+
+#[tokio::test]
+async fn pending_traces_a_flush_could_not_push_are_kept() {
+    let server = MockServer::start().await;
+    server.state().failing_pushes = 3;
+    let dir = test_dir("flush-failing");
+    let path = dir.join("pending_traces.jsonl");
+    let lines = ["t1", "t2", "t3"]
+        .map(|id| serde_json::to_string(&trace(id)).unwrap() + "\n")
+        .concat();
+    fs::write(&path, &lines).unwrap();
+    let options = TraceWatcherOptions {
+        compress: false,
+        verbose: false,
+        sample_rate: 1.0,
+        dedup_traces: false,
+        pending_traces_path: None,
+        save_traces_path: None,
+        upload_budget: Arc::new(UploadBudget::default()),
+        max_push_bytes: DEFAULT_MAX_PUSH_BYTES,
+        auth_token: None,
+    };
+
+    let result = flush_pending_traces(&path, &server.url, VAULT_KEY, &options).await;
+
+    assert!(result.is_err());
+    assert_eq!(fs::read_to_string(&path).unwrap(), lines);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    assert!(server.state().traces.is_empty());
+    // Refused pushes don't count against the upload budget
+    assert_eq!(options.upload_budget.spent(), 0);
+}
+
+#[tokio::test]
+async fn shutdown_sends_everything_still_queued() {
+    let server = MockServer::start().await;
+    test_dir("shutdown");
+    let progress = Arc::new(RunProgress::new(false));
+    let mut transport = Transport::new(&server.url, VAULT_KEY, EventLog::new(false));
+
+    let (trace_tx, trace_rx) = mpsc::channel(1);
+    let (_flush_tx, flush_rx) = mpsc::channel(1);
+    let options = TraceWatcherOptions {
+        compress: true,
+        verbose: false,
+        sample_rate: 1.0,
+        dedup_traces: false,
+        pending_traces_path: None,
+        save_traces_path: None,
+        upload_budget: Arc::new(UploadBudget::default()),
+        max_push_bytes: DEFAULT_MAX_PUSH_BYTES,
+        auth_token: None,
+    };
+    let (connection, watcher_progress) = (transport.connection(), progress.clone());
+    transport.spawn("traces", async move {
+        watch_traces(trace_rx, connection, flush_rx, &options, &watcher_progress).await
+    });
+    let (output_tx, output_rx) = mpsc::channel(100);
+    let (connection, watcher_progress) = (transport.connection(), progress.clone());
+    transport.spawn("output", async move {
+        watch_subprocess_output(
+            output_rx,
+            connection,
+            &watcher_progress,
+            &UploadBudget::default(),
+            None,
+        )
+        .await
+    });
+
+    let traces = tokio::spawn(async move {
+        for i in 0..500 {
+            trace_tx.send(trace(&i.to_string())).await.unwrap();
+        }
+        trace_tx
+    });
+    for i in 0..2000 {
+        output_tx
+            .send((format!("line {}", i), OutputSource::Stdout))
+            .await
+            .unwrap();
+    }
+    let _trace_tx = traces.await.unwrap();
+
+    // The senders are still open, what they queued must be sent anyway
+    transport.shutdown().await;
+
+    assert_eq!(progress.traces_pushed(), 500);
+    assert_eq!(progress.lines_streamed(), 2000);
+    assert_eq!(progress.lines_lost(), 0);
+    assert_eq!(server.state().traces.len(), 500);
+    drop(output_tx);
+}
+
+#[test]
+fn recap_is_fetched_once_then_cached() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(MockServer::start());
+    server.state().recap = Some("Everything went fine".to_string());
+    let root = test_dir("recap");
+    fs::create_dir_all(root.join(ARIANA_DIR)).unwrap();
+    fs::write(root.join(ARIANA_DIR).join(".vault_secret_key"), VAULT_KEY).unwrap();
+
+    let recap = || {
+        ariana()
+            .args(["--api-url", &server.url, "--recap"])
+            .current_dir(&root)
+            .output()
+            .unwrap()
+    };
+    let first = recap();
+    let second = recap();
+
+    for output in [&first, &second] {
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("Everything went fine"));
+    }
+    let fetches = server
+        .state()
+        .requests
+        .iter()
+        .filter(|request| request.ends_with("/get-trace-tree"))
+        .count();
+    assert_eq!(fetches, 1);
+}
+
+#[test]
+fn recaps_larger_than_the_limit_are_refused_without_content_length() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(MockServer::start());
+    server.state().recap = Some("a".repeat(11 * 1024 * 1024));
+    server.state().chunked_responses = true;
+    let root = test_dir("oversized_recap");
+    fs::create_dir_all(root.join(ARIANA_DIR)).unwrap();
+    fs::write(root.join(ARIANA_DIR).join(".vault_secret_key"), VAULT_KEY).unwrap();
+
+    let output = ariana()
+        .args(["--api-url", &server.url, "--recap"])
+        .current_dir(&root)
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("the response is larger than"));
+}
+
+/// Starts `ariana --inplace` on a project whose command runs until stopped, and waits for
+/// the command to have started.
+#[cfg(unix)]
+fn start_inplace_run(server: &MockServer, root: &Path, args: &[&str]) -> std::process::Child {
+    fs::write(root.join("app.js"), "console.log('hello');\n").unwrap();
+    let child = ariana()
+        .args(["--api-url", &server.url, "--inplace"])
+        .args(args)
+        .args(["sh", "-c", "echo started; sleep 30"])
+        .current_dir(root)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !server
+        .state()
+        .output_lines
+        .iter()
+        .any(|line| line == "started")
+    {
+        assert!(Instant::now() < deadline, "the command never started");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    child
+}
+
+#[cfg(unix)]
+fn interrupt(child: &std::process::Child) {
+    unsafe { libc::kill(child.id() as i32, libc::SIGINT) };
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupted_runs_print_a_summary_with_summary_on_signal() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(MockServer::start());
+    let root = test_dir("summary-on-signal");
+    let child = start_inplace_run(
+        &server,
+        &root,
+        &[
+            "--summary-on-signal",
+            "--quiet",
+            "--metrics-file",
+            "metrics.prom",
+        ],
+    );
+
+    interrupt(&child);
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(130));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Stopped by Ctrl+C after"));
+    assert!(stdout.contains("1 files instrumented"));
+    assert!(stdout.contains("Time spent per phase"));
+    let metrics = fs::read_to_string(root.join("metrics.prom")).unwrap();
+    assert!(metrics.contains("exit_code 130"));
+}
+
+#[cfg(unix)]
+#[test]
+fn output_of_interrupted_runs_is_not_recorded_as_the_baseline() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(MockServer::start());
+    let root = test_dir("interrupted-baseline");
+    let child = start_inplace_run(&server, &root, &["--baseline"]);
+
+    interrupt(&child);
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(130));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not checked against the baseline"));
+    assert!(!root.join(ARIANA_DIR).join(BASELINE_FILE).exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn second_ctrl_c_lets_shutdown_finish() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(MockServer::start());
+    server.state().finish_delay = Some(Duration::from_secs(2));
+    let root = test_dir("ctrl-c-twice");
+    let child = start_inplace_run(&server, &root, &[]);
+
+    interrupt(&child);
+    std::thread::sleep(Duration::from_millis(500));
+    interrupt(&child);
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(130));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Shutting down, please wait"));
+    assert_eq!(
+        fs::read_to_string(root.join("app.js")).unwrap(),
+        "console.log('hello');\n"
+    );
+    assert!(server
+        .state()
+        .requests
+        .iter()
+        .any(|r| r.ends_with("/finish")));
+}
+
+#[cfg(unix)]
+#[test]
+fn third_ctrl_c_exits_right_away() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(MockServer::start());
+    server.state().finish_delay = Some(Duration::from_secs(60));
+    let root = test_dir("ctrl-c-thrice");
+    let child = start_inplace_run(&server, &root, &[]);
+
+    let interrupted_at = Instant::now();
+    for _ in 0..3 {
+        interrupt(&child);
+        std::thread::sleep(Duration::from_millis(300));
+    }
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(130));
+    assert!(interrupted_at.elapsed() < Duration::from_secs(30));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("ariana --restore"));
+}
+
+/// Starts `ariana` running `command` in `root`, in a new pseudo-terminal it is in the
+/// foreground of, the way a shell starts it. Returns it with the terminal, to type in, and
+/// what was printed in it so far.
+#[cfg(unix)]
+fn ariana_in_terminal(
+    server: &MockServer,
+    root: &Path,
+    command: &[&str],
+) -> (std::process::Child, fs::File, Arc<std::sync::Mutex<String>>) {
+    use std::os::fd::FromRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let (mut master, mut slave) = (0, 0);
+    let opened = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    assert_eq!(opened, 0);
+    let (terminal, slave) =
+        unsafe { (fs::File::from_raw_fd(master), fs::File::from_raw_fd(slave)) };
+    fs::write(root.join("app.js"), "console.log('hello');\n").unwrap();
+    let mut ariana = ariana();
+    ariana
+        .args(["--api-url", &server.url])
+        .args(command)
+        .current_dir(root)
+        .stdin(slave.try_clone().unwrap())
+        .stdout(slave.try_clone().unwrap())
+        .stderr(slave);
+    unsafe {
+        ariana.pre_exec(|| {
+            // A session of its own, whose controlling terminal is the one on stdin
+            if libc::setsid() < 0 || libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY as _, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = ariana.spawn().unwrap();
+    drop(ariana);
+
+    let output = Arc::new(std::sync::Mutex::new(String::new()));
+    let mut reader = terminal.try_clone().unwrap();
+    let printed = output.clone();
+    std::thread::spawn(move || {
+        let mut buf = [0; 4096];
+        // Fails once nothing has the terminal open anymore
+        while let Ok(read @ 1..) = reader.read(&mut buf) {
+            printed
+                .lock()
+                .unwrap()
+                .push_str(&String::from_utf8_lossy(&buf[..read]));
+        }
+    });
+    (child, terminal, output)
+}
+
+/// Waits for `child` to exit, killing it if it takes more than 30 s.
+#[cfg(unix)]
+fn wait_for_exit(
+    child: &mut std::process::Child,
+    output: &std::sync::Mutex<String>,
+) -> std::process::ExitStatus {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status;
+        }
+        if Instant::now() > deadline {
+            child.kill().unwrap();
+            panic!("ariana never exited:\n{}", output.lock().unwrap());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn commands_can_read_the_terminal_ariana_runs_in() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(MockServer::start());
+    let root = test_dir("terminal-stdin");
+    let (mut child, mut terminal, output) = ariana_in_terminal(
+        &server,
+        &root,
+        &["sh", "-c", "read line; echo \"got $line\""],
+    );
+
+    terminal.write_all(b"hello\n").unwrap();
+    let status = wait_for_exit(&mut child, &output);
+
+    assert!(status.success(), "{}", output.lock().unwrap());
+    assert!(server
+        .state()
+        .output_lines
+        .contains(&"got hello".to_string()));
+}
+
+#[cfg(unix)]
+#[test]
+fn ctrl_c_hit_in_the_terminal_stops_the_command() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(MockServer::start());
+    let root = test_dir("terminal-ctrl-c");
+    let (mut child, mut terminal, output) =
+        ariana_in_terminal(&server, &root, &["sh", "-c", "echo started; sleep 30"]);
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !server
+        .state()
+        .output_lines
+        .iter()
+        .any(|line| line == "started")
+    {
+        assert!(Instant::now() < deadline, "the command never started");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    terminal.write_all(b"\x03").unwrap();
+    let status = wait_for_exit(&mut child, &output);
+
+    assert_eq!(status.code(), Some(130), "{}", output.lock().unwrap());
+    assert!(server
+        .state()
+        .requests
+        .iter()
+        .any(|r| r.ends_with("/finish")));
+}
+
+/// Runs `ariana --remote` through a stand-in for `ssh` running the remote script locally, in
+/// a remote directory under `root`.
+#[cfg(unix)]
+fn run_remotely(server: &MockServer, root: &Path, script: &str) -> std::process::Output {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fake_ssh = root.join("fake-ssh");
+    fs::write(
+        &fake_ssh,
+        "#!/bin/sh\nfor last; do :; done\nexec sh -c \"$last\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(&fake_ssh, fs::Permissions::from_mode(0o755)).unwrap();
+    let project = root.join("project");
+    fs::create_dir_all(&project).unwrap();
+    fs::write(project.join("app.js"), "console.log('hello');\n").unwrap();
+
+    ariana()
+        .env(SSH_PROGRAM_ENV, &fake_ssh)
+        .args(["--api-url", &server.url, "--remote"])
+        .arg(format!("somewhere:{}", root.join("remote").display()))
+        .args(["sh", "-c", script, &trace_line("t1")])
+        .current_dir(&project)
+        .output()
+        .unwrap()
+}
+
+#[cfg(unix)]
+#[test]
+fn remote_runs_send_traces_and_clean_up() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(MockServer::start());
+    let root = test_dir("remote");
+
+    let output = run_remotely(&server, &root, "pwd; ls -A; echo \"$0\"");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout
+        .lines()
+        .any(|line| line == root.join("remote").to_str().unwrap()));
+    assert!(stdout.lines().any(|line| line == "app.js"));
+    assert!(!stdout.contains(".vault_secret_key"));
+    assert!(!root.join("remote").exists());
+    let trace_ids = server
+        .state()
+        .traces
+        .iter()
+        .map(|trace| trace.trace_id.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(trace_ids, ["t1"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn remote_directory_in_use_is_left_alone() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(MockServer::start());
+    let root = test_dir("remote-in-use");
+    fs::create_dir_all(root.join("remote")).unwrap();
+    fs::write(root.join("remote/precious.txt"), "keep me").unwrap();
+
+    let output = run_remotely(&server, &root, "true");
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not empty"));
+    assert!(root.join("remote/precious.txt").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn tagged_output_tells_stdout_and_stderr_apart() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(MockServer::start());
+    let root = test_dir("tag-output");
+    fs::write(root.join("app.js"), "console.log('hello');\n").unwrap();
+
+    let output = ariana()
+        .args([
+            "--api-url",
+            &server.url,
+            "--tag-output",
+            "color",
+            "--no-color",
+        ])
+        .args(["sh", "-c", "echo to-stdout; echo to-stderr >&2"])
+        .current_dir(&root)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stdout.lines().any(|line| line == "[stdout] to-stdout"));
+    assert!(stderr.lines().any(|line| line == "[stderr] to-stderr"));
+    assert!(!stderr.contains('\x1b'));
+}
+
+#[test]
+fn run_manifest_records_the_run() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(MockServer::start());
+    let root = test_dir("manifest");
+    fs::write(root.join("app.js"), "console.log('hello');\n").unwrap();
+
+    let output = ariana()
+        .args(["--api-url", &server.url, "sh", "-c", "exit 3"])
+        .current_dir(&root)
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(3));
+    let manifest = read_run_manifest(&root.join(ARIANA_DIR)).unwrap().unwrap();
+    assert_eq!(manifest.exit_code, Some(3));
+    assert_eq!(manifest.vault_key.as_deref(), Some(VAULT_KEY));
+    assert_eq!(manifest.commands, [["sh", "-c", "exit 3"]]);
+    assert_eq!(manifest.files_instrumented, 1);
+    assert!(manifest.args.iter().any(|arg| arg == "--api-url"));
+    assert!(manifest
+        .timings
+        .iter()
+        .any(|timing| timing.phase == "instrumentation"));
+}
+
+#[test]
+fn reset_machine_id_replaces_the_stored_id() {
+    let config_dir = test_dir("machine-id");
+    let reset = || {
+        let output = ariana()
+            .arg("--config-dir")
+            .arg(&config_dir)
+            .arg("--reset-machine-id")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stored = fs::read_to_string(config_dir.join("machine-id")).unwrap();
+        (String::from_utf8_lossy(&output.stdout).into_owned(), stored)
+    };
+
+    let (first_output, first_id) = reset();
+    let (second_output, second_id) = reset();
+
+    assert_ne!(first_id, second_id);
+    assert!(first_output.contains("its hash is"));
+    assert_ne!(first_output, second_output);
+}
+
+fn zip_files(path: &Path, files: &[(&str, &str)]) {
+    let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+    for (name, content) in files {
+        zip.start_file(*name, zip::write::FileOptions::<()>::default())
+            .unwrap();
+        zip.write_all(content.as_bytes()).unwrap();
+    }
+    zip.finish().unwrap();
+}
+
+#[tokio::test]
+async fn archived_projects_are_instrumented_into_another_archive() {
+    let server = MockServer::start().await;
+    let dir = test_dir("archive");
+    zip_files(
+        &dir.join("project.zip"),
+        &[
+            ("src/app.js", "console.log('hello');\n"),
+            ("README.md", "hello\n"),
+        ],
+    );
+
+    let extracted = ExtractedArchive::extract(&dir.join("project.zip")).unwrap();
+    let extracted_dir = extracted.path().to_path_buf();
+    let mut session = InstrumentationSession::new(SessionConfig {
+        quiet: true,
+        git_metadata: false,
+        ..session_config(&server, extracted_dir.clone(), vec!["true".to_string()])
+    })
+    .unwrap();
+    session.prepare().await.unwrap();
+    session.create_vault().await.unwrap();
+    session.collect().unwrap();
+    session.instrument().await.unwrap();
+    let archived = write_archive(session.ariana_dir(), &dir.join("instrumented.zip")).unwrap();
+    drop(session);
+    drop(extracted);
+
+    assert!(!extracted_dir.exists());
+    let mut zip =
+        zip::ZipArchive::new(fs::File::open(dir.join("instrumented.zip")).unwrap()).unwrap();
+    assert_eq!(archived, zip.len());
+    let mut app = String::new();
+    zip.by_name("src/app.js")
+        .unwrap()
+        .read_to_string(&mut app)
+        .unwrap();
+    assert_eq!(
+        app,
+        format!("{}console.log('hello');\n", INSTRUMENTED_HEADER)
+    );
+    assert!(zip.by_name("README.md").is_ok());
+    assert!(zip.by_name(".vault_secret_key").is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn transcripts_of_archived_projects_are_written_in_the_current_directory() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = runtime.block_on(MockServer::start());
+    let dir = test_dir("archive-transcript");
+    zip_files(
+        &dir.join("project.zip"),
+        &[("src/app.js", "console.log('hello');\n")],
+    );
+
+    let output = ariana()
+        .args(["--api-url", &server.url, "--from-archive", "project.zip"])
+        .args(["--transcript", "transcript.log", "echo", "archived"])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let transcript = fs::read_to_string(dir.join("transcript.log")).unwrap();
+    assert!(transcript.contains("archived"));
+}
+
+#[test]
+fn archive_entries_outside_of_the_project_are_refused() {
+    let dir = test_dir("archive-traversal");
+    let archive = dir.join("project").join("project.zip");
+    fs::create_dir_all(archive.parent().unwrap()).unwrap();
+    zip_files(
+        &archive,
+        &[("src/app.js", "f();\n"), ("../../escape.js", "g();\n")],
+    );
+
+    let error = ExtractedArchive::extract(&archive).err().unwrap();
+
+    assert!(error.to_string().contains("outside of the project"));
+    assert!(!std::env::temp_dir().join("escape.js").exists());
+}
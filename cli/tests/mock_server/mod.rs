@@ -0,0 +1,293 @@
+//! A stand-in for the Ariana server, serving the endpoints the CLI uses over plain HTTP/1.1 and
+//! WebSocket on a local port, and recording what it receives so that tests can check it.
+
+use std::io::Read;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use ariana_cli::capabilities::ServerCapabilities;
+use ariana_server::traces::Trace;
+use ariana_server::web::traces::instrument::{
+    CodeInstrumentationBatchRequest, CodeInstrumentationBatchResponse,
+};
+use ariana_server::web::traces::PushTracesRequest;
+use ariana_server::web::vaults::{GetTraceTreeLLMResponse, VaultPublicData};
+use futures_util::StreamExt;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Key of the vault every `create` request gets.
+pub const VAULT_KEY: &str = "mock-vault-key";
+
+/// Prepended by `instrument-batched` to the files it instruments.
+pub const INSTRUMENTED_HEADER: &str = "// instrumented by the mock server\n";
+
+/// What the server received, and how it should misbehave.
+#[derive(Debug, Default)]
+pub struct State {
+    /// `METHOD path` of every request, in order.
+    pub requests: Vec<String>,
+    /// `METHOD path` of the requests sent with an `Authorization` header, and its value.
+    pub authorized_requests: Vec<(String, String)>,
+    /// Paths of the files sent to `instrument-batched`.
+    pub instrumented_files: Vec<String>,
+    /// Number of files of each `instrument-batched` request, in order.
+    pub batch_sizes: Vec<usize>,
+    /// Language sent along with each file of `instrumented_files`, if any.
+    pub files_languages: Vec<Option<String>>,
+    pub traces: Vec<Trace>,
+    /// Lines received on `subprocess-stdout/stream`.
+    pub output_lines: Vec<String>,
+    /// Exit code sent to `finish`.
+    pub finished: Option<i32>,
+    /// Answer of `get-trace-tree`.
+    pub recap: Option<String>,
+    /// The next pushes answer 503 Service Unavailable.
+    pub failing_pushes: usize,
+    /// `instrument-batched` answers 500 Internal Server Error.
+    pub failing_instrumentation: bool,
+    /// `instrument-batched` answers `null` for files with this in their path.
+    pub declined_suffix: Option<String>,
+    /// Answer of `unauthenticated/capabilities`, which is not found without it.
+    pub capabilities: Option<ServerCapabilities>,
+    /// Whether `status` answers with the number of traces pushed, it is not found otherwise.
+    pub vault_status: bool,
+    /// WebSocket upgrades are answered 404 Not Found, like by a server without WebSockets.
+    pub refuse_websockets: bool,
+    /// `finish` answers after this long.
+    pub finish_delay: Option<Duration>,
+    /// Responses are sent chunked, without `content-length`.
+    pub chunked_responses: bool,
+}
+
+pub struct MockServer {
+    /// API URL to point the CLI at.
+    pub url: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+        let server_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle_connection(stream, server_state.clone()));
+            }
+        });
+        MockServer { url, state }
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+async fn handle_connection(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let refuse_websockets = state.lock().unwrap().refuse_websockets;
+    if !refuse_websockets && is_websocket_upgrade(&stream).await {
+        handle_websocket(stream, state).await;
+        return;
+    }
+
+    let mut reader = BufReader::new(stream);
+    while let Some(request) = read_request(&mut reader).await {
+        let (status, body) = respond(&request, &state);
+        let (finish_delay, chunked) = {
+            let state = state.lock().unwrap();
+            (state.finish_delay, state.chunked_responses)
+        };
+        if let Some(delay) = finish_delay.filter(|_| request.path.ends_with("/finish")) {
+            tokio::time::sleep(delay).await;
+        }
+        let (length_header, body) = if chunked {
+            let mut chunked_body = format!("{:x}\r\n", body.len()).into_bytes();
+            chunked_body.extend_from_slice(&body);
+            chunked_body.extend_from_slice(b"\r\n0\r\n\r\n");
+            ("transfer-encoding: chunked".to_string(), chunked_body)
+        } else {
+            (format!("content-length: {}", body.len()), body)
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\ncontent-type: application/json\r\netag: \"mock\"\r\n{}\r\n\r\n",
+            status, length_header
+        );
+        let stream = reader.get_mut();
+        if stream.write_all(response.as_bytes()).await.is_err()
+            || stream.write_all(&body).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Whether the first request of the connection asks to upgrade it to a WebSocket.
+async fn is_websocket_upgrade(stream: &TcpStream) -> bool {
+    let mut head = [0; 4096];
+    let Ok(read) = stream.peek(&mut head).await else {
+        return false;
+    };
+    String::from_utf8_lossy(&head[..read])
+        .to_ascii_lowercase()
+        .contains("upgrade: websocket")
+}
+
+struct Request {
+    method: String,
+    path: String,
+    gzipped: bool,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+/// Reads a request of a keep-alive connection, `None` once the client closes it.
+async fn read_request(reader: &mut BufReader<TcpStream>) -> Option<Request> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).await.ok()? == 0 {
+        return None;
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+    let mut content_length = 0;
+    let mut gzipped = false;
+    let mut authorization = None;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).await.ok()?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':')?;
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => content_length = value.trim().parse().ok()?,
+            "content-encoding" => gzipped = value.trim() == "gzip",
+            "authorization" => authorization = Some(value.trim().to_string()),
+            _ => {}
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await.ok()?;
+    Some(Request {
+        method,
+        path,
+        gzipped,
+        authorization,
+        body,
+    })
+}
+
+fn respond(request: &Request, state: &Mutex<State>) -> (&'static str, Vec<u8>) {
+    let mut state = state.lock().unwrap();
+    let request_line = format!("{} {}", request.method, request.path);
+    if let Some(authorization) = &request.authorization {
+        state
+            .authorized_requests
+            .push((request_line.clone(), authorization.clone()));
+    }
+    state.requests.push(request_line);
+    let body = if request.gzipped {
+        let mut body = vec![];
+        flate2::read::GzDecoder::new(&request.body[..])
+            .read_to_end(&mut body)
+            .unwrap();
+        body
+    } else {
+        request.body.clone()
+    };
+
+    let path = request.path.trim_start_matches('/');
+    if path == "unauthenticated/vaults/create" {
+        return ok(&VaultPublicData {
+            secret_key: VAULT_KEY.to_string(),
+            created_at: 0,
+            command: None,
+            cwd: None,
+        });
+    }
+    if path == "unauthenticated/capabilities" {
+        if let Some(capabilities) = &state.capabilities {
+            return ok(capabilities);
+        }
+    }
+    if path.ends_with("/instrument-batched") {
+        if state.failing_instrumentation {
+            return ("500 Internal Server Error", vec![]);
+        }
+        let batch: CodeInstrumentationBatchRequest = serde_json::from_slice(&body).unwrap();
+        let instrumented_contents = batch
+            .files_paths
+            .iter()
+            .zip(batch.files_contents)
+            .map(|(path, content)| match &state.declined_suffix {
+                Some(suffix) if path.ends_with(suffix.as_str()) => None,
+                _ => Some(format!("{}{}", INSTRUMENTED_HEADER, content)),
+            })
+            .collect();
+        let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let languages = payload["files_languages"].as_array().cloned();
+        state
+            .files_languages
+            .extend((0..batch.files_paths.len()).map(|i| {
+                languages
+                    .as_ref()
+                    .and_then(|languages| languages[i].as_str())
+                    .map(str::to_string)
+            }));
+        state.batch_sizes.push(batch.files_paths.len());
+        state.instrumented_files.extend(batch.files_paths);
+        return ok(&CodeInstrumentationBatchResponse {
+            instrumented_contents,
+        });
+    }
+    if path.ends_with("/push") {
+        if state.failing_pushes > 0 {
+            state.failing_pushes -= 1;
+            return ("503 Service Unavailable", vec![]);
+        }
+        let push: PushTracesRequest = serde_json::from_slice(&body).unwrap();
+        state.traces.extend(push.traces);
+        return ("200 OK", vec![]);
+    }
+    if path.ends_with("/finish") {
+        let finish: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        state.finished = finish["exit_code"].as_i64().map(|code| code as i32);
+        return ("200 OK", vec![]);
+    }
+    if path.ends_with("/status") && state.vault_status {
+        return (
+            "200 OK",
+            format!("{{\"traces_received\":{}}}", state.traces.len()).into_bytes(),
+        );
+    }
+    if path.ends_with("/get-trace-tree") {
+        return match &state.recap {
+            Some(recap) => ok(&GetTraceTreeLLMResponse {
+                answer: recap.clone(),
+            }),
+            None => ("200 OK", b"{\"answer\":null}".to_vec()),
+        };
+    }
+    ("404 Not Found", vec![])
+}
+
+fn ok(body: &impl serde::Serialize) -> (&'static str, Vec<u8>) {
+    ("200 OK", serde_json::to_vec(body).unwrap())
+}
+
+async fn handle_websocket(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let Ok(mut websocket) = tokio_tungstenite::accept_async(stream).await else {
+        return;
+    };
+    while let Some(Ok(message)) = websocket.next().await {
+        if let Ok(text) = message.to_text() {
+            if let Ok(output) = serde_json::from_str::<serde_json::Value>(text) {
+                let line = output["line"].as_str().unwrap_or_default().to_string();
+                state.lock().unwrap().output_lines.push(line);
+            }
+        }
+    }
+}